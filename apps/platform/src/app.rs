@@ -70,10 +70,109 @@ async fn request_auth(endpoint: &str, payload: &AuthBody, action: &str) -> Resul
 #[cfg(feature = "hydrate")]
 #[derive(Debug, Clone, Deserialize)]
 struct ChatHistoryItem {
+    id: i64,
     room_id: String,
     from_user: String,
     text: String,
     ts: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    reply_count: i64,
+    #[serde(default)]
+    attachments: Vec<shared::ChatAttachment>,
+    #[serde(default)]
+    e2ee: Option<shared::E2eePayload>,
+    #[serde(default)]
+    format: shared::MessageFormat,
+    #[serde(default)]
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ScheduledItem {
+    id: i64,
+    room_id: String,
+    text: String,
+    send_at: chrono::DateTime<chrono::Utc>,
+    status: String,
+    last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct MatchmakingStatus {
+    queued: bool,
+    mode: Option<String>,
+    rating: Option<f64>,
+    waited_secs: i64,
+    rating_window: Option<f64>,
+    radius_km: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct MatchReplayItem {
+    match_id: String,
+    mode: String,
+    winner: Option<String>,
+    tick_hz: i32,
+    frames: i32,
+    created_at: chrono::DateTime<chrono::Utc>,
+    url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LiveMatchPlayer {
+    username: String,
+    lon: Option<f64>,
+    lat: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LiveMatchItem {
+    match_id: String,
+    mode: String,
+    players: Vec<LiveMatchPlayer>,
+    delay_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LeaderboardEntry {
+    rank: usize,
+    user_id: String,
+    username: String,
+    rating: f64,
+    rd: f64,
+    games: i32,
+    distance_km: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LeaderboardView {
+    mode: String,
+    radius_km: Option<f64>,
+    entries: Vec<LeaderboardEntry>,
+    me: Option<LeaderboardEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SendChatResult {
+    #[serde(default)]
+    scheduled_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PollView {
+    #[serde(flatten)]
+    poll: shared::PollState,
+    #[serde(default)]
+    my_choices: Vec<u32>,
+}
+
+#[derive(Debug, Clone)]
+struct ChatLine {
+    id: Option<i64>,
+    meta: String,
+    text: String,
+    format: shared::MessageFormat,
+    suffix: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -89,6 +188,14 @@ struct RoomStateResponse {
     members: Vec<RoomMemberState>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+struct InboxItem {
+    room_id: String,
+    unread_count: i64,
+    last_preview: Option<String>,
+    last_activity_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct InviteItem {
     invite_id: String,
@@ -97,6 +204,34 @@ struct InviteItem {
     mode: String,
     status: String,
     ts: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct NotificationItem {
+    id: i64,
+    room_id: String,
+    message_id: i64,
+    from_username: String,
+    preview: String,
+    ts: chrono::DateTime<chrono::Utc>,
+    read: bool,
+}
+
+#[cfg(feature = "hydrate")]
+#[derive(Debug, Clone, Deserialize)]
+struct NotificationsResponse {
+    items: Vec<NotificationItem>,
+}
+
+#[cfg(feature = "hydrate")]
+#[derive(Debug, Clone, Deserialize)]
+struct LocalRoomActivity {
+    room_id: String,
+    bounds: [f64; 4],
+    message_count: i64,
+    member_count: i64,
 }
 
 #[derive(Debug, Clone)]
@@ -129,6 +264,8 @@ async fn fetch_ready_status() -> Option<ReadyResponse> {
 }
 
 const CHAT_HISTORY_PAGE_SIZE: i64 = 20;
+#[cfg(feature = "hydrate")]
+const CHAT_SEND_ATTEMPTS: usize = 3;
 
 #[cfg(feature = "hydrate")]
 async fn load_pending_invites(token: &str) -> Result<Vec<InviteItem>, String> {
@@ -144,14 +281,136 @@ async fn load_pending_invites(token: &str) -> Result<Vec<InviteItem>, String> {
 }
 
 #[cfg(feature = "hydrate")]
-async fn load_history_page(room_id: &str, page: i64) -> Result<Vec<String>, String> {
+async fn load_active_match(token: &str) -> Result<Option<shared::MatchReady>, String> {
+    let url = format!("/api/match/active?token={}", urlencoding::encode(token));
+    let resp = gloo_net::http::Request::get(&url)
+        .send()
+        .await
+        .map_err(|_| "加载对局失败".to_string())?;
+    if !resp.ok() {
+        return Err(format!("加载对局失败（HTTP {}）", resp.status()));
+    }
+
+    resp.json::<Option<shared::MatchReady>>()
+        .await
+        .map_err(|_| "解析对局失败".to_string())
+}
+
+#[cfg(feature = "hydrate")]
+async fn load_match_replays(token: &str) -> Result<Vec<MatchReplayItem>, String> {
+    let url = format!("/api/match/replays?token={}", urlencoding::encode(token));
+    let resp = gloo_net::http::Request::get(&url)
+        .send()
+        .await
+        .map_err(|_| "加载对局回放失败".to_string())?;
+    if !resp.ok() {
+        return Err(format!("加载对局回放失败（HTTP {}）", resp.status()));
+    }
+
+    resp.json::<Vec<MatchReplayItem>>()
+        .await
+        .map_err(|_| "解析对局回放失败".to_string())
+}
+
+#[cfg(feature = "hydrate")]
+async fn load_leaderboard(token: &str, near: bool) -> Result<LeaderboardView, String> {
+    let url = format!("/api/leaderboard?token={}&mode=duel&near={near}", urlencoding::encode(token));
+    let resp = gloo_net::http::Request::get(&url)
+        .send()
+        .await
+        .map_err(|_| "加载排行榜失败".to_string())?;
+    if !resp.ok() {
+        let msg = resp
+            .json::<ApiErrorBody>()
+            .await
+            .map(|e| e.error)
+            .unwrap_or_else(|_| format!("HTTP {}", resp.status()));
+        return Err(format!("加载排行榜失败：{}", msg));
+    }
+
+    resp.json::<LeaderboardView>()
+        .await
+        .map_err(|_| "解析排行榜失败".to_string())
+}
+
+#[cfg(feature = "hydrate")]
+async fn load_matchmaking_status(token: &str) -> Result<MatchmakingStatus, String> {
+    let url = format!("/api/matchmaking/status?token={}", urlencoding::encode(token));
+    let resp = gloo_net::http::Request::get(&url)
+        .send()
+        .await
+        .map_err(|_| "加载匹配状态失败".to_string())?;
+    if !resp.ok() {
+        return Err(format!("加载匹配状态失败（HTTP {}）", resp.status()));
+    }
+
+    resp.json::<MatchmakingStatus>()
+        .await
+        .map_err(|_| "解析匹配状态失败".to_string())
+}
+
+#[cfg(feature = "hydrate")]
+async fn load_polls(token: &str, room_id: &str) -> Result<Vec<PollView>, String> {
+    let url = format!(
+        "/api/chat/polls?token={}&room_id={}",
+        urlencoding::encode(token),
+        urlencoding::encode(room_id)
+    );
+    let resp = gloo_net::http::Request::get(&url)
+        .send()
+        .await
+        .map_err(|_| "加载投票失败".to_string())?;
+    if !resp.ok() {
+        return Err(format!("加载投票失败（HTTP {}）", resp.status()));
+    }
+
+    resp.json::<Vec<PollView>>()
+        .await
+        .map_err(|_| "解析投票失败".to_string())
+}
+
+#[cfg(feature = "hydrate")]
+async fn load_scheduled(token: &str) -> Result<Vec<ScheduledItem>, String> {
+    let url = format!("/api/chat/scheduled?token={}&limit=50", urlencoding::encode(token));
+    let resp = gloo_net::http::Request::get(&url)
+        .send()
+        .await
+        .map_err(|_| "加载定时消息失败".to_string())?;
+    if !resp.ok() {
+        return Err(format!("加载定时消息失败（HTTP {}）", resp.status()));
+    }
+
+    resp.json::<Vec<ScheduledItem>>()
+        .await
+        .map_err(|_| "解析定时消息失败".to_string())
+}
+
+#[cfg(feature = "hydrate")]
+async fn load_notifications(token: &str) -> Result<Vec<NotificationItem>, String> {
+    let url = format!("/api/notifications?token={}&limit=50", urlencoding::encode(token));
+    let resp = gloo_net::http::Request::get(&url)
+        .send()
+        .await
+        .map_err(|_| "加载提及通知失败".to_string())?;
+
+    resp.json::<NotificationsResponse>()
+        .await
+        .map(|body| body.items)
+        .map_err(|_| "解析提及通知失败".to_string())
+}
+
+#[cfg(feature = "hydrate")]
+async fn load_history_page(room_id: &str, page: i64, token: Option<&str>) -> Result<Vec<ChatLine>, String> {
     let page = page.max(1);
     let limit = (page * CHAT_HISTORY_PAGE_SIZE).clamp(CHAT_HISTORY_PAGE_SIZE, 500);
-    let url = format!(
+    let mut url = format!(
         "/api/chat/history?room_id={}&limit={}",
         urlencoding::encode(room_id),
         limit
     );
+    if let Some(token) = token {
+        url.push_str(&format!("&token={}", urlencoding::encode(token)));
+    }
 
     let resp = gloo_net::http::Request::get(&url)
         .send()
@@ -166,17 +425,131 @@ async fn load_history_page(room_id: &str, page: i64) -> Result<Vec<String>, Stri
     Ok(rows
         .into_iter()
         .map(|r| {
-            format!(
-                "[{}][{}] {}: {}",
-                r.room_id,
-                r.ts.format("%H:%M:%S"),
-                r.from_user.chars().take(8).collect::<String>(),
-                r.text
-            )
+            let replies = if r.reply_count > 0 {
+                format!(" ({}条回复)", r.reply_count)
+            } else {
+                String::new()
+            };
+            let files = r
+                .attachments
+                .iter()
+                .map(|a| format!(" [附件: {}]", a.file_name))
+                .collect::<String>();
+            ChatLine {
+                id: Some(r.id),
+                meta: format!(
+                    "[{}][{}] #{} {}:",
+                    r.room_id,
+                    r.ts.format("%H:%M:%S"),
+                    r.id,
+                    r.from_user.chars().take(8).collect::<String>()
+                ),
+                text: display_text(&r.room_id, &r.text, r.e2ee.as_ref()),
+                format: r.format,
+                suffix: format!("{}{}{}", files, replies, expiry_tag(r.expires_at)),
+            }
         })
         .collect())
 }
 
+#[cfg(feature = "hydrate")]
+thread_local! {
+    static REALTIME_SOCKET: std::cell::RefCell<Option<web_sys::WebSocket>> = const { std::cell::RefCell::new(None) };
+    static E2EE_KEYS: std::cell::RefCell<Option<(uuid::Uuid, crate::e2ee::KeyStore)>> = const { std::cell::RefCell::new(None) };
+}
+
+#[cfg(feature = "hydrate")]
+fn expiry_tag(expires_at: Option<chrono::DateTime<chrono::Utc>>) -> String {
+    expires_at
+        .map(|at| format!(" ⏳{} 到期", at.format("%H:%M:%S")))
+        .unwrap_or_default()
+}
+
+#[cfg(feature = "hydrate")]
+fn display_text(room_id: &str, text: &str, e2ee: Option<&shared::E2eePayload>) -> String {
+    let Some(payload) = e2ee else {
+        return text.to_string();
+    };
+    E2EE_KEYS.with(|slot| match slot.borrow().as_ref() {
+        Some((me, keys)) => match crate::e2ee::decrypt(keys, *me, room_id, payload) {
+            Ok(plaintext) => format!("🔒 {}", plaintext),
+            Err(err) => format!("[无法解密: {}]", err),
+        },
+        None => "[加密消息]".to_string(),
+    })
+}
+
+#[cfg(feature = "hydrate")]
+async fn publish_key_bundle(token: &str, user_id: &str) -> Result<(), String> {
+    let Ok(me) = uuid::Uuid::parse_str(user_id) else {
+        return Err("用户ID无效".to_string());
+    };
+    let keys = crate::e2ee::load_or_create(user_id);
+    let bundle = keys.bundle(me);
+    E2EE_KEYS.with(|slot| *slot.borrow_mut() = Some((me, keys)));
+
+    let payload = serde_json::json!({
+        "token": token,
+        "identity_key": bundle.identity_key,
+        "prekey_id": bundle.prekey_id,
+        "prekey": bundle.prekey,
+        "prekey_signature": bundle.prekey_signature,
+    });
+    let req = gloo_net::http::Request::post("/api/keys/bundle")
+        .header("content-type", "application/json")
+        .body(payload.to_string())
+        .map_err(|_| "密钥请求构建失败".to_string())?;
+    match req.send().await {
+        Ok(resp) if resp.ok() => Ok(()),
+        _ => Err("发布加密密钥失败".to_string()),
+    }
+}
+
+#[cfg(feature = "hydrate")]
+async fn encrypt_for_dm(token: &str, room_id: &str, text: &str) -> Result<shared::E2eePayload, String> {
+    let (me, own_bundle) = E2EE_KEYS
+        .with(|slot| slot.borrow().as_ref().map(|(me, keys)| (*me, keys.bundle(*me))))
+        .ok_or_else(|| "本地加密密钥未就绪".to_string())?;
+    let peer = shared::dm::peer_of(room_id, me).ok_or_else(|| "仅私信支持端到端加密".to_string())?;
+
+    let url = format!(
+        "/api/keys/bundle?token={}&user_id={}",
+        urlencoding::encode(token),
+        peer
+    );
+    let resp = gloo_net::http::Request::get(&url)
+        .send()
+        .await
+        .map_err(|_| "获取对方密钥失败".to_string())?;
+    if resp.status() == 404 {
+        return Err("对方尚未开启端到端加密".to_string());
+    }
+    let peer_bundle = resp
+        .json::<shared::KeyBundle>()
+        .await
+        .map_err(|_| "对方密钥解析失败".to_string())?;
+    if peer_bundle.user_id != peer {
+        return Err("对方密钥不匹配".to_string());
+    }
+    crate::e2ee::check_pinned_identity(&peer_bundle)?;
+
+    crate::e2ee::encrypt(text, room_id, &[peer_bundle, own_bundle])
+}
+
+#[cfg(feature = "hydrate")]
+fn send_realtime(packet: &shared::RealtimePacket) -> bool {
+    let Ok(bin) = rmp_serde::to_vec(packet) else {
+        return false;
+    };
+    REALTIME_SOCKET.with(|slot| {
+        slot.borrow()
+            .as_ref()
+            .is_some_and(|ws| ws.send_with_u8_array(&bin).is_ok())
+    })
+}
+
+const TYPING_SEND_INTERVAL_MS: f64 = 2_000.0;
+
 #[cfg(feature = "hydrate")]
 fn ws_url(token: &str) -> Option<String> {
     let window = web_sys::window()?;
@@ -187,6 +560,93 @@ fn ws_url(token: &str) -> Option<String> {
     Some(format!("{ws_proto}://{host}/ws?token={token}"))
 }
 
+#[cfg(feature = "hydrate")]
+fn match_ws_url(token: &str, ready: &shared::MatchReady) -> Option<String> {
+    let window = web_sys::window()?;
+    let location = window.location();
+    let host = location.host().ok()?;
+    let protocol = location.protocol().ok()?;
+    let ws_proto = if protocol == "https:" { "wss" } else { "ws" };
+    Some(format!("{ws_proto}://{host}{}&token={token}", ready.ws_path))
+}
+
+// The game client is a separate wasm bundle served from /game; a module script boots it into the canvas.
+// Bevy owns the event loop once started, so only one match client runs per page load.
+#[cfg(feature = "hydrate")]
+fn launch_game(ws_url: &str, slot: usize, user_id: &str) -> bool {
+    let Some(document) = web_sys::window().and_then(|w| w.document()) else {
+        return false;
+    };
+    let Some(body) = document.body() else {
+        return false;
+    };
+    let Ok(script) = document.create_element("script") else {
+        return false;
+    };
+    let source = format!(
+        "import init, {{ start_match }} from \"/game/game_wasm.js\";\nawait init();\nstart_match(\"#{GAME_CANVAS_ID}\", {}, {slot}, {});",
+        serde_json::to_string(ws_url).unwrap_or_default(),
+        serde_json::to_string(user_id).unwrap_or_default(),
+    );
+    let _ = script.set_attribute("type", "module");
+    script.set_text_content(Some(&source));
+    body.append_child(&script).is_ok()
+}
+
+#[cfg(feature = "hydrate")]
+fn match_watch_url(token: &str, match_id: &str) -> Option<String> {
+    let window = web_sys::window()?;
+    let location = window.location();
+    let host = location.host().ok()?;
+    let protocol = location.protocol().ok()?;
+    let ws_proto = if protocol == "https:" { "wss" } else { "ws" };
+    Some(format!("{ws_proto}://{host}/ws/match/watch?match_id={match_id}&token={token}"))
+}
+
+// Spectating reuses the match canvas with a read-only client fed by delayed snapshots.
+#[cfg(feature = "hydrate")]
+fn launch_spectate(ws_url: &str) -> bool {
+    let Some(document) = web_sys::window().and_then(|w| w.document()) else {
+        return false;
+    };
+    let Some(body) = document.body() else {
+        return false;
+    };
+    let Ok(script) = document.create_element("script") else {
+        return false;
+    };
+    let source = format!(
+        "import init, {{ start_spectate }} from \"/game/game_wasm.js\";\nawait init();\nstart_spectate(\"#{GAME_CANVAS_ID}\", {});",
+        serde_json::to_string(ws_url).unwrap_or_default(),
+    );
+    let _ = script.set_attribute("type", "module");
+    script.set_text_content(Some(&source));
+    body.append_child(&script).is_ok()
+}
+
+// Replays play in the same canvas; the script fetches the recording from its presigned URL.
+#[cfg(feature = "hydrate")]
+fn launch_replay(replay_url: &str) -> bool {
+    let Some(document) = web_sys::window().and_then(|w| w.document()) else {
+        return false;
+    };
+    let Some(body) = document.body() else {
+        return false;
+    };
+    let Ok(script) = document.create_element("script") else {
+        return false;
+    };
+    let source = format!(
+        "import init, {{ start_replay }} from \"/game/game_wasm.js\";\nawait init();\nconst bytes = new Uint8Array(await (await fetch({})).arrayBuffer());\nstart_replay(\"#{GAME_CANVAS_ID}\", bytes);",
+        serde_json::to_string(replay_url).unwrap_or_default(),
+    );
+    let _ = script.set_attribute("type", "module");
+    script.set_text_content(Some(&source));
+    body.append_child(&script).is_ok()
+}
+
+const GAME_CANVAS_ID: &str = "game-canvas";
+
 #[cfg(feature = "hydrate")]
 fn build_geojson(users: &[NearbyUserDto], me: Option<&str>) -> String {
     let features = users
@@ -217,6 +677,90 @@ fn build_geojson(users: &[NearbyUserDto], me: Option<&str>) -> String {
     .to_string()
 }
 
+// One marker per located player, so a local match shows up where its duelists are.
+#[cfg(feature = "hydrate")]
+fn build_live_geojson(matches: &[LiveMatchItem]) -> String {
+    let features = matches
+        .iter()
+        .flat_map(|m| {
+            let label = format!(
+                "⚔ {}",
+                m.players.iter().map(|p| p.username.as_str()).collect::<Vec<_>>().join(" vs ")
+            );
+            m.players.iter().filter_map(move |p| {
+                Some(serde_json::json!({
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "Point",
+                        "coordinates": [p.lon?, p.lat?]
+                    },
+                    "properties": {
+                        "match_id": m.match_id,
+                        "label": label
+                    }
+                }))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::json!({
+        "type": "FeatureCollection",
+        "features": features
+    })
+    .to_string()
+}
+
+#[cfg(feature = "hydrate")]
+fn build_activity_geojson(cells: &[LocalRoomActivity]) -> String {
+    let features = cells
+        .iter()
+        .map(|c| {
+            let [min_lon, min_lat, max_lon, max_lat] = c.bounds;
+            serde_json::json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[
+                        [min_lon, min_lat],
+                        [max_lon, min_lat],
+                        [max_lon, max_lat],
+                        [min_lon, max_lat],
+                        [min_lon, min_lat]
+                    ]]
+                },
+                "properties": {
+                    "room_id": c.room_id,
+                    "message_count": c.message_count,
+                    "member_count": c.member_count
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::json!({
+        "type": "FeatureCollection",
+        "features": features
+    })
+    .to_string()
+}
+
+#[cfg(feature = "hydrate")]
+async fn refresh_chat_activity(lon: f64, lat: f64) {
+    let url = format!(
+        "/api/map/chat-activity?min_lon={}&min_lat={}&max_lon={}&max_lat={}",
+        lon - 0.1,
+        lat - 0.1,
+        lon + 0.1,
+        lat + 0.1
+    );
+    let Ok(resp) = gloo_net::http::Request::get(&url).send().await else {
+        return;
+    };
+    if let Ok(cells) = resp.json::<Vec<LocalRoomActivity>>().await {
+        crate::map::update_chat_activity_geojson(&build_activity_geojson(&cells));
+    }
+}
+
 #[cfg(feature = "hydrate")]
 fn connect_realtime(
     token: String,
@@ -225,9 +769,16 @@ fn connect_realtime(
     ws_connected: RwSignal<bool>,
     refresh_tick: RwSignal<u64>,
     status: RwSignal<String>,
-    chat_messages: RwSignal<Vec<String>>,
+    chat_messages: RwSignal<Vec<ChatLine>>,
     invite_events: RwSignal<Vec<String>>,
     pending_invites: RwSignal<Vec<InviteItem>>,
+    typing_users: RwSignal<Vec<shared::TypingEvent>>,
+    local_room: RwSignal<Option<String>>,
+    joined_rooms: RwSignal<Vec<String>>,
+    notifications: RwSignal<Vec<NotificationItem>>,
+    pending_sends: RwSignal<Vec<(uuid::Uuid, String)>>,
+    room_polls: RwSignal<Vec<PollView>>,
+    current_match: RwSignal<Option<shared::MatchReady>>,
 ) {
     let Some(url) = ws_url(&token) else {
         status.set("WebSocket 地址生成失败".to_string());
@@ -240,6 +791,7 @@ fn connect_realtime(
     };
 
     ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
+    REALTIME_SOCKET.with(|slot| *slot.borrow_mut() = Some(ws.clone()));
 
     let on_open_connected = ws_connected;
     let on_open_status = status;
@@ -263,21 +815,77 @@ fn connect_realtime(
     let on_msg_chat = chat_messages;
     let on_msg_invite_events = invite_events;
     let on_msg_pending_invites = pending_invites;
+    let on_msg_typing = typing_users;
+    let on_msg_local_room = local_room;
+    let on_msg_joined = joined_rooms;
+    let on_msg_notifications = notifications;
+    let on_msg_pending = pending_sends;
+    let on_msg_polls = room_polls;
+    let on_msg_match = current_match;
     let my_uid = user_id.clone();
 
+    let mut last_mailbox_id = 0_i64;
+
     let on_message = Closure::wrap(Box::new(move |event: web_sys::MessageEvent| {
         if let Ok(buf) = event.data().dyn_into::<js_sys::ArrayBuffer>() {
             let bytes = js_sys::Uint8Array::new(&buf).to_vec();
             if let Ok(packet) = rmp_serde::from_slice::<shared::RealtimePacket>(&bytes) {
+                let packet = match packet {
+                    shared::RealtimePacket::Mailbox(envelope) => {
+                        // Drained backlog and live copies can overlap; ids are ordered per user.
+                        if envelope.user_id.to_string() != my_uid || envelope.id <= last_mailbox_id {
+                            return;
+                        }
+                        last_mailbox_id = envelope.id;
+                        send_realtime(&shared::RealtimePacket::MailboxAck(shared::MailboxAck { up_to: envelope.id }));
+                        *envelope.packet
+                    }
+                    other => other,
+                };
                 match packet {
                     shared::RealtimePacket::Chat(chat) => {
+                        if let Some(client_id) = chat.client_id {
+                            on_msg_pending.update(|list| list.retain(|(id, _)| *id != client_id));
+                        }
+                        on_msg_typing.update(|list| {
+                            list.retain(|t| !(t.room_id == chat.room_id && t.user_id == chat.from_user));
+                        });
+                        if let (Some(message_id), Ok(me)) = (chat.id, uuid::Uuid::parse_str(&my_uid)) {
+                            // Only rooms we hold a membership in get delivery receipts.
+                            let member = shared::dm::is_participant(&chat.room_id, me)
+                                || on_msg_joined.with_untracked(|rooms| rooms.contains(&chat.room_id));
+                            if chat.from_user != me && member {
+                                send_realtime(&shared::RealtimePacket::Receipt(shared::ReceiptEvent {
+                                    room_id: chat.room_id.clone(),
+                                    message_id,
+                                    user_id: me,
+                                    kind: shared::ReceiptKind::Delivered,
+                                    ts: chrono::Utc::now(),
+                                }));
+                            }
+                        }
+                        let thread_tag = chat
+                            .parent_id
+                            .map(|parent| format!(" ↳#{}", parent))
+                            .unwrap_or_default();
+                        let files = chat
+                            .attachments
+                            .iter()
+                            .map(|a| format!(" [附件: {}]", a.file_name))
+                            .collect::<String>();
                         on_msg_chat.update(|list| {
-                            list.push(format!(
-                                "[{}] {}: {}",
-                                chat.room_id,
-                                chat.from_user.to_string().chars().take(8).collect::<String>(),
-                                chat.text
-                            ));
+                            list.push(ChatLine {
+                                id: chat.id,
+                                meta: format!(
+                                    "[{}]{} {}:",
+                                    chat.room_id,
+                                    thread_tag,
+                                    chat.from_user.to_string().chars().take(8).collect::<String>()
+                                ),
+                                text: display_text(&chat.room_id, &chat.text, chat.e2ee.as_ref()),
+                                format: chat.format,
+                                suffix: format!("{}{}", files, expiry_tag(chat.expires_at)),
+                            });
                             if list.len() > 200 {
                                 let keep_from = list.len().saturating_sub(200);
                                 *list = list[keep_from..].to_vec();
@@ -293,7 +901,7 @@ fn connect_realtime(
                             from_id.chars().take(8).collect::<String>(),
                             to_id.chars().take(8).collect::<String>(),
                             inv.mode,
-                            inv.status
+                            shared::invite::as_str(inv.status)
                         );
 
                         on_msg_invite_events.update(|list| {
@@ -305,14 +913,15 @@ fn connect_realtime(
                         });
 
                         on_msg_pending_invites.update(|list| {
-                            if inv.status == "pending" && to_id == my_uid {
+                            if inv.status == shared::InviteStatus::Pending && (to_id == my_uid || from_id == my_uid) {
                                 let incoming = InviteItem {
                                     invite_id: inv.invite_id.to_string(),
                                     from_user: from_id,
                                     to_user: to_id,
                                     mode: inv.mode,
-                                    status: inv.status,
+                                    status: shared::invite::as_str(inv.status).to_string(),
                                     ts: inv.ts,
+                                    expires_at: inv.expires_at,
                                 };
                                 if !list.iter().any(|it| it.invite_id == incoming.invite_id) {
                                     list.push(incoming);
@@ -322,6 +931,76 @@ fn connect_realtime(
                             }
                         });
                     }
+                    shared::RealtimePacket::LocalRoom(local) => {
+                        if local.user_id.to_string() == my_uid {
+                            on_msg_local_room.set(Some(local.room_id));
+                        }
+                        return;
+                    }
+                    shared::RealtimePacket::Redaction(redaction) => {
+                        on_msg_chat.update(|list| list.retain(|line| line.id != Some(redaction.message_id)));
+                        return;
+                    }
+                    shared::RealtimePacket::MatchReady(ready) => {
+                        if !ready.players.iter().any(|p| p.to_string() == my_uid) {
+                            return;
+                        }
+                        if shared::matches::is_live(ready.state) {
+                            on_msg_match.set(Some(ready));
+                        } else {
+                            on_msg_match.update(|current| {
+                                if current.as_ref().is_some_and(|m| m.match_id == ready.match_id) {
+                                    *current = None;
+                                }
+                            });
+                        }
+                        return;
+                    }
+                    shared::RealtimePacket::Poll(poll) => {
+                        on_msg_polls.update(|list| match list.iter_mut().find(|p| p.poll.id == poll.id) {
+                            Some(existing) => existing.poll = poll,
+                            None => {
+                                list.insert(0, PollView { poll, my_choices: Vec::new() });
+                                list.truncate(50);
+                            }
+                        });
+                        return;
+                    }
+                    shared::RealtimePacket::Notification(note) => {
+                        if note.user_id.to_string() != my_uid {
+                            return;
+                        }
+                        on_msg_notifications.update(|list| {
+                            if !list.iter().any(|n| n.id == note.id) {
+                                list.insert(
+                                    0,
+                                    NotificationItem {
+                                        id: note.id,
+                                        room_id: note.room_id,
+                                        message_id: note.message_id,
+                                        from_username: note.from_user.to_string().chars().take(8).collect(),
+                                        preview: note.preview,
+                                        ts: note.ts,
+                                        read: false,
+                                    },
+                                );
+                                list.truncate(100);
+                            }
+                        });
+                        return;
+                    }
+                    shared::RealtimePacket::Typing(typing) => {
+                        if typing.user_id.to_string() == my_uid {
+                            return;
+                        }
+                        on_msg_typing.update(|list| {
+                            list.retain(|t| !(t.room_id == typing.room_id && t.user_id == typing.user_id));
+                            if typing.typing {
+                                list.push(typing);
+                            }
+                        });
+                        return;
+                    }
                     _ => {}
                 }
             }
@@ -396,6 +1075,72 @@ pub async fn query_nearby(lon: f64, lat: f64, radius_m: i32) -> Result<Vec<Nearb
     }
 }
 
+fn render_inlines(inlines: Vec<shared::markdown::Inline>) -> impl IntoView {
+    use shared::markdown::Inline;
+
+    inlines
+        .into_iter()
+        .map(|inline| match inline {
+            Inline::Text(text) => view! { <span>{text}</span> }.into_any(),
+            Inline::Code(text) => view! { <code class="rounded bg-slate-800 px-1 font-mono text-amber-200">{text}</code> }.into_any(),
+            Inline::Strong(text) => view! { <strong class="font-semibold text-slate-100">{text}</strong> }.into_any(),
+            Inline::Emphasis(text) => view! { <em>{text}</em> }.into_any(),
+            Inline::Link { label, url } => view! {
+                <a class="text-cyan-400 underline" href=url target="_blank" rel="noopener noreferrer nofollow">{label}</a>
+            }
+            .into_any(),
+            Inline::Break => view! { <br /> }.into_any(),
+        })
+        .collect_view()
+}
+
+// Markdown goes through shared::markdown into text nodes; message text is never set as HTML.
+fn render_message_body(format: shared::MessageFormat, text: String) -> AnyView {
+    use shared::markdown::Block;
+
+    match format {
+        shared::MessageFormat::Plain => view! { <span class="whitespace-pre-wrap">{text}</span> }.into_any(),
+        shared::MessageFormat::System | shared::MessageFormat::Event => {
+            view! { <span class="italic text-slate-500">{text}</span> }.into_any()
+        }
+        shared::MessageFormat::Markdown => {
+            let blocks = shared::markdown::parse(&text)
+                .into_iter()
+                .map(|block| match block {
+                    Block::Paragraph(inlines) => view! { <p>{render_inlines(inlines)}</p> }.into_any(),
+                    Block::Heading(level, inlines) => {
+                        let class = if level <= 2 { "text-sm font-semibold" } else { "font-semibold" };
+                        view! { <p class=class>{render_inlines(inlines)}</p> }.into_any()
+                    }
+                    Block::Quote(inlines) => view! {
+                        <blockquote class="border-l-2 border-slate-600 pl-2 text-slate-400">{render_inlines(inlines)}</blockquote>
+                    }
+                    .into_any(),
+                    Block::List { ordered, items } => {
+                        let items = items
+                            .into_iter()
+                            .map(|item| view! { <li>{render_inlines(item)}</li> })
+                            .collect_view();
+                        if ordered {
+                            view! { <ol class="list-decimal pl-5">{items}</ol> }.into_any()
+                        } else {
+                            view! { <ul class="list-disc pl-5">{items}</ul> }.into_any()
+                        }
+                    }
+                    Block::Code { lang, text } => view! {
+                        <pre class="rounded border border-slate-800 bg-slate-950 p-2 overflow-x-auto font-mono text-[11px]">
+                            {lang.map(|lang| view! { <span class="block text-slate-500">{lang}</span> })}
+                            <code>{text}</code>
+                        </pre>
+                    }
+                    .into_any(),
+                })
+                .collect_view();
+            view! { <div class="space-y-1">{blocks}</div> }.into_any()
+        }
+    }
+}
+
 #[component]
 pub fn HomePage() -> impl IntoView {
     let username = RwSignal::new(String::new());
@@ -410,12 +1155,36 @@ pub fn HomePage() -> impl IntoView {
     let ws_connected = RwSignal::new(false);
     let status = RwSignal::new("请先登录以开启实时联调".to_string());
     let selected_user = RwSignal::new(String::new());
+    let e2ee_enabled = RwSignal::new(false);
+    let markdown_enabled = RwSignal::new(false);
+    let schedule_minutes = RwSignal::new(String::new());
+    let burn_secs = RwSignal::new(String::new());
+    let scheduled_items = RwSignal::new(Vec::<ScheduledItem>::new());
 
-    let chat_messages = RwSignal::new(Vec::<String>::new());
+    let chat_messages = RwSignal::new(Vec::<ChatLine>::new());
     let invite_events = RwSignal::new(Vec::<String>::new());
     let pending_invites = RwSignal::new(Vec::<InviteItem>::new());
     let history_page = RwSignal::new(1_i64);
     let ready_state = RwSignal::new(None::<ReadyResponse>);
+    let typing_users = RwSignal::new(Vec::<shared::TypingEvent>::new());
+    let local_room = RwSignal::new(None::<String>);
+    let joined_rooms = RwSignal::new(Vec::<String>::new());
+    let notifications = RwSignal::new(Vec::<NotificationItem>::new());
+    let pending_sends = RwSignal::new(Vec::<(uuid::Uuid, String)>::new());
+    let room_polls = RwSignal::new(Vec::<PollView>::new());
+    let current_match = RwSignal::new(None::<shared::MatchReady>);
+    let game_launched = RwSignal::new(false);
+    let match_replays = RwSignal::new(Vec::<MatchReplayItem>::new());
+    let leaderboard = RwSignal::new(None::<LeaderboardView>);
+    let mm_near = RwSignal::new(false);
+    let mm_status = RwSignal::new(None::<MatchmakingStatus>);
+    let mm_heartbeat_started = RwSignal::new(false);
+    let poll_question = RwSignal::new(String::new());
+    let poll_options = RwSignal::new(String::new());
+    let poll_multi = RwSignal::new(false);
+    let poll_minutes = RwSignal::new(String::new());
+    #[cfg(feature = "hydrate")]
+    let last_typing_sent = RwSignal::new(0.0_f64);
     #[cfg(feature = "hydrate")]
     let invite_poll_started = RwSignal::new(false);
 
@@ -471,6 +1240,23 @@ pub fn HomePage() -> impl IntoView {
         poll.forget();
     });
 
+    #[cfg(feature = "hydrate")]
+    Effect::new(move |_| {
+        let pos = my_position;
+        let poll = Closure::wrap(Box::new(move || {
+            let (lon, lat) = pos.get_untracked();
+            leptos::task::spawn_local(refresh_chat_activity(lon, lat));
+        }) as Box<dyn FnMut()>);
+
+        if let Some(window) = web_sys::window() {
+            let _ = window.set_interval_with_callback_and_timeout_and_arguments_0(
+                poll.as_ref().unchecked_ref(),
+                15_000,
+            );
+        }
+        poll.forget();
+    });
+
     let nearby = LocalResource::new(move || {
         let tick = refresh_tick.get();
         let active = session.get().is_some();
@@ -517,6 +1303,67 @@ pub fn HomePage() -> impl IntoView {
         }
     });
 
+    let inbox: LocalResource<Vec<InboxItem>> = LocalResource::new(move || {
+        let tick = refresh_tick.get();
+        let session_value = session.get();
+
+        async move {
+            #[cfg(feature = "hydrate")]
+            {
+                let _ = tick;
+                let Some(s) = session_value else {
+                    return Vec::new();
+                };
+                let url = format!("/api/chat/inbox?token={}", urlencoding::encode(&s.token));
+                let rows = match gloo_net::http::Request::get(&url).send().await {
+                    Ok(resp) => resp.json::<Vec<InboxItem>>().await.unwrap_or_default(),
+                    Err(_) => Vec::new(),
+                };
+                joined_rooms.set(rows.iter().map(|item| item.room_id.clone()).collect());
+                rows
+            }
+
+            #[cfg(not(feature = "hydrate"))]
+            {
+                let _ = (tick, session_value);
+                Vec::new()
+            }
+        }
+    });
+
+    let live_matches: LocalResource<Vec<LiveMatchItem>> = LocalResource::new(move || {
+        let tick = refresh_tick.get();
+        let session_value = session.get();
+
+        async move {
+            #[cfg(feature = "hydrate")]
+            {
+                let _ = tick;
+                let Some(s) = session_value else {
+                    return Vec::new();
+                };
+                let url = format!("/api/match/live?token={}", urlencoding::encode(&s.token));
+                match gloo_net::http::Request::get(&url).send().await {
+                    Ok(resp) => resp.json::<Vec<LiveMatchItem>>().await.unwrap_or_default(),
+                    Err(_) => Vec::new(),
+                }
+            }
+
+            #[cfg(not(feature = "hydrate"))]
+            {
+                let _ = (tick, session_value);
+                Vec::new()
+            }
+        }
+    });
+
+    #[cfg(feature = "hydrate")]
+    Effect::new(move |_| {
+        if let Some(items) = live_matches.get().and_then(|wrapped| wrapped.take()) {
+            crate::map::update_live_matches_geojson(&build_live_geojson(&items));
+        }
+    });
+
     #[cfg(feature = "hydrate")]
     Effect::new(move |_| {
         if let Some(items) = nearby.get().and_then(|wrapped| wrapped.take()) {
@@ -548,6 +1395,14 @@ pub fn HomePage() -> impl IntoView {
             let chat_state = chat_messages;
             let invite_state = invite_events;
             let pending_state = pending_invites;
+            let typing_state = typing_users;
+            let local_room_state = local_room;
+            let joined_state = joined_rooms;
+            let notification_state = notifications;
+            let pending_send_state = pending_sends;
+            let poll_state = room_polls;
+            let match_state = current_match;
+            let replay_state = match_replays;
             let poll_started = invite_poll_started;
 
             leptos::task::spawn_local(async move {
@@ -593,6 +1448,25 @@ pub fn HomePage() -> impl IntoView {
                     pending_state.set(rows);
                 }
 
+                match load_active_match(&token).await {
+                    Ok(ready) => match_state.set(ready),
+                    Err(err) => status_setter.set(err),
+                }
+
+                match load_match_replays(&token).await {
+                    Ok(rows) => replay_state.set(rows),
+                    Err(err) => status_setter.set(err),
+                }
+
+                match load_notifications(&token).await {
+                    Ok(rows) => notification_state.set(rows),
+                    Err(err) => status_setter.set(err),
+                }
+
+                if let Err(err) = publish_key_bundle(&token, &user_id).await {
+                    status_setter.set(err);
+                }
+
                 if !poll_started.get_untracked() {
                     poll_started.set(true);
                     let token_for_poll = token.clone();
@@ -632,145 +1506,741 @@ pub fn HomePage() -> impl IntoView {
                     chat_state,
                     invite_state,
                     pending_state,
+                    typing_state,
+                    local_room_state,
+                    joined_state,
+                    notification_state,
+                    pending_send_state,
+                    poll_state,
+                    match_state,
                 );
             });
         }
     };
 
-    let on_send_chat = move |_| {
+    let on_send_chat = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(s) = session.get() else {
+                status.set("请先登录".to_string());
+                return;
+            };
+
+            let text = chat_input.get();
+            if text.trim().is_empty() {
+                return;
+            }
+
+            let room = room_id.get();
+            let encrypt = e2ee_enabled.get();
+            let format = if markdown_enabled.get() {
+                shared::MessageFormat::Markdown
+            } else {
+                shared::MessageFormat::Plain
+            };
+            if encrypt && shared::dm::participants(&room).is_none() {
+                status.set("仅私信支持端到端加密".to_string());
+                return;
+            }
+            let send_at = match schedule_minutes.get().trim() {
+                "" => None,
+                raw => match raw.parse::<i64>() {
+                    Ok(minutes) if minutes > 0 => Some(chrono::Utc::now() + chrono::Duration::minutes(minutes)),
+                    _ => {
+                        status.set("定时分钟数无效".to_string());
+                        return;
+                    }
+                },
+            };
+            let ttl_secs = match burn_secs.get().trim() {
+                "" => None,
+                raw => match raw.parse::<i64>() {
+                    Ok(secs) if secs > 0 => Some(secs),
+                    _ => {
+                        status.set("阅后即焚秒数无效".to_string());
+                        return;
+                    }
+                },
+            };
+            if encrypt && send_at.is_some() {
+                status.set("加密消息不支持定时发送".to_string());
+                return;
+            }
+
+            let client_id = uuid::Uuid::new_v4();
+            let status_setter = status;
+            let chat_input_setter = chat_input;
+            let pending_setter = pending_sends;
+            last_typing_sent.set(0.0);
+            pending_setter.update(|list| list.push((client_id, text.clone())));
+            chat_input_setter.set(String::new());
+
+            leptos::task::spawn_local(async move {
+                let mut payload = if encrypt {
+                    match encrypt_for_dm(&s.token, &room, &text).await {
+                        Ok(e2ee) => serde_json::json!({
+                            "token": s.token,
+                            "room_id": room,
+                            "text": "",
+                            "client_id": client_id,
+                            "e2ee": e2ee,
+                            "format": format,
+                        }),
+                        Err(err) => {
+                            status_setter.set(err);
+                            pending_setter.update(|list| list.retain(|(id, _)| *id != client_id));
+                            chat_input_setter.set(text);
+                            return;
+                        }
+                    }
+                } else {
+                    serde_json::json!({
+                        "token": s.token,
+                        "room_id": room,
+                        "text": text,
+                        "client_id": client_id,
+                        "format": format,
+                    })
+                };
+                payload["send_at"] = serde_json::json!(send_at);
+                payload["ttl_secs"] = serde_json::json!(ttl_secs);
+
+                // The server dedupes on client_id, so a timed-out request can be retried safely.
+                for attempt in 0..CHAT_SEND_ATTEMPTS {
+                    let req = gloo_net::http::Request::post("/api/chat/send")
+                        .header("content-type", "application/json")
+                        .body(payload.to_string());
+
+                    let Ok(r) = req else {
+                        status_setter.set("消息请求构建失败".to_string());
+                        break;
+                    };
+
+                    match r.send().await {
+                        Ok(resp) if resp.ok() => {
+                            pending_setter.update(|list| list.retain(|(id, _)| *id != client_id));
+                            if let Ok(SendChatResult { scheduled_id: Some(id) }) = resp.json::<SendChatResult>().await {
+                                status_setter.set(format!("已定时 #{}", id));
+                            }
+                            return;
+                        }
+                        Ok(resp) => {
+                            let msg = resp
+                                .json::<ApiErrorBody>()
+                                .await
+                                .map(|body| body.error)
+                                .unwrap_or_else(|_| format!("HTTP {}", resp.status()));
+                            status_setter.set(format!("消息发送失败：{}", msg));
+                            break;
+                        }
+                        Err(_) if attempt + 1 < CHAT_SEND_ATTEMPTS => continue,
+                        Err(_) => status_setter.set("消息发送失败".to_string()),
+                    }
+                }
+                pending_setter.update(|list| list.retain(|(id, _)| *id != client_id));
+                chat_input_setter.set(text);
+            });
+        }
+    };
+
+    let on_open_dm = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(s) = session.get() else {
+                status.set("请先登录".to_string());
+                return;
+            };
+            let (Ok(me), Ok(peer)) = (
+                uuid::Uuid::parse_str(&s.user_id),
+                uuid::Uuid::parse_str(selected_user.get().trim()),
+            ) else {
+                status.set("目标用户ID无效".to_string());
+                return;
+            };
+            if me == peer {
+                status.set("不能给自己发私信".to_string());
+                return;
+            }
+            room_id.set(shared::dm::room_id(me, peer));
+        }
+    };
+
+    let on_chat_input = move |ev: leptos::ev::Event| {
+        chat_input.set(event_target_value(&ev));
+
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(s) = session.get_untracked() else {
+                return;
+            };
+            let Ok(user_id) = uuid::Uuid::parse_str(&s.user_id) else {
+                return;
+            };
+            let now = js_sys::Date::now();
+            if now - last_typing_sent.get_untracked() < TYPING_SEND_INTERVAL_MS {
+                return;
+            }
+            let packet = shared::RealtimePacket::Typing(shared::TypingEvent {
+                room_id: room_id.get_untracked(),
+                user_id,
+                typing: true,
+                ts: chrono::Utc::now(),
+            });
+            if send_realtime(&packet) {
+                last_typing_sent.set(now);
+            }
+        }
+    };
+
+    let on_load_history = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let room = room_id.get();
+            let token = session.get().map(|s| s.token);
+            let chat_state = chat_messages;
+            let status_setter = status;
+            let page = history_page.get();
+            leptos::task::spawn_local(async move {
+                match load_history_page(&room, page, token.as_deref()).await {
+                    Ok(rows) => chat_state.set(rows),
+                    Err(err) => status_setter.set(err),
+                }
+            });
+        }
+    };
+
+    let on_load_older_history = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let room = room_id.get();
+            let token = session.get().map(|s| s.token);
+            let chat_state = chat_messages;
+            let status_setter = status;
+            let page_signal = history_page;
+            page_signal.update(|v| *v += 1);
+            let page = page_signal.get();
+
+            leptos::task::spawn_local(async move {
+                match load_history_page(&room, page, token.as_deref()).await {
+                    Ok(rows) => chat_state.set(rows),
+                    Err(err) => status_setter.set(err),
+                }
+            });
+        }
+    };
+
+    let on_load_newer_history = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let room = room_id.get();
+            let token = session.get().map(|s| s.token);
+            let chat_state = chat_messages;
+            let status_setter = status;
+            let page_signal = history_page;
+            page_signal.update(|v| {
+                if *v > 1 {
+                    *v -= 1;
+                }
+            });
+            let page = page_signal.get();
+
+            leptos::task::spawn_local(async move {
+                match load_history_page(&room, page, token.as_deref()).await {
+                    Ok(rows) => chat_state.set(rows),
+                    Err(err) => status_setter.set(err),
+                }
+            });
+        }
+    };
+
+    let on_mark_read = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(s) = session.get() else {
+                status.set("请先登录".to_string());
+                return;
+            };
+
+            let payload = serde_json::json!({
+                "token": s.token,
+                "room_id": room_id.get(),
+            });
+
+            let status_setter = status;
+            let tick = refresh_tick;
+
+            leptos::task::spawn_local(async move {
+                let req = gloo_net::http::Request::post("/api/chat/mark-read")
+                    .header("content-type", "application/json")
+                    .body(payload.to_string());
+                match req {
+                    Ok(r) => {
+                        if r.send().await.is_ok() {
+                            status_setter.set("已标记为已读".to_string());
+                            tick.update(|v| *v += 1);
+                        } else {
+                            status_setter.set("标记已读失败".to_string());
+                        }
+                    }
+                    Err(_) => status_setter.set("已读请求构建失败".to_string()),
+                }
+            });
+        }
+    };
+
+    let on_read_notifications = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(s) = session.get() else {
+                status.set("请先登录".to_string());
+                return;
+            };
+
+            let payload = serde_json::json!({ "token": s.token });
+            let status_setter = status;
+            let notification_state = notifications;
+
+            leptos::task::spawn_local(async move {
+                let req = gloo_net::http::Request::post("/api/notifications/mark-read")
+                    .header("content-type", "application/json")
+                    .body(payload.to_string());
+                match req {
+                    Ok(r) => {
+                        if r.send().await.is_ok() {
+                            notification_state.update(|list| list.iter_mut().for_each(|n| n.read = true));
+                        } else {
+                            status_setter.set("标记通知已读失败".to_string());
+                        }
+                    }
+                    Err(_) => status_setter.set("通知请求构建失败".to_string()),
+                }
+            });
+        }
+    };
+
+    let on_load_polls = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(s) = session.get() else {
+                status.set("请先登录".to_string());
+                return;
+            };
+            let room = room_id.get();
+            let status_setter = status;
+            let poll_state = room_polls;
+
+            leptos::task::spawn_local(async move {
+                match load_polls(&s.token, &room).await {
+                    Ok(rows) => poll_state.update(|list| {
+                        list.retain(|p| p.poll.room_id != room);
+                        list.extend(rows);
+                    }),
+                    Err(err) => status_setter.set(err),
+                }
+            });
+        }
+    };
+
+    let on_create_poll = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(s) = session.get() else {
+                status.set("请先登录".to_string());
+                return;
+            };
+
+            let options = poll_options
+                .get()
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            let closes_at = match poll_minutes.get().trim() {
+                "" => None,
+                raw => match raw.parse::<i64>() {
+                    Ok(minutes) if minutes > 0 => Some(chrono::Utc::now() + chrono::Duration::minutes(minutes)),
+                    _ => {
+                        status.set("截止时间需为正整数分钟".to_string());
+                        return;
+                    }
+                },
+            };
+            let payload = serde_json::json!({
+                "token": s.token,
+                "room_id": room_id.get(),
+                "question": poll_question.get(),
+                "options": options,
+                "multi_choice": poll_multi.get(),
+                "closes_at": closes_at,
+            });
+
+            let status_setter = status;
+            let poll_state = room_polls;
+            let question_setter = poll_question;
+            let options_setter = poll_options;
+
+            leptos::task::spawn_local(async move {
+                let req = gloo_net::http::Request::post("/api/chat/polls")
+                    .header("content-type", "application/json")
+                    .body(payload.to_string());
+                let Ok(r) = req else {
+                    status_setter.set("投票请求构建失败".to_string());
+                    return;
+                };
+                match r.send().await {
+                    Ok(resp) if resp.ok() => {
+                        if let Ok(view) = resp.json::<PollView>().await {
+                            poll_state.update(|list| {
+                                if !list.iter().any(|p| p.poll.id == view.poll.id) {
+                                    list.insert(0, view);
+                                }
+                            });
+                        }
+                        question_setter.set(String::new());
+                        options_setter.set(String::new());
+                    }
+                    Ok(resp) => {
+                        let msg = resp
+                            .json::<ApiErrorBody>()
+                            .await
+                            .map(|body| body.error)
+                            .unwrap_or_else(|_| format!("HTTP {}", resp.status()));
+                        status_setter.set(format!("发起投票失败：{}", msg));
+                    }
+                    Err(_) => status_setter.set("发起投票失败".to_string()),
+                }
+            });
+        }
+    };
+
+    let cast_vote = move |poll_id: i64, choice: u32| {
         #[cfg(feature = "hydrate")]
         {
-            let Some(s) = session.get() else {
+            let Some(s) = session.get_untracked() else {
                 status.set("请先登录".to_string());
                 return;
             };
+            let Ok(me) = uuid::Uuid::parse_str(&s.user_id) else {
+                return;
+            };
+            let Some(current) = room_polls.with_untracked(|list| list.iter().find(|p| p.poll.id == poll_id).cloned()) else {
+                return;
+            };
 
-            let text = chat_input.get();
-            if text.trim().is_empty() {
+            // Single choice polls switch the ballot; clicking the chosen option again withdraws it.
+            let mut choices = current.my_choices.clone();
+            if choices.contains(&choice) {
+                choices.retain(|c| *c != choice);
+            } else if current.poll.multi_choice {
+                choices.push(choice);
+            } else {
+                choices = vec![choice];
+            }
+            choices.sort_unstable();
+
+            let previous = current.my_choices;
+            let optimistic = choices.clone();
+            room_polls.update(|list| {
+                if let Some(p) = list.iter_mut().find(|p| p.poll.id == poll_id) {
+                    p.my_choices = optimistic;
+                }
+            });
+
+            let packet = shared::RealtimePacket::PollVote(shared::PollVote {
+                poll_id,
+                user_id: me,
+                choices: choices.clone(),
+            });
+            if send_realtime(&packet) {
                 return;
             }
 
             let payload = serde_json::json!({
                 "token": s.token,
-                "room_id": room_id.get(),
-                "text": text,
+                "poll_id": poll_id,
+                "choices": choices,
             });
-
             let status_setter = status;
-            let chat_input_setter = chat_input;
-
+            let poll_state = room_polls;
             leptos::task::spawn_local(async move {
-                let req = gloo_net::http::Request::post("/api/chat/send")
+                let sent = match gloo_net::http::Request::post("/api/chat/polls/vote")
                     .header("content-type", "application/json")
-                    .body(payload.to_string());
-
-                match req {
-                    Ok(r) => {
-                        if r.send().await.is_ok() {
-                            chat_input_setter.set(String::new());
-                        } else {
-                            status_setter.set("消息发送失败".to_string());
+                    .body(payload.to_string())
+                {
+                    Ok(r) => r.send().await.ok().filter(|resp| resp.ok()),
+                    Err(_) => None,
+                };
+                if sent.is_none() {
+                    status_setter.set("投票失败".to_string());
+                    poll_state.update(|list| {
+                        if let Some(p) = list.iter_mut().find(|p| p.poll.id == poll_id) {
+                            p.my_choices = previous;
                         }
-                    }
-                    Err(_) => status_setter.set("消息请求构建失败".to_string()),
+                    });
                 }
             });
         }
+        #[cfg(not(feature = "hydrate"))]
+        {
+            let _ = (poll_id, choice);
+        }
     };
 
-    let on_load_history = move |_| {
+    let close_poll = move |poll_id: i64| {
         #[cfg(feature = "hydrate")]
         {
-            let room = room_id.get();
-            let chat_state = chat_messages;
+            let Some(s) = session.get_untracked() else {
+                return;
+            };
+            let payload = serde_json::json!({ "token": s.token, "poll_id": poll_id });
             let status_setter = status;
-            let page = history_page.get();
             leptos::task::spawn_local(async move {
-                match load_history_page(&room, page).await {
-                    Ok(rows) => chat_state.set(rows),
-                    Err(err) => status_setter.set(err),
+                let req = gloo_net::http::Request::post("/api/chat/polls/close")
+                    .header("content-type", "application/json")
+                    .body(payload.to_string());
+                match req {
+                    Ok(r) => match r.send().await {
+                        Ok(resp) if resp.ok() => {}
+                        _ => status_setter.set("结束投票失败".to_string()),
+                    },
+                    Err(_) => status_setter.set("投票请求构建失败".to_string()),
                 }
             });
         }
+        #[cfg(not(feature = "hydrate"))]
+        {
+            let _ = poll_id;
+        }
     };
 
-    let on_load_older_history = move |_| {
+    let on_load_scheduled = move |_| {
         #[cfg(feature = "hydrate")]
         {
-            let room = room_id.get();
-            let chat_state = chat_messages;
+            let Some(s) = session.get() else {
+                status.set("请先登录".to_string());
+                return;
+            };
             let status_setter = status;
-            let page_signal = history_page;
-            page_signal.update(|v| *v += 1);
-            let page = page_signal.get();
+            let scheduled_state = scheduled_items;
 
             leptos::task::spawn_local(async move {
-                match load_history_page(&room, page).await {
-                    Ok(rows) => chat_state.set(rows),
+                match load_scheduled(&s.token).await {
+                    Ok(rows) => scheduled_state.set(rows),
                     Err(err) => status_setter.set(err),
                 }
             });
         }
     };
 
-    let on_load_newer_history = move |_| {
+    let cancel_scheduled = move |scheduled_id: i64| {
         #[cfg(feature = "hydrate")]
         {
-            let room = room_id.get();
-            let chat_state = chat_messages;
+            let Some(s) = session.get_untracked() else {
+                return;
+            };
+            let payload = serde_json::json!({ "token": s.token, "id": scheduled_id });
             let status_setter = status;
-            let page_signal = history_page;
-            page_signal.update(|v| {
-                if *v > 1 {
-                    *v -= 1;
+            let scheduled_state = scheduled_items;
+            leptos::task::spawn_local(async move {
+                let req = gloo_net::http::Request::post("/api/chat/scheduled/cancel")
+                    .header("content-type", "application/json")
+                    .body(payload.to_string());
+                match req {
+                    Ok(r) => match r.send().await {
+                        Ok(resp) if resp.ok() => scheduled_state.update(|list| {
+                            if let Some(item) = list.iter_mut().find(|item| item.id == scheduled_id) {
+                                item.status = "cancelled".to_string();
+                            }
+                        }),
+                        _ => status_setter.set("取消定时消息失败".to_string()),
+                    },
+                    Err(_) => status_setter.set("定时消息请求构建失败".to_string()),
                 }
             });
-            let page = page_signal.get();
+        }
+        #[cfg(not(feature = "hydrate"))]
+        {
+            let _ = scheduled_id;
+        }
+    };
 
+    let on_load_leaderboard = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(s) = session.get() else {
+                status.set("请先登录".to_string());
+                return;
+            };
+            let near = mm_near.get();
             leptos::task::spawn_local(async move {
-                match load_history_page(&room, page).await {
-                    Ok(rows) => chat_state.set(rows),
-                    Err(err) => status_setter.set(err),
+                match load_leaderboard(&s.token, near).await {
+                    Ok(board) => leaderboard.set(Some(board)),
+                    Err(err) => status.set(err),
                 }
             });
         }
     };
 
-    let on_mark_read = move |_| {
+    let on_enqueue_match = move |_| {
         #[cfg(feature = "hydrate")]
         {
             let Some(s) = session.get() else {
                 status.set("请先登录".to_string());
                 return;
             };
-
             let payload = serde_json::json!({
                 "token": s.token,
-                "room_id": room_id.get(),
+                "mode": "duel",
+                "near": mm_near.get(),
             });
-
             let status_setter = status;
-            let tick = refresh_tick;
+            let queue_state = mm_status;
+
+            if !mm_heartbeat_started.get_untracked() {
+                mm_heartbeat_started.set(true);
+                let token = s.token.clone();
+                // Polling while queued keeps the server-side ticket alive and refreshes the widening window.
+                let heartbeat = Closure::wrap(Box::new(move || {
+                    if !queue_state.get_untracked().is_some_and(|q| q.queued) {
+                        return;
+                    }
+                    let token = token.clone();
+                    leptos::task::spawn_local(async move {
+                        if let Ok(fresh) = load_matchmaking_status(&token).await {
+                            queue_state.set(Some(fresh));
+                        }
+                    });
+                }) as Box<dyn FnMut()>);
+                if let Some(window) = web_sys::window() {
+                    let _ = window.set_interval_with_callback_and_timeout_and_arguments_0(
+                        heartbeat.as_ref().unchecked_ref(),
+                        5_000,
+                    );
+                }
+                heartbeat.forget();
+            }
 
             leptos::task::spawn_local(async move {
-                let req = gloo_net::http::Request::post("/api/chat/mark-read")
+                let req = gloo_net::http::Request::post("/api/matchmaking/enqueue")
                     .header("content-type", "application/json")
                     .body(payload.to_string());
                 match req {
-                    Ok(r) => {
-                        if r.send().await.is_ok() {
-                            status_setter.set("已标记为已读".to_string());
-                            tick.update(|v| *v += 1);
-                        } else {
-                            status_setter.set("标记已读失败".to_string());
+                    Ok(r) => match r.send().await {
+                        Ok(resp) if resp.ok() => {
+                            if let Ok(fresh) = resp.json::<MatchmakingStatus>().await {
+                                queue_state.set(Some(fresh));
+                            }
                         }
-                    }
-                    Err(_) => status_setter.set("已读请求构建失败".to_string()),
+                        Ok(resp) => {
+                            let msg = resp
+                                .json::<ApiErrorBody>()
+                                .await
+                                .map(|body| body.error)
+                                .unwrap_or_else(|_| format!("HTTP {}", resp.status()));
+                            status_setter.set(format!("匹配失败：{}", msg));
+                        }
+                        Err(_) => status_setter.set("匹配请求失败".to_string()),
+                    },
+                    Err(_) => status_setter.set("匹配请求构建失败".to_string()),
+                }
+            });
+        }
+    };
+
+    let on_leave_queue = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(s) = session.get() else {
+                return;
+            };
+            let payload = serde_json::json!({ "token": s.token });
+            let status_setter = status;
+            let queue_state = mm_status;
+            leptos::task::spawn_local(async move {
+                let req = gloo_net::http::Request::post("/api/matchmaking/leave")
+                    .header("content-type", "application/json")
+                    .body(payload.to_string());
+                match req {
+                    Ok(r) => match r.send().await {
+                        Ok(resp) if resp.ok() => queue_state.set(None),
+                        _ => status_setter.set("取消匹配失败".to_string()),
+                    },
+                    Err(_) => status_setter.set("匹配请求构建失败".to_string()),
                 }
             });
         }
     };
 
+    let on_enter_match = move |_| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(s) = session.get() else {
+                status.set("请先登录".to_string());
+                return;
+            };
+            let Some(ready) = current_match.get() else {
+                return;
+            };
+            if game_launched.get() {
+                status.set("对局客户端已启动，刷新页面后可进入新对局".to_string());
+                return;
+            }
+            let Some(slot) = ready.players.iter().position(|p| p.to_string() == s.user_id) else {
+                status.set("你不在该对局中".to_string());
+                return;
+            };
+            let Some(url) = match_ws_url(&s.token, &ready) else {
+                status.set("无法构建对局连接地址".to_string());
+                return;
+            };
+            game_launched.set(true);
+            if !launch_game(&url, slot, &s.user_id) {
+                status.set("对局客户端加载失败".to_string());
+            }
+        }
+    };
+
+    let on_watch_replay = move |replay_url: String| {
+        #[cfg(feature = "hydrate")]
+        {
+            if game_launched.get() {
+                status.set("对局客户端已启动，刷新页面后可观看回放".to_string());
+                return;
+            }
+            game_launched.set(true);
+            if !launch_replay(&replay_url) {
+                status.set("回放加载失败".to_string());
+            }
+        }
+        #[cfg(not(feature = "hydrate"))]
+        let _ = replay_url;
+    };
+
+    let on_watch_match = move |match_id: String| {
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(s) = session.get() else {
+                status.set("请先登录".to_string());
+                return;
+            };
+            if game_launched.get() {
+                status.set("对局客户端已启动，刷新页面后可观战".to_string());
+                return;
+            }
+            let Some(url) = match_watch_url(&s.token, &match_id) else {
+                status.set("无法构建观战连接地址".to_string());
+                return;
+            };
+            game_launched.set(true);
+            if !launch_spectate(&url) {
+                status.set("观战客户端加载失败".to_string());
+            }
+        }
+        #[cfg(not(feature = "hydrate"))]
+        let _ = match_id;
+    };
+
     let on_send_invite = move |_| {
         #[cfg(feature = "hydrate")]
         {
@@ -798,19 +2268,25 @@ pub fn HomePage() -> impl IntoView {
                     .body(payload.to_string());
 
                 match req {
-                    Ok(r) => {
-                        if r.send().await.is_ok() {
-                            status_setter.set("邀请已发送".to_string());
-                        } else {
-                            status_setter.set("邀请发送失败".to_string());
+                    Ok(r) => match r.send().await {
+                        Ok(resp) if resp.ok() => status_setter.set("邀请已发送".to_string()),
+                        Ok(resp) => {
+                            let msg = resp
+                                .json::<ApiErrorBody>()
+                                .await
+                                .map(|body| body.error)
+                                .unwrap_or_else(|_| format!("HTTP {}", resp.status()));
+                            status_setter.set(format!("邀请发送失败：{}", msg));
                         }
-                    }
+                        Err(_) => status_setter.set("邀请发送失败".to_string()),
+                    },
                     Err(_) => status_setter.set("邀请请求构建失败".to_string()),
                 }
             });
         }
     };
 
+    // "cancel" goes to its own endpoint; the server only lets the sender use it.
     let on_respond_invite = move |_invite_id: String, _action: &'static str| {
         #[cfg(feature = "hydrate")]
         {
@@ -830,25 +2306,37 @@ pub fn HomePage() -> impl IntoView {
             let pending_state = pending_invites;
 
             leptos::task::spawn_local(async move {
-                let req = gloo_net::http::Request::post("/api/invite/respond")
+                let url = if _action == "cancel" { "/api/invite/cancel" } else { "/api/invite/respond" };
+                let req = gloo_net::http::Request::post(url)
                     .header("content-type", "application/json")
                     .body(payload.to_string());
 
                 match req {
-                    Ok(r) => {
-                        if r.send().await.is_ok() {
+                    Ok(r) => match r.send().await {
+                        Ok(resp) if resp.ok() => {
                             pending_state.update(|list| {
                                 list.retain(|it| it.invite_id != invite_id_key);
                             });
-                            status_setter.set(if _action == "accept" {
-                                "邀请已接受".to_string()
-                            } else {
-                                "邀请已拒绝".to_string()
+                            status_setter.set(match _action {
+                                "accept" => "邀请已接受".to_string(),
+                                "cancel" => "邀请已撤回".to_string(),
+                                _ => "邀请已拒绝".to_string(),
                             });
-                        } else {
-                            status_setter.set("邀请响应失败".to_string());
                         }
-                    }
+                        Ok(resp) => {
+                            let msg = resp
+                                .json::<ApiErrorBody>()
+                                .await
+                                .map(|body| body.error)
+                                .unwrap_or_else(|_| format!("HTTP {}", resp.status()));
+                            // Whatever the reason, the invite is no longer actionable here.
+                            if resp.status() == 409 || resp.status() == 410 {
+                                pending_state.update(|list| list.retain(|it| it.invite_id != invite_id_key));
+                            }
+                            status_setter.set(format!("邀请操作失败：{}", msg));
+                        }
+                        Err(_) => status_setter.set("邀请响应失败".to_string()),
+                    },
                     Err(_) => status_setter.set("邀请响应请求构建失败".to_string()),
                 }
             });
@@ -944,6 +2432,27 @@ pub fn HomePage() -> impl IntoView {
                         </Show>
                     </section>
 
+                    <section class="rounded-lg border border-slate-700 p-3 space-y-3">
+                        <h2 class="font-medium">"会话列表"</h2>
+                        <div class="max-h-32 overflow-auto rounded border border-slate-800 p-2 text-xs text-slate-300 space-y-1">
+                            {move || {
+                                let rows = inbox.get().and_then(|wrapped| wrapped.take()).unwrap_or_default();
+                                rows.into_iter().map(|item| {
+                                    let target = item.room_id.clone();
+                                    let when = item.last_activity_at.map(|ts| ts.format("%H:%M").to_string()).unwrap_or_default();
+                                    view! {
+                                        <button class="w-full text-left rounded px-2 py-1 hover:bg-slate-800" on:click=move |_| room_id.set(target.clone())>
+                                            <span class="text-sky-300">{item.room_id}</span>
+                                            <span class="ml-2 text-amber-300">{if item.unread_count > 0 { format!("({})", item.unread_count) } else { String::new() }}</span>
+                                            <span class="ml-2 text-slate-500">{when}</span>
+                                            <p class="text-slate-400 truncate">{item.last_preview.unwrap_or_default()}</p>
+                                        </button>
+                                    }
+                                }).collect_view()
+                            }}
+                        </div>
+                    </section>
+
                     <section class="rounded-lg border border-slate-700 p-3 space-y-3">
                         <h2 class="font-medium">"房间状态"</h2>
                         <div class="flex gap-2">
@@ -954,6 +2463,13 @@ pub fn HomePage() -> impl IntoView {
                             <button class="rounded bg-cyan-600 hover:bg-cyan-500 px-2 py-1 text-xs" on:click=on_mark_read>"标记已读"</button>
                         </div>
                         <p class="text-[11px] text-slate-500">{move || format!("历史页: {} (每页{}条)", history_page.get(), CHAT_HISTORY_PAGE_SIZE)}</p>
+                        <Show when=move || local_room.get().is_some()>
+                            <button class="rounded bg-pink-600 hover:bg-pink-500 px-2 py-1 text-xs" on:click=move |_| {
+                                if let Some(local) = local_room.get() {
+                                    room_id.set(local);
+                                }
+                            }>{move || format!("切换到附近聊天 {}", local_room.get().unwrap_or_default())}</button>
+                        </Show>
                         <div class="max-h-24 overflow-auto rounded border border-slate-800 p-2 text-xs text-slate-300 space-y-1">
                             {move || {
                                 #[cfg(feature = "hydrate")]
@@ -1019,42 +2535,333 @@ pub fn HomePage() -> impl IntoView {
                         <div class="flex gap-2">
                             <input class="flex-1 rounded bg-slate-950 border border-slate-700 px-2 py-1 text-xs" placeholder="目标用户ID" prop:value=move || selected_user.get() on:input=move |ev| selected_user.set(event_target_value(&ev)) />
                             <button class="rounded bg-violet-500 hover:bg-violet-400 text-slate-950 font-medium px-3 py-1 text-xs" on:click=on_send_invite>"发邀请"</button>
+                            <button class="rounded bg-slate-700 hover:bg-slate-600 px-3 py-1 text-xs" on:click=on_open_dm>"私信"</button>
                         </div>
                         <div class="max-h-32 overflow-auto rounded border border-slate-800 p-2 text-xs text-slate-300 space-y-1">
-                            {move || pending_invites.get().into_iter().map(|inv| {
-                                let invite_id_accept = inv.invite_id.clone();
-                                let invite_id_reject = inv.invite_id.clone();
-                                view! {
-                                    <div class="border border-slate-700 rounded p-2 space-y-1">
-                                        <p>{format!("来自 {} 的 {} 邀请", inv.from_user.chars().take(8).collect::<String>(), inv.mode)}</p>
-                                        <p class="text-slate-500">{format!("{} | {}", inv.status, inv.ts.format("%H:%M:%S"))}</p>
-                                        <div class="flex gap-2">
-                                            <button class="rounded bg-emerald-500 hover:bg-emerald-400 text-slate-950 px-2 py-1" on:click={
-                                                let on_respond_invite = on_respond_invite;
-                                                move |_| on_respond_invite(invite_id_accept.clone(), "accept")
-                                            }>"接受"</button>
-                                            <button class="rounded bg-rose-500 hover:bg-rose-400 text-slate-950 px-2 py-1" on:click={
-                                                let on_respond_invite = on_respond_invite;
-                                                move |_| on_respond_invite(invite_id_reject.clone(), "reject")
-                                            }>"拒绝"</button>
+                            {move || {
+                                let me = session.get().map(|s| s.user_id).unwrap_or_default();
+                                pending_invites.get().into_iter().map(|inv| {
+                                    let invite_id_accept = inv.invite_id.clone();
+                                    let invite_id_reject = inv.invite_id.clone();
+                                    let invite_id_cancel = inv.invite_id.clone();
+                                    let outgoing = inv.from_user == me;
+                                    let expiry = inv.expires_at.map(|at| format!(" | {} 过期", at.format("%H:%M:%S"))).unwrap_or_default();
+                                    let title = if outgoing {
+                                        format!("发给 {} 的 {} 邀请", inv.to_user.chars().take(8).collect::<String>(), inv.mode)
+                                    } else {
+                                        format!("来自 {} 的 {} 邀请", inv.from_user.chars().take(8).collect::<String>(), inv.mode)
+                                    };
+                                    let actions = if outgoing {
+                                        view! {
+                                            <button class="rounded bg-slate-700 hover:bg-slate-600 px-2 py-1" on:click=move |_| on_respond_invite(invite_id_cancel.clone(), "cancel")>"撤回"</button>
+                                        }
+                                        .into_any()
+                                    } else {
+                                        view! {
+                                            <div class="flex gap-2">
+                                                <button class="rounded bg-emerald-500 hover:bg-emerald-400 text-slate-950 px-2 py-1" on:click=move |_| on_respond_invite(invite_id_accept.clone(), "accept")>"接受"</button>
+                                                <button class="rounded bg-rose-500 hover:bg-rose-400 text-slate-950 px-2 py-1" on:click=move |_| on_respond_invite(invite_id_reject.clone(), "reject")>"拒绝"</button>
+                                            </div>
+                                        }
+                                        .into_any()
+                                    };
+                                    view! {
+                                        <div class="border border-slate-700 rounded p-2 space-y-1">
+                                            <p>{title}</p>
+                                            <p class="text-slate-500">{format!("{} | {}{}", inv.status, inv.ts.format("%H:%M:%S"), expiry)}</p>
+                                            {actions}
                                         </div>
-                                    </div>
-                                }
-                            }).collect_view()}
+                                    }
+                                }).collect_view()
+                            }}
+                        </div>
+                        <div class="flex items-center gap-2 text-xs">
+                            <button class="rounded bg-sky-500 hover:bg-sky-400 text-slate-950 font-medium px-3 py-1" on:click=on_enqueue_match>"快速匹配"</button>
+                            <button class="rounded bg-slate-700 hover:bg-slate-600 px-3 py-1" on:click=on_leave_queue>"取消匹配"</button>
+                            <label class="flex items-center gap-1 text-[11px] text-slate-400">
+                                <input type="checkbox" prop:checked=move || mm_near.get() on:change=move |ev| mm_near.set(event_target_checked(&ev)) />
+                                "附近优先"
+                            </label>
+                            <button class="rounded bg-slate-700 hover:bg-slate-600 px-3 py-1" on:click=on_load_leaderboard>"排行榜"</button>
+                        </div>
+                        {move || leaderboard.get().map(|board| {
+                            let me = session.get().map(|s| s.user_id).unwrap_or_default();
+                            let title = match board.radius_km {
+                                Some(km) => format!("{} 附近 {:.0}km 排行", board.mode, km),
+                                None => format!("{} 全服排行", board.mode),
+                            };
+                            let mine = board.me.map(|e| format!("我的全服排名 #{} · {:.0}±{:.0} · {} 场", e.rank, e.rating, e.rd * 2.0, e.games));
+                            view! {
+                                <div class="rounded border border-slate-800 p-2 text-xs text-slate-300 space-y-1">
+                                    <p class="text-slate-400">{title}</p>
+                                    {board.entries.into_iter().map(|e| {
+                                        let highlight = if e.user_id == me { "text-amber-300" } else { "" };
+                                        let distance = e.distance_km.map(|km| format!(" · {:.1}km", km)).unwrap_or_default();
+                                        view! {
+                                            <p class=highlight>{format!("#{} {} · {:.0} · {} 场{}", e.rank, e.username, e.rating, e.games, distance)}</p>
+                                        }
+                                    }).collect_view()}
+                                    <p class="text-slate-500">{mine.unwrap_or_else(|| "暂无排名，完成一场对局后上榜".to_string())}</p>
+                                </div>
+                            }
+                        })}
+                        <p class="text-[11px] text-slate-400">
+                            {move || match mm_status.get() {
+                                Some(q) if q.queued && current_match.get().is_none() => format!(
+                                    "匹配中 {} · 积分 {:.0} · 已等待 {}s · 分差 ±{:.0}{}",
+                                    q.mode.unwrap_or_default(),
+                                    q.rating.unwrap_or_default(),
+                                    q.waited_secs,
+                                    q.rating_window.unwrap_or_default(),
+                                    q.radius_km.map(|km| format!(" · 半径 {:.0}km", km)).unwrap_or_default()
+                                ),
+                                _ => String::new(),
+                            }}
+                        </p>
+                        {move || current_match.get().map(|ready| {
+                            let state = match ready.state {
+                                shared::MatchState::Running => "进行中",
+                                _ => "等待双方加入",
+                            };
+                            view! {
+                                <div class="rounded border border-emerald-600 p-2 text-xs text-emerald-200 space-y-1">
+                                    <p class="font-medium">{format!("对局已就绪 · {} · {}", ready.mode, state)}</p>
+                                    <p class="text-slate-400 break-all">{format!("对局 {}", ready.match_id)}</p>
+                                    <p class="text-slate-400 break-all">{format!("连接 {}", ready.ws_path)}</p>
+                                    <button class="rounded bg-emerald-600 hover:bg-emerald-500 px-2 py-1 text-white" on:click=on_enter_match>"进入对局"</button>
+                                </div>
+                            }
+                        })}
+                        <div class=move || {
+                            if game_launched.get() { "h-72 rounded border border-slate-800 overflow-hidden" } else { "hidden" }
+                        }>
+                            <canvas id=GAME_CANVAS_ID class="w-full h-full"></canvas>
                         </div>
+                        {move || {
+                            let live = live_matches.get().and_then(|wrapped| wrapped.take()).unwrap_or_default();
+                            (!live.is_empty()).then(|| view! {
+                                <div class="rounded border border-rose-900 p-2 text-xs text-slate-300 space-y-1">
+                                    <p class="text-rose-300">"正在进行的对局"</p>
+                                    {live.into_iter().map(|m| {
+                                        let label = format!(
+                                            "{} · {} · 延迟 {}s",
+                                            m.mode,
+                                            m.players.iter().map(|p| p.username.as_str()).collect::<Vec<_>>().join(" vs "),
+                                            m.delay_secs
+                                        );
+                                        let match_id = m.match_id.clone();
+                                        view! {
+                                            <div class="flex items-center justify-between gap-2">
+                                                <span>{label}</span>
+                                                <button class="rounded bg-rose-700 hover:bg-rose-600 px-2 py-0.5 text-white" on:click=move |_| on_watch_match(match_id.clone())>"观战"</button>
+                                            </div>
+                                        }
+                                    }).collect_view()}
+                                </div>
+                            })
+                        }}
+                        <Show when=move || !match_replays.get().is_empty()>
+                            <div class="rounded border border-slate-800 p-2 text-xs text-slate-300 space-y-1">
+                                <p class="text-slate-400">"最近对局回放"</p>
+                                {move || {
+                                    let me = session.get().map(|s| s.user_id).unwrap_or_default();
+                                    match_replays.get().into_iter().map(|r| {
+                                        let result = match r.winner.as_deref() {
+                                            Some(w) if w == me => "胜",
+                                            Some(_) => "负",
+                                            None => "平/中断",
+                                        };
+                                        let label = format!(
+                                            "{} · {} · {} · {}s",
+                                            r.created_at.format("%m-%d %H:%M"),
+                                            r.mode,
+                                            result,
+                                            r.frames / r.tick_hz.max(1)
+                                        );
+                                        let url = r.url.clone();
+                                        view! {
+                                            <div class="flex items-center justify-between gap-2" title=r.match_id.clone()>
+                                                <span>{label}</span>
+                                                <button class="rounded bg-slate-700 hover:bg-slate-600 px-2 py-0.5" on:click=move |_| on_watch_replay(url.clone())>"回放"</button>
+                                            </div>
+                                        }
+                                    }).collect_view()
+                                }}
+                            </div>
+                        </Show>
                         <div class="max-h-24 overflow-auto rounded border border-slate-800 p-2 text-xs text-slate-300 space-y-1">
                             {move || invite_events.get().into_iter().rev().map(|line| view!{ <p>{line}</p>}).collect_view()}
                         </div>
                     </section>
 
+                    <section class="rounded-lg border border-slate-700 p-3 space-y-3">
+                        <div class="flex items-center justify-between">
+                            <h2 class="font-medium">
+                                {move || format!("提及通知（{} 未读）", notifications.get().iter().filter(|n| !n.read).count())}
+                            </h2>
+                            <button class="rounded bg-cyan-600 hover:bg-cyan-500 px-2 py-1 text-xs" on:click=on_read_notifications>"全部已读"</button>
+                        </div>
+                        <div class="max-h-32 overflow-auto rounded border border-slate-800 p-2 text-xs text-slate-300 space-y-1">
+                            {move || notifications.get().into_iter().map(|n| {
+                                let class = if n.read { "text-slate-500" } else { "text-amber-300" };
+                                view! {
+                                    <p class=class>
+                                        {format!("[{}] #{} @{}: {} ({})", n.room_id, n.message_id, n.from_username, n.preview, n.ts.format("%H:%M:%S"))}
+                                    </p>
+                                }
+                            }).collect_view()}
+                        </div>
+                    </section>
+
                     <section class="rounded-lg border border-slate-700 p-3 space-y-3">
                         <h2 class="font-medium">"聊天室"</h2>
                         <div class="flex gap-2">
-                            <input class="flex-1 rounded bg-slate-950 border border-slate-700 px-2 py-1 text-xs" placeholder="输入消息" prop:value=move || chat_input.get() on:input=move |ev| chat_input.set(event_target_value(&ev)) />
+                            <textarea class="flex-1 rounded bg-slate-950 border border-slate-700 px-2 py-1 text-xs font-mono" rows="3" placeholder="输入消息" prop:value=move || chat_input.get() on:input=on_chat_input></textarea>
                             <button class="rounded bg-emerald-500 hover:bg-emerald-400 text-slate-950 font-medium px-3 py-1 text-xs" on:click=on_send_chat>"发送"</button>
                         </div>
+                        <label class="flex items-center gap-2 text-[11px] text-slate-400">
+                            <input type="checkbox" prop:checked=move || e2ee_enabled.get() on:change=move |ev| e2ee_enabled.set(event_target_checked(&ev)) />
+                            "端到端加密（仅私信）"
+                        </label>
+                        <label class="flex items-center gap-2 text-[11px] text-slate-400">
+                            <input type="checkbox" prop:checked=move || markdown_enabled.get() on:change=move |ev| markdown_enabled.set(event_target_checked(&ev)) />
+                            "Markdown（支持代码块、链接、列表）"
+                        </label>
+                        <div class="flex items-center gap-2 text-[11px] text-slate-400">
+                            <input class="w-24 rounded bg-slate-950 border border-slate-700 px-2 py-1 text-xs" placeholder="定时(分钟)" prop:value=move || schedule_minutes.get() on:input=move |ev| schedule_minutes.set(event_target_value(&ev)) />
+                            <input class="w-28 rounded bg-slate-950 border border-slate-700 px-2 py-1 text-xs" placeholder="阅后即焚(秒)" prop:value=move || burn_secs.get() on:input=move |ev| burn_secs.set(event_target_value(&ev)) />
+                            <button class="ml-auto rounded bg-slate-700 hover:bg-slate-600 px-2 py-1 text-xs" on:click=on_load_scheduled>"我的定时消息"</button>
+                        </div>
+                        <div class="max-h-32 overflow-auto space-y-1 text-[11px] text-slate-400">
+                            {move || {
+                                scheduled_items
+                                    .get()
+                                    .into_iter()
+                                    .map(|item| {
+                                        let scheduled_id = item.id;
+                                        let pending = item.status == "pending";
+                                        let line = format!(
+                                            "#{} [{}] {} · {} · {}{}",
+                                            item.id,
+                                            item.room_id,
+                                            item.send_at.format("%m-%d %H:%M"),
+                                            item.status,
+                                            item.text.chars().take(40).collect::<String>(),
+                                            item.last_error.map(|e| format!(" ({})", e)).unwrap_or_default()
+                                        );
+                                        view! {
+                                            <div class="flex items-center gap-2">
+                                                <span class="flex-1 truncate">{line}</span>
+                                                <Show when=move || pending>
+                                                    <button class="rounded bg-slate-700 hover:bg-slate-600 px-2 py-0.5" on:click=move |_| cancel_scheduled(scheduled_id)>"取消"</button>
+                                                </Show>
+                                            </div>
+                                        }
+                                    })
+                                    .collect_view()
+                            }}
+                        </div>
+                        <p class="text-[11px] text-slate-500 h-4">
+                            {move || {
+                                let room = room_id.get();
+                                let names = typing_users
+                                    .get()
+                                    .into_iter()
+                                    .filter(|t| t.room_id == room)
+                                    .map(|t| t.user_id.to_string().chars().take(8).collect::<String>())
+                                    .collect::<Vec<_>>();
+                                if names.is_empty() {
+                                    String::new()
+                                } else {
+                                    format!("{} 正在输入...", names.join(", "))
+                                }
+                            }}
+                        </p>
                         <div class="max-h-56 overflow-auto rounded border border-slate-800 p-2 text-xs text-slate-300 space-y-1">
-                            {move || chat_messages.get().into_iter().rev().map(|line| view!{ <p>{line}</p>}).collect_view()}
+                            {move || pending_sends.get().into_iter().rev().map(|(_, text)| view!{ <p class="text-slate-500">{format!("{}（发送中）", text)}</p>}).collect_view()}
+                            {move || chat_messages.get().into_iter().rev().map(|line| view!{
+                                <div>
+                                    <span class="text-slate-500">{line.meta}</span>" "
+                                    {render_message_body(line.format, line.text)}
+                                    <span class="text-slate-500">{line.suffix}</span>
+                                </div>
+                            }).collect_view()}
+                        </div>
+                    </section>
+
+                    <section class="rounded-lg border border-slate-700 p-3 space-y-3">
+                        <div class="flex items-center justify-between">
+                            <h2 class="font-medium">"投票"</h2>
+                            <button class="rounded bg-slate-700 hover:bg-slate-600 px-2 py-1 text-xs" on:click=on_load_polls>"加载本房间投票"</button>
+                        </div>
+                        <input class="w-full rounded bg-slate-950 border border-slate-700 px-2 py-1 text-xs" placeholder="投票问题" prop:value=move || poll_question.get() on:input=move |ev| poll_question.set(event_target_value(&ev)) />
+                        <textarea class="w-full rounded bg-slate-950 border border-slate-700 px-2 py-1 text-xs" rows="3" placeholder="选项，每行一个（2-10个）" prop:value=move || poll_options.get() on:input=move |ev| poll_options.set(event_target_value(&ev))></textarea>
+                        <div class="flex items-center gap-2 text-[11px] text-slate-400">
+                            <label class="flex items-center gap-1">
+                                <input type="checkbox" prop:checked=move || poll_multi.get() on:change=move |ev| poll_multi.set(event_target_checked(&ev)) />
+                                "多选"
+                            </label>
+                            <input class="w-24 rounded bg-slate-950 border border-slate-700 px-2 py-1 text-xs" placeholder="截止(分钟)" prop:value=move || poll_minutes.get() on:input=move |ev| poll_minutes.set(event_target_value(&ev)) />
+                            <button class="ml-auto rounded bg-amber-500 hover:bg-amber-400 text-slate-950 font-medium px-3 py-1 text-xs" on:click=on_create_poll>"发起投票"</button>
+                        </div>
+                        <div class="max-h-72 overflow-auto space-y-2 text-xs text-slate-300">
+                            {move || {
+                                let room = room_id.get();
+                                let me = session.get().map(|s| s.user_id).unwrap_or_default();
+                                room_polls
+                                    .get()
+                                    .into_iter()
+                                    .filter(|view| view.poll.room_id == room)
+                                    .map(|view| {
+                                        let poll = view.poll;
+                                        let poll_id = poll.id;
+                                        let closed = poll.closed;
+                                        let total = poll.options.iter().map(|o| o.votes).sum::<i64>().max(1);
+                                        let can_close = !closed && poll.created_by.to_string() == me;
+                                        let header = format!(
+                                            "{}{} · {}人参与{}",
+                                            if poll.multi_choice { "[多选] " } else { "" },
+                                            poll.question,
+                                            poll.voters,
+                                            match (closed, poll.closes_at) {
+                                                (true, _) => " · 已结束".to_string(),
+                                                (false, Some(at)) => format!(" · 截止 {}", at.format("%m-%d %H:%M")),
+                                                (false, None) => String::new(),
+                                            }
+                                        );
+                                        let options = poll
+                                            .options
+                                            .into_iter()
+                                            .enumerate()
+                                            .map(|(idx, option)| {
+                                                let choice = idx as u32;
+                                                let chosen = view.my_choices.contains(&choice);
+                                                let width = format!("width: {}%", option.votes * 100 / total);
+                                                view! {
+                                                    <button
+                                                        class="relative w-full overflow-hidden rounded border border-slate-700 px-2 py-1 text-left disabled:opacity-60"
+                                                        class:border-amber-400=chosen
+                                                        disabled=closed
+                                                        on:click=move |_| cast_vote(poll_id, choice)
+                                                    >
+                                                        <span class="absolute inset-y-0 left-0 bg-amber-500/20" style=width></span>
+                                                        <span class="relative">{format!("{}{} · {}票", if chosen { "✓ " } else { "" }, option.text, option.votes)}</span>
+                                                    </button>
+                                                }
+                                            })
+                                            .collect_view();
+                                        view! {
+                                            <div class="rounded border border-slate-800 p-2 space-y-1">
+                                                <p class="font-medium">{header}</p>
+                                                {options}
+                                                <Show when=move || can_close>
+                                                    <button class="rounded bg-slate-700 hover:bg-slate-600 px-2 py-1 text-[11px]" on:click=move |_| close_poll(poll_id)>"结束投票"</button>
+                                                </Show>
+                                            </div>
+                                        }
+                                    })
+                                    .collect_view()
+                            }}
                         </div>
                     </section>
                 </aside>
@@ -0,0 +1,273 @@
+use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const ENVELOPE_VERSION: u8 = 1;
+const WRAP_INFO: &[u8] = b"platform-e2ee-v1/wrap";
+const KEEP_PREVIOUS_PREKEYS: usize = 8;
+const PREKEY_MAX_AGE_MS: f64 = 7.0 * 24.0 * 60.0 * 60.0 * 1000.0;
+const KEY_STORE_PREFIX: &str = "e2ee:keys:";
+const PINNED_IDENTITIES: &str = "e2ee:pins";
+
+// Each message gets a fresh content key; that key is wrapped once per participant
+// with an ephemeral X25519 exchange against their signed prekey. There is no ratchet,
+// so forward secrecy is bounded by how often prekeys are rotated.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyStore {
+    signing_key: [u8; 32],
+    prekey_id: i64,
+    prekey: [u8; 32],
+    #[serde(default)]
+    prekey_created_ms: f64,
+    #[serde(default)]
+    previous_prekeys: Vec<(i64, [u8; 32])>,
+}
+
+impl KeyStore {
+    pub fn generate(now_ms: f64) -> Self {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        KeyStore {
+            signing_key: signing_key.to_bytes(),
+            prekey_id: 1,
+            prekey: StaticSecret::random_from_rng(OsRng).to_bytes(),
+            prekey_created_ms: now_ms,
+            previous_prekeys: Vec::new(),
+        }
+    }
+
+    pub fn rotate_prekey_if_older_than(&mut self, now_ms: f64, max_age_ms: f64) -> bool {
+        if now_ms - self.prekey_created_ms < max_age_ms {
+            return false;
+        }
+        self.previous_prekeys.insert(0, (self.prekey_id, self.prekey));
+        self.previous_prekeys.truncate(KEEP_PREVIOUS_PREKEYS);
+        self.prekey_id += 1;
+        self.prekey = StaticSecret::random_from_rng(OsRng).to_bytes();
+        self.prekey_created_ms = now_ms;
+        true
+    }
+
+    pub fn bundle(&self, user_id: Uuid) -> shared::KeyBundle {
+        let signing_key = SigningKey::from_bytes(&self.signing_key);
+        let prekey_public = PublicKey::from(&StaticSecret::from(self.prekey));
+        let signature = signing_key.sign(prekey_public.as_bytes());
+
+        shared::KeyBundle {
+            user_id,
+            identity_key: B64.encode(signing_key.verifying_key().as_bytes()),
+            prekey_id: self.prekey_id,
+            prekey: B64.encode(prekey_public.as_bytes()),
+            prekey_signature: B64.encode(signature.to_bytes()),
+            updated_at: None,
+        }
+    }
+
+    fn prekey_secret(&self, prekey_id: i64) -> Option<StaticSecret> {
+        if prekey_id == self.prekey_id {
+            return Some(StaticSecret::from(self.prekey));
+        }
+        self.previous_prekeys
+            .iter()
+            .find(|(id, _)| *id == prekey_id)
+            .map(|(_, bytes)| StaticSecret::from(*bytes))
+    }
+}
+
+fn decode_array<const N: usize>(value: &str, what: &str) -> Result<[u8; N], String> {
+    B64.decode(value)
+        .ok()
+        .and_then(|bytes| <[u8; N]>::try_from(bytes.as_slice()).ok())
+        .ok_or_else(|| format!("malformed {what}"))
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut out = [0_u8; N];
+    OsRng.fill_bytes(&mut out);
+    out
+}
+
+pub fn verify_bundle(bundle: &shared::KeyBundle) -> Result<PublicKey, String> {
+    let identity = VerifyingKey::from_bytes(&decode_array::<32>(&bundle.identity_key, "identity key")?)
+        .map_err(|_| "invalid identity key".to_string())?;
+    let prekey = decode_array::<32>(&bundle.prekey, "prekey")?;
+    let signature = Signature::from_bytes(&decode_array::<64>(&bundle.prekey_signature, "prekey signature")?);
+    identity
+        .verify_strict(&prekey, &signature)
+        .map_err(|_| "prekey signature does not match identity key".to_string())?;
+    Ok(PublicKey::from(prekey))
+}
+
+fn wrapping_key(ephemeral: &PublicKey, prekey: &PublicKey, shared_secret: &[u8; 32]) -> Key {
+    let mut salt = [0_u8; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(prekey.as_bytes());
+
+    let mut okm = [0_u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret)
+        .expand(WRAP_INFO, &mut okm)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Key::from(okm)
+}
+
+pub fn encrypt(plaintext: &str, room_id: &str, recipients: &[shared::KeyBundle]) -> Result<shared::E2eePayload, String> {
+    let content_key = random_bytes::<32>();
+    let nonce = random_bytes::<12>();
+    let ciphertext = ChaCha20Poly1305::new(&Key::from(content_key))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext.as_bytes(),
+                aad: room_id.as_bytes(),
+            },
+        )
+        .map_err(|_| "encryption failed".to_string())?;
+
+    let mut wraps = Vec::with_capacity(recipients.len());
+    for bundle in recipients {
+        let prekey = verify_bundle(bundle)?;
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared_secret = ephemeral.diffie_hellman(&prekey);
+        if !shared_secret.was_contributory() {
+            return Err("recipient prekey is a low-order point".to_string());
+        }
+
+        let wrap_nonce = random_bytes::<12>();
+        let wrapped = ChaCha20Poly1305::new(&wrapping_key(&ephemeral_public, &prekey, shared_secret.as_bytes()))
+            .encrypt(
+                Nonce::from_slice(&wrap_nonce),
+                Payload {
+                    msg: &content_key,
+                    aad: bundle.user_id.as_bytes(),
+                },
+            )
+            .map_err(|_| "key wrap failed".to_string())?;
+
+        wraps.push(shared::E2eeKeyWrap {
+            user_id: bundle.user_id,
+            prekey_id: bundle.prekey_id,
+            ephemeral_key: B64.encode(ephemeral_public.as_bytes()),
+            nonce: B64.encode(wrap_nonce),
+            wrapped_key: B64.encode(wrapped),
+        });
+    }
+
+    Ok(shared::E2eePayload {
+        version: ENVELOPE_VERSION,
+        nonce: B64.encode(nonce),
+        ciphertext: B64.encode(ciphertext),
+        recipients: wraps,
+    })
+}
+
+pub fn decrypt(store: &KeyStore, me: Uuid, room_id: &str, payload: &shared::E2eePayload) -> Result<String, String> {
+    if payload.version != ENVELOPE_VERSION {
+        return Err(format!("unsupported envelope version {}", payload.version));
+    }
+    let wrap = payload
+        .recipients
+        .iter()
+        .find(|wrap| wrap.user_id == me)
+        .ok_or_else(|| "message was not encrypted for this user".to_string())?;
+    let prekey_secret = store
+        .prekey_secret(wrap.prekey_id)
+        .ok_or_else(|| format!("prekey {} is no longer available", wrap.prekey_id))?;
+
+    let ephemeral_public = PublicKey::from(decode_array::<32>(&wrap.ephemeral_key, "ephemeral key")?);
+    let shared_secret = prekey_secret.diffie_hellman(&ephemeral_public);
+    if !shared_secret.was_contributory() {
+        return Err("ephemeral key is a low-order point".to_string());
+    }
+    let wrapping = wrapping_key(&ephemeral_public, &PublicKey::from(&prekey_secret), shared_secret.as_bytes());
+
+    let wrap_nonce = decode_array::<12>(&wrap.nonce, "wrap nonce")?;
+    let wrapped = B64.decode(&wrap.wrapped_key).map_err(|_| "malformed wrapped key".to_string())?;
+    let content_key = ChaCha20Poly1305::new(&wrapping)
+        .decrypt(
+            Nonce::from_slice(&wrap_nonce),
+            Payload {
+                msg: &wrapped,
+                aad: me.as_bytes(),
+            },
+        )
+        .map_err(|_| "key unwrap failed".to_string())?;
+    let content_key = <[u8; 32]>::try_from(content_key.as_slice()).map_err(|_| "malformed content key".to_string())?;
+
+    let nonce = decode_array::<12>(&payload.nonce, "nonce")?;
+    let ciphertext = B64.decode(&payload.ciphertext).map_err(|_| "malformed ciphertext".to_string())?;
+    let plaintext = ChaCha20Poly1305::new(&Key::from(content_key))
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: room_id.as_bytes(),
+            },
+        )
+        .map_err(|_| "decryption failed".to_string())?;
+
+    String::from_utf8(plaintext).map_err(|_| "plaintext is not UTF-8".to_string())
+}
+
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+pub fn load_or_create(user_id: &str) -> KeyStore {
+    let now_ms = js_sys::Date::now();
+    let key = format!("{KEY_STORE_PREFIX}{user_id}");
+    let stored = local_storage()
+        .and_then(|s| s.get_item(&key).ok().flatten())
+        .and_then(|raw| serde_json::from_str::<KeyStore>(&raw).ok());
+
+    match stored {
+        Some(mut store) => {
+            if store.rotate_prekey_if_older_than(now_ms, PREKEY_MAX_AGE_MS) {
+                save(user_id, &store);
+            }
+            store
+        }
+        None => {
+            let store = KeyStore::generate(now_ms);
+            save(user_id, &store);
+            store
+        }
+    }
+}
+
+fn save(user_id: &str, store: &KeyStore) {
+    if let (Some(storage), Ok(raw)) = (local_storage(), serde_json::to_string(store)) {
+        let _ = storage.set_item(&format!("{KEY_STORE_PREFIX}{user_id}"), &raw);
+    }
+}
+
+// Trust on first use: remember each peer's identity key and refuse to encrypt to a
+// different one until the user clears the pin.
+pub fn check_pinned_identity(bundle: &shared::KeyBundle) -> Result<(), String> {
+    let storage = local_storage();
+    let mut pins = storage
+        .as_ref()
+        .and_then(|s| s.get_item(PINNED_IDENTITIES).ok().flatten())
+        .and_then(|raw| serde_json::from_str::<std::collections::HashMap<Uuid, String>>(&raw).ok())
+        .unwrap_or_default();
+
+    match pins.get(&bundle.user_id) {
+        Some(pinned) if *pinned != bundle.identity_key => Err("对方身份密钥已变更，请核实后再发送".to_string()),
+        Some(_) => Ok(()),
+        None => {
+            pins.insert(bundle.user_id, bundle.identity_key.clone());
+            if let (Some(storage), Ok(raw)) = (storage, serde_json::to_string(&pins)) {
+                let _ = storage.set_item(PINNED_IDENTITIES, &raw);
+            }
+            Ok(())
+        }
+    }
+}
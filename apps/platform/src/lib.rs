@@ -1,5 +1,7 @@
 pub mod app;
 #[cfg(feature = "hydrate")]
+pub mod e2ee;
+#[cfg(feature = "hydrate")]
 pub mod map;
 #[cfg(feature = "ssr")]
 pub mod server;
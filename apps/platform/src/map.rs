@@ -4,6 +4,8 @@ use web_sys::window;
 #[wasm_bindgen(inline_js = r#"
 let appMap = null;
 const SOURCE_ID = 'online-users';
+const ACTIVITY_SOURCE_ID = 'chat-activity';
+const LIVE_SOURCE_ID = 'live-matches';
 
 export function initMap(targetId, styleUrl, centerLon, centerLat, zoom) {
   if (!window.maplibregl) {
@@ -23,6 +25,29 @@ export function initMap(targetId, styleUrl, centerLon, centerLat, zoom) {
   });
 
   map.on('load', () => {
+    if (!map.getSource(ACTIVITY_SOURCE_ID)) {
+      map.addSource(ACTIVITY_SOURCE_ID, {
+        type: 'geojson',
+        data: {
+          type: 'FeatureCollection',
+          features: []
+        }
+      });
+    }
+
+    if (!map.getLayer('chat-activity-fill')) {
+      map.addLayer({
+        id: 'chat-activity-fill',
+        type: 'fill',
+        source: ACTIVITY_SOURCE_ID,
+        paint: {
+          'fill-color': '#f472b6',
+          'fill-opacity': ['min', 0.6, ['*', 0.05, ['get', 'message_count']]],
+          'fill-outline-color': '#db2777'
+        }
+      });
+    }
+
     if (!map.getSource(SOURCE_ID)) {
       map.addSource(SOURCE_ID, {
         type: 'geojson',
@@ -63,6 +88,48 @@ export function initMap(targetId, styleUrl, centerLon, centerLat, zoom) {
         }
       });
     }
+
+    if (!map.getSource(LIVE_SOURCE_ID)) {
+      map.addSource(LIVE_SOURCE_ID, {
+        type: 'geojson',
+        data: {
+          type: 'FeatureCollection',
+          features: []
+        }
+      });
+    }
+
+    if (!map.getLayer('live-matches-circle')) {
+      map.addLayer({
+        id: 'live-matches-circle',
+        type: 'circle',
+        source: LIVE_SOURCE_ID,
+        paint: {
+          'circle-radius': 10,
+          'circle-color': '#f43f5e',
+          'circle-opacity': 0.8,
+          'circle-stroke-color': '#fecdd3',
+          'circle-stroke-width': 2
+        }
+      });
+    }
+
+    if (!map.getLayer('live-matches-label')) {
+      map.addLayer({
+        id: 'live-matches-label',
+        type: 'symbol',
+        source: LIVE_SOURCE_ID,
+        layout: {
+          'text-field': ['get', 'label'],
+          'text-size': 11,
+          'text-offset': [0, -1.8],
+          'text-anchor': 'bottom'
+        },
+        paint: {
+          'text-color': '#fda4af'
+        }
+      });
+    }
   });
 
   appMap = map;
@@ -80,6 +147,28 @@ export function updateOnlineUsersGeoJson(featureCollectionJson) {
   source.setData(JSON.parse(featureCollectionJson));
 }
 
+export function updateChatActivityGeoJson(featureCollectionJson) {
+  if (!appMap) {
+    return;
+  }
+  const source = appMap.getSource(ACTIVITY_SOURCE_ID);
+  if (!source) {
+    return;
+  }
+  source.setData(JSON.parse(featureCollectionJson));
+}
+
+export function updateLiveMatchesGeoJson(featureCollectionJson) {
+  if (!appMap) {
+    return;
+  }
+  const source = appMap.getSource(LIVE_SOURCE_ID);
+  if (!source) {
+    return;
+  }
+  source.setData(JSON.parse(featureCollectionJson));
+}
+
 export function setMapCenter(lon, lat) {
   if (!appMap) {
     return;
@@ -90,6 +179,8 @@ export function setMapCenter(lon, lat) {
 extern "C" {
   fn initMap(target_id: &str, style_url: &str, center_lon: f64, center_lat: f64, zoom: f64) -> JsValue;
     fn updateOnlineUsersGeoJson(feature_collection_json: &str);
+    fn updateChatActivityGeoJson(feature_collection_json: &str);
+    fn updateLiveMatchesGeoJson(feature_collection_json: &str);
     fn setMapCenter(lon: f64, lat: f64);
 }
 
@@ -107,6 +198,20 @@ pub fn update_online_users_geojson(feature_collection_json: &str) {
     updateOnlineUsersGeoJson(feature_collection_json);
 }
 
+pub fn update_chat_activity_geojson(feature_collection_json: &str) {
+    if window().is_none() {
+        return;
+    }
+    updateChatActivityGeoJson(feature_collection_json);
+}
+
+pub fn update_live_matches_geojson(feature_collection_json: &str) {
+    if window().is_none() {
+        return;
+    }
+    updateLiveMatchesGeoJson(feature_collection_json);
+}
+
 pub fn set_center(lon: f64, lat: f64) {
     if window().is_none() {
         return;
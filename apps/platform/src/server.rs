@@ -50,8 +50,31 @@ pub mod state {
         pub jetstream: jetstream::Context,
         pub clickhouse: clickhouse::Client,
         pub r2: aws_sdk_s3::Client,
+        pub r2_bucket: String,
         pub jwt: JwtConfig,
-        pub realtime_tx: broadcast::Sender<Vec<u8>>,
+        pub realtime_tx: broadcast::Sender<Outbound>,
+    }
+
+    // One encoded realtime packet and who may see it. `to: None` reaches every socket;
+    // otherwise only sockets authenticated as one of the listed users receive it.
+    #[derive(Debug, Clone)]
+    pub struct Outbound {
+        pub to: Option<Vec<Uuid>>,
+        pub payload: Vec<u8>,
+    }
+
+    impl Outbound {
+        pub fn everyone(payload: Vec<u8>) -> Self {
+            Self { to: None, payload }
+        }
+
+        pub fn users(to: Vec<Uuid>, payload: Vec<u8>) -> Self {
+            Self { to: Some(to), payload }
+        }
+
+        pub fn reaches(&self, user_id: Uuid) -> bool {
+            self.to.as_ref().is_none_or(|to| to.contains(&user_id))
+        }
     }
 
     #[derive(Clone)]
@@ -226,671 +249,8005 @@ pub mod services {
 
             store_presence(&app.redis, &user_id.to_string(), lon, lat).await?;
             publish_position(&app.jetstream, payload.clone()).await?;
-            let _ = app.realtime_tx.send(payload);
+            let _ = app.realtime_tx.send(state::Outbound::everyone(payload));
+            if let Err(err) = services::local::sync_cell(app, user_id, lon, lat).await {
+                tracing::warn!(?err, %user_id, "local room sync failed");
+            }
             Ok(())
         }
     }
 
     pub mod chat {
         use super::*;
+        use sqlx::postgres::PgRow;
+
+        #[derive(Debug)]
+        pub enum SendError {
+            InvalidParent,
+            InvalidAttachment,
+            NotInRoom,
+            NotParticipant,
+            InvalidEncryption(&'static str),
+            InvalidContent(&'static str),
+            TooLong(usize),
+            Banned(Option<chrono::DateTime<chrono::Utc>>),
+            Muted(Option<chrono::DateTime<chrono::Utc>>),
+            SlowMode(i64),
+            Blocked,
+            Storage(anyhow::Error),
+        }
 
-        pub async fn insert_message(pg: &PgPool, msg: &shared::ChatMessage) -> anyhow::Result<()> {
-            sqlx::query(
+        impl SendError {
+            pub fn status_code(&self) -> StatusCode {
+                match self {
+                    SendError::InvalidParent => StatusCode::BAD_REQUEST,
+                    SendError::InvalidAttachment => StatusCode::BAD_REQUEST,
+                    SendError::NotInRoom => StatusCode::FORBIDDEN,
+                    SendError::NotParticipant => StatusCode::FORBIDDEN,
+                    SendError::InvalidEncryption(_) => StatusCode::BAD_REQUEST,
+                    SendError::InvalidContent(_) => StatusCode::BAD_REQUEST,
+                    SendError::TooLong(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                    SendError::Banned(_) | SendError::Muted(_) => StatusCode::FORBIDDEN,
+                    SendError::SlowMode(_) => StatusCode::TOO_MANY_REQUESTS,
+                    SendError::Blocked => StatusCode::UNPROCESSABLE_ENTITY,
+                    SendError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                }
+            }
+
+            pub fn message(&self) -> String {
+                match self {
+                    SendError::InvalidParent => "parent message not found in this room or is itself a reply".to_string(),
+                    SendError::InvalidAttachment => "attachment is not uploaded, already used, or belongs to another room".to_string(),
+                    SendError::NotInRoom => "local rooms only accept messages from users currently inside the area".to_string(),
+                    SendError::NotParticipant => "only the two participants can post in a direct message room".to_string(),
+                    SendError::InvalidEncryption(reason) => format!("invalid encrypted message: {reason}"),
+                    SendError::InvalidContent(reason) => reason.to_string(),
+                    SendError::TooLong(limit) => format!("message is longer than {limit} characters"),
+                    SendError::Banned(None) => "you are banned from this room".to_string(),
+                    SendError::Banned(Some(until)) => format!("you are banned from this room until {}", until.to_rfc3339()),
+                    SendError::Muted(None) => "you are muted in this room".to_string(),
+                    SendError::Muted(Some(until)) => format!("you are muted in this room until {}", until.to_rfc3339()),
+                    SendError::SlowMode(retry) => format!("slow mode is on, try again in {retry}s"),
+                    SendError::Blocked => "message contains blocked words".to_string(),
+                    SendError::Storage(_) => "failed to store message".to_string(),
+                }
+            }
+        }
+
+        impl From<anyhow::Error> for SendError {
+            fn from(err: anyhow::Error) -> Self {
+                SendError::Storage(err)
+            }
+        }
+
+        impl From<sqlx::Error> for SendError {
+            fn from(err: sqlx::Error) -> Self {
+                SendError::Storage(err.into())
+            }
+        }
+
+        fn history_item(row: &PgRow) -> ChatHistoryItem {
+            ChatHistoryItem {
+                id: row.get::<i64, _>("id"),
+                room_id: row.get::<String, _>("room_id"),
+                from_user: row.get::<String, _>("from_user"),
+                text: row.get::<String, _>("message"),
+                ts: row.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+                parent_id: row.get::<Option<i64>, _>("parent_id"),
+                reply_count: row.get::<i64, _>("reply_count"),
+                last_reply_at: row.get::<Option<chrono::DateTime<chrono::Utc>>, _>("last_reply_at"),
+                attachments: Vec::new(),
+                e2ee: row
+                    .get::<Option<sqlx::types::Json<shared::E2eePayload>>, _>("e2ee")
+                    .map(|payload| payload.0),
+                format: services::content::parse_format(&row.get::<String, _>("format")),
+                event: row
+                    .get::<Option<sqlx::types::Json<shared::ChatEvent>>, _>("event")
+                    .map(|event| event.0),
+                expires_at: row.get::<Option<chrono::DateTime<chrono::Utc>>, _>("expires_at"),
+            }
+        }
+
+        const PREVIEW_CHARS: usize = 80;
+        const E2EE_VERSION: u8 = 1;
+        const MAX_E2EE_CIPHERTEXT: usize = 64 * 1024;
+
+        pub struct Sent {
+            pub message: shared::ChatMessage,
+            pub duplicate: bool,
+        }
+
+        pub async fn insert_message<'e>(pg: impl sqlx::PgExecutor<'e>, msg: &shared::ChatMessage) -> anyhow::Result<Option<i64>> {
+            let row = sqlx::query(
                 r#"
-                INSERT INTO room_messages(room_id, from_user, message, created_at)
-                VALUES ($1, $2, $3, now())
+                INSERT INTO room_messages(room_id, from_user, message, parent_id, client_id, e2ee, format, event, expires_at, search_vector, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, to_tsvector('simple', $10), now())
+                ON CONFLICT (from_user, client_id) WHERE client_id IS NOT NULL DO NOTHING
+                RETURNING id
                 "#,
             )
             .bind(&msg.room_id)
             .bind(msg.from_user)
             .bind(&msg.text)
-            .execute(pg)
+            .bind(msg.parent_id)
+            .bind(msg.client_id)
+            .bind(msg.e2ee.as_ref().map(sqlx::types::Json))
+            .bind(services::content::format_str(msg.format))
+            .bind(msg.event.as_ref().map(sqlx::types::Json))
+            .bind(msg.expires_at)
+            .bind(services::search::segment(&services::content::plain_text(msg.format, &msg.text)))
+            .fetch_optional(pg)
+            .await?;
+            Ok(row.map(|r| r.get::<i64, _>("id")))
+        }
+
+        fn preview(format: shared::MessageFormat, text: &str, encrypted: bool, has_attachments: bool) -> String {
+            if encrypted {
+                "[encrypted]".to_string()
+            } else if text.is_empty() && has_attachments {
+                "[attachment]".to_string()
+            } else {
+                services::content::plain_text(format, text)
+                    .chars()
+                    .take(PREVIEW_CHARS)
+                    .collect::<String>()
+            }
+        }
+
+        pub async fn record_activity(conn: &mut sqlx::PgConnection, msg: &shared::ChatMessage, message_id: i64) -> anyhow::Result<()> {
+            let preview = preview(msg.format, &msg.text, msg.e2ee.is_some(), !msg.attachments.is_empty());
+
+            sqlx::query(
+                r#"
+                INSERT INTO room_stats(room_id, message_count, last_message_id, last_from_user, last_preview, last_activity_at)
+                VALUES ($1, 1, $2, $3, $4, now())
+                ON CONFLICT (room_id)
+                DO UPDATE SET
+                  message_count = room_stats.message_count + 1,
+                  last_message_id = EXCLUDED.last_message_id,
+                  last_from_user = EXCLUDED.last_from_user,
+                  last_preview = EXCLUDED.last_preview,
+                  last_activity_at = EXCLUDED.last_activity_at
+                "#,
+            )
+            .bind(&msg.room_id)
+            .bind(message_id)
+            .bind(msg.from_user)
+            .bind(&preview)
+            .execute(&mut *conn)
             .await?;
+
+            join(&mut *conn, &msg.room_id, msg.from_user).await?;
+
+            // Thread replies are tracked by thread_unread_count, not the room counter.
+            if msg.parent_id.is_none() {
+                sqlx::query(
+                    r#"
+                    UPDATE room_memberships
+                    SET unread_count = unread_count + 1
+                    WHERE room_id = $1 AND user_id <> $2
+                    "#,
+                )
+                .bind(&msg.room_id)
+                .bind(msg.from_user)
+                .execute(&mut *conn)
+                .await?;
+            }
+            // The recipient of a direct message is a member from the first message on, so it
+            // reaches their inbox and their delivery receipts count. Joining after the increment
+            // lets the initial count include this message exactly once.
+            if let Some(peer) = shared::dm::peer_of(&msg.room_id, msg.from_user) {
+                join(&mut *conn, &msg.room_id, peer).await?;
+            }
             Ok(())
         }
 
-        pub(crate) async fn history(pg: &PgPool, room_id: &str, limit: i64) -> anyhow::Result<Vec<ChatHistoryItem>> {
+        // One-off seeding of room_stats for rooms that had messages before the table existed.
+        // Previews go through the same path as `record_activity`; rows it already wrote for the
+        // same last message are only corrected.
+        pub async fn backfill_stats(conn: &mut sqlx::PgConnection) -> anyhow::Result<()> {
             let rows = sqlx::query(
                 r#"
-                SELECT room_id, from_user::text AS from_user, message, created_at
-                FROM room_messages
-                WHERE room_id = $1
-                ORDER BY created_at DESC
-                LIMIT $2
+                SELECT DISTINCT ON (m.room_id)
+                  m.room_id,
+                  COUNT(*) OVER (PARTITION BY m.room_id) AS message_count,
+                  m.id,
+                  m.from_user,
+                  m.message,
+                  m.format,
+                  m.created_at,
+                  m.e2ee IS NOT NULL AS encrypted,
+                  (m.redacted_at IS NOT NULL OR COALESCE(m.expires_at <= now(), false)) AS expired,
+                  EXISTS (SELECT 1 FROM chat_attachments a WHERE a.message_id = m.id) AS has_attachments
+                FROM room_messages m
+                ORDER BY m.room_id, m.id DESC
                 "#,
             )
-            .bind(room_id)
-            .bind(limit)
-            .fetch_all(pg)
+            .fetch_all(&mut *conn)
             .await?;
 
-            let mut messages = rows
-                .into_iter()
-                .map(|row| ChatHistoryItem {
-                    room_id: row.get::<String, _>("room_id"),
-                    from_user: row.get::<String, _>("from_user"),
-                    text: row.get::<String, _>("message"),
-                    ts: row.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
-                })
-                .collect::<Vec<_>>();
+            for row in &rows {
+                let preview = if row.get::<bool, _>("expired") {
+                    "[expired]".to_string()
+                } else {
+                    preview(
+                        services::content::parse_format(&row.get::<String, _>("format")),
+                        &row.get::<String, _>("message"),
+                        row.get::<bool, _>("encrypted"),
+                        row.get::<bool, _>("has_attachments"),
+                    )
+                };
+                sqlx::query(
+                    r#"
+                    INSERT INTO room_stats(room_id, message_count, last_message_id, last_from_user, last_preview, last_activity_at)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT (room_id)
+                    DO UPDATE SET last_preview = EXCLUDED.last_preview
+                    WHERE room_stats.last_message_id = EXCLUDED.last_message_id
+                    "#,
+                )
+                .bind(row.get::<String, _>("room_id"))
+                .bind(row.get::<i64, _>("message_count"))
+                .bind(row.get::<i64, _>("id"))
+                .bind(row.get::<Uuid, _>("from_user"))
+                .bind(&preview)
+                .bind(row.get::<chrono::DateTime<chrono::Utc>, _>("created_at"))
+                .execute(&mut *conn)
+                .await?;
+            }
+            Ok(())
+        }
 
-            messages.reverse();
-            Ok(messages)
+        // Direct message rooms exist for their two participants only; every other room is open.
+        pub fn can_view(room_id: &str, user_id: Uuid) -> bool {
+            !room_id.starts_with(shared::dm::DM_ROOM_PREFIX) || shared::dm::is_participant(room_id, user_id)
         }
 
-        pub async fn mark_read(pg: &PgPool, room_id: &str, user_id: Uuid) -> anyhow::Result<()> {
-            sqlx::query(
+        // Returns whether a membership row was created.
+        pub async fn join<'e>(pg: impl sqlx::PgExecutor<'e>, room_id: &str, user_id: Uuid) -> anyhow::Result<bool> {
+            anyhow::ensure!(can_view(room_id, user_id), "{user_id} is not a participant of {room_id}");
+            let created = sqlx::query(
                 r#"
-                INSERT INTO room_member_reads(room_id, user_id, last_read_at)
-                VALUES ($1, $2, now())
-                ON CONFLICT (room_id, user_id)
-                DO UPDATE SET last_read_at = now()
+                INSERT INTO room_memberships(room_id, user_id, joined_at, unread_count)
+                SELECT $1, $2, now(), (
+                  SELECT COUNT(*)::int
+                  FROM room_messages m
+                  WHERE m.room_id = $1
+                    AND m.parent_id IS NULL
+                    AND m.from_user <> $2
+                    AND m.created_at > COALESCE(
+                      (SELECT last_read_at FROM room_member_reads WHERE room_id = $1 AND user_id = $2),
+                      to_timestamp(0)
+                    )
+                )
+                WHERE NOT EXISTS (
+                  SELECT 1 FROM room_memberships WHERE room_id = $1 AND user_id = $2
+                )
+                ON CONFLICT (room_id, user_id) DO NOTHING
                 "#,
             )
             .bind(room_id)
             .bind(user_id)
             .execute(pg)
             .await?;
-            Ok(())
+            Ok(created.rows_affected() > 0)
         }
 
-        pub async fn unread_count(pg: &PgPool, room_id: &str, user_id: Uuid) -> anyhow::Result<i64> {
+        pub async fn find_by_client_id(
+            app: &state::AppState,
+            from_user: Uuid,
+            client_id: Uuid,
+        ) -> anyhow::Result<Option<shared::ChatMessage>> {
             let row = sqlx::query(
                 r#"
-                WITH marker AS (
-                  SELECT last_read_at
-                  FROM room_member_reads
-                  WHERE room_id = $1 AND user_id = $2
-                )
-                SELECT COUNT(*)::bigint AS unread_count
+                SELECT id, room_id, from_user, message, parent_id, client_id, e2ee, format, event, expires_at, created_at
                 FROM room_messages
-                WHERE room_id = $1
-                  AND from_user <> $2
-                  AND created_at > COALESCE((SELECT last_read_at FROM marker), to_timestamp(0))
+                WHERE from_user = $1 AND client_id = $2
+                "#,
+            )
+            .bind(from_user)
+            .bind(client_id)
+            .fetch_optional(&app.pg)
+            .await?;
+
+            let Some(row) = row else {
+                return Ok(None);
+            };
+            let id = row.get::<i64, _>("id");
+            Ok(Some(shared::ChatMessage {
+                room_id: row.get::<String, _>("room_id"),
+                from_user: row.get::<Uuid, _>("from_user"),
+                text: row.get::<String, _>("message"),
+                ts: row.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+                id: Some(id),
+                parent_id: row.get::<Option<i64>, _>("parent_id"),
+                attachments: services::attachment::for_message(app, id).await?,
+                client_id: row.get::<Option<Uuid>, _>("client_id"),
+                e2ee: row
+                    .get::<Option<sqlx::types::Json<shared::E2eePayload>>, _>("e2ee")
+                    .map(|payload| payload.0),
+                format: services::content::parse_format(&row.get::<String, _>("format")),
+                event: row
+                    .get::<Option<sqlx::types::Json<shared::ChatEvent>>, _>("event")
+                    .map(|event| event.0),
+                expires_at: row.get::<Option<chrono::DateTime<chrono::Utc>>, _>("expires_at"),
+            }))
+        }
+
+        pub async fn is_thread_root(pg: &PgPool, room_id: &str, message_id: i64) -> anyhow::Result<bool> {
+            let row = sqlx::query(
+                r#"
+                SELECT EXISTS(
+                  SELECT 1
+                  FROM room_messages
+                  WHERE id = $1 AND room_id = $2 AND parent_id IS NULL
+                    AND (expires_at IS NULL OR expires_at > now())
+                ) AS found
                 "#,
             )
+            .bind(message_id)
             .bind(room_id)
-            .bind(user_id)
             .fetch_one(pg)
             .await?;
+            Ok(row.get::<bool, _>("found"))
+        }
 
-            Ok(row.get::<i64, _>("unread_count"))
+        // The server only checks the envelope shape; it never sees keys or plaintext.
+        fn validate_e2ee(payload: &shared::E2eePayload, msg: &shared::ChatMessage, participants: [Uuid; 2]) -> Result<(), &'static str> {
+            if payload.version != E2EE_VERSION {
+                return Err("unsupported envelope version");
+            }
+            if !msg.text.is_empty() || !msg.attachments.is_empty() {
+                return Err("encrypted messages cannot carry plaintext or attachments");
+            }
+            if payload.ciphertext.is_empty() || payload.ciphertext.len() > MAX_E2EE_CIPHERTEXT {
+                return Err("ciphertext is empty or too large");
+            }
+            if payload.nonce.is_empty() || payload.nonce.len() > 64 {
+                return Err("bad nonce");
+            }
+            if payload.recipients.is_empty() || payload.recipients.len() > participants.len() {
+                return Err("recipient list must cover the conversation participants only");
+            }
+            let mut seen = Vec::with_capacity(payload.recipients.len());
+            for wrap in &payload.recipients {
+                if !participants.contains(&wrap.user_id) || seen.contains(&wrap.user_id) {
+                    return Err("recipient list must cover the conversation participants only");
+                }
+                if wrap.wrapped_key.len() > 256 || wrap.ephemeral_key.len() > 64 || wrap.nonce.len() > 64 {
+                    return Err("bad key wrap");
+                }
+                seen.push(wrap.user_id);
+            }
+            Ok(())
         }
 
-        pub async fn room_members(pg: &PgPool, room_id: &str) -> anyhow::Result<Vec<Uuid>> {
+        pub async fn authorize(app: &state::AppState, room_id: &str, user_id: Uuid) -> Result<(), SendError> {
+            if !services::local::can_post(&app.redis, room_id, user_id).await? {
+                return Err(SendError::NotInRoom);
+            }
+            if room_id.starts_with(shared::dm::DM_ROOM_PREFIX) && !shared::dm::is_participant(room_id, user_id) {
+                return Err(SendError::NotParticipant);
+            }
+            Ok(())
+        }
+
+        pub async fn moderate(app: &state::AppState, room_id: &str, user_id: Uuid, text: &str) -> Result<String, SendError> {
+            match services::moderation::check(app, room_id, user_id, text).await? {
+                services::moderation::Verdict::Allow(text) => Ok(text),
+                services::moderation::Verdict::Banned(until) => Err(SendError::Banned(until)),
+                services::moderation::Verdict::Muted(until) => Err(SendError::Muted(until)),
+                services::moderation::Verdict::SlowMode(retry) => Err(SendError::SlowMode(retry)),
+                services::moderation::Verdict::Blocked => Err(SendError::Blocked),
+            }
+        }
+
+        // Who may see a room's live traffic: the two participants of a direct message, or
+        // everyone (`None`) for any other room.
+        pub fn audience(room_id: &str) -> Option<Vec<Uuid>> {
+            shared::dm::participants(room_id).map(|(lo, hi)| vec![lo, hi])
+        }
+
+        // Live-only room events such as receipts and typing: no mailbox, same audience as `publish`.
+        pub fn emit(app: &state::AppState, room_id: &str, packet: &shared::RealtimePacket) {
+            let Ok(payload) = rmp_serde::to_vec(packet) else {
+                return;
+            };
+            let outbound = match audience(room_id) {
+                Some(users) => state::Outbound::users(users, payload),
+                None => state::Outbound::everyone(payload),
+            };
+            let _ = app.realtime_tx.send(outbound);
+        }
+
+        // Direct message traffic is queued in each participant's mailbox, which pushes it to that
+        // user's sockets only; everything else is fanned out.
+        pub async fn publish(app: &state::AppState, room_id: &str, packet: shared::RealtimePacket) {
+            match audience(room_id) {
+                Some(users) => {
+                    for user_id in users {
+                        services::mailbox::deliver(app, user_id, packet.clone()).await;
+                    }
+                }
+                None => {
+                    if let Ok(payload) = rmp_serde::to_vec(&packet) {
+                        let _ = app.realtime_tx.send(state::Outbound::everyone(payload));
+                    }
+                }
+            }
+        }
+
+        pub async fn send(app: &state::AppState, mut msg: shared::ChatMessage) -> Result<Sent, SendError> {
+            authorize(app, &msg.room_id, msg.from_user).await?;
+            if let Some(client_id) = msg.client_id {
+                if let Some(existing) = find_by_client_id(app, msg.from_user, client_id).await? {
+                    return Ok(Sent { message: existing, duplicate: true });
+                }
+            }
+            if let Some(payload) = &msg.e2ee {
+                let Some((lo, hi)) = shared::dm::participants(&msg.room_id) else {
+                    return Err(SendError::InvalidEncryption("end-to-end encryption is only available in direct messages"));
+                };
+                validate_e2ee(payload, &msg, [lo, hi]).map_err(SendError::InvalidEncryption)?;
+            }
+            services::content::prepare(&mut msg)?;
+            if let Some(expires_at) = msg.expires_at {
+                services::scheduled::check_expiry(expires_at)?;
+            }
+
+            if let Some(parent_id) = msg.parent_id {
+                if !is_thread_root(&app.pg, &msg.room_id, parent_id).await? {
+                    return Err(SendError::InvalidParent);
+                }
+            }
+
+            msg.text = moderate(app, &msg.room_id, msg.from_user, &msg.text).await?;
+
+            let mut tx = app.pg.begin().await?;
+            let Some(id) = insert_message(&mut *tx, &msg).await? else {
+                // A concurrent retry with the same client id won the insert.
+                tx.rollback().await?;
+                let client_id = msg.client_id.unwrap_or_default();
+                let existing = find_by_client_id(app, msg.from_user, client_id)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("duplicate client id {client_id} without stored message"))?;
+                return Ok(Sent { message: existing, duplicate: true });
+            };
+            msg.id = Some(id);
+            record_activity(&mut *tx, &msg, id).await?;
+            let mentions = services::mention::record(&mut *tx, &msg, id).await?;
+
+            if !msg.attachments.is_empty() {
+                let mut requested = msg.attachments.iter().map(|a| a.id).collect::<Vec<_>>();
+                requested.sort();
+                requested.dedup();
+                let claimed = services::attachment::claim(&mut *tx, id, &msg.room_id, msg.from_user, &requested).await?;
+                if claimed.len() != requested.len() {
+                    return Err(SendError::InvalidAttachment);
+                }
+                msg.attachments = services::attachment::with_download_urls(app, claimed).await;
+            }
+            tx.commit().await?;
+            if let Err(err) = services::moderation::start_slow_mode(app, &msg.room_id, msg.from_user).await {
+                tracing::warn!(?err, room_id = %msg.room_id, "slow mode window not started");
+            }
+
+            if let Some(parent_id) = msg.parent_id {
+                let _ = mark_thread_read(&app.pg, &msg.room_id, parent_id, msg.from_user).await;
+            }
+            services::typing::clear(app, &msg.room_id, msg.from_user).await;
+
+            publish(app, &msg.room_id, shared::RealtimePacket::Chat(msg.clone())).await;
+            services::mention::publish(app, mentions).await;
+            Ok(Sent { message: msg, duplicate: false })
+        }
+
+        pub(crate) async fn history(pg: &PgPool, room_id: &str, limit: i64) -> anyhow::Result<Vec<ChatHistoryItem>> {
             let rows = sqlx::query(
                 r#"
-                SELECT DISTINCT from_user
-                FROM room_messages
-                WHERE room_id = $1
-                ORDER BY from_user
+                SELECT
+                    m.id,
+                    m.room_id,
+                    m.from_user::text AS from_user,
+                    m.message,
+                    m.parent_id,
+                    m.created_at,
+                    m.e2ee,
+                    m.format,
+                    m.event,
+                    m.expires_at,
+                    t.reply_count,
+                    t.last_reply_at
+                FROM room_messages m
+                CROSS JOIN LATERAL (
+                  SELECT COUNT(*)::bigint AS reply_count, MAX(r.created_at) AS last_reply_at
+                  FROM room_messages r
+                  WHERE r.parent_id = m.id
+                    AND (r.expires_at IS NULL OR r.expires_at > now())
+                ) t
+                WHERE m.room_id = $1 AND m.parent_id IS NULL
+                  AND (m.expires_at IS NULL OR m.expires_at > now())
+                ORDER BY m.created_at DESC
+                LIMIT $2
                 "#,
             )
             .bind(room_id)
+            .bind(limit)
             .fetch_all(pg)
             .await?;
 
-            Ok(rows
-                .into_iter()
-                .map(|r| r.get::<Uuid, _>("from_user"))
-                .collect())
+            let mut messages = rows.iter().map(history_item).collect::<Vec<_>>();
+
+            messages.reverse();
+            Ok(messages)
         }
-    }
 
-    pub mod invite {
-        use super::*;
+        pub(crate) async fn thread_root(pg: &PgPool, room_id: &str, parent_id: i64) -> anyhow::Result<Option<ChatHistoryItem>> {
+            let row = sqlx::query(
+                r#"
+                SELECT
+                    m.id,
+                    m.room_id,
+                    m.from_user::text AS from_user,
+                    m.message,
+                    m.parent_id,
+                    m.created_at,
+                    m.e2ee,
+                    m.format,
+                    m.event,
+                    m.expires_at,
+                    t.reply_count,
+                    t.last_reply_at
+                FROM room_messages m
+                CROSS JOIN LATERAL (
+                  SELECT COUNT(*)::bigint AS reply_count, MAX(r.created_at) AS last_reply_at
+                  FROM room_messages r
+                  WHERE r.parent_id = m.id
+                    AND (r.expires_at IS NULL OR r.expires_at > now())
+                ) t
+                WHERE m.id = $1 AND m.room_id = $2 AND m.parent_id IS NULL
+                  AND (m.expires_at IS NULL OR m.expires_at > now())
+                "#,
+            )
+            .bind(parent_id)
+            .bind(room_id)
+            .fetch_optional(pg)
+            .await?;
 
-        pub async fn create(pg: &PgPool, from_user: Uuid, to_user: Uuid, mode: &str) -> anyhow::Result<Uuid> {
-            let invite_id = Uuid::new_v4();
-            sqlx::query(
+            Ok(row.as_ref().map(history_item))
+        }
+
+        pub(crate) async fn thread_replies(
+            pg: &PgPool,
+            parent_id: i64,
+            before_id: Option<i64>,
+            limit: i64,
+        ) -> anyhow::Result<Vec<ChatHistoryItem>> {
+            let rows = sqlx::query(
                 r#"
-                INSERT INTO invites(id, from_user, to_user, mode, status, created_at)
-                VALUES ($1, $2, $3, $4, 'pending', now())
+                SELECT
+                    id,
+                    room_id,
+                    from_user::text AS from_user,
+                    message,
+                    parent_id,
+                    created_at,
+                    e2ee,
+                    format,
+                    event,
+                    expires_at,
+                    0::bigint AS reply_count,
+                    NULL::timestamptz AS last_reply_at
+                FROM room_messages
+                WHERE parent_id = $1
+                  AND ($2::bigint IS NULL OR id < $2)
+                  AND (expires_at IS NULL OR expires_at > now())
+                ORDER BY id DESC
+                LIMIT $3
                 "#,
             )
-            .bind(invite_id)
-            .bind(from_user)
-            .bind(to_user)
-            .bind(mode)
-            .execute(pg)
+            .bind(parent_id)
+            .bind(before_id)
+            .bind(limit)
+            .fetch_all(pg)
             .await?;
-            Ok(invite_id)
+
+            let mut replies = rows.iter().map(history_item).collect::<Vec<_>>();
+
+            replies.reverse();
+            Ok(replies)
         }
 
-        pub async fn respond(pg: &PgPool, invite_id: Uuid, to_user: Uuid, status: &str) -> anyhow::Result<Option<(Uuid, Uuid, String)>> {
+        // Moves the read marker forward and takes the newly read messages off the room's unread
+        // counter in the same transaction; reading up to the room's latest message zeroes it.
+        pub async fn mark_read(pg: &PgPool, room_id: &str, user_id: Uuid, message_id: Option<i64>) -> anyhow::Result<Option<i64>> {
+            if !can_view(room_id, user_id) {
+                return Ok(None);
+            }
+            let mut tx = pg.begin().await?;
+            let previous = sqlx::query("SELECT last_read_at FROM room_member_reads WHERE room_id = $1 AND user_id = $2 FOR UPDATE")
+                .bind(room_id)
+                .bind(user_id)
+                .fetch_optional(&mut *tx)
+                .await?
+                .map(|r| r.get::<chrono::DateTime<chrono::Utc>, _>("last_read_at"));
+
             let row = sqlx::query(
                 r#"
-                UPDATE invites
-                SET status = $1, responded_at = now()
-                WHERE id = $2 AND to_user = $3 AND status = 'pending'
-                RETURNING from_user, to_user, mode
+                WITH target AS (
+                  SELECT id, created_at
+                  FROM room_messages
+                  WHERE room_id = $1 AND ($3::bigint IS NULL OR id = $3)
+                  ORDER BY id DESC
+                  LIMIT 1
+                )
+                INSERT INTO room_member_reads(room_id, user_id, last_read_at, last_read_message_id, last_delivered_message_id)
+                SELECT $1, $2, target.created_at, target.id, target.id
+                FROM target
+                ON CONFLICT (room_id, user_id)
+                DO UPDATE SET
+                  last_read_at = GREATEST(room_member_reads.last_read_at, EXCLUDED.last_read_at),
+                  last_read_message_id = GREATEST(room_member_reads.last_read_message_id, EXCLUDED.last_read_message_id),
+                  last_delivered_message_id = GREATEST(room_member_reads.last_delivered_message_id, EXCLUDED.last_delivered_message_id)
+                RETURNING last_read_message_id, last_read_at
                 "#,
             )
-            .bind(status)
-            .bind(invite_id)
-            .bind(to_user)
-            .fetch_optional(pg)
+            .bind(room_id)
+            .bind(user_id)
+            .bind(message_id)
+            .fetch_optional(&mut *tx)
             .await?;
 
-            Ok(row.map(|r| {
-                (
-                    r.get::<Uuid, _>("from_user"),
-                    r.get::<Uuid, _>("to_user"),
-                    r.get::<String, _>("mode"),
+            // A fresh membership row already counts from the new read marker.
+            let joined = join(&mut *tx, room_id, user_id).await?;
+            if let (Some(row), false) = (row.as_ref(), joined) {
+                sqlx::query(
+                    r#"
+                    UPDATE room_memberships
+                    SET unread_count = CASE
+                      WHEN $3 >= (SELECT last_message_id FROM room_stats WHERE room_id = $1) THEN 0
+                      ELSE GREATEST(unread_count - (
+                        SELECT COUNT(*)::int
+                        FROM room_messages m
+                        WHERE m.room_id = $1
+                          AND m.parent_id IS NULL
+                          AND m.from_user <> $2
+                          AND m.created_at > $4
+                          AND m.created_at <= $5
+                      ), 0)
+                    END
+                    WHERE room_id = $1 AND user_id = $2
+                    "#,
                 )
-            }))
+                .bind(room_id)
+                .bind(user_id)
+                .bind(row.get::<i64, _>("last_read_message_id"))
+                .bind(previous.unwrap_or_default())
+                .bind(row.get::<chrono::DateTime<chrono::Utc>, _>("last_read_at"))
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+
+            Ok(row.map(|r| r.get::<i64, _>("last_read_message_id")))
         }
 
-        pub(crate) async fn pending_for_user(pg: &PgPool, to_user: Uuid) -> anyhow::Result<Vec<InviteItem>> {
+        pub(crate) async fn inbox(pg: &PgPool, user_id: Uuid) -> anyhow::Result<Vec<InboxItem>> {
+            join(pg, "global", user_id).await?;
             let rows = sqlx::query(
                 r#"
-                SELECT id::text AS invite_id, from_user::text AS from_user, to_user::text AS to_user, mode, status, created_at
-                FROM invites
-                WHERE to_user = $1 AND status = 'pending'
-                ORDER BY created_at DESC
-                LIMIT 100
+                SELECT
+                    rm.room_id,
+                    rm.unread_count,
+                    s.last_message_id,
+                    s.last_from_user::text AS last_from_user,
+                    s.last_preview,
+                    s.last_activity_at
+                FROM room_memberships rm
+                LEFT JOIN room_stats s ON s.room_id = rm.room_id
+                WHERE rm.user_id = $1
+                ORDER BY s.last_activity_at DESC NULLS LAST, rm.room_id
                 "#,
             )
-            .bind(to_user)
+            .bind(user_id)
             .fetch_all(pg)
             .await?;
 
             Ok(rows
                 .into_iter()
-                .map(|r| InviteItem {
-                    invite_id: r.get::<String, _>("invite_id"),
-                    from_user: r.get::<String, _>("from_user"),
-                    to_user: r.get::<String, _>("to_user"),
-                    mode: r.get::<String, _>("mode"),
-                    status: r.get::<String, _>("status"),
-                    ts: r.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+                .filter(|r| can_view(&r.get::<String, _>("room_id"), user_id))
+                .map(|r| InboxItem {
+                    room_id: r.get::<String, _>("room_id"),
+                    unread_count: i64::from(r.get::<i32, _>("unread_count")),
+                    last_message_id: r.get::<Option<i64>, _>("last_message_id"),
+                    last_from_user: r.get::<Option<String>, _>("last_from_user"),
+                    last_preview: r.get::<Option<String>, _>("last_preview"),
+                    last_activity_at: r.get::<Option<chrono::DateTime<chrono::Utc>>, _>("last_activity_at"),
                 })
                 .collect())
         }
-    }
-
-    pub mod game {
-        use super::*;
 
-        pub async fn websocket_fallback_loop(
-            mut ws: WebSocket,
-            app: Arc<state::AppState>,
-            auth_user: Uuid,
-            mut rx: broadcast::Receiver<Vec<u8>>,
-        ) {
-            loop {
-                tokio::select! {
-                    incoming = ws.recv() => {
-                        match incoming {
-                            Some(Ok(Message::Binary(bin))) => {
-                                let Ok(packet) = rmp_serde::from_slice::<shared::RealtimePacket>(&bin) else {
-                                    continue;
-                                };
+        pub async fn mark_delivered(pg: &PgPool, room_id: &str, user_id: Uuid, message_id: i64) -> anyhow::Result<bool> {
+            if !can_view(room_id, user_id) {
+                return Ok(false);
+            }
+            let row = sqlx::query(
+                r#"
+                INSERT INTO room_member_reads(room_id, user_id, last_read_at, last_delivered_message_id)
+                SELECT $1, $2, to_timestamp(0), m.id
+                FROM room_messages m
+                WHERE m.id = $3 AND m.room_id = $1
+                  AND EXISTS (SELECT 1 FROM room_memberships rm WHERE rm.room_id = $1 AND rm.user_id = $2)
+                ON CONFLICT (room_id, user_id)
+                DO UPDATE SET last_delivered_message_id = GREATEST(room_member_reads.last_delivered_message_id, EXCLUDED.last_delivered_message_id)
+                RETURNING last_delivered_message_id
+                "#,
+            )
+            .bind(room_id)
+            .bind(user_id)
+            .bind(message_id)
+            .fetch_optional(pg)
+            .await?;
+            Ok(row.is_some())
+        }
 
-                                if let shared::RealtimePacket::Position(mut pos) = packet {
-                                    pos.user_id = auth_user;
-                                    let _ = services::realtime::ingest_position(&app, auth_user, pos.lon, pos.lat).await;
-                                } else if let shared::RealtimePacket::Chat(mut chat) = packet {
-                                    chat.from_user = auth_user;
-                                    if chat.room_id.trim().is_empty() {
-                                        chat.room_id = "global".to_string();
-                                    }
-                                    let _ = services::chat::insert_message(&app.pg, &chat).await;
-                                    if let Ok(payload) = rmp_serde::to_vec(&shared::RealtimePacket::Chat(chat)) {
-                                        let _ = app.realtime_tx.send(payload);
-                                    }
-                                } else if let shared::RealtimePacket::Invite(mut invite) = packet {
-                                    invite.from_user = auth_user;
-                                    if let Ok(payload) = rmp_serde::to_vec(&shared::RealtimePacket::Invite(invite)) {
-                                        let _ = app.realtime_tx.send(payload);
-                                    }
-                                }
-                            }
-                            Some(Ok(Message::Close(_))) | None => break,
-                            _ => {}
-                        }
+        pub(crate) async fn receipts(pg: &PgPool, room_id: &str, message_id: i64) -> anyhow::Result<Vec<MessageReceipt>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT
+                    r.user_id::text AS user_id,
+                    COALESCE(r.last_read_message_id >= m.id, false) AS read,
+                    r.last_read_at
+                FROM room_messages m
+                JOIN room_member_reads r ON r.room_id = m.room_id
+                WHERE m.id = $2
+                  AND m.room_id = $1
+                  AND r.user_id <> m.from_user
+                  AND GREATEST(r.last_delivered_message_id, r.last_read_message_id) >= m.id
+                ORDER BY r.last_read_at DESC
+                "#,
+            )
+            .bind(room_id)
+            .bind(message_id)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|r| {
+                    let read = r.get::<bool, _>("read");
+                    MessageReceipt {
+                        user_id: r.get::<String, _>("user_id"),
+                        delivered: true,
+                        read,
+                        read_at: read.then(|| r.get::<chrono::DateTime<chrono::Utc>, _>("last_read_at")),
                     }
-                    outbound = rx.recv() => {
-                        match outbound {
-                            Ok(bin) => {
-                                if ws.send(Message::Binary(bin.into())).await.is_err() {
-                                    break;
-                                }
-                            }
-                            Err(_) => break,
-                        }
+                })
+                .collect())
+        }
+
+        pub async fn message_exists(pg: &PgPool, room_id: &str, message_id: i64) -> anyhow::Result<bool> {
+            let row = sqlx::query(
+                r#"
+                SELECT EXISTS(
+                  SELECT 1
+                  FROM room_messages
+                  WHERE id = $1 AND room_id = $2
+                ) AS found
+                "#,
+            )
+            .bind(message_id)
+            .bind(room_id)
+            .fetch_one(pg)
+            .await?;
+            Ok(row.get::<bool, _>("found"))
+        }
+
+        pub fn broadcast_receipt(app: &state::AppState, room_id: &str, message_id: i64, user_id: Uuid, kind: shared::ReceiptKind) {
+            let packet = shared::RealtimePacket::Receipt(shared::ReceiptEvent {
+                room_id: room_id.to_string(),
+                message_id,
+                user_id,
+                kind,
+                ts: chrono::Utc::now(),
+            });
+            emit(app, room_id, &packet);
+        }
+
+        pub async fn mark_thread_read(pg: &PgPool, room_id: &str, parent_id: i64, user_id: Uuid) -> anyhow::Result<()> {
+            sqlx::query(
+                r#"
+                INSERT INTO room_thread_reads(room_id, parent_id, user_id, last_read_at)
+                VALUES ($1, $2, $3, now())
+                ON CONFLICT (parent_id, user_id)
+                DO UPDATE SET last_read_at = now()
+                "#,
+            )
+            .bind(room_id)
+            .bind(parent_id)
+            .bind(user_id)
+            .execute(pg)
+            .await?;
+            Ok(())
+        }
+
+        pub async fn unread_count(pg: &PgPool, room_id: &str, user_id: Uuid) -> anyhow::Result<i64> {
+            let row = sqlx::query(
+                r#"
+                WITH marker AS (
+                  SELECT last_read_at
+                  FROM room_member_reads
+                  WHERE room_id = $1 AND user_id = $2
+                )
+                SELECT COUNT(*)::bigint AS unread_count
+                FROM room_messages
+                WHERE room_id = $1
+                  AND parent_id IS NULL
+                  AND from_user <> $2
+                  AND created_at > COALESCE((SELECT last_read_at FROM marker), to_timestamp(0))
+                "#,
+            )
+            .bind(room_id)
+            .bind(user_id)
+            .fetch_one(pg)
+            .await?;
+
+            Ok(row.get::<i64, _>("unread_count"))
+        }
+
+        pub async fn thread_unread_count(pg: &PgPool, parent_id: i64, user_id: Uuid) -> anyhow::Result<i64> {
+            let row = sqlx::query(
+                r#"
+                WITH marker AS (
+                  SELECT last_read_at
+                  FROM room_thread_reads
+                  WHERE parent_id = $1 AND user_id = $2
+                )
+                SELECT COUNT(*)::bigint AS unread_count
+                FROM room_messages
+                WHERE parent_id = $1
+                  AND from_user <> $2
+                  AND created_at > COALESCE((SELECT last_read_at FROM marker), to_timestamp(0))
+                "#,
+            )
+            .bind(parent_id)
+            .bind(user_id)
+            .fetch_one(pg)
+            .await?;
+
+            Ok(row.get::<i64, _>("unread_count"))
+        }
+
+        pub(crate) async fn followed_thread_unreads(pg: &PgPool, room_id: &str, user_id: Uuid) -> anyhow::Result<Vec<ThreadUnreadState>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT t.parent_id, COUNT(m.id)::bigint AS unread_count
+                FROM room_thread_reads t
+                JOIN room_messages m
+                  ON m.parent_id = t.parent_id
+                 AND m.from_user <> t.user_id
+                 AND m.created_at > t.last_read_at
+                WHERE t.room_id = $1 AND t.user_id = $2
+                GROUP BY t.parent_id
+                ORDER BY t.parent_id
+                "#,
+            )
+            .bind(room_id)
+            .bind(user_id)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|r| ThreadUnreadState {
+                    parent_id: r.get::<i64, _>("parent_id"),
+                    unread_count: r.get::<i64, _>("unread_count"),
+                })
+                .collect())
+        }
+
+        pub async fn room_members(pg: &PgPool, room_id: &str) -> anyhow::Result<Vec<Uuid>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT DISTINCT from_user
+                FROM room_messages
+                WHERE room_id = $1
+                ORDER BY from_user
+                "#,
+            )
+            .bind(room_id)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|r| r.get::<Uuid, _>("from_user"))
+                .collect())
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn wrap(user_id: Uuid) -> shared::E2eeKeyWrap {
+                shared::E2eeKeyWrap {
+                    user_id,
+                    prekey_id: 1,
+                    ephemeral_key: "e".repeat(44),
+                    nonce: "n".repeat(16),
+                    wrapped_key: "w".repeat(64),
+                }
+            }
+
+            fn envelope(recipients: Vec<shared::E2eeKeyWrap>) -> shared::E2eePayload {
+                shared::E2eePayload {
+                    version: E2EE_VERSION,
+                    nonce: "n".repeat(16),
+                    ciphertext: "c".repeat(128),
+                    recipients,
+                }
+            }
+
+            fn message(text: &str) -> shared::ChatMessage {
+                shared::ChatMessage {
+                    room_id: "dm:test".to_string(),
+                    from_user: Uuid::nil(),
+                    text: text.to_string(),
+                    ts: chrono::Utc::now(),
+                    id: None,
+                    parent_id: None,
+                    attachments: Vec::new(),
+                    client_id: None,
+                    e2ee: None,
+                    format: shared::MessageFormat::default(),
+                    event: None,
+                    expires_at: None,
+                }
+            }
+
+            #[test]
+            fn envelope_shape_is_enforced() {
+                let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+                let ok = envelope(vec![wrap(a), wrap(b)]);
+                assert_eq!(validate_e2ee(&ok, &message(""), [a, b]), Ok(()));
+                assert_eq!(validate_e2ee(&envelope(vec![wrap(b)]), &message(""), [a, b]), Ok(()));
+
+                let mut old = ok.clone();
+                old.version = E2EE_VERSION + 1;
+                assert!(validate_e2ee(&old, &message(""), [a, b]).is_err());
+
+                let mut huge = ok.clone();
+                huge.ciphertext = "c".repeat(MAX_E2EE_CIPHERTEXT + 1);
+                assert!(validate_e2ee(&huge, &message(""), [a, b]).is_err());
+                huge.ciphertext = "c".repeat(MAX_E2EE_CIPHERTEXT);
+                assert_eq!(validate_e2ee(&huge, &message(""), [a, b]), Ok(()));
+
+                let mut empty = ok.clone();
+                empty.ciphertext.clear();
+                assert!(validate_e2ee(&empty, &message(""), [a, b]).is_err());
+
+                assert!(validate_e2ee(&ok, &message("leaked"), [a, b]).is_err());
+
+                let mut bad_wrap = envelope(vec![wrap(a)]);
+                bad_wrap.recipients[0].wrapped_key = "w".repeat(257);
+                assert!(validate_e2ee(&bad_wrap, &message(""), [a, b]).is_err());
+            }
+
+            #[test]
+            fn recipients_must_be_the_participants() {
+                let (a, b, outsider) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+                for recipients in [
+                    vec![],
+                    vec![wrap(a), wrap(outsider)],
+                    vec![wrap(outsider)],
+                    vec![wrap(a), wrap(a)],
+                    vec![wrap(a), wrap(b), wrap(outsider)],
+                ] {
+                    let payload = envelope(recipients);
+                    assert!(validate_e2ee(&payload, &message(""), [a, b]).is_err());
+                }
+            }
+        }
+    }
+
+    pub mod content {
+        use super::*;
+        use once_cell::sync::Lazy;
+        use regex::Regex;
+        use services::chat::SendError;
+
+        pub const MAX_PLAIN_CHARS: usize = 2_000;
+        pub const MAX_MARKDOWN_CHARS: usize = 8_000;
+
+        // Clients render bodies as text nodes, so this is defence in depth for anything
+        // else that reads room_messages (exports, search snippets, older clients).
+        static DANGEROUS_BLOCK: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"(?is)<(script|style|iframe|object|embed|template|noscript)\b[^>]*>.*?</\s*(script|style|iframe|object|embed|template|noscript)\s*>")
+                .expect("static regex")
+        });
+        static HTML_COMMENT: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)<!--.*?(-->|$)").expect("static regex"));
+        static HTML_TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"</?[A-Za-z][A-Za-z0-9:-]*(\s[^<>]*)?/?>").expect("static regex"));
+        static INLINE_CODE: Lazy<Regex> = Lazy::new(|| Regex::new(r"`[^`\n]+`").expect("static regex"));
+        static BLANK_RUN: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n{3,}").expect("static regex"));
+
+        pub fn format_str(format: shared::MessageFormat) -> &'static str {
+            match format {
+                shared::MessageFormat::Plain => "plain",
+                shared::MessageFormat::Markdown => "markdown",
+                shared::MessageFormat::System => "system",
+                shared::MessageFormat::Event => "event",
+            }
+        }
+
+        pub fn parse_format(value: &str) -> shared::MessageFormat {
+            match value {
+                "markdown" => shared::MessageFormat::Markdown,
+                "system" => shared::MessageFormat::System,
+                "event" => shared::MessageFormat::Event,
+                _ => shared::MessageFormat::Plain,
+            }
+        }
+
+        fn max_chars(format: shared::MessageFormat) -> usize {
+            match format {
+                shared::MessageFormat::Markdown => MAX_MARKDOWN_CHARS,
+                _ => MAX_PLAIN_CHARS,
+            }
+        }
+
+        // Bidi overrides can make a message display differently from what it says.
+        fn is_stripped_char(c: char) -> bool {
+            (c.is_control() && c != '\n' && c != '\t') || matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+        }
+
+        fn strip_html(text: &str) -> String {
+            let text = DANGEROUS_BLOCK.replace_all(text, "");
+            let text = HTML_COMMENT.replace_all(&text, "");
+            HTML_TAG.replace_all(&text, "").into_owned()
+        }
+
+        // Code spans and fenced blocks keep their contents verbatim so snippets survive.
+        fn strip_html_outside_code(text: &str) -> String {
+            let mut out = String::with_capacity(text.len());
+            let mut prose = String::new();
+            let mut in_fence = false;
+
+            let flush = |prose: &mut String, out: &mut String| {
+                let mut last = 0;
+                for span in INLINE_CODE.find_iter(prose) {
+                    out.push_str(&strip_html(&prose[last..span.start()]));
+                    out.push_str(span.as_str());
+                    last = span.end();
+                }
+                out.push_str(&strip_html(&prose[last..]));
+                prose.clear();
+            };
+
+            for line in text.split_inclusive('\n') {
+                let is_fence = line.trim_start().starts_with("```");
+                if in_fence || is_fence {
+                    flush(&mut prose, &mut out);
+                    out.push_str(line);
+                    if is_fence {
+                        in_fence = !in_fence;
                     }
+                } else {
+                    prose.push_str(line);
+                }
+            }
+            flush(&mut prose, &mut out);
+            out
+        }
+
+        pub fn sanitize(format: shared::MessageFormat, text: &str) -> String {
+            let text = text.replace("\r\n", "\n").replace('\r', "\n");
+            let text = text.chars().filter(|c| !is_stripped_char(*c)).collect::<String>();
+            let text = match format {
+                shared::MessageFormat::Markdown => strip_html_outside_code(&text),
+                _ => strip_html(&text),
+            };
+            BLANK_RUN.replace_all(text.trim(), "\n\n").into_owned()
+        }
+
+        pub fn prepare(msg: &mut shared::ChatMessage) -> Result<(), SendError> {
+            if matches!(msg.format, shared::MessageFormat::System | shared::MessageFormat::Event) {
+                return Err(SendError::InvalidContent("system and event messages can only be posted by the server"));
+            }
+            msg.event = None;
+
+            // Encrypted bodies are opaque here; validate_e2ee bounds the ciphertext instead.
+            if msg.e2ee.is_some() {
+                return Ok(());
+            }
+
+            let text = sanitize(msg.format, &msg.text);
+            let limit = max_chars(msg.format);
+            if text.chars().count() > limit {
+                return Err(SendError::TooLong(limit));
+            }
+            if text.is_empty() && msg.attachments.is_empty() {
+                return Err(SendError::InvalidContent("message is empty after removing markup"));
+            }
+            msg.text = text;
+            Ok(())
+        }
+
+        pub fn plain_text(format: shared::MessageFormat, text: &str) -> String {
+            match format {
+                shared::MessageFormat::Markdown => shared::markdown::to_plain(text),
+                _ => text.to_string(),
+            }
+        }
+    }
+
+    pub mod moderation {
+        use super::*;
+        use once_cell::sync::Lazy;
+        use regex::{Regex, RegexBuilder};
+        use std::collections::HashMap;
+        use std::sync::RwLock;
+
+        const MAX_FILTER_ENTRIES: usize = 200;
+        const MAX_PATTERN_LEN: usize = 200;
+        const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+        static ADMIN_IDS: Lazy<Vec<Uuid>> = Lazy::new(|| {
+            std::env::var("PLATFORM_ADMIN_IDS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|raw| Uuid::parse_str(raw.trim()).ok())
+                .collect()
+        });
+
+        static POLICY_CACHE: Lazy<RwLock<HashMap<String, Arc<RoomPolicy>>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum FilterAction {
+            Reject,
+            Mask,
+        }
+
+        impl FilterAction {
+            pub fn parse(raw: &str) -> Option<Self> {
+                match raw {
+                    "reject" => Some(FilterAction::Reject),
+                    "mask" => Some(FilterAction::Mask),
+                    _ => None,
+                }
+            }
+
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    FilterAction::Reject => "reject",
+                    FilterAction::Mask => "mask",
+                }
+            }
+        }
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum SanctionKind {
+            Mute,
+            Ban,
+        }
+
+        impl SanctionKind {
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    SanctionKind::Mute => "mute",
+                    SanctionKind::Ban => "ban",
+                }
+            }
+        }
+
+        pub struct RoomPolicy {
+            pub slow_mode_secs: i32,
+            pub filter_action: FilterAction,
+            pub blocked_words: Vec<String>,
+            pub patterns: Vec<Regex>,
+            updated_at: Option<chrono::DateTime<chrono::Utc>>,
+        }
+
+        impl Default for RoomPolicy {
+            fn default() -> Self {
+                RoomPolicy {
+                    slow_mode_secs: 0,
+                    filter_action: FilterAction::Reject,
+                    blocked_words: Vec::new(),
+                    patterns: Vec::new(),
+                    updated_at: None,
                 }
             }
         }
 
-        pub fn webtransport_placeholder() {
-            let _ = "webtransport-enabled";
-        }
+        pub enum Verdict {
+            Allow(String),
+            Muted(Option<chrono::DateTime<chrono::Utc>>),
+            Banned(Option<chrono::DateTime<chrono::Utc>>),
+            SlowMode(i64),
+            Blocked,
+        }
+
+        pub struct SettingsUpdate {
+            pub slow_mode_secs: Option<i32>,
+            pub filter_action: Option<FilterAction>,
+            pub blocked_words: Option<Vec<String>>,
+            pub blocked_patterns: Option<Vec<String>>,
+        }
+
+        pub fn is_admin(user_id: Uuid) -> bool {
+            ADMIN_IDS.contains(&user_id)
+        }
+
+        pub async fn is_moderator(pg: &PgPool, room_id: &str, user_id: Uuid) -> anyhow::Result<bool> {
+            if is_admin(user_id) {
+                return Ok(true);
+            }
+            let row = sqlx::query(
+                r#"
+                SELECT EXISTS(
+                  SELECT 1 FROM room_moderators WHERE room_id = $1 AND user_id = $2
+                ) AS found
+                "#,
+            )
+            .bind(room_id)
+            .bind(user_id)
+            .fetch_one(pg)
+            .await?;
+            Ok(row.get::<bool, _>("found"))
+        }
+
+        pub fn compile_patterns(patterns: &[String]) -> Result<Vec<Regex>, String> {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    if pattern.len() > MAX_PATTERN_LEN {
+                        return Err(format!("pattern longer than {MAX_PATTERN_LEN} bytes"));
+                    }
+                    RegexBuilder::new(pattern)
+                        .case_insensitive(true)
+                        .size_limit(REGEX_SIZE_LIMIT)
+                        .build()
+                        .map_err(|e| format!("invalid pattern {pattern:?}: {e}"))
+                })
+                .collect()
+        }
+
+        // Checks a settings update's lists before anything is stored.
+        pub fn check_lists(words: Option<&[String]>, patterns: Option<&[String]>) -> Result<(), String> {
+            let too_many = |list: Option<&[String]>| list.is_some_and(|l| l.len() > MAX_FILTER_ENTRIES);
+            if too_many(words) || too_many(patterns) {
+                return Err("too many filter entries".to_string());
+            }
+            if let Some(patterns) = patterns {
+                compile_patterns(patterns)?;
+            }
+            Ok(())
+        }
+
+        pub async fn load_policy(pg: &PgPool, room_id: &str) -> anyhow::Result<Arc<RoomPolicy>> {
+            let row = sqlx::query(
+                r#"
+                SELECT slow_mode_secs, filter_action, blocked_words, blocked_patterns, updated_at
+                FROM room_settings
+                WHERE room_id = $1
+                "#,
+            )
+            .bind(room_id)
+            .fetch_optional(pg)
+            .await?;
+
+            let Some(row) = row else {
+                return Ok(Arc::new(RoomPolicy::default()));
+            };
+
+            let updated_at = row.get::<chrono::DateTime<chrono::Utc>, _>("updated_at");
+            if let Some(cached) = POLICY_CACHE.read().ok().and_then(|cache| cache.get(room_id).cloned()) {
+                if cached.updated_at == Some(updated_at) {
+                    return Ok(cached);
+                }
+            }
+
+            let patterns = row.get::<Vec<String>, _>("blocked_patterns");
+            let policy = Arc::new(RoomPolicy {
+                slow_mode_secs: row.get::<i32, _>("slow_mode_secs"),
+                filter_action: FilterAction::parse(&row.get::<String, _>("filter_action")).unwrap_or(FilterAction::Reject),
+                blocked_words: row
+                    .get::<Vec<String>, _>("blocked_words")
+                    .into_iter()
+                    .map(|w| w.to_lowercase())
+                    .filter(|w| !w.is_empty())
+                    .collect(),
+                patterns: compile_patterns(&patterns).unwrap_or_default(),
+                updated_at: Some(updated_at),
+            });
+
+            if let Ok(mut cache) = POLICY_CACHE.write() {
+                cache.insert(room_id.to_string(), policy.clone());
+            }
+            Ok(policy)
+        }
+
+        fn mask_range(chars: &mut [char], start: usize, len: usize) {
+            chars[start..start + len].iter_mut().for_each(|c| *c = '*');
+        }
+
+        pub fn apply_filter(policy: &RoomPolicy, text: &str) -> Option<String> {
+            if policy.blocked_words.is_empty() && policy.patterns.is_empty() {
+                return Some(text.to_string());
+            }
+
+            let mut chars = text.chars().collect::<Vec<_>>();
+            let folded = chars
+                .iter()
+                .map(|c| c.to_lowercase().next().unwrap_or(*c))
+                .collect::<Vec<_>>();
+            let mut hit = false;
+
+            for word in &policy.blocked_words {
+                let needle = word.chars().collect::<Vec<_>>();
+                if needle.is_empty() || needle.len() > folded.len() {
+                    continue;
+                }
+                for start in 0..=folded.len() - needle.len() {
+                    if folded[start..start + needle.len()] == needle[..] {
+                        hit = true;
+                        mask_range(&mut chars, start, needle.len());
+                    }
+                }
+            }
+
+            for pattern in &policy.patterns {
+                for found in pattern.find_iter(text) {
+                    hit = true;
+                    let start = text[..found.start()].chars().count();
+                    let len = found.as_str().chars().count();
+                    mask_range(&mut chars, start, len);
+                }
+            }
+
+            match (hit, policy.filter_action) {
+                (false, _) => Some(text.to_string()),
+                (true, FilterAction::Mask) => Some(chars.into_iter().collect()),
+                (true, FilterAction::Reject) => None,
+            }
+        }
+
+        pub async fn active_sanction(
+            pg: &PgPool,
+            room_id: &str,
+            user_id: Uuid,
+        ) -> anyhow::Result<Option<(SanctionKind, Option<chrono::DateTime<chrono::Utc>>)>> {
+            let row = sqlx::query(
+                r#"
+                SELECT kind, expires_at
+                FROM room_sanctions
+                WHERE room_id = $1
+                  AND user_id = $2
+                  AND (expires_at IS NULL OR expires_at > now())
+                ORDER BY (kind = 'ban') DESC
+                LIMIT 1
+                "#,
+            )
+            .bind(room_id)
+            .bind(user_id)
+            .fetch_optional(pg)
+            .await?;
+
+            Ok(row.map(|r| {
+                let kind = if r.get::<String, _>("kind") == "ban" {
+                    SanctionKind::Ban
+                } else {
+                    SanctionKind::Mute
+                };
+                (kind, r.get::<Option<chrono::DateTime<chrono::Utc>>, _>("expires_at"))
+            }))
+        }
+
+        pub async fn check(app: &state::AppState, room_id: &str, user_id: Uuid, text: &str) -> anyhow::Result<Verdict> {
+            match active_sanction(&app.pg, room_id, user_id).await? {
+                Some((SanctionKind::Ban, until)) => return Ok(Verdict::Banned(until)),
+                Some((SanctionKind::Mute, until)) => return Ok(Verdict::Muted(until)),
+                None => {}
+            }
+
+            let policy = load_policy(&app.pg, room_id).await?;
+            let Some(text) = apply_filter(&policy, text) else {
+                return Ok(Verdict::Blocked);
+            };
+
+            if policy.slow_mode_secs > 0 && !is_moderator(&app.pg, room_id, user_id).await? {
+                let mut conn = app.redis.get().await?;
+                let ttl: i64 = conn.ttl(slow_mode_key(room_id, user_id)).await?;
+                if ttl > 0 {
+                    return Ok(Verdict::SlowMode(ttl));
+                }
+            }
+
+            Ok(Verdict::Allow(text))
+        }
+
+        fn slow_mode_key(room_id: &str, user_id: Uuid) -> String {
+            format!("slowmode:{room_id}:{user_id}")
+        }
+
+        // Opens the sender's slow-mode window. Called once the message is stored, so a send
+        // that fails after `check` does not cost the user a wait.
+        pub async fn start_slow_mode(app: &state::AppState, room_id: &str, user_id: Uuid) -> anyhow::Result<()> {
+            let policy = load_policy(&app.pg, room_id).await?;
+            if policy.slow_mode_secs <= 0 || is_moderator(&app.pg, room_id, user_id).await? {
+                return Ok(());
+            }
+            let mut conn = app.redis.get().await?;
+            let _: () = conn.set_ex(slow_mode_key(room_id, user_id), "1", policy.slow_mode_secs as u64).await?;
+            Ok(())
+        }
+
+        async fn log(
+            pg: &PgPool,
+            room_id: &str,
+            actor: Uuid,
+            action: &str,
+            target_user: Option<Uuid>,
+            details: serde_json::Value,
+        ) -> anyhow::Result<()> {
+            sqlx::query(
+                r#"
+                INSERT INTO moderation_log(room_id, actor, action, target_user, details, created_at)
+                VALUES ($1, $2, $3, $4, $5, now())
+                "#,
+            )
+            .bind(room_id)
+            .bind(actor)
+            .bind(action)
+            .bind(target_user)
+            .bind(details)
+            .execute(pg)
+            .await?;
+            Ok(())
+        }
+
+        fn broadcast(
+            app: &state::AppState,
+            room_id: &str,
+            actor: Uuid,
+            action: shared::ModerationAction,
+            target_user: Option<Uuid>,
+            expires_at: Option<chrono::DateTime<chrono::Utc>>,
+        ) {
+            let packet = shared::RealtimePacket::Moderation(shared::ModerationEvent {
+                room_id: room_id.to_string(),
+                actor,
+                action,
+                target_user,
+                expires_at,
+                ts: chrono::Utc::now(),
+            });
+            services::chat::emit(app, room_id, &packet);
+        }
+
+        pub async fn sanction(
+            app: &state::AppState,
+            room_id: &str,
+            actor: Uuid,
+            target: Uuid,
+            kind: SanctionKind,
+            duration_secs: Option<i64>,
+            reason: Option<&str>,
+        ) -> anyhow::Result<Option<chrono::DateTime<chrono::Utc>>> {
+            let expires_at = duration_secs.map(|secs| chrono::Utc::now() + chrono::Duration::seconds(secs));
+            sqlx::query(
+                r#"
+                INSERT INTO room_sanctions(room_id, user_id, kind, reason, expires_at, created_by, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, now())
+                ON CONFLICT (room_id, user_id, kind)
+                DO UPDATE SET reason = EXCLUDED.reason, expires_at = EXCLUDED.expires_at,
+                              created_by = EXCLUDED.created_by, created_at = now()
+                "#,
+            )
+            .bind(room_id)
+            .bind(target)
+            .bind(kind.as_str())
+            .bind(reason)
+            .bind(expires_at)
+            .bind(actor)
+            .execute(&app.pg)
+            .await?;
+
+            log(
+                &app.pg,
+                room_id,
+                actor,
+                kind.as_str(),
+                Some(target),
+                serde_json::json!({ "duration_secs": duration_secs, "reason": reason }),
+            )
+            .await?;
+
+            let action = match kind {
+                SanctionKind::Mute => shared::ModerationAction::Mute,
+                SanctionKind::Ban => shared::ModerationAction::Ban,
+            };
+            broadcast(app, room_id, actor, action, Some(target), expires_at);
+            Ok(expires_at)
+        }
+
+        pub async fn lift(app: &state::AppState, room_id: &str, actor: Uuid, target: Uuid, kind: SanctionKind) -> anyhow::Result<bool> {
+            let result = sqlx::query("DELETE FROM room_sanctions WHERE room_id = $1 AND user_id = $2 AND kind = $3")
+                .bind(room_id)
+                .bind(target)
+                .bind(kind.as_str())
+                .execute(&app.pg)
+                .await?;
+
+            let (label, action) = match kind {
+                SanctionKind::Mute => ("unmute", shared::ModerationAction::Unmute),
+                SanctionKind::Ban => ("unban", shared::ModerationAction::Unban),
+            };
+            log(&app.pg, room_id, actor, label, Some(target), serde_json::json!({})).await?;
+            broadcast(app, room_id, actor, action, Some(target), None);
+            Ok(result.rows_affected() > 0)
+        }
+
+        pub async fn update_settings(app: &state::AppState, room_id: &str, actor: Uuid, update: SettingsUpdate) -> anyhow::Result<()> {
+            sqlx::query(
+                r#"
+                INSERT INTO room_settings(room_id, slow_mode_secs, filter_action, blocked_words, blocked_patterns, updated_by, updated_at)
+                VALUES ($1, COALESCE($2, 0), COALESCE($3, 'reject'), COALESCE($4, '{}'::text[]), COALESCE($5, '{}'::text[]), $6, now())
+                ON CONFLICT (room_id)
+                DO UPDATE SET
+                  slow_mode_secs = COALESCE($2, room_settings.slow_mode_secs),
+                  filter_action = COALESCE($3, room_settings.filter_action),
+                  blocked_words = COALESCE($4, room_settings.blocked_words),
+                  blocked_patterns = COALESCE($5, room_settings.blocked_patterns),
+                  updated_by = $6,
+                  updated_at = now()
+                "#,
+            )
+            .bind(room_id)
+            .bind(update.slow_mode_secs)
+            .bind(update.filter_action.map(|a| a.as_str()))
+            .bind(&update.blocked_words)
+            .bind(&update.blocked_patterns)
+            .bind(actor)
+            .execute(&app.pg)
+            .await?;
+
+            log(
+                &app.pg,
+                room_id,
+                actor,
+                "settings",
+                None,
+                serde_json::json!({
+                    "slow_mode_secs": update.slow_mode_secs,
+                    "filter_action": update.filter_action.map(|a| a.as_str()),
+                    "blocked_words": update.blocked_words.as_ref().map(Vec::len),
+                    "blocked_patterns": update.blocked_patterns,
+                }),
+            )
+            .await?;
+
+            broadcast(app, room_id, actor, shared::ModerationAction::SettingsChanged, None, None);
+            Ok(())
+        }
+
+        pub async fn set_moderator(pg: &PgPool, room_id: &str, actor: Uuid, target: Uuid, grant: bool) -> anyhow::Result<()> {
+            if grant {
+                sqlx::query(
+                    r#"
+                    INSERT INTO room_moderators(room_id, user_id, granted_by, created_at)
+                    VALUES ($1, $2, $3, now())
+                    ON CONFLICT (room_id, user_id) DO NOTHING
+                    "#,
+                )
+                .bind(room_id)
+                .bind(target)
+                .bind(actor)
+                .execute(pg)
+                .await?;
+            } else {
+                sqlx::query("DELETE FROM room_moderators WHERE room_id = $1 AND user_id = $2")
+                    .bind(room_id)
+                    .bind(target)
+                    .execute(pg)
+                    .await?;
+            }
+
+            let action = if grant { "grant_moderator" } else { "revoke_moderator" };
+            log(pg, room_id, actor, action, Some(target), serde_json::json!({})).await
+        }
+
+        pub(crate) async fn recent_log(pg: &PgPool, room_id: &str, limit: i64) -> anyhow::Result<Vec<ModerationLogItem>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT id, room_id, actor::text AS actor, action, target_user::text AS target_user, details, created_at
+                FROM moderation_log
+                WHERE room_id = $1
+                ORDER BY created_at DESC
+                LIMIT $2
+                "#,
+            )
+            .bind(room_id)
+            .bind(limit)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|r| ModerationLogItem {
+                    id: r.get::<i64, _>("id"),
+                    room_id: r.get::<String, _>("room_id"),
+                    actor: r.get::<Option<String>, _>("actor"),
+                    action: r.get::<String, _>("action"),
+                    target_user: r.get::<Option<String>, _>("target_user"),
+                    details: r.get::<serde_json::Value, _>("details"),
+                    ts: r.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+                })
+                .collect())
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn policy(action: FilterAction, words: &[&str], patterns: &[&str]) -> RoomPolicy {
+                let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                RoomPolicy {
+                    filter_action: action,
+                    blocked_words: words.iter().map(|w| w.to_lowercase()).collect(),
+                    patterns: compile_patterns(&patterns).unwrap(),
+                    ..RoomPolicy::default()
+                }
+            }
+
+            #[test]
+            fn overlapping_words_are_all_masked() {
+                let mask = policy(FilterAction::Mask, &["abc", "bcd"], &[]);
+                assert_eq!(apply_filter(&mask, "xabcdx").as_deref(), Some("x****x"));
+                let repeated = policy(FilterAction::Mask, &["aa"], &[]);
+                assert_eq!(apply_filter(&repeated, "aaa ok").as_deref(), Some("*** ok"));
+            }
+
+            #[test]
+            fn matching_ignores_case() {
+                assert_eq!(apply_filter(&policy(FilterAction::Mask, &["Bad"], &[]), "BaD day").as_deref(), Some("*** day"));
+                assert_eq!(apply_filter(&policy(FilterAction::Reject, &["bad"], &[]), "so BAD"), None);
+                assert_eq!(apply_filter(&policy(FilterAction::Mask, &[], &["spam+"]), "SPAMMM!").as_deref(), Some("******!"));
+                assert_eq!(apply_filter(&policy(FilterAction::Reject, &["bad"], &[]), "fine").as_deref(), Some("fine"));
+            }
+
+            #[test]
+            fn multibyte_text_is_masked_per_character() {
+                let words = policy(FilterAction::Mask, &["坏蛋"], &[]);
+                assert_eq!(apply_filter(&words, "你是坏蛋吗").as_deref(), Some("你是**吗"));
+                // Pattern matches report byte offsets; masking must land on the right characters.
+                let patterns = policy(FilterAction::Mask, &[], &["bad", "坏."]);
+                assert_eq!(apply_filter(&patterns, "坏人说bad话").as_deref(), Some("**说***话"));
+                assert_eq!(apply_filter(&patterns, "ÄÖ bad").as_deref(), Some("ÄÖ ***"));
+            }
+
+            #[test]
+            fn patterns_are_bounded() {
+                assert!(compile_patterns(&["(".to_string()]).is_err());
+                assert!(compile_patterns(&["a".repeat(MAX_PATTERN_LEN + 1)]).is_err());
+                assert!(compile_patterns(&["a{1000}{1000}".to_string()]).is_err());
+                assert_eq!(compile_patterns(&["ok".to_string(), "f.o".to_string()]).map(|p| p.len()), Ok(2));
+            }
+
+            #[test]
+            fn filter_lists_are_capped() {
+                let full = vec!["w".to_string(); MAX_FILTER_ENTRIES];
+                let over = vec!["w".to_string(); MAX_FILTER_ENTRIES + 1];
+                assert!(check_lists(Some(&full), Some(&full)).is_ok());
+                assert!(check_lists(Some(&over), None).is_err());
+                assert!(check_lists(None, Some(&over)).is_err());
+                assert!(check_lists(None, Some(&["[".to_string()])).is_err());
+                assert!(check_lists(None, None).is_ok());
+            }
+        }
+    }
+
+    pub mod mention {
+        use super::*;
+
+        const MAX_MENTIONS: usize = 20;
+        const PREVIEW_CHARS: usize = 120;
+
+        fn is_name_char(c: char) -> bool {
+            c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+        }
+
+        pub fn parse(text: &str) -> Vec<String> {
+            let mut out = Vec::<String>::new();
+            let mut prev = None::<char>;
+
+            for (idx, c) in text.char_indices() {
+                if c == '@' && !prev.is_some_and(is_name_char) {
+                    let rest = &text[idx + 1..];
+                    let end = rest.find(|ch: char| !is_name_char(ch)).unwrap_or(rest.len());
+                    let name = rest[..end].trim_end_matches(['.', '-']);
+                    if !name.is_empty() && !out.iter().any(|n| n == name) {
+                        out.push(name.to_string());
+                        if out.len() >= MAX_MENTIONS {
+                            break;
+                        }
+                    }
+                }
+                prev = Some(c);
+            }
+            out
+        }
+
+        // Mentions only reach people who can read the room: the other participant of a direct
+        // message, anyone in an open room, never the author.
+        pub fn recipients(room_id: &str, from_user: Uuid, candidates: Vec<Uuid>) -> Vec<Uuid> {
+            candidates
+                .into_iter()
+                .filter(|id| *id != from_user && services::chat::can_view(room_id, *id))
+                .collect()
+        }
+
+        pub async fn record(
+            conn: &mut sqlx::PgConnection,
+            msg: &shared::ChatMessage,
+            message_id: i64,
+        ) -> anyhow::Result<Vec<shared::NotificationEvent>> {
+            let names = parse(&msg.text);
+            if names.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            let rows = sqlx::query(
+                r#"
+                SELECT u.id
+                FROM users u
+                WHERE u.username = ANY($1)
+                  AND NOT EXISTS (
+                    SELECT 1
+                    FROM room_sanctions s
+                    WHERE s.room_id = $2
+                      AND s.user_id = u.id
+                      AND s.kind = 'ban'
+                      AND (s.expires_at IS NULL OR s.expires_at > now())
+                  )
+                "#,
+            )
+            .bind(&names)
+            .bind(&msg.room_id)
+            .fetch_all(&mut *conn)
+            .await?;
+            let targets = recipients(&msg.room_id, msg.from_user, rows.iter().map(|r| r.get::<Uuid, _>("id")).collect());
+            if targets.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            let preview = msg.text.chars().take(PREVIEW_CHARS).collect::<String>();
+            let rows = sqlx::query(
+                r#"
+                INSERT INTO notifications(user_id, kind, room_id, message_id, from_user, preview, created_at)
+                SELECT unnest($1::uuid[]), 'mention', $2, $3, $4, $5, now()
+                ON CONFLICT (user_id, message_id, kind) DO NOTHING
+                RETURNING id, user_id, created_at
+                "#,
+            )
+            .bind(&targets)
+            .bind(&msg.room_id)
+            .bind(message_id)
+            .bind(msg.from_user)
+            .bind(&preview)
+            .fetch_all(&mut *conn)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|r| shared::NotificationEvent {
+                    id: r.get::<i64, _>("id"),
+                    user_id: r.get::<Uuid, _>("user_id"),
+                    kind: shared::NotificationKind::Mention,
+                    room_id: msg.room_id.clone(),
+                    message_id,
+                    from_user: msg.from_user,
+                    preview: preview.clone(),
+                    ts: r.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+                })
+                .collect())
+        }
+
+        pub async fn publish(app: &state::AppState, events: Vec<shared::NotificationEvent>) {
+            for event in events {
+                services::mailbox::deliver(app, event.user_id, shared::RealtimePacket::Notification(event)).await;
+            }
+        }
+
+        pub(crate) async fn inbox(
+            pg: &PgPool,
+            user_id: Uuid,
+            unread_only: bool,
+            before_id: Option<i64>,
+            limit: i64,
+        ) -> anyhow::Result<Vec<NotificationItem>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT n.id, n.kind, n.room_id, n.message_id, n.from_user::text AS from_user,
+                       u.username AS from_username, n.preview, n.created_at, n.read_at
+                FROM notifications n
+                JOIN users u ON u.id = n.from_user
+                WHERE n.user_id = $1
+                  AND ($2 = false OR n.read_at IS NULL)
+                  AND ($3::bigint IS NULL OR n.id < $3)
+                ORDER BY n.id DESC
+                LIMIT $4
+                "#,
+            )
+            .bind(user_id)
+            .bind(unread_only)
+            .bind(before_id)
+            .bind(limit)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|r| NotificationItem {
+                    id: r.get::<i64, _>("id"),
+                    kind: r.get::<String, _>("kind"),
+                    room_id: r.get::<String, _>("room_id"),
+                    message_id: r.get::<i64, _>("message_id"),
+                    from_user: r.get::<String, _>("from_user"),
+                    from_username: r.get::<String, _>("from_username"),
+                    preview: r.get::<String, _>("preview"),
+                    ts: r.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+                    read: r.get::<Option<chrono::DateTime<chrono::Utc>>, _>("read_at").is_some(),
+                })
+                .collect())
+        }
+
+        pub async fn unread_count(pg: &PgPool, user_id: Uuid) -> anyhow::Result<i64> {
+            let row = sqlx::query("SELECT COUNT(*) AS unread FROM notifications WHERE user_id = $1 AND read_at IS NULL")
+                .bind(user_id)
+                .fetch_one(pg)
+                .await?;
+            Ok(row.get::<i64, _>("unread"))
+        }
+
+        pub async fn mark_read(pg: &PgPool, user_id: Uuid, ids: Option<&[i64]>) -> anyhow::Result<u64> {
+            let result = sqlx::query(
+                r#"
+                UPDATE notifications
+                SET read_at = now()
+                WHERE user_id = $1
+                  AND read_at IS NULL
+                  AND ($2::bigint[] IS NULL OR id = ANY($2))
+                "#,
+            )
+            .bind(user_id)
+            .bind(ids)
+            .execute(pg)
+            .await?;
+            Ok(result.rows_affected())
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            #[test]
+            fn direct_message_mentions_reach_the_peer_only() {
+                let (author, peer, outsider) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+                let room_id = shared::dm::room_id(author, peer);
+                assert_eq!(recipients(&room_id, author, vec![outsider, peer, author]), vec![peer]);
+                assert!(recipients(&room_id, author, vec![outsider]).is_empty());
+            }
+
+            #[test]
+            fn open_room_mentions_skip_the_author() {
+                let (author, other) = (Uuid::new_v4(), Uuid::new_v4());
+                assert_eq!(recipients("global", author, vec![author, other]), vec![other]);
+            }
+        }
+    }
+
+    pub mod mailbox {
+        use super::*;
+
+        const MAILBOX_TTL_DAYS: i64 = 7;
+        pub const DRAIN_BATCH: i64 = 200;
+
+        fn kind_of(packet: &shared::RealtimePacket) -> &'static str {
+            match packet {
+                shared::RealtimePacket::Invite(_) => "invite",
+                shared::RealtimePacket::Notification(_) => "notification",
+                shared::RealtimePacket::Chat(_) => "chat",
+                shared::RealtimePacket::Moderation(_) => "moderation",
+                shared::RealtimePacket::Poll(_) => "poll",
+                shared::RealtimePacket::Redaction(_) => "redaction",
+                shared::RealtimePacket::MatchReady(_) => "match",
+                _ => "other",
+            }
+        }
+
+        // Lets message expiry find and purge stored copies of a message's content.
+        fn message_of(packet: &shared::RealtimePacket) -> Option<i64> {
+            match packet {
+                shared::RealtimePacket::Chat(chat) => chat.id,
+                shared::RealtimePacket::Notification(notification) => Some(notification.message_id),
+                _ => None,
+            }
+        }
+
+        async fn enqueue(
+            pg: &PgPool,
+            user_id: Uuid,
+            packet: &shared::RealtimePacket,
+        ) -> anyhow::Result<(i64, chrono::DateTime<chrono::Utc>)> {
+            let payload = rmp_serde::to_vec(packet)?;
+            let row = sqlx::query(
+                r#"
+                INSERT INTO user_mailbox(user_id, kind, payload, created_at, expires_at, message_id)
+                VALUES ($1, $2, $3, now(), now() + make_interval(days => $4), $5)
+                RETURNING id, created_at
+                "#,
+            )
+            .bind(user_id)
+            .bind(kind_of(packet))
+            .bind(payload)
+            .bind(MAILBOX_TTL_DAYS as i32)
+            .bind(message_of(packet))
+            .fetch_one(pg)
+            .await?;
+            Ok((row.get::<i64, _>("id"), row.get::<chrono::DateTime<chrono::Utc>, _>("created_at")))
+        }
+
+        pub async fn deliver(app: &state::AppState, user_id: Uuid, packet: shared::RealtimePacket) {
+            let outbound = match enqueue(&app.pg, user_id, &packet).await {
+                Ok((id, ts)) => shared::RealtimePacket::Mailbox(shared::MailboxEnvelope {
+                    id,
+                    user_id,
+                    packet: Box::new(packet),
+                    ts,
+                }),
+                Err(err) => {
+                    tracing::warn!(?err, %user_id, "mailbox enqueue failed, delivering live only");
+                    packet
+                }
+            };
+            if let Ok(payload) = rmp_serde::to_vec(&outbound) {
+                let _ = app.realtime_tx.send(state::Outbound::users(vec![user_id], payload));
+            }
+        }
+
+        pub async fn pending(
+            pg: &PgPool,
+            user_id: Uuid,
+            after_id: i64,
+            limit: i64,
+        ) -> anyhow::Result<Vec<shared::MailboxEnvelope>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT id, payload, created_at
+                FROM user_mailbox
+                WHERE user_id = $1
+                  AND id > $2
+                  AND acked_at IS NULL
+                  AND expires_at > now()
+                ORDER BY id
+                LIMIT $3
+                "#,
+            )
+            .bind(user_id)
+            .bind(after_id)
+            .bind(limit)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .filter_map(|r| {
+                    let packet = rmp_serde::from_slice::<shared::RealtimePacket>(&r.get::<Vec<u8>, _>("payload")).ok()?;
+                    Some(shared::MailboxEnvelope {
+                        id: r.get::<i64, _>("id"),
+                        user_id,
+                        packet: Box::new(packet),
+                        ts: r.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+                    })
+                })
+                .collect())
+        }
+
+        pub async fn ack(pg: &PgPool, user_id: Uuid, up_to: i64) -> anyhow::Result<u64> {
+            let result = sqlx::query(
+                r#"
+                UPDATE user_mailbox
+                SET acked_at = now()
+                WHERE user_id = $1 AND id <= $2 AND acked_at IS NULL
+                "#,
+            )
+            .bind(user_id)
+            .bind(up_to)
+            .execute(pg)
+            .await?;
+            Ok(result.rows_affected())
+        }
+
+        pub async fn run_cleanup(pg: PgPool) {
+            let mut every = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+            loop {
+                every.tick().await;
+                let result = sqlx::query(
+                    r#"
+                    DELETE FROM user_mailbox
+                    WHERE expires_at < now()
+                       OR acked_at < now() - interval '1 day'
+                    "#,
+                )
+                .execute(&pg)
+                .await;
+                match result {
+                    Ok(done) if done.rows_affected() > 0 => tracing::info!(removed = done.rows_affected(), "mailbox cleanup"),
+                    Ok(_) => {}
+                    Err(err) => tracing::warn!(?err, "mailbox cleanup failed"),
+                }
+            }
+        }
+    }
+
+    pub mod keys {
+        use super::*;
+        use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
+        use ed25519_dalek::{Signature, VerifyingKey};
+
+        fn decode<const N: usize>(value: &str) -> Option<[u8; N]> {
+            B64.decode(value).ok().and_then(|bytes| <[u8; N]>::try_from(bytes.as_slice()).ok())
+        }
+
+        // The same check clients make before encrypting, so a bundle that would be refused
+        // there is refused here instead of being handed out.
+        pub fn validate(bundle: &shared::KeyBundle) -> Result<(), &'static str> {
+            let Some(identity) = decode::<32>(&bundle.identity_key).and_then(|k| VerifyingKey::from_bytes(&k).ok()) else {
+                return Err("identity_key must be a base64 Ed25519 public key");
+            };
+            let Some(prekey) = decode::<32>(&bundle.prekey) else {
+                return Err("prekey must be a base64 X25519 public key");
+            };
+            let Some(signature) = decode::<64>(&bundle.prekey_signature) else {
+                return Err("prekey_signature must be a base64 Ed25519 signature");
+            };
+            if identity.verify_strict(&prekey, &Signature::from_bytes(&signature)).is_err() {
+                return Err("prekey_signature does not match identity_key");
+            }
+            if bundle.prekey_id <= 0 {
+                return Err("prekey_id must be positive");
+            }
+            Ok(())
+        }
+
+        pub async fn publish(pg: &PgPool, bundle: &shared::KeyBundle) -> anyhow::Result<()> {
+            sqlx::query(
+                r#"
+                INSERT INTO user_key_bundles(user_id, identity_key, prekey_id, prekey, prekey_signature, updated_at)
+                VALUES ($1, $2, $3, $4, $5, now())
+                ON CONFLICT (user_id)
+                DO UPDATE SET
+                  identity_key = EXCLUDED.identity_key,
+                  prekey_id = EXCLUDED.prekey_id,
+                  prekey = EXCLUDED.prekey,
+                  prekey_signature = EXCLUDED.prekey_signature,
+                  updated_at = now()
+                "#,
+            )
+            .bind(bundle.user_id)
+            .bind(&bundle.identity_key)
+            .bind(bundle.prekey_id)
+            .bind(&bundle.prekey)
+            .bind(&bundle.prekey_signature)
+            .execute(pg)
+            .await?;
+            Ok(())
+        }
+
+        pub async fn fetch(pg: &PgPool, user_id: Uuid) -> anyhow::Result<Option<shared::KeyBundle>> {
+            let row = sqlx::query(
+                r#"
+                SELECT user_id, identity_key, prekey_id, prekey, prekey_signature, updated_at
+                FROM user_key_bundles
+                WHERE user_id = $1
+                "#,
+            )
+            .bind(user_id)
+            .fetch_optional(pg)
+            .await?;
+
+            Ok(row.map(|r| shared::KeyBundle {
+                user_id: r.get::<Uuid, _>("user_id"),
+                identity_key: r.get::<String, _>("identity_key"),
+                prekey_id: r.get::<i64, _>("prekey_id"),
+                prekey: r.get::<String, _>("prekey"),
+                prekey_signature: r.get::<String, _>("prekey_signature"),
+                updated_at: Some(r.get::<chrono::DateTime<chrono::Utc>, _>("updated_at")),
+            }))
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use ed25519_dalek::{Signer, SigningKey};
+
+            fn bundle(identity: &SigningKey, prekey: [u8; 32], signed: [u8; 32]) -> shared::KeyBundle {
+                shared::KeyBundle {
+                    user_id: Uuid::new_v4(),
+                    identity_key: B64.encode(identity.verifying_key().as_bytes()),
+                    prekey_id: 1,
+                    prekey: B64.encode(prekey),
+                    prekey_signature: B64.encode(identity.sign(&signed).to_bytes()),
+                    updated_at: None,
+                }
+            }
+
+            #[test]
+            fn signed_bundles_pass() {
+                let identity = SigningKey::from_bytes(&[7; 32]);
+                assert_eq!(validate(&bundle(&identity, [9; 32], [9; 32])), Ok(()));
+            }
+
+            #[test]
+            fn keys_must_decode_to_the_right_length() {
+                let identity = SigningKey::from_bytes(&[7; 32]);
+                let good = bundle(&identity, [9; 32], [9; 32]);
+                for bad in [B64.encode([1_u8; 31]), B64.encode([1_u8; 33]), "!".repeat(44), String::new()] {
+                    let mut b = good.clone();
+                    b.identity_key = bad.clone();
+                    assert!(validate(&b).is_err(), "identity {bad:?}");
+                    let mut b = good.clone();
+                    b.prekey = bad.clone();
+                    assert!(validate(&b).is_err(), "prekey {bad:?}");
+                    let mut b = good.clone();
+                    b.prekey_signature = bad.clone();
+                    assert!(validate(&b).is_err(), "signature {bad:?}");
+                }
+                let mut b = good.clone();
+                b.prekey_id = 0;
+                assert!(validate(&b).is_err());
+            }
+
+            #[test]
+            fn signatures_must_cover_the_prekey() {
+                let identity = SigningKey::from_bytes(&[7; 32]);
+                assert!(validate(&bundle(&identity, [9; 32], [8; 32])).is_err());
+
+                let mut swapped = bundle(&identity, [9; 32], [9; 32]);
+                swapped.identity_key = B64.encode(SigningKey::from_bytes(&[6; 32]).verifying_key().as_bytes());
+                assert!(validate(&swapped).is_err());
+
+                let mut flipped = bundle(&identity, [9; 32], [9; 32]);
+                let mut raw = B64.decode(&flipped.prekey_signature).unwrap();
+                raw[0] ^= 1;
+                flipped.prekey_signature = B64.encode(raw);
+                assert!(validate(&flipped).is_err());
+            }
+        }
+    }
+
+    pub mod polls {
+        use super::*;
+        use services::chat::SendError;
+        use sqlx::postgres::PgRow;
+        use std::collections::HashMap;
+
+        const MIN_OPTIONS: usize = 2;
+        pub const MAX_OPTIONS: usize = 10;
+        const MAX_QUESTION_CHARS: usize = 200;
+        const MAX_OPTION_CHARS: usize = 80;
+        const MAX_OPEN_DAYS: i64 = 30;
+        const CLOSE_SWEEP_SECS: u64 = 15;
+
+        pub enum PollError {
+            Invalid(&'static str),
+            NotFound,
+            Closed,
+            Forbidden,
+            Send(SendError),
+            Storage(anyhow::Error),
+        }
+
+        impl PollError {
+            pub fn status_code(&self) -> StatusCode {
+                match self {
+                    PollError::Invalid(_) => StatusCode::BAD_REQUEST,
+                    PollError::NotFound => StatusCode::NOT_FOUND,
+                    PollError::Closed => StatusCode::CONFLICT,
+                    PollError::Forbidden => StatusCode::FORBIDDEN,
+                    PollError::Send(err) => err.status_code(),
+                    PollError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                }
+            }
+
+            pub fn message(&self) -> String {
+                match self {
+                    PollError::Invalid(reason) => reason.to_string(),
+                    PollError::NotFound => "poll not found".to_string(),
+                    PollError::Closed => "poll is closed".to_string(),
+                    PollError::Forbidden => "you cannot take part in this poll".to_string(),
+                    PollError::Send(err) => err.message(),
+                    PollError::Storage(_) => "failed to store poll".to_string(),
+                }
+            }
+        }
+
+        impl From<anyhow::Error> for PollError {
+            fn from(err: anyhow::Error) -> Self {
+                PollError::Storage(err)
+            }
+        }
+
+        impl From<sqlx::Error> for PollError {
+            fn from(err: sqlx::Error) -> Self {
+                PollError::Storage(err.into())
+            }
+        }
+
+        impl From<SendError> for PollError {
+            fn from(err: SendError) -> Self {
+                match err {
+                    SendError::Storage(cause) => PollError::Storage(cause),
+                    other => PollError::Send(other),
+                }
+            }
+        }
+
+        pub struct NewPoll {
+            pub room_id: String,
+            pub question: String,
+            pub options: Vec<String>,
+            pub multi_choice: bool,
+            pub closes_at: Option<chrono::DateTime<chrono::Utc>>,
+        }
+
+        fn single_line(text: &str) -> String {
+            services::content::sanitize(shared::MessageFormat::Plain, text)
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+
+        // Runs again after moderation, since masking can empty an option or make two equal.
+        fn check_text(question: &str, options: &[String]) -> Result<(), PollError> {
+            if question.is_empty() || question.chars().count() > MAX_QUESTION_CHARS {
+                return Err(PollError::Invalid("question must be 1-200 characters"));
+            }
+            if !(MIN_OPTIONS..=MAX_OPTIONS).contains(&options.len()) {
+                return Err(PollError::Invalid("a poll needs 2-10 options"));
+            }
+            if options.iter().any(|o| o.is_empty() || o.chars().count() > MAX_OPTION_CHARS) {
+                return Err(PollError::Invalid("options must be 1-80 characters"));
+            }
+            let mut seen = options.iter().map(|o| o.to_lowercase()).collect::<Vec<_>>();
+            seen.sort();
+            seen.dedup();
+            if seen.len() != options.len() {
+                return Err(PollError::Invalid("options must be distinct"));
+            }
+            Ok(())
+        }
+
+        fn validate(poll: &NewPoll) -> Result<(String, Vec<String>), PollError> {
+            let question = single_line(&poll.question);
+            let options = poll.options.iter().map(|o| single_line(o)).collect::<Vec<_>>();
+            check_text(&question, &options)?;
+
+            if let Some(closes_at) = poll.closes_at {
+                let now = chrono::Utc::now();
+                if closes_at <= now + chrono::Duration::seconds(30) {
+                    return Err(PollError::Invalid("deadline must be at least 30 seconds from now"));
+                }
+                if closes_at > now + chrono::Duration::days(MAX_OPEN_DAYS) {
+                    return Err(PollError::Invalid("deadline must be within 30 days"));
+                }
+            }
+            Ok((question, options))
+        }
+
+        fn state_from_row(row: &PgRow) -> shared::PollState {
+            shared::PollState {
+                id: row.get::<i64, _>("id"),
+                room_id: row.get::<String, _>("room_id"),
+                message_id: row.get::<Option<i64>, _>("message_id"),
+                created_by: row.get::<Uuid, _>("created_by"),
+                question: row.get::<String, _>("question"),
+                options: row
+                    .get::<Vec<String>, _>("options")
+                    .into_iter()
+                    .map(|text| shared::PollOption { text, votes: 0 })
+                    .collect(),
+                multi_choice: row.get::<bool, _>("multi_choice"),
+                closes_at: row.get::<Option<chrono::DateTime<chrono::Utc>>, _>("closes_at"),
+                closed: row.get::<bool, _>("closed"),
+                voters: 0,
+                ts: row.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+            }
+        }
+
+        const POLL_COLUMNS: &str = r#"
+            id, room_id, message_id, created_by, question, options, multi_choice, closes_at, created_at,
+            (closed_at IS NOT NULL OR (closes_at IS NOT NULL AND closes_at <= now())) AS closed
+        "#;
+
+        async fn fill_tallies(pg: &PgPool, polls: &mut [shared::PollState]) -> anyhow::Result<()> {
+            if polls.is_empty() {
+                return Ok(());
+            }
+            let ids = polls.iter().map(|p| p.id).collect::<Vec<_>>();
+            let rows = sqlx::query(
+                r#"
+                SELECT b.poll_id, c.choice, COUNT(*)::bigint AS votes, NULL::bigint AS voters
+                FROM poll_ballots b
+                CROSS JOIN LATERAL unnest(b.choices) AS c(choice)
+                WHERE b.poll_id = ANY($1)
+                GROUP BY b.poll_id, c.choice
+                UNION ALL
+                SELECT poll_id, NULL::int, NULL::bigint, COUNT(*)::bigint
+                FROM poll_ballots
+                WHERE poll_id = ANY($1)
+                GROUP BY poll_id
+                "#,
+            )
+            .bind(&ids)
+            .fetch_all(pg)
+            .await?;
+
+            for row in rows {
+                let poll_id = row.get::<i64, _>("poll_id");
+                let Some(poll) = polls.iter_mut().find(|p| p.id == poll_id) else {
+                    continue;
+                };
+                if let Some(voters) = row.get::<Option<i64>, _>("voters") {
+                    poll.voters = voters;
+                } else if let (Some(choice), Some(votes)) = (row.get::<Option<i32>, _>("choice"), row.get::<Option<i64>, _>("votes")) {
+                    if let Some(option) = usize::try_from(choice).ok().and_then(|idx| poll.options.get_mut(idx)) {
+                        option.votes = votes;
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        pub async fn load(pg: &PgPool, poll_id: i64) -> anyhow::Result<Option<shared::PollState>> {
+            let row = sqlx::query(&format!("SELECT {POLL_COLUMNS} FROM polls WHERE id = $1"))
+                .bind(poll_id)
+                .fetch_optional(pg)
+                .await?;
+            let Some(row) = row else {
+                return Ok(None);
+            };
+            let mut polls = [state_from_row(&row)];
+            fill_tallies(pg, &mut polls).await?;
+            let [poll] = polls;
+            Ok(Some(poll))
+        }
+
+        pub async fn for_room(pg: &PgPool, room_id: &str, limit: i64) -> anyhow::Result<Vec<shared::PollState>> {
+            let rows = sqlx::query(&format!(
+                "SELECT {POLL_COLUMNS} FROM polls WHERE room_id = $1 ORDER BY created_at DESC LIMIT $2"
+            ))
+            .bind(room_id)
+            .bind(limit)
+            .fetch_all(pg)
+            .await?;
+            let mut polls = rows.iter().map(state_from_row).collect::<Vec<_>>();
+            fill_tallies(pg, &mut polls).await?;
+            Ok(polls)
+        }
+
+        pub async fn choices_of(pg: &PgPool, poll_ids: &[i64], user_id: Uuid) -> anyhow::Result<HashMap<i64, Vec<u32>>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT poll_id, choices
+                FROM poll_ballots
+                WHERE poll_id = ANY($1) AND user_id = $2
+                "#,
+            )
+            .bind(poll_ids)
+            .bind(user_id)
+            .fetch_all(pg)
+            .await?;
+            Ok(rows
+                .iter()
+                .map(|row| {
+                    let choices = row
+                        .get::<Vec<i32>, _>("choices")
+                        .into_iter()
+                        .filter_map(|c| u32::try_from(c).ok())
+                        .collect();
+                    (row.get::<i64, _>("poll_id"), choices)
+                })
+                .collect())
+        }
+
+        pub async fn can_view(pg: &PgPool, room_id: &str, user_id: Uuid) -> anyhow::Result<bool> {
+            if room_id.starts_with(shared::dm::DM_ROOM_PREFIX) && !shared::dm::is_participant(room_id, user_id) {
+                return Ok(false);
+            }
+            let banned = matches!(
+                services::moderation::active_sanction(pg, room_id, user_id).await?,
+                Some((services::moderation::SanctionKind::Ban, _))
+            );
+            Ok(!banned)
+        }
+
+        pub async fn create(app: &state::AppState, creator: Uuid, poll: NewPoll) -> Result<shared::PollState, PollError> {
+            let (question, options) = validate(&poll)?;
+            services::chat::authorize(app, &poll.room_id, creator).await?;
+
+            // Each line passes the room filter on its own so masking can never merge two of them.
+            // Slow mode only opens its window once, after the poll is stored.
+            let question = services::chat::moderate(app, &poll.room_id, creator, &question).await?;
+            let mut masked = Vec::with_capacity(options.len());
+            for option in &options {
+                masked.push(services::chat::moderate(app, &poll.room_id, creator, option).await?);
+            }
+            let options = masked;
+            check_text(&question, &options)?;
+
+            let mut tx = app.pg.begin().await?;
+            let row = sqlx::query(
+                r#"
+                INSERT INTO polls(room_id, created_by, question, options, multi_choice, closes_at, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, now())
+                RETURNING id
+                "#,
+            )
+            .bind(&poll.room_id)
+            .bind(creator)
+            .bind(&question)
+            .bind(&options)
+            .bind(poll.multi_choice)
+            .bind(poll.closes_at)
+            .fetch_one(&mut *tx)
+            .await?;
+            let poll_id = row.get::<i64, _>("id");
+
+            let mut msg = shared::ChatMessage {
+                room_id: poll.room_id.clone(),
+                from_user: creator,
+                text: format!("Poll: {question}"),
+                ts: chrono::Utc::now(),
+                id: None,
+                parent_id: None,
+                attachments: Vec::new(),
+                client_id: None,
+                e2ee: None,
+                format: shared::MessageFormat::Event,
+                event: Some(shared::ChatEvent {
+                    name: "poll".to_string(),
+                    params: [("poll_id".to_string(), poll_id.to_string())].into_iter().collect(),
+                }),
+                expires_at: None,
+            };
+            let message_id = services::chat::insert_message(&mut *tx, &msg)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("poll message insert skipped"))?;
+            msg.id = Some(message_id);
+            services::chat::record_activity(&mut *tx, &msg, message_id).await?;
+            sqlx::query("UPDATE polls SET message_id = $2 WHERE id = $1")
+                .bind(poll_id)
+                .bind(message_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            if let Err(err) = services::moderation::start_slow_mode(app, &poll.room_id, creator).await {
+                tracing::warn!(?err, room_id = %poll.room_id, "slow mode window not started");
+            }
+
+            let state = load(&app.pg, poll_id).await?.ok_or(PollError::NotFound)?;
+            services::chat::publish(app, &state.room_id, shared::RealtimePacket::Chat(msg)).await;
+            services::chat::publish(app, &state.room_id, shared::RealtimePacket::Poll(state.clone())).await;
+            Ok(state)
+        }
+
+        // Each user holds a single ballot per poll; voting again replaces it and an empty
+        // choice list withdraws it.
+        pub async fn vote(app: &state::AppState, user_id: Uuid, poll_id: i64, mut choices: Vec<u32>) -> Result<shared::PollState, PollError> {
+            choices.sort_unstable();
+            choices.dedup();
+
+            let mut tx = app.pg.begin().await?;
+            let row = sqlx::query(
+                r#"
+                SELECT room_id, cardinality(options) AS option_count, multi_choice,
+                       (closed_at IS NOT NULL OR (closes_at IS NOT NULL AND closes_at <= now())) AS closed
+                FROM polls
+                WHERE id = $1
+                FOR SHARE
+                "#,
+            )
+            .bind(poll_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(PollError::NotFound)?;
+
+            let room_id = row.get::<String, _>("room_id");
+            if !can_view(&app.pg, &room_id, user_id).await? {
+                return Err(PollError::Forbidden);
+            }
+            if row.get::<bool, _>("closed") {
+                return Err(PollError::Closed);
+            }
+            let option_count = row.get::<i32, _>("option_count");
+            if choices.iter().any(|c| i64::from(*c) >= i64::from(option_count)) {
+                return Err(PollError::Invalid("choice is not an option of this poll"));
+            }
+            if !row.get::<bool, _>("multi_choice") && choices.len() > 1 {
+                return Err(PollError::Invalid("this poll accepts a single choice"));
+            }
+
+            if choices.is_empty() {
+                sqlx::query("DELETE FROM poll_ballots WHERE poll_id = $1 AND user_id = $2")
+                    .bind(poll_id)
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+            } else {
+                sqlx::query(
+                    r#"
+                    INSERT INTO poll_ballots(poll_id, user_id, choices, voted_at)
+                    VALUES ($1, $2, $3, now())
+                    ON CONFLICT (poll_id, user_id)
+                    DO UPDATE SET choices = EXCLUDED.choices, voted_at = EXCLUDED.voted_at
+                    "#,
+                )
+                .bind(poll_id)
+                .bind(user_id)
+                .bind(choices.iter().map(|c| *c as i32).collect::<Vec<_>>())
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+
+            let state = load(&app.pg, poll_id).await?.ok_or(PollError::NotFound)?;
+            services::chat::publish(app, &room_id, shared::RealtimePacket::Poll(state.clone())).await;
+            Ok(state)
+        }
+
+        pub async fn close(app: &state::AppState, user_id: Uuid, poll_id: i64) -> Result<shared::PollState, PollError> {
+            let mut tx = app.pg.begin().await?;
+            let row = sqlx::query(
+                r#"
+                SELECT room_id, created_by,
+                       (closed_at IS NOT NULL OR (closes_at IS NOT NULL AND closes_at <= now())) AS closed
+                FROM polls
+                WHERE id = $1
+                FOR UPDATE
+                "#,
+            )
+            .bind(poll_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(PollError::NotFound)?;
+
+            let room_id = row.get::<String, _>("room_id");
+            if row.get::<Uuid, _>("created_by") != user_id && !services::moderation::is_moderator(&app.pg, &room_id, user_id).await? {
+                return Err(PollError::Forbidden);
+            }
+            if row.get::<bool, _>("closed") {
+                return Err(PollError::Closed);
+            }
+            sqlx::query("UPDATE polls SET closed_at = now() WHERE id = $1")
+                .bind(poll_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            let state = load(&app.pg, poll_id).await?.ok_or(PollError::NotFound)?;
+            services::chat::publish(app, &room_id, shared::RealtimePacket::Poll(state.clone())).await;
+            Ok(state)
+        }
+
+        // Deadlines are enforced on every vote; this only announces final tallies once.
+        pub async fn run_closer(app: Arc<state::AppState>) {
+            let mut every = tokio::time::interval(std::time::Duration::from_secs(CLOSE_SWEEP_SECS));
+            loop {
+                every.tick().await;
+                let closed = sqlx::query(
+                    r#"
+                    UPDATE polls
+                    SET closed_at = closes_at
+                    WHERE closed_at IS NULL AND closes_at <= now()
+                    RETURNING id
+                    "#,
+                )
+                .fetch_all(&app.pg)
+                .await;
+
+                let rows = match closed {
+                    Ok(rows) => rows,
+                    Err(err) => {
+                        tracing::warn!(?err, "poll close sweep failed");
+                        continue;
+                    }
+                };
+                for row in rows {
+                    match load(&app.pg, row.get::<i64, _>("id")).await {
+                        Ok(Some(state)) => {
+                            let room_id = state.room_id.clone();
+                            services::chat::publish(&app, &room_id, shared::RealtimePacket::Poll(state)).await;
+                        }
+                        Ok(None) => {}
+                        Err(err) => tracing::warn!(?err, "poll reload failed"),
+                    }
+                }
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn lines(items: &[&str]) -> Vec<String> {
+                items.iter().map(|item| item.to_string()).collect()
+            }
+
+            #[test]
+            fn masked_options_are_checked_again() {
+                assert!(check_text("Lunch?", &lines(&["noodles", "rice"])).is_ok());
+                assert!(matches!(check_text("Lunch?", &lines(&["****", "****"])), Err(PollError::Invalid(_))));
+                assert!(matches!(check_text("Lunch?", &lines(&["****"])), Err(PollError::Invalid(_))));
+                assert!(matches!(check_text("", &lines(&["a", "b"])), Err(PollError::Invalid(_))));
+            }
+        }
+    }
+
+    pub mod scheduled {
+        use super::*;
+        use services::chat::SendError;
+
+        pub const MIN_TTL_SECS: i64 = 10;
+        pub const MAX_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+        const MAX_SCHEDULE_DAYS: i64 = 30;
+        const MAX_PENDING_PER_USER: i64 = 50;
+        const MAX_ATTEMPTS: i32 = 5;
+        const BATCH: i64 = 100;
+        const TICK_SECS: u64 = 5;
+
+        pub fn check_expiry(expires_at: chrono::DateTime<chrono::Utc>) -> Result<(), SendError> {
+            // A little slack for client clocks and request latency on WS-supplied deadlines.
+            let ttl = (expires_at - chrono::Utc::now()).num_seconds();
+            if ttl < MIN_TTL_SECS - 2 || ttl > MAX_TTL_SECS {
+                return Err(SendError::InvalidContent("expiry must be between 10 seconds and 7 days from now"));
+            }
+            Ok(())
+        }
+
+        pub fn expiry_after(ttl_secs: Option<i64>) -> Result<Option<chrono::DateTime<chrono::Utc>>, SendError> {
+            match ttl_secs {
+                None => Ok(None),
+                Some(ttl) if (MIN_TTL_SECS..=MAX_TTL_SECS).contains(&ttl) => Ok(Some(chrono::Utc::now() + chrono::Duration::seconds(ttl))),
+                Some(_) => Err(SendError::InvalidContent("ttl_secs must be between 10 seconds and 7 days")),
+            }
+        }
+
+        pub async fn schedule(
+            app: &state::AppState,
+            mut msg: shared::ChatMessage,
+            send_at: chrono::DateTime<chrono::Utc>,
+            ttl_secs: Option<i64>,
+        ) -> Result<i64, SendError> {
+            if send_at > chrono::Utc::now() + chrono::Duration::days(MAX_SCHEDULE_DAYS) {
+                return Err(SendError::InvalidContent("messages can be scheduled at most 30 days ahead"));
+            }
+            if !msg.attachments.is_empty() || msg.e2ee.is_some() {
+                return Err(SendError::InvalidContent("scheduled messages cannot carry attachments or encrypted bodies"));
+            }
+            expiry_after(ttl_secs)?;
+
+            // Permissions and moderation are checked again when the message is actually posted.
+            services::chat::authorize(app, &msg.room_id, msg.from_user).await?;
+            services::content::prepare(&mut msg)?;
+            if let Some(parent_id) = msg.parent_id {
+                if !services::chat::is_thread_root(&app.pg, &msg.room_id, parent_id).await? {
+                    return Err(SendError::InvalidParent);
+                }
+            }
+
+            let pending = sqlx::query("SELECT COUNT(*)::bigint AS pending FROM scheduled_messages WHERE from_user = $1 AND status = 'pending'")
+                .bind(msg.from_user)
+                .fetch_one(&app.pg)
+                .await?
+                .get::<i64, _>("pending");
+            if pending >= MAX_PENDING_PER_USER {
+                return Err(SendError::InvalidContent("too many pending scheduled messages"));
+            }
+
+            let client_id = msg.client_id.unwrap_or_else(Uuid::new_v4);
+            let row = sqlx::query(
+                r#"
+                WITH inserted AS (
+                  INSERT INTO scheduled_messages(room_id, from_user, message, format, parent_id, client_id, ttl_secs, send_at, created_at)
+                  VALUES ($1, $2, $3, $4, $5, $6, $7, $8, now())
+                  ON CONFLICT (from_user, client_id) DO NOTHING
+                  RETURNING id
+                )
+                SELECT id FROM inserted
+                UNION ALL
+                SELECT id FROM scheduled_messages WHERE from_user = $2 AND client_id = $6
+                LIMIT 1
+                "#,
+            )
+            .bind(&msg.room_id)
+            .bind(msg.from_user)
+            .bind(&msg.text)
+            .bind(services::content::format_str(msg.format))
+            .bind(msg.parent_id)
+            .bind(client_id)
+            .bind(ttl_secs.map(|ttl| ttl as i32))
+            .bind(send_at)
+            .fetch_one(&app.pg)
+            .await?;
+            Ok(row.get::<i64, _>("id"))
+        }
+
+        pub(crate) async fn list(pg: &PgPool, user_id: Uuid, limit: i64) -> anyhow::Result<Vec<ScheduledItem>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT id, room_id, message, format, send_at, ttl_secs, status, last_error, message_id
+                FROM scheduled_messages
+                WHERE from_user = $1
+                ORDER BY (status = 'pending') DESC, send_at DESC
+                LIMIT $2
+                "#,
+            )
+            .bind(user_id)
+            .bind(limit)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows
+                .iter()
+                .map(|row| ScheduledItem {
+                    id: row.get::<i64, _>("id"),
+                    room_id: row.get::<String, _>("room_id"),
+                    text: row.get::<String, _>("message"),
+                    format: services::content::parse_format(&row.get::<String, _>("format")),
+                    send_at: row.get::<chrono::DateTime<chrono::Utc>, _>("send_at"),
+                    ttl_secs: row.get::<Option<i32>, _>("ttl_secs"),
+                    status: row.get::<String, _>("status"),
+                    last_error: row.get::<Option<String>, _>("last_error"),
+                    message_id: row.get::<Option<i64>, _>("message_id"),
+                })
+                .collect())
+        }
+
+        pub async fn cancel(pg: &PgPool, user_id: Uuid, id: i64) -> anyhow::Result<bool> {
+            let result = sqlx::query(
+                r#"
+                UPDATE scheduled_messages
+                SET status = 'cancelled', claimed_at = NULL
+                WHERE id = $1 AND from_user = $2 AND status = 'pending'
+                "#,
+            )
+            .bind(id)
+            .bind(user_id)
+            .execute(pg)
+            .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        async fn finish(pg: &PgPool, id: i64, status: &str, message_id: Option<i64>, error: Option<String>) -> anyhow::Result<()> {
+            sqlx::query(
+                r#"
+                UPDATE scheduled_messages
+                SET status = $2, message_id = $3, last_error = $4, claimed_at = NULL
+                WHERE id = $1
+                "#,
+            )
+            .bind(id)
+            .bind(status)
+            .bind(message_id)
+            .bind(error)
+            .execute(pg)
+            .await?;
+            Ok(())
+        }
+
+        // Rows are claimed with SKIP LOCKED so several instances can share the work; a claim
+        // older than a minute is considered abandoned. Delivery reuses the stored client id,
+        // so a crash between posting and marking the row sent cannot post twice.
+        async fn dispatch_due(app: &state::AppState) -> anyhow::Result<usize> {
+            let rows = sqlx::query(
+                r#"
+                UPDATE scheduled_messages s
+                SET claimed_at = now(), attempts = s.attempts + 1
+                WHERE s.id IN (
+                  SELECT id
+                  FROM scheduled_messages
+                  WHERE status = 'pending'
+                    AND send_at <= now()
+                    AND (claimed_at IS NULL OR claimed_at < now() - interval '1 minute')
+                  ORDER BY send_at
+                  LIMIT $1
+                  FOR UPDATE SKIP LOCKED
+                )
+                RETURNING s.id, s.room_id, s.from_user, s.message, s.format, s.parent_id, s.client_id, s.ttl_secs, s.attempts
+                "#,
+            )
+            .bind(BATCH)
+            .fetch_all(&app.pg)
+            .await?;
+
+            for row in &rows {
+                let id = row.get::<i64, _>("id");
+                let attempts = row.get::<i32, _>("attempts");
+                let msg = shared::ChatMessage {
+                    room_id: row.get::<String, _>("room_id"),
+                    from_user: row.get::<Uuid, _>("from_user"),
+                    text: row.get::<String, _>("message"),
+                    ts: chrono::Utc::now(),
+                    id: None,
+                    parent_id: row.get::<Option<i64>, _>("parent_id"),
+                    attachments: Vec::new(),
+                    client_id: Some(row.get::<Uuid, _>("client_id")),
+                    e2ee: None,
+                    format: services::content::parse_format(&row.get::<String, _>("format")),
+                    event: None,
+                    expires_at: row
+                        .get::<Option<i32>, _>("ttl_secs")
+                        .map(|ttl| chrono::Utc::now() + chrono::Duration::seconds(i64::from(ttl))),
+                };
+
+                let outcome = match services::chat::send(app, msg).await {
+                    Ok(sent) => finish(&app.pg, id, "sent", sent.message.id, None).await,
+                    // Transient: leave the claim in place so the row is retried after it lapses.
+                    Err(err @ (SendError::SlowMode(_) | SendError::Storage(_))) if attempts < MAX_ATTEMPTS => {
+                        tracing::debug!(id, reason = %err.message(), "scheduled message deferred");
+                        sqlx::query("UPDATE scheduled_messages SET last_error = $2 WHERE id = $1")
+                            .bind(id)
+                            .bind(err.message())
+                            .execute(&app.pg)
+                            .await
+                            .map(|_| ())
+                            .map_err(Into::into)
+                    }
+                    Err(err) => finish(&app.pg, id, "failed", None, Some(err.message())).await,
+                };
+                if let Err(err) = outcome {
+                    tracing::warn!(?err, id, "scheduled message bookkeeping failed");
+                }
+            }
+            Ok(rows.len())
+        }
+
+        pub async fn run_dispatcher(app: Arc<state::AppState>) {
+            let mut every = tokio::time::interval(std::time::Duration::from_secs(TICK_SECS));
+            loop {
+                every.tick().await;
+                match dispatch_due(&app).await {
+                    Ok(0) => {}
+                    Ok(count) => tracing::debug!(count, "scheduled messages dispatched"),
+                    Err(err) => tracing::warn!(?err, "scheduled message dispatch failed"),
+                }
+            }
+        }
+
+        // Expired messages keep their row (threads, receipts and counters reference it) but
+        // lose their content and files; readers already hide them once expires_at passes.
+        // Everything that copies the content is cleared in the same transaction, while the
+        // files are only marked here and removed from storage by `purge_expired_files`.
+        async fn redact_expired(app: &state::AppState) -> anyhow::Result<usize> {
+            let mut tx = app.pg.begin().await?;
+            let rows = sqlx::query(
+                r#"
+                WITH expired AS (
+                  SELECT id
+                  FROM room_messages
+                  WHERE expires_at <= now() AND redacted_at IS NULL
+                  ORDER BY expires_at
+                  LIMIT $1
+                  FOR UPDATE SKIP LOCKED
+                )
+                UPDATE room_messages m
+                SET message = '', e2ee = NULL, event = NULL, search_vector = NULL, redacted_at = now()
+                FROM expired
+                WHERE m.id = expired.id
+                RETURNING m.id, m.room_id
+                "#,
+            )
+            .bind(BATCH)
+            .fetch_all(&mut *tx)
+            .await?;
+            if rows.is_empty() {
+                return Ok(0);
+            }
+            let ids = rows.iter().map(|row| row.get::<i64, _>("id")).collect::<Vec<_>>();
+
+            sqlx::query("UPDATE chat_attachments SET status = 'expired' WHERE message_id = ANY($1)")
+                .bind(&ids)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("UPDATE room_stats SET last_preview = '[expired]' WHERE last_message_id = ANY($1)")
+                .bind(&ids)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("UPDATE notifications SET preview = '' WHERE message_id = ANY($1)")
+                .bind(&ids)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("DELETE FROM user_mailbox WHERE message_id = ANY($1)")
+                .bind(&ids)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            for row in &rows {
+                let room_id = row.get::<String, _>("room_id");
+                let packet = shared::RealtimePacket::Redaction(shared::MessageRedaction {
+                    room_id: room_id.clone(),
+                    message_id: row.get::<i64, _>("id"),
+                    ts: chrono::Utc::now(),
+                });
+                services::chat::publish(app, &room_id, packet).await;
+            }
+            Ok(rows.len())
+        }
+
+        // A row is dropped only once its object is gone, so failed deletes are retried on
+        // the next tick.
+        async fn purge_expired_files(app: &state::AppState) -> anyhow::Result<usize> {
+            let rows = sqlx::query("SELECT id, object_key FROM chat_attachments WHERE status = 'expired' LIMIT $1")
+                .bind(BATCH)
+                .fetch_all(&app.pg)
+                .await?;
+            if rows.is_empty() {
+                return Ok(0);
+            }
+            let mut removed = Vec::with_capacity(rows.len());
+            for row in &rows {
+                let key = row.get::<String, _>("object_key");
+                match app.r2.delete_object().bucket(&app.r2_bucket).key(&key).send().await {
+                    Ok(_) => removed.push(row.get::<Uuid, _>("id")),
+                    Err(err) => tracing::warn!(?err, %key, "expired attachment delete failed"),
+                }
+            }
+            sqlx::query("DELETE FROM chat_attachments WHERE id = ANY($1)")
+                .bind(&removed)
+                .execute(&app.pg)
+                .await?;
+            Ok(removed.len())
+        }
+
+        pub async fn run_expiry(app: Arc<state::AppState>) {
+            let mut every = tokio::time::interval(std::time::Duration::from_secs(TICK_SECS));
+            loop {
+                every.tick().await;
+                match redact_expired(&app).await {
+                    Ok(0) => {}
+                    Ok(count) => tracing::debug!(count, "expired messages redacted"),
+                    Err(err) => tracing::warn!(?err, "message expiry failed"),
+                }
+                if let Err(err) = purge_expired_files(&app).await {
+                    tracing::warn!(?err, "expired attachment purge failed");
+                }
+            }
+        }
+    }
+
+    pub mod local {
+        use super::*;
+        use shared::geocell;
+
+        const CELL_TTL_SECS: u64 = 15 * 60;
+        const ACTIVITY_WINDOW_MINUTES: i32 = 60;
+        const LAPSE_SWEEP_SECS: u64 = 60;
+        const LAPSE_BATCH: i64 = 500;
+
+        fn cell_key(user_id: Uuid) -> String {
+            format!("local:cell:{user_id}")
+        }
+
+        pub async fn current_cell(redis: &RedisPool, user_id: Uuid) -> anyhow::Result<Option<String>> {
+            let mut conn = redis.get().await?;
+            Ok(conn.get(cell_key(user_id)).await?)
+        }
+
+        pub async fn sync_cell(app: &state::AppState, user_id: Uuid, lon: f64, lat: f64) -> anyhow::Result<()> {
+            let cell = geocell::encode(lon, lat, geocell::LOCAL_ROOM_PRECISION);
+            let mut conn = app.redis.get().await?;
+            let previous: Option<String> = redis::cmd("SET")
+                .arg(cell_key(user_id))
+                .arg(&cell)
+                .arg("EX")
+                .arg(CELL_TTL_SECS)
+                .arg("GET")
+                .query_async(&mut conn)
+                .await?;
+
+            if previous.as_deref() == Some(cell.as_str()) {
+                return Ok(());
+            }
+
+            let room_id = geocell::local_room_id(&cell);
+            let mut tx = app.pg.begin().await?;
+            let left = sqlx::query(
+                r#"
+                DELETE FROM room_memberships
+                WHERE user_id = $1 AND room_id LIKE 'local:%' AND room_id <> $2
+                RETURNING room_id
+                "#,
+            )
+            .bind(user_id)
+            .bind(&room_id)
+            .fetch_all(&mut *tx)
+            .await?;
+            sqlx::query(
+                r#"
+                INSERT INTO room_memberships(room_id, user_id, joined_at)
+                VALUES ($1, $2, now())
+                ON CONFLICT (room_id, user_id) DO NOTHING
+                "#,
+            )
+            .bind(&room_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+
+            let packet = shared::RealtimePacket::LocalRoom(shared::LocalRoomEvent {
+                user_id,
+                room_id,
+                neighbours: geocell::neighbours(&cell)
+                    .iter()
+                    .map(|n| geocell::local_room_id(n))
+                    .collect(),
+                cell,
+                left_room_id: left.first().map(|r| r.get::<String, _>("room_id")),
+                ts: chrono::Utc::now(),
+            });
+            if let Ok(payload) = rmp_serde::to_vec(&packet) {
+                let _ = app.realtime_tx.send(state::Outbound::everyone(payload));
+            }
+            Ok(())
+        }
+
+        // Drops local-room memberships whose cell key has expired or moved on. The joined_at
+        // guard keeps a row that sync_cell re-inserted after the keys were read.
+        async fn drop_lapsed(app: &state::AppState, after: Option<Uuid>) -> anyhow::Result<(usize, Option<Uuid>)> {
+            let rows = sqlx::query(
+                r#"
+                SELECT user_id, room_id
+                FROM room_memberships
+                WHERE room_id LIKE 'local:%' AND ($1::uuid IS NULL OR user_id > $1)
+                ORDER BY user_id
+                LIMIT $2
+                "#,
+            )
+            .bind(after)
+            .bind(LAPSE_BATCH)
+            .fetch_all(&app.pg)
+            .await?;
+            if rows.is_empty() {
+                return Ok((0, None));
+            }
+
+            let keys = rows.iter().map(|r| cell_key(r.get::<Uuid, _>("user_id"))).collect::<Vec<_>>();
+            let mut conn = app.redis.get().await?;
+            let cells: Vec<Option<String>> = redis::cmd("MGET").arg(&keys).query_async(&mut conn).await?;
+
+            let (users, rooms): (Vec<Uuid>, Vec<String>) = rows
+                .iter()
+                .zip(&cells)
+                .filter_map(|(r, cell)| {
+                    let room_id = r.get::<String, _>("room_id");
+                    let current = cell.as_deref().map(geocell::local_room_id);
+                    (current.as_deref() != Some(room_id.as_str())).then(|| (r.get::<Uuid, _>("user_id"), room_id))
+                })
+                .unzip();
+            if !users.is_empty() {
+                sqlx::query(
+                    r#"
+                    DELETE FROM room_memberships m
+                    USING UNNEST($1::uuid[], $2::text[]) AS l(user_id, room_id)
+                    WHERE m.user_id = l.user_id
+                      AND m.room_id = l.room_id
+                      AND m.joined_at <= now() - make_interval(secs => $3)
+                    "#,
+                )
+                .bind(&users)
+                .bind(&rooms)
+                .bind(CELL_TTL_SECS as f64)
+                .execute(&app.pg)
+                .await?;
+            }
+
+            let last = rows.last().map(|r| r.get::<Uuid, _>("user_id"));
+            Ok((rows.len(), last))
+        }
+
+        pub async fn run_cleanup(app: Arc<state::AppState>) {
+            let mut every = tokio::time::interval(std::time::Duration::from_secs(LAPSE_SWEEP_SECS));
+            loop {
+                every.tick().await;
+                let mut after = None;
+                loop {
+                    match drop_lapsed(&app, after).await {
+                        Ok((n, last)) if n as i64 == LAPSE_BATCH => after = last,
+                        Ok(_) => break,
+                        Err(err) => {
+                            tracing::warn!(?err, "local membership sweep failed");
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        pub async fn can_post(redis: &RedisPool, room_id: &str, user_id: Uuid) -> anyhow::Result<bool> {
+            let Some(cell) = geocell::cell_of_room(room_id) else {
+                return Ok(true);
+            };
+            Ok(current_cell(redis, user_id).await?.as_deref() == Some(cell))
+        }
+
+        pub(crate) async fn activity(pg: &PgPool, cells: &[String]) -> anyhow::Result<Vec<LocalRoomActivity>> {
+            let room_ids = cells.iter().map(|c| geocell::local_room_id(c)).collect::<Vec<_>>();
+            let rows = sqlx::query(
+                r#"
+                SELECT
+                    r.room_id,
+                    COALESCE(m.message_count, 0)::bigint AS message_count,
+                    m.last_message_at,
+                    COALESCE(p.member_count, 0)::bigint AS member_count
+                FROM UNNEST($1::text[]) AS r(room_id)
+                LEFT JOIN (
+                  SELECT room_id, COUNT(*) AS message_count, MAX(created_at) AS last_message_at
+                  FROM room_messages
+                  WHERE room_id = ANY($1) AND created_at > now() - make_interval(mins => $2)
+                  GROUP BY room_id
+                ) m ON m.room_id = r.room_id
+                LEFT JOIN (
+                  SELECT room_id, COUNT(*) AS member_count
+                  FROM room_memberships
+                  WHERE room_id = ANY($1)
+                  GROUP BY room_id
+                ) p ON p.room_id = r.room_id
+                "#,
+            )
+            .bind(&room_ids)
+            .bind(ACTIVITY_WINDOW_MINUTES)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .filter_map(|r| {
+                    let room_id = r.get::<String, _>("room_id");
+                    let cell = geocell::cell_of_room(&room_id)?.to_string();
+                    let bounds = geocell::decode(&cell)?;
+                    let (lon, lat) = bounds.center();
+                    Some(LocalRoomActivity {
+                        room_id,
+                        cell,
+                        lon,
+                        lat,
+                        bounds: [bounds.min_lon, bounds.min_lat, bounds.max_lon, bounds.max_lat],
+                        message_count: r.get::<i64, _>("message_count"),
+                        member_count: r.get::<i64, _>("member_count"),
+                        last_message_at: r.get::<Option<chrono::DateTime<chrono::Utc>>, _>("last_message_at"),
+                    })
+                })
+                .collect())
+        }
+
+        pub(crate) async fn active_cells_in(
+            pg: &PgPool,
+            min_lon: f64,
+            min_lat: f64,
+            max_lon: f64,
+            max_lat: f64,
+        ) -> anyhow::Result<Vec<LocalRoomActivity>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT DISTINCT room_id
+                FROM room_messages
+                WHERE room_id LIKE 'local:%' AND created_at > now() - make_interval(mins => $1)
+                LIMIT 2000
+                "#,
+            )
+            .bind(ACTIVITY_WINDOW_MINUTES)
+            .fetch_all(pg)
+            .await?;
+
+            let cells = rows
+                .into_iter()
+                .filter_map(|r| {
+                    let room_id = r.get::<String, _>("room_id");
+                    let cell = geocell::cell_of_room(&room_id)?;
+                    let (lon, lat) = geocell::decode(cell)?.center();
+                    (lon >= min_lon && lon <= max_lon && lat >= min_lat && lat <= max_lat).then(|| cell.to_string())
+                })
+                .take(500)
+                .collect::<Vec<_>>();
+
+            activity(pg, &cells).await
+        }
+    }
+
+    pub mod attachment {
+        use super::*;
+        use aws_sdk_s3::presigning::PresigningConfig;
+
+        pub const MAX_ATTACHMENT_BYTES: i64 = 20 * 1024 * 1024;
+        const MAX_IMAGE_EDGE: i32 = 16_384;
+        const UPLOAD_URL_TTL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+        const DOWNLOAD_URL_TTL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+        const ALLOWED_MIME_TYPES: &[&str] = &[
+            "image/png",
+            "image/jpeg",
+            "image/gif",
+            "image/webp",
+            "application/pdf",
+            "application/zip",
+            "text/plain",
+            "audio/mpeg",
+            "video/mp4",
+        ];
+
+        pub struct PendingUpload {
+            pub attachment_id: Uuid,
+            pub object_key: String,
+            pub upload_url: String,
+            pub expires_at: chrono::DateTime<chrono::Utc>,
+        }
+
+        pub enum ConfirmError {
+            NotFound,
+            NotUploaded,
+            Mismatch(&'static str),
+            Storage(anyhow::Error),
+        }
+
+        impl From<anyhow::Error> for ConfirmError {
+            fn from(err: anyhow::Error) -> Self {
+                ConfirmError::Storage(err)
+            }
+        }
+
+        pub fn is_allowed_mime(mime_type: &str) -> bool {
+            ALLOWED_MIME_TYPES.contains(&mime_type)
+        }
+
+        pub fn is_image(mime_type: &str) -> bool {
+            mime_type.starts_with("image/")
+        }
+
+        fn sanitize_file_name(raw: &str) -> String {
+            let cleaned = raw
+                .rsplit(['/', '\\'])
+                .next()
+                .unwrap_or_default()
+                .chars()
+                .map(|c| if c.is_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+                .take(120)
+                .collect::<String>();
+            if cleaned.trim_matches(['.', '_']).is_empty() {
+                "file".to_string()
+            } else {
+                cleaned
+            }
+        }
+
+        fn room_segment(room_id: &str) -> String {
+            room_id
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_') { c } else { '_' })
+                .collect()
+        }
+
+        pub async fn presign_upload(
+            app: &state::AppState,
+            room_id: &str,
+            uploader: Uuid,
+            file_name: &str,
+            mime_type: &str,
+            size_bytes: i64,
+        ) -> anyhow::Result<PendingUpload> {
+            let attachment_id = Uuid::new_v4();
+            let file_name = sanitize_file_name(file_name);
+            let object_key = format!("attachments/{}/{attachment_id}/{file_name}", room_segment(room_id));
+
+            let presigned = app
+                .r2
+                .put_object()
+                .bucket(&app.r2_bucket)
+                .key(&object_key)
+                .content_type(mime_type)
+                .content_length(size_bytes)
+                .presigned(PresigningConfig::expires_in(UPLOAD_URL_TTL)?)
+                .await?;
+
+            sqlx::query(
+                r#"
+                INSERT INTO chat_attachments(id, room_id, uploader, object_key, file_name, mime_type, size_bytes, status, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, 'pending', now())
+                "#,
+            )
+            .bind(attachment_id)
+            .bind(room_id)
+            .bind(uploader)
+            .bind(&object_key)
+            .bind(&file_name)
+            .bind(mime_type)
+            .bind(size_bytes)
+            .execute(&app.pg)
+            .await?;
+
+            Ok(PendingUpload {
+                attachment_id,
+                object_key,
+                upload_url: presigned.uri().to_string(),
+                expires_at: chrono::Utc::now() + chrono::Duration::from_std(UPLOAD_URL_TTL)?,
+            })
+        }
+
+        pub async fn confirm(
+            app: &state::AppState,
+            attachment_id: Uuid,
+            uploader: Uuid,
+            width: Option<i32>,
+            height: Option<i32>,
+        ) -> Result<shared::ChatAttachment, ConfirmError> {
+            let row = sqlx::query(
+                r#"
+                SELECT object_key, file_name, mime_type, size_bytes
+                FROM chat_attachments
+                WHERE id = $1 AND uploader = $2 AND status IN ('pending', 'uploaded')
+                "#,
+            )
+            .bind(attachment_id)
+            .bind(uploader)
+            .fetch_optional(&app.pg)
+            .await
+            .map_err(anyhow::Error::from)?;
+            let Some(row) = row else {
+                return Err(ConfirmError::NotFound);
+            };
+
+            let object_key = row.get::<String, _>("object_key");
+            let mime_type = row.get::<String, _>("mime_type");
+            let declared_size = row.get::<i64, _>("size_bytes");
+
+            let head = match app.r2.head_object().bucket(&app.r2_bucket).key(&object_key).send().await {
+                Ok(head) => head,
+                Err(err) => {
+                    let service_err = err.into_service_error();
+                    if service_err.is_not_found() {
+                        return Err(ConfirmError::NotUploaded);
+                    }
+                    return Err(ConfirmError::Storage(service_err.into()));
+                }
+            };
+
+            if head.content_length().unwrap_or_default() != declared_size {
+                return Err(ConfirmError::Mismatch("uploaded size does not match the declared size"));
+            }
+            if head.content_type().is_some_and(|stored| stored != mime_type) {
+                return Err(ConfirmError::Mismatch("uploaded content type does not match the declared type"));
+            }
+
+            let (width, height) = if is_image(&mime_type) {
+                match (width, height) {
+                    (Some(w), Some(h)) if (1..=MAX_IMAGE_EDGE).contains(&w) && (1..=MAX_IMAGE_EDGE).contains(&h) => (Some(w), Some(h)),
+                    _ => return Err(ConfirmError::Mismatch("images require width and height within bounds")),
+                }
+            } else {
+                (None, None)
+            };
+
+            sqlx::query(
+                r#"
+                UPDATE chat_attachments
+                SET status = 'uploaded', width = $2, height = $3, uploaded_at = now()
+                WHERE id = $1
+                "#,
+            )
+            .bind(attachment_id)
+            .bind(width)
+            .bind(height)
+            .execute(&app.pg)
+            .await
+            .map_err(anyhow::Error::from)?;
+
+            Ok(shared::ChatAttachment {
+                id: attachment_id,
+                file_name: row.get::<String, _>("file_name"),
+                mime_type,
+                size_bytes: declared_size,
+                width,
+                height,
+                url: download_url(app, &object_key).await.ok(),
+            })
+        }
+
+        pub async fn claim<'e>(
+            pg: impl sqlx::PgExecutor<'e>,
+            message_id: i64,
+            room_id: &str,
+            uploader: Uuid,
+            attachment_ids: &[Uuid],
+        ) -> anyhow::Result<Vec<(String, shared::ChatAttachment)>> {
+            let rows = sqlx::query(
+                r#"
+                UPDATE chat_attachments
+                SET message_id = $1, status = 'attached'
+                WHERE id = ANY($2)
+                  AND room_id = $3
+                  AND uploader = $4
+                  AND status = 'uploaded'
+                  AND message_id IS NULL
+                RETURNING id, object_key, file_name, mime_type, size_bytes, width, height
+                "#,
+            )
+            .bind(message_id)
+            .bind(attachment_ids)
+            .bind(room_id)
+            .bind(uploader)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows.iter().map(attachment_row).collect())
+        }
+
+        fn attachment_row(row: &sqlx::postgres::PgRow) -> (String, shared::ChatAttachment) {
+            (
+                row.get::<String, _>("object_key"),
+                shared::ChatAttachment {
+                    id: row.get::<Uuid, _>("id"),
+                    file_name: row.get::<String, _>("file_name"),
+                    mime_type: row.get::<String, _>("mime_type"),
+                    size_bytes: row.get::<i64, _>("size_bytes"),
+                    width: row.get::<Option<i32>, _>("width"),
+                    height: row.get::<Option<i32>, _>("height"),
+                    url: None,
+                },
+            )
+        }
+
+        pub async fn for_message(app: &state::AppState, message_id: i64) -> anyhow::Result<Vec<shared::ChatAttachment>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT id, object_key, file_name, mime_type, size_bytes, width, height
+                FROM chat_attachments
+                WHERE message_id = $1 AND status = 'attached'
+                ORDER BY created_at
+                "#,
+            )
+            .bind(message_id)
+            .fetch_all(&app.pg)
+            .await?;
+
+            Ok(with_download_urls(app, rows.iter().map(attachment_row).collect()).await)
+        }
+
+        pub async fn download_url(app: &state::AppState, object_key: &str) -> anyhow::Result<String> {
+            let presigned = app
+                .r2
+                .get_object()
+                .bucket(&app.r2_bucket)
+                .key(object_key)
+                .presigned(PresigningConfig::expires_in(DOWNLOAD_URL_TTL)?)
+                .await?;
+            Ok(presigned.uri().to_string())
+        }
+
+        pub async fn with_download_urls(
+            app: &state::AppState,
+            rows: Vec<(String, shared::ChatAttachment)>,
+        ) -> Vec<shared::ChatAttachment> {
+            let mut out = Vec::with_capacity(rows.len());
+            for (object_key, mut attachment) in rows {
+                attachment.url = download_url(app, &object_key).await.ok();
+                out.push(attachment);
+            }
+            out
+        }
+
+        pub(crate) async fn fill_history(app: &state::AppState, items: &mut [ChatHistoryItem]) -> anyhow::Result<()> {
+            let ids = items.iter().map(|item| item.id).collect::<Vec<_>>();
+            if ids.is_empty() {
+                return Ok(());
+            }
+
+            let rows = sqlx::query(
+                r#"
+                SELECT message_id, id, object_key, file_name, mime_type, size_bytes, width, height
+                FROM chat_attachments
+                WHERE message_id = ANY($1) AND status = 'attached'
+                ORDER BY created_at
+                "#,
+            )
+            .bind(&ids)
+            .fetch_all(&app.pg)
+            .await?;
+
+            let mut by_message: std::collections::HashMap<i64, Vec<(String, shared::ChatAttachment)>> =
+                std::collections::HashMap::new();
+            for row in &rows {
+                by_message
+                    .entry(row.get::<i64, _>("message_id"))
+                    .or_default()
+                    .push(attachment_row(row));
+            }
+
+            for item in items.iter_mut() {
+                if let Some(found) = by_message.remove(&item.id) {
+                    item.attachments = with_download_urls(app, found).await;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    pub mod search {
+        use super::*;
+
+        const SNIPPET_RADIUS: usize = 32;
+
+        fn is_cjk(c: char) -> bool {
+            matches!(
+                c as u32,
+                0x3040..=0x30FF
+                    | 0x3400..=0x4DBF
+                    | 0x4E00..=0x9FFF
+                    | 0xAC00..=0xD7AF
+                    | 0xF900..=0xFAFF
+                    | 0x20000..=0x2FA1F
+            )
+        }
+
+        enum Run {
+            Cjk(Vec<char>),
+            Word(String),
+        }
+
+        fn runs(text: &str) -> Vec<Run> {
+            let mut out = Vec::new();
+            let mut cjk = Vec::new();
+            let mut word = String::new();
+            for c in text.chars().flat_map(char::to_lowercase) {
+                if is_cjk(c) {
+                    if !word.is_empty() {
+                        out.push(Run::Word(std::mem::take(&mut word)));
+                    }
+                    cjk.push(c);
+                } else if c.is_alphanumeric() {
+                    if !cjk.is_empty() {
+                        out.push(Run::Cjk(std::mem::take(&mut cjk)));
+                    }
+                    word.push(c);
+                } else {
+                    if !word.is_empty() {
+                        out.push(Run::Word(std::mem::take(&mut word)));
+                    }
+                    if !cjk.is_empty() {
+                        out.push(Run::Cjk(std::mem::take(&mut cjk)));
+                    }
+                }
+            }
+            if !word.is_empty() {
+                out.push(Run::Word(word));
+            }
+            if !cjk.is_empty() {
+                out.push(Run::Cjk(cjk));
+            }
+            out
+        }
+
+        fn run_tokens(run: &Run) -> Vec<String> {
+            match run {
+                Run::Word(word) => vec![word.clone()],
+                Run::Cjk(chars) if chars.len() == 1 => vec![chars[0].to_string()],
+                Run::Cjk(chars) => chars.windows(2).map(|pair| pair.iter().collect()).collect(),
+            }
+        }
+
+        fn index_tokens(run: &Run) -> Vec<String> {
+            let mut tokens = run_tokens(run);
+            if let Run::Cjk(chars) = run {
+                if let (true, Some(last)) = (chars.len() > 1, chars.last()) {
+                    tokens.push(last.to_string());
+                }
+            }
+            tokens
+        }
+
+        fn run_text(run: &Run) -> String {
+            match run {
+                Run::Word(word) => word.clone(),
+                Run::Cjk(chars) => chars.iter().collect(),
+            }
+        }
+
+        pub fn segment(text: &str) -> String {
+            runs(text)
+                .iter()
+                .flat_map(index_tokens)
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+
+        pub fn to_tsquery(query: &str) -> Option<String> {
+            let clauses = runs(query)
+                .iter()
+                .map(|run| {
+                    if let Run::Cjk(chars) = run {
+                        if chars.len() == 1 {
+                            return format!("'{}':*", chars[0]);
+                        }
+                    }
+                    let phrase = run_tokens(run)
+                        .into_iter()
+                        .map(|token| format!("'{token}'"))
+                        .collect::<Vec<_>>()
+                        .join(" <-> ");
+                    format!("({phrase})")
+                })
+                .collect::<Vec<_>>();
+
+            if clauses.is_empty() {
+                None
+            } else {
+                Some(clauses.join(" & "))
+            }
+        }
+
+        fn escape_html(c: char, out: &mut String) {
+            match c {
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '&' => out.push_str("&amp;"),
+                '"' => out.push_str("&quot;"),
+                '\'' => out.push_str("&#39;"),
+                _ => out.push(c),
+            }
+        }
+
+        pub fn highlight(text: &str, query: &str) -> String {
+            let chars = text.chars().collect::<Vec<_>>();
+            let folded = chars
+                .iter()
+                .map(|c| c.to_lowercase().next().unwrap_or(*c))
+                .collect::<Vec<_>>();
+            let terms = runs(query)
+                .iter()
+                .map(|run| run_text(run).chars().collect::<Vec<_>>())
+                .filter(|term| !term.is_empty())
+                .collect::<Vec<_>>();
+
+            let mut marked = vec![false; chars.len()];
+            for term in &terms {
+                if term.len() > folded.len() {
+                    continue;
+                }
+                for start in 0..=folded.len() - term.len() {
+                    if folded[start..start + term.len()] == term[..] {
+                        marked[start..start + term.len()].iter_mut().for_each(|m| *m = true);
+                    }
+                }
+            }
+
+            let first = marked.iter().position(|m| *m).unwrap_or(0);
+            let from = first.saturating_sub(SNIPPET_RADIUS);
+            let to = (first + SNIPPET_RADIUS * 2).min(chars.len());
+
+            let mut out = String::new();
+            if from > 0 {
+                out.push('…');
+            }
+            let mut open = false;
+            for i in from..to {
+                if marked[i] && !open {
+                    out.push_str("<mark>");
+                    open = true;
+                } else if !marked[i] && open {
+                    out.push_str("</mark>");
+                    open = false;
+                }
+                escape_html(chars[i], &mut out);
+            }
+            if open {
+                out.push_str("</mark>");
+            }
+            if to < chars.len() {
+                out.push('…');
+            }
+            out
+        }
+
+        pub(crate) async fn search_messages(
+            pg: &PgPool,
+            user_id: Uuid,
+            filter: &SearchFilter,
+        ) -> anyhow::Result<Vec<SearchHit>> {
+            let Some(tsquery) = to_tsquery(&filter.query) else {
+                return Ok(Vec::new());
+            };
+
+            let rows = sqlx::query(
+                r#"
+                WITH member_rooms AS (
+                  SELECT 'global'::text AS room_id
+                  UNION
+                  SELECT room_id FROM room_memberships WHERE user_id = $1
+                  UNION
+                  SELECT room_id FROM room_member_reads WHERE user_id = $1
+                  UNION
+                  SELECT room_id FROM room_messages WHERE from_user = $1
+                ),
+                q AS (
+                  SELECT to_tsquery('simple', $2) AS query
+                )
+                SELECT
+                    m.id,
+                    m.room_id,
+                    m.from_user::text AS from_user,
+                    m.message,
+                    m.parent_id,
+                    m.created_at,
+                    ts_rank_cd(m.search_vector, q.query) AS rank
+                FROM room_messages m, q
+                WHERE m.search_vector @@ q.query
+                  AND (m.expires_at IS NULL OR m.expires_at > now())
+                  AND m.room_id IN (SELECT room_id FROM member_rooms)
+                  AND (m.room_id NOT LIKE 'dm:%' OR strpos(m.room_id, $1::text) > 0)
+                  AND ($3::text IS NULL OR m.room_id = $3)
+                  AND ($4::uuid IS NULL OR m.from_user = $4)
+                  AND ($5::timestamptz IS NULL OR m.created_at >= $5)
+                  AND ($6::timestamptz IS NULL OR m.created_at < $6)
+                ORDER BY rank DESC, m.created_at DESC
+                LIMIT $7 OFFSET $8
+                "#,
+            )
+            .bind(user_id)
+            .bind(tsquery)
+            .bind(filter.room_id.as_deref())
+            .bind(filter.author_id)
+            .bind(filter.since)
+            .bind(filter.until)
+            .bind(filter.limit)
+            .bind(filter.offset)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|r| {
+                    let text = r.get::<String, _>("message");
+                    SearchHit {
+                        id: r.get::<i64, _>("id"),
+                        room_id: r.get::<String, _>("room_id"),
+                        from_user: r.get::<String, _>("from_user"),
+                        snippet: highlight(&text, &filter.query),
+                        text,
+                        parent_id: r.get::<Option<i64>, _>("parent_id"),
+                        rank: r.get::<f32, _>("rank"),
+                        ts: r.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+                    }
+                })
+                .collect())
+        }
+
+        pub async fn backfill(pg: &PgPool) -> anyhow::Result<u64> {
+            let mut total = 0_u64;
+            loop {
+                let rows = sqlx::query(
+                    r#"
+                    SELECT id, message, format
+                    FROM room_messages
+                    WHERE search_vector IS NULL
+                    ORDER BY id
+                    LIMIT 500
+                    "#,
+                )
+                .fetch_all(pg)
+                .await?;
+
+                if rows.is_empty() {
+                    return Ok(total);
+                }
+
+                for row in rows {
+                    // Same text as `insert_message` indexes, so markdown syntax is never searchable.
+                    let format = services::content::parse_format(&row.get::<String, _>("format"));
+                    let text = services::content::plain_text(format, &row.get::<String, _>("message"));
+                    sqlx::query("UPDATE room_messages SET search_vector = to_tsvector('simple', $2) WHERE id = $1")
+                        .bind(row.get::<i64, _>("id"))
+                        .bind(segment(&text))
+                        .execute(pg)
+                        .await?;
+                    total += 1;
+                }
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            #[test]
+            fn segment_splits_cjk_into_bigrams() {
+                assert_eq!(segment("Hello 世界和平, WORLD!"), "hello 世界 界和 和平 平 world");
+                assert_eq!(segment("rust编程abc"), "rust 编程 程 abc");
+                assert_eq!(segment("中"), "中");
+                assert_eq!(segment("한국어 テスト"), "한국 국어 어 テス スト ト");
+                assert_eq!(segment(" ...!? "), "");
+            }
+
+            #[test]
+            fn tsquery_chains_bigrams_as_phrases() {
+                assert_eq!(to_tsquery("世界 hello").as_deref(), Some("('世界') & ('hello')"));
+                assert_eq!(to_tsquery("和平世界").as_deref(), Some("('和平' <-> '平世' <-> '世界')"));
+                assert_eq!(to_tsquery("中").as_deref(), Some("'中':*"));
+                assert_eq!(to_tsquery("rust编程").as_deref(), Some("('rust') & ('编程')"));
+                // Operators and quotes never reach the query text.
+                assert_eq!(to_tsquery("a' | !b:*").as_deref(), Some("('a') & ('b')"));
+            }
+
+            #[test]
+            fn empty_queries_match_nothing() {
+                assert_eq!(to_tsquery(""), None);
+                assert_eq!(to_tsquery("  ?!... --- "), None);
+            }
+
+            #[test]
+            fn highlight_marks_terms_and_escapes_html() {
+                assert_eq!(highlight("Hello World", "world"), "Hello <mark>World</mark>");
+                assert_eq!(highlight("<b>世界</b> & co", "世界"), "&lt;b&gt;<mark>世界</mark>&lt;/b&gt; &amp; co");
+                assert_eq!(highlight("学习rust编程", "Rust 编程"), "学习<mark>rust编程</mark>");
+                assert_eq!(highlight("plain text", ""), "plain text");
+                assert_eq!(highlight("plain text", "!!"), "plain text");
+            }
+
+            #[test]
+            fn highlight_trims_long_text_around_the_first_hit() {
+                let text = format!("{}target{}", "a".repeat(100), "b".repeat(100));
+                let expected = format!("…{}<mark>target</mark>{}…", "a".repeat(32), "b".repeat(58));
+                assert_eq!(highlight(&text, "target"), expected);
+            }
+        }
+    }
+
+    pub mod typing {
+        use super::*;
+
+        pub const TYPING_TTL_MS: i64 = 5_000;
+        const TYPING_THROTTLE_MS: i64 = 2_000;
+
+        fn room_key(room_id: &str) -> String {
+            format!("typing:{room_id}")
+        }
+
+        fn throttle_key(room_id: &str, user_id: Uuid) -> String {
+            format!("typing:throttle:{room_id}:{user_id}")
+        }
+
+        pub async fn start(redis: &RedisPool, room_id: &str, user_id: Uuid) -> anyhow::Result<bool> {
+            let mut conn = redis.get().await?;
+            let expires_at = chrono::Utc::now().timestamp_millis() + TYPING_TTL_MS;
+            let _: usize = redis::cmd("ZADD")
+                .arg(room_key(room_id))
+                .arg(expires_at)
+                .arg(user_id.to_string())
+                .query_async(&mut conn)
+                .await?;
+            let _: bool = conn.pexpire(room_key(room_id), TYPING_TTL_MS).await?;
+
+            let fresh: Option<String> = redis::cmd("SET")
+                .arg(throttle_key(room_id, user_id))
+                .arg("1")
+                .arg("NX")
+                .arg("PX")
+                .arg(TYPING_THROTTLE_MS)
+                .query_async(&mut conn)
+                .await?;
+            Ok(fresh.is_some())
+        }
+
+        pub async fn stop(redis: &RedisPool, room_id: &str, user_id: Uuid) -> anyhow::Result<bool> {
+            let mut conn = redis.get().await?;
+            let removed: usize = conn.zrem(room_key(room_id), user_id.to_string()).await?;
+            let _: usize = conn.del(throttle_key(room_id, user_id)).await?;
+            Ok(removed > 0)
+        }
+
+        pub async fn typing_users(redis: &RedisPool, room_id: &str) -> anyhow::Result<Vec<String>> {
+            let mut conn = redis.get().await?;
+            let now = chrono::Utc::now().timestamp_millis();
+            let _: usize = conn.zrembyscore(room_key(room_id), "-inf", now).await?;
+            let users: Vec<String> = conn.zrangebyscore(room_key(room_id), now, "+inf").await?;
+            Ok(users)
+        }
+
+        pub fn broadcast(app: &state::AppState, room_id: &str, user_id: Uuid, typing: bool) {
+            let packet = shared::RealtimePacket::Typing(shared::TypingEvent {
+                room_id: room_id.to_string(),
+                user_id,
+                typing,
+                ts: chrono::Utc::now(),
+            });
+            services::chat::emit(app, room_id, &packet);
+        }
+
+        pub async fn clear(app: &state::AppState, room_id: &str, user_id: Uuid) {
+            if stop(&app.redis, room_id, user_id).await.unwrap_or(false) {
+                broadcast(app, room_id, user_id, false);
+            }
+        }
+    }
+
+    pub mod invite {
+        use super::*;
+
+        pub const INVITE_TTL_SECS: i64 = 300;
+        const MAX_MODE_LEN: usize = 32;
+        const EXPIRE_BATCH: i64 = 200;
+        const EXPIRE_SWEEP_SECS: u64 = 5;
+
+        pub enum InviteError {
+            Invalid(&'static str),
+            SelfInvite,
+            UnknownUser,
+            Duplicate(Uuid),
+            NotFound,
+            Forbidden(&'static str),
+            AlreadySettled(shared::InviteStatus),
+            Expired,
+            Storage(anyhow::Error),
+        }
+
+        impl InviteError {
+            pub fn status_code(&self) -> StatusCode {
+                match self {
+                    InviteError::Invalid(_) | InviteError::SelfInvite => StatusCode::BAD_REQUEST,
+                    InviteError::UnknownUser | InviteError::NotFound => StatusCode::NOT_FOUND,
+                    InviteError::Forbidden(_) => StatusCode::FORBIDDEN,
+                    InviteError::Duplicate(_) | InviteError::AlreadySettled(_) => StatusCode::CONFLICT,
+                    InviteError::Expired => StatusCode::GONE,
+                    InviteError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                }
+            }
+
+            pub fn message(&self) -> String {
+                match self {
+                    InviteError::Invalid(reason) | InviteError::Forbidden(reason) => reason.to_string(),
+                    InviteError::SelfInvite => "you cannot invite yourself".to_string(),
+                    InviteError::UnknownUser => "invited user does not exist".to_string(),
+                    InviteError::Duplicate(id) => format!("a pending invite to this user already exists ({id})"),
+                    InviteError::NotFound => "invite not found".to_string(),
+                    InviteError::AlreadySettled(status) => format!("invite is already {}", shared::invite::as_str(*status)),
+                    InviteError::Expired => "invite has expired".to_string(),
+                    InviteError::Storage(_) => "failed to store invite".to_string(),
+                }
+            }
+        }
+
+        impl From<anyhow::Error> for InviteError {
+            fn from(err: anyhow::Error) -> Self {
+                InviteError::Storage(err)
+            }
+        }
+
+        impl From<sqlx::Error> for InviteError {
+            fn from(err: sqlx::Error) -> Self {
+                // The recipient can be deleted between the existence check and the insert.
+                if let sqlx::Error::Database(db) = &err {
+                    if db.code().as_deref() == Some("23503") {
+                        return InviteError::UnknownUser;
+                    }
+                }
+                InviteError::Storage(err.into())
+            }
+        }
+
+        pub fn normalize_mode(mode: &str) -> Result<String, InviteError> {
+            let mode = mode.trim();
+            if mode.is_empty() {
+                return Ok("duel".to_string());
+            }
+            if mode.len() > MAX_MODE_LEN || !mode.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                return Err(InviteError::Invalid("mode must be up to 32 letters, digits, '-' or '_'"));
+            }
+            let mode = mode.to_ascii_lowercase();
+            if !services::runtime::supports(&mode) {
+                return Err(InviteError::Invalid("unsupported game mode"));
+            }
+            Ok(mode)
+        }
+
+        fn event_from_row(row: &sqlx::postgres::PgRow) -> shared::InviteEvent {
+            shared::InviteEvent {
+                invite_id: row.get::<Uuid, _>("id"),
+                from_user: row.get::<Uuid, _>("from_user"),
+                to_user: row.get::<Uuid, _>("to_user"),
+                mode: row.get::<String, _>("mode"),
+                status: shared::invite::parse(&row.get::<String, _>("status")).unwrap_or_default(),
+                ts: row
+                    .get::<Option<chrono::DateTime<chrono::Utc>>, _>("responded_at")
+                    .unwrap_or_else(|| row.get::<chrono::DateTime<chrono::Utc>, _>("created_at")),
+                expires_at: Some(row.get::<chrono::DateTime<chrono::Utc>, _>("expires_at")),
+            }
+        }
+
+        pub async fn publish(app: &state::AppState, event: shared::InviteEvent) {
+            let (from_user, to_user) = (event.from_user, event.to_user);
+            let packet = shared::RealtimePacket::Invite(event);
+            services::mailbox::deliver(app, to_user, packet.clone()).await;
+            services::mailbox::deliver(app, from_user, packet).await;
+        }
+
+        pub async fn create(app: &state::AppState, from_user: Uuid, to_user: Uuid, mode: &str) -> Result<shared::InviteEvent, InviteError> {
+            if from_user == to_user {
+                return Err(InviteError::SelfInvite);
+            }
+            let mode = normalize_mode(mode)?;
+
+            let exists = sqlx::query("SELECT EXISTS(SELECT 1 FROM users WHERE id = $1) AS found")
+                .bind(to_user)
+                .fetch_one(&app.pg)
+                .await?
+                .get::<bool, _>("found");
+            if !exists {
+                return Err(InviteError::UnknownUser);
+            }
+
+            let mut tx = app.pg.begin().await?;
+            // A lapsed invite the sweeper has not reached yet must not block a fresh one.
+            let stale = sqlx::query(
+                r#"
+                UPDATE invites
+                SET status = 'expired', responded_at = now()
+                WHERE from_user = $1 AND to_user = $2 AND status = 'pending' AND expires_at <= now()
+                RETURNING id, from_user, to_user, mode, status, created_at, responded_at, expires_at
+                "#,
+            )
+            .bind(from_user)
+            .bind(to_user)
+            .fetch_all(&mut *tx)
+            .await?;
+
+            let inserted = sqlx::query(
+                r#"
+                INSERT INTO invites(id, from_user, to_user, mode, status, created_at, expires_at)
+                VALUES ($1, $2, $3, $4, 'pending', now(), now() + make_interval(secs => $5))
+                ON CONFLICT (from_user, to_user) WHERE status = 'pending' DO NOTHING
+                RETURNING id, from_user, to_user, mode, status, created_at, responded_at, expires_at
+                "#,
+            )
+            .bind(Uuid::new_v4())
+            .bind(from_user)
+            .bind(to_user)
+            .bind(&mode)
+            .bind(INVITE_TTL_SECS as f64)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            let Some(row) = inserted else {
+                tx.rollback().await?;
+                let existing = sqlx::query("SELECT id FROM invites WHERE from_user = $1 AND to_user = $2 AND status = 'pending'")
+                    .bind(from_user)
+                    .bind(to_user)
+                    .fetch_optional(&app.pg)
+                    .await?;
+                return Err(match existing {
+                    Some(row) => InviteError::Duplicate(row.get::<Uuid, _>("id")),
+                    None => InviteError::Storage(anyhow::anyhow!("pending invite vanished during insert")),
+                });
+            };
+            tx.commit().await?;
+
+            for row in &stale {
+                publish(app, event_from_row(row)).await;
+            }
+            let event = event_from_row(&row);
+            publish(app, event.clone()).await;
+            Ok(event)
+        }
+
+        pub async fn transition(
+            app: &state::AppState,
+            invite_id: Uuid,
+            actor: Uuid,
+            next: shared::InviteStatus,
+        ) -> Result<(shared::InviteEvent, Option<shared::MatchReady>), InviteError> {
+            let mut tx = app.pg.begin().await?;
+            let row = sqlx::query(
+                r#"
+                SELECT from_user, to_user, status, expires_at <= now() AS lapsed
+                FROM invites
+                WHERE id = $1
+                FOR UPDATE
+                "#,
+            )
+            .bind(invite_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            // Invites are private to their two participants, so outsiders get a plain 404.
+            let Some(row) = row else {
+                return Err(InviteError::NotFound);
+            };
+            let is_sender = row.get::<Uuid, _>("from_user") == actor;
+            let is_recipient = row.get::<Uuid, _>("to_user") == actor;
+            if !is_sender && !is_recipient {
+                return Err(InviteError::NotFound);
+            }
+            if !shared::invite::may_set(next, is_sender, is_recipient) {
+                return Err(InviteError::Forbidden(match next {
+                    shared::InviteStatus::Cancelled => "only the sender can cancel an invite",
+                    _ => "only the invited user can answer an invite",
+                }));
+            }
+
+            let current = shared::invite::parse(&row.get::<String, _>("status")).unwrap_or_default();
+            if !shared::invite::can_transition(current, next) {
+                return Err(InviteError::AlreadySettled(current));
+            }
+            if row.get::<bool, _>("lapsed") {
+                return Err(InviteError::Expired);
+            }
+
+            let updated = sqlx::query(
+                r#"
+                UPDATE invites
+                SET status = $2, responded_at = now()
+                WHERE id = $1
+                RETURNING id, from_user, to_user, mode, status, created_at, responded_at, expires_at
+                "#,
+            )
+            .bind(invite_id)
+            .bind(shared::invite::as_str(next))
+            .fetch_one(&mut *tx)
+            .await?;
+            let event = event_from_row(&updated);
+
+            let ready = if next == shared::InviteStatus::Accepted {
+                Some(services::matches::create(&mut *tx, Some(invite_id), &event.mode, &[event.from_user, event.to_user]).await?)
+            } else {
+                None
+            };
+            tx.commit().await?;
+
+            publish(app, event.clone()).await;
+            if let Some(ready) = ready.clone() {
+                services::matches::announce(app, ready).await;
+            }
+            Ok((event, ready))
+        }
+
+        pub async fn expire_due(app: &state::AppState) -> anyhow::Result<usize> {
+            let rows = sqlx::query(
+                r#"
+                UPDATE invites
+                SET status = 'expired', responded_at = now()
+                WHERE id IN (
+                  SELECT id FROM invites
+                  WHERE status = 'pending' AND expires_at <= now()
+                  ORDER BY expires_at
+                  LIMIT $1
+                  FOR UPDATE SKIP LOCKED
+                )
+                RETURNING id, from_user, to_user, mode, status, created_at, responded_at, expires_at
+                "#,
+            )
+            .bind(EXPIRE_BATCH)
+            .fetch_all(&app.pg)
+            .await?;
+
+            for row in &rows {
+                publish(app, event_from_row(row)).await;
+            }
+            Ok(rows.len())
+        }
+
+        pub async fn run_expiry(app: Arc<state::AppState>) {
+            let mut every = tokio::time::interval(std::time::Duration::from_secs(EXPIRE_SWEEP_SECS));
+            loop {
+                every.tick().await;
+                loop {
+                    match expire_due(&app).await {
+                        Ok(n) if (n as i64) == EXPIRE_BATCH => continue,
+                        Ok(_) => break,
+                        Err(err) => {
+                            tracing::warn!(?err, "invite expiry sweep failed");
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Both directions, so the sender can see and cancel what they have outstanding.
+        pub(crate) async fn pending_for_user(pg: &PgPool, user_id: Uuid) -> anyhow::Result<Vec<InviteItem>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT id::text AS invite_id, from_user::text AS from_user, to_user::text AS to_user, mode, status, created_at, expires_at
+                FROM invites
+                WHERE (to_user = $1 OR from_user = $1)
+                  AND status = 'pending'
+                  AND expires_at > now()
+                ORDER BY created_at DESC
+                LIMIT 100
+                "#,
+            )
+            .bind(user_id)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|r| InviteItem {
+                    invite_id: r.get::<String, _>("invite_id"),
+                    from_user: r.get::<String, _>("from_user"),
+                    to_user: r.get::<String, _>("to_user"),
+                    mode: r.get::<String, _>("mode"),
+                    status: r.get::<String, _>("status"),
+                    ts: r.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+                    expires_at: r.get::<chrono::DateTime<chrono::Utc>, _>("expires_at"),
+                })
+                .collect())
+        }
+    }
+
+    pub mod matches {
+        use super::*;
+        use sqlx::postgres::PgRow;
+
+        const JOIN_TIMEOUT_SECS: i64 = 120;
+        // A running match this old lost its runtime, e.g. to a restart.
+        const MAX_RUNNING_SECS: i64 = 900;
+        const ABANDON_SWEEP_SECS: u64 = 15;
+        const MAX_FRAME_BYTES: usize = 4096;
+
+        fn ready_from_row(row: &PgRow) -> shared::MatchReady {
+            let match_id = row.get::<Uuid, _>("id");
+            shared::MatchReady {
+                match_id,
+                invite_id: row.get::<Option<Uuid>, _>("invite_id"),
+                mode: row.get::<String, _>("mode"),
+                players: row.get::<Vec<Uuid>, _>("players"),
+                state: shared::matches::parse(&row.get::<String, _>("state")).unwrap_or_default(),
+                channel: row.get::<String, _>("channel"),
+                ws_path: shared::matches::ws_path(match_id),
+                ts: row.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+            }
+        }
+
+        // Runs inside the caller's transaction so an accepted invite always has its match.
+        pub async fn create(
+            conn: &mut sqlx::PgConnection,
+            invite_id: Option<Uuid>,
+            mode: &str,
+            players: &[Uuid],
+        ) -> anyhow::Result<shared::MatchReady> {
+            let match_id = Uuid::new_v4();
+            let channel = shared::matches::channel(match_id);
+            let row = sqlx::query(
+                r#"
+                INSERT INTO matches(id, invite_id, mode, state, channel, created_at)
+                VALUES ($1, $2, $3, 'waiting', $4, now())
+                RETURNING created_at
+                "#,
+            )
+            .bind(match_id)
+            .bind(invite_id)
+            .bind(mode)
+            .bind(&channel)
+            .fetch_one(&mut *conn)
+            .await?;
+
+            for (slot, user_id) in players.iter().enumerate() {
+                sqlx::query("INSERT INTO match_players(match_id, user_id, slot) VALUES ($1, $2, $3)")
+                    .bind(match_id)
+                    .bind(user_id)
+                    .bind(slot as i16)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+
+            Ok(shared::MatchReady {
+                match_id,
+                invite_id,
+                mode: mode.to_string(),
+                players: players.to_vec(),
+                state: shared::MatchState::Waiting,
+                channel,
+                ws_path: shared::matches::ws_path(match_id),
+                ts: row.get::<chrono::DateTime<chrono::Utc>, _>("created_at"),
+            })
+        }
+
+        pub async fn announce(app: &state::AppState, ready: shared::MatchReady) {
+            let players = ready.players.clone();
+            let packet = shared::RealtimePacket::MatchReady(ready);
+            for user_id in players {
+                services::mailbox::deliver(app, user_id, packet.clone()).await;
+            }
+        }
+
+        pub async fn load(pg: &PgPool, match_id: Uuid) -> anyhow::Result<Option<shared::MatchReady>> {
+            let row = sqlx::query(
+                r#"
+                SELECT m.id, m.invite_id, m.mode, m.state, m.channel, m.created_at,
+                       array_agg(p.user_id ORDER BY p.slot) AS players
+                FROM matches m
+                JOIN match_players p ON p.match_id = m.id
+                WHERE m.id = $1
+                GROUP BY m.id
+                "#,
+            )
+            .bind(match_id)
+            .fetch_optional(pg)
+            .await?;
+            Ok(row.as_ref().map(ready_from_row))
+        }
+
+        pub async fn active_for_user(pg: &PgPool, user_id: Uuid) -> anyhow::Result<Option<shared::MatchReady>> {
+            let row = sqlx::query(
+                r#"
+                SELECT m.id, m.invite_id, m.mode, m.state, m.channel, m.created_at,
+                       array_agg(p.user_id ORDER BY p.slot) AS players
+                FROM matches m
+                JOIN match_players p ON p.match_id = m.id
+                WHERE m.state IN ('waiting', 'running')
+                  AND EXISTS (SELECT 1 FROM match_players me WHERE me.match_id = m.id AND me.user_id = $1)
+                GROUP BY m.id
+                ORDER BY m.created_at DESC
+                LIMIT 1
+                "#,
+            )
+            .bind(user_id)
+            .fetch_optional(pg)
+            .await?;
+            Ok(row.as_ref().map(ready_from_row))
+        }
+
+        // Marks the player present; the match starts once every slot has joined, and only the
+        // caller that made that transition gets `true` back and owns the runtime.
+        pub async fn join(app: &state::AppState, match_id: Uuid, user_id: Uuid) -> anyhow::Result<Option<(shared::MatchReady, bool)>> {
+            let mut tx = app.pg.begin().await?;
+            let joined = sqlx::query(
+                r#"
+                UPDATE match_players p
+                SET joined_at = COALESCE(p.joined_at, now())
+                FROM matches m
+                WHERE p.match_id = $1 AND p.user_id = $2
+                  AND m.id = p.match_id
+                  AND m.state IN ('waiting', 'running')
+                RETURNING p.slot
+                "#,
+            )
+            .bind(match_id)
+            .bind(user_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+            if joined.is_none() {
+                return Ok(None);
+            }
+
+            let started = sqlx::query(
+                r#"
+                UPDATE matches
+                SET state = 'running', started_at = now()
+                WHERE id = $1 AND state = 'waiting'
+                  AND NOT EXISTS (SELECT 1 FROM match_players WHERE match_id = $1 AND joined_at IS NULL)
+                "#,
+            )
+            .bind(match_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+                > 0;
+            tx.commit().await?;
+
+            let Some(ready) = load(&app.pg, match_id).await? else {
+                return Ok(None);
+            };
+            if started {
+                announce(app, ready.clone()).await;
+            }
+            Ok(Some((ready, started)))
+        }
+
+        pub async fn finish(app: &state::AppState, match_id: Uuid, state: shared::MatchState, winner: Option<Uuid>) {
+            let updated = sqlx::query(
+                r#"
+                UPDATE matches
+                SET state = $2, ended_at = now(), winner_id = $3
+                WHERE id = $1 AND state IN ('waiting', 'running')
+                "#,
+            )
+            .bind(match_id)
+            .bind(shared::matches::as_str(state))
+            .bind(winner)
+            .execute(&app.pg)
+            .await;
+
+            match updated {
+                Ok(done) if done.rows_affected() > 0 => match load(&app.pg, match_id).await {
+                    Ok(Some(ready)) => {
+                        if state == shared::MatchState::Finished {
+                            if let Err(err) = services::ratings::record(app, &ready, winner).await {
+                                tracing::warn!(?err, %match_id, "rating update failed");
+                            }
+                        }
+                        announce(app, ready).await
+                    }
+                    Ok(None) => {}
+                    Err(err) => tracing::warn!(?err, %match_id, "match reload failed"),
+                },
+                Ok(_) => {}
+                Err(err) => tracing::warn!(?err, %match_id, "match finish failed"),
+            }
+        }
+
+        pub async fn run_abandoner(app: Arc<state::AppState>) {
+            let mut every = tokio::time::interval(std::time::Duration::from_secs(ABANDON_SWEEP_SECS));
+            loop {
+                every.tick().await;
+                let abandoned = sqlx::query(
+                    r#"
+                    UPDATE matches
+                    SET state = 'abandoned', ended_at = now()
+                    WHERE (state = 'waiting' AND created_at <= now() - make_interval(secs => $1))
+                       OR (state = 'running' AND started_at <= now() - make_interval(secs => $2))
+                    RETURNING id
+                    "#,
+                )
+                .bind(JOIN_TIMEOUT_SECS as f64)
+                .bind(MAX_RUNNING_SECS as f64)
+                .fetch_all(&app.pg)
+                .await;
+
+                let rows = match abandoned {
+                    Ok(rows) => rows,
+                    Err(err) => {
+                        tracing::warn!(?err, "match abandon sweep failed");
+                        continue;
+                    }
+                };
+                for row in rows {
+                    match load(&app.pg, row.get::<Uuid, _>("id")).await {
+                        Ok(Some(ready)) => announce(&app, ready).await,
+                        Ok(None) => {}
+                        Err(err) => tracing::warn!(?err, "match reload failed"),
+                    }
+                }
+            }
+        }
+
+        // Bridges a player's socket to the match runtime: inputs go out on the input subject
+        // stamped with the authenticated user id, snapshots come back from the state subject.
+        pub async fn connect(mut ws: WebSocket, app: Arc<state::AppState>, ready: shared::MatchReady, user_id: Uuid) {
+            let mut snapshots = match app.nats.subscribe(services::runtime::state_subject(&ready.channel)).await {
+                Ok(sub) => sub,
+                Err(err) => {
+                    tracing::warn!(?err, match_id = %ready.match_id, "match state subscribe failed");
+                    return;
+                }
+            };
+            let input_subject = services::runtime::input_subject(&ready.channel);
+
+            loop {
+                tokio::select! {
+                    incoming = ws.recv() => {
+                        match incoming {
+                            Some(Ok(Message::Binary(bin))) => {
+                                if bin.len() > MAX_FRAME_BYTES {
+                                    continue;
+                                }
+                                let mut frame = Vec::with_capacity(16 + bin.len());
+                                frame.extend_from_slice(user_id.as_bytes());
+                                frame.extend_from_slice(&bin);
+                                if let Err(err) = app.nats.publish(input_subject.clone(), frame.into()).await {
+                                    tracing::warn!(?err, match_id = %ready.match_id, "match input publish failed");
+                                    break;
+                                }
+                            }
+                            Some(Ok(Message::Close(_))) | None => break,
+                            _ => {}
+                        }
+                    }
+                    snapshot = snapshots.next() => {
+                        let Some(message) = snapshot else {
+                            break;
+                        };
+                        if ws.send(Message::Binary(message.payload)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub mod matchmaking {
+        use super::*;
+
+        const TICKET_TTL_SECS: u64 = 120;
+        const MATCHER_TICK_MS: u64 = 1_000;
+        const MATCHER_LOCK_MS: u64 = 900;
+        const MAX_SCAN: isize = 1_000;
+        const MODES_KEY: &str = "mm:modes";
+        const BASE_RATING_WINDOW: f64 = 100.0;
+        const RATING_WINDOW_STEP: f64 = 50.0;
+        const MAX_RATING_WINDOW: f64 = 800.0;
+        const BASE_RADIUS_KM: f64 = 5.0;
+        const MAX_RADIUS_KM: f64 = 500.0;
+        const WIDEN_EVERY_MS: i64 = 5_000;
+        const GEO_GIVE_UP_MS: i64 = 60_000;
+
+        #[derive(Debug, Clone, Serialize, Deserialize)]
+        pub struct Ticket {
+            pub user_id: Uuid,
+            pub mode: String,
+            pub rating: f64,
+            pub enqueued_at_ms: i64,
+            pub near: bool,
+            pub lon: Option<f64>,
+            pub lat: Option<f64>,
+        }
+
+        pub fn rating_window(waited_ms: i64) -> f64 {
+            let steps = (waited_ms.max(0) / WIDEN_EVERY_MS) as f64;
+            (BASE_RATING_WINDOW + RATING_WINDOW_STEP * steps).min(MAX_RATING_WINDOW)
+        }
+
+        // `None` means the player has waited long enough that distance no longer matters.
+        pub fn radius_km(waited_ms: i64) -> Option<f64> {
+            if waited_ms >= GEO_GIVE_UP_MS {
+                return None;
+            }
+            let steps = (waited_ms.max(0) / WIDEN_EVERY_MS) as i32;
+            Some((BASE_RADIUS_KM * 2_f64.powi(steps)).min(MAX_RADIUS_KM))
+        }
+
+        fn distance_km(a: (f64, f64), b: (f64, f64)) -> f64 {
+            let (lon1, lat1) = (a.0.to_radians(), a.1.to_radians());
+            let (lon2, lat2) = (b.0.to_radians(), b.1.to_radians());
+            let h = ((lat2 - lat1) / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+            2.0 * 6371.0 * h.sqrt().asin()
+        }
+
+        fn location(ticket: &Ticket) -> Option<(f64, f64)> {
+            Some((ticket.lon?, ticket.lat?))
+        }
+
+        // Both sides must accept the pairing; the returned cost prefers the closest rating.
+        fn cost(a: &Ticket, b: &Ticket, now_ms: i64) -> Option<f64> {
+            let (waited_a, waited_b) = (now_ms - a.enqueued_at_ms, now_ms - b.enqueued_at_ms);
+            let diff = (a.rating - b.rating).abs();
+            if diff > rating_window(waited_a).min(rating_window(waited_b)) {
+                return None;
+            }
+            for (ticket, waited) in [(a, waited_a), (b, waited_b)] {
+                if !ticket.near {
+                    continue;
+                }
+                if let Some(radius) = radius_km(waited) {
+                    let dist = distance_km(location(a)?, location(b)?);
+                    if dist > radius {
+                        return None;
+                    }
+                }
+            }
+            Some(diff)
+        }
+
+        // Greedy pairing, longest-waiting player first, so nobody is starved by a stream of newcomers.
+        pub fn pair(mut tickets: Vec<Ticket>, now_ms: i64) -> Vec<(Ticket, Ticket)> {
+            tickets.sort_by_key(|t| t.enqueued_at_ms);
+            let mut seen = std::collections::HashSet::new();
+            tickets.retain(|t| seen.insert(t.user_id));
+            let mut taken = vec![false; tickets.len()];
+            let mut pairs = Vec::new();
+            for i in 0..tickets.len() {
+                if taken[i] {
+                    continue;
+                }
+                let best = (i + 1..tickets.len())
+                    .filter(|&j| !taken[j])
+                    .filter_map(|j| cost(&tickets[i], &tickets[j], now_ms).map(|c| (j, c)))
+                    .min_by(|x, y| x.1.total_cmp(&y.1));
+                if let Some((j, _)) = best {
+                    taken[i] = true;
+                    taken[j] = true;
+                    pairs.push((tickets[i].clone(), tickets[j].clone()));
+                }
+            }
+            pairs
+        }
+
+        fn queue_key(mode: &str) -> String {
+            format!("mm:queue:{mode}")
+        }
+
+        fn ticket_key(user_id: Uuid) -> String {
+            format!("mm:ticket:{user_id}")
+        }
+
+        fn lock_key(mode: &str) -> String {
+            format!("mm:lock:{mode}")
+        }
+
+        pub enum MatchmakingError {
+            Invalid(&'static str),
+            InMatch(Uuid),
+            Storage(anyhow::Error),
+        }
+
+        impl MatchmakingError {
+            pub fn status_code(&self) -> StatusCode {
+                match self {
+                    MatchmakingError::Invalid(_) => StatusCode::BAD_REQUEST,
+                    MatchmakingError::InMatch(_) => StatusCode::CONFLICT,
+                    MatchmakingError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                }
+            }
+
+            pub fn message(&self) -> String {
+                match self {
+                    MatchmakingError::Invalid(reason) => reason.to_string(),
+                    MatchmakingError::InMatch(id) => format!("you are already in match {id}"),
+                    MatchmakingError::Storage(_) => "matchmaking is unavailable".to_string(),
+                }
+            }
+        }
+
+        impl From<anyhow::Error> for MatchmakingError {
+            fn from(err: anyhow::Error) -> Self {
+                MatchmakingError::Storage(err)
+            }
+        }
+
+        impl From<sqlx::Error> for MatchmakingError {
+            fn from(err: sqlx::Error) -> Self {
+                MatchmakingError::Storage(err.into())
+            }
+        }
+
+        impl From<services::invite::InviteError> for MatchmakingError {
+            fn from(err: services::invite::InviteError) -> Self {
+                match err {
+                    services::invite::InviteError::Invalid(reason) => MatchmakingError::Invalid(reason),
+                    other => MatchmakingError::Storage(anyhow::anyhow!(other.message())),
+                }
+            }
+        }
+
+        pub async fn rating_of(pg: &PgPool, user_id: Uuid, mode: &str) -> anyhow::Result<f64> {
+            let row = sqlx::query("SELECT rating FROM player_ratings WHERE user_id = $1 AND mode = $2")
+                .bind(user_id)
+                .bind(mode)
+                .fetch_optional(pg)
+                .await?;
+            Ok(row.map(|r| r.get::<f64, _>("rating")).unwrap_or(services::ratings::DEFAULT_RATING))
+        }
+
+        pub async fn last_location(pg: &PgPool, user_id: Uuid) -> anyhow::Result<Option<(f64, f64)>> {
+            let row = sqlx::query(
+                r#"
+                SELECT ST_X(location::geometry) AS lon, ST_Y(location::geometry) AS lat
+                FROM user_locations
+                WHERE user_id = $1
+                "#,
+            )
+            .bind(user_id)
+            .fetch_optional(pg)
+            .await?;
+            Ok(row.map(|r| (r.get::<f64, _>("lon"), r.get::<f64, _>("lat"))))
+        }
+
+        async fn load_ticket(redis: &RedisPool, user_id: Uuid) -> anyhow::Result<Option<Ticket>> {
+            let mut conn = redis.get().await?;
+            let raw: Option<String> = conn.get(ticket_key(user_id)).await?;
+            Ok(raw.and_then(|raw| serde_json::from_str(&raw).ok()))
+        }
+
+        async fn store_ticket(redis: &RedisPool, ticket: &Ticket) -> anyhow::Result<()> {
+            let mut conn = redis.get().await?;
+            let _: () = conn
+                .set_ex(ticket_key(ticket.user_id), serde_json::to_string(ticket)?, TICKET_TTL_SECS)
+                .await?;
+            let _: usize = conn.zadd(queue_key(&ticket.mode), ticket.user_id.to_string(), ticket.rating).await?;
+            let _: usize = conn.sadd(MODES_KEY, &ticket.mode).await?;
+            Ok(())
+        }
+
+        pub(crate) fn status_of(ticket: Option<&Ticket>) -> MatchmakingStatus {
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            match ticket {
+                Some(ticket) => {
+                    let waited = now_ms - ticket.enqueued_at_ms;
+                    MatchmakingStatus {
+                        queued: true,
+                        mode: Some(ticket.mode.clone()),
+                        rating: Some(ticket.rating),
+                        waited_secs: waited / 1000,
+                        rating_window: Some(rating_window(waited)),
+                        radius_km: if ticket.near { radius_km(waited) } else { None },
+                    }
+                }
+                None => MatchmakingStatus {
+                    queued: false,
+                    mode: None,
+                    rating: None,
+                    waited_secs: 0,
+                    rating_window: None,
+                    radius_km: None,
+                },
+            }
+        }
+
+        pub(crate) async fn enqueue(app: &state::AppState, user_id: Uuid, mode: &str, near: bool) -> Result<MatchmakingStatus, MatchmakingError> {
+            let mode = services::invite::normalize_mode(mode)?;
+            if let Some(live) = services::matches::active_for_user(&app.pg, user_id).await? {
+                return Err(MatchmakingError::InMatch(live.match_id));
+            }
+
+            let location = last_location(&app.pg, user_id).await?;
+            if near && location.is_none() {
+                return Err(MatchmakingError::Invalid("share your location before matching with nearby players"));
+            }
+
+            // Re-queueing keeps the original wait time so a player does not lose their widened window.
+            let previous = load_ticket(&app.redis, user_id).await?;
+            if let Some(prev) = previous.as_ref().filter(|prev| prev.mode != mode) {
+                leave(&app.redis, prev.user_id).await?;
+            }
+            let enqueued_at_ms = previous
+                .filter(|prev| prev.mode == mode)
+                .map(|prev| prev.enqueued_at_ms)
+                .unwrap_or_else(|| chrono::Utc::now().timestamp_millis());
+
+            let ticket = Ticket {
+                user_id,
+                rating: rating_of(&app.pg, user_id, &mode).await?,
+                mode,
+                enqueued_at_ms,
+                near,
+                lon: location.map(|l| l.0),
+                lat: location.map(|l| l.1),
+            };
+            store_ticket(&app.redis, &ticket).await?;
+            Ok(status_of(Some(&ticket)))
+        }
+
+        pub async fn leave(redis: &RedisPool, user_id: Uuid) -> anyhow::Result<bool> {
+            let Some(ticket) = load_ticket(redis, user_id).await? else {
+                return Ok(false);
+            };
+            let mut conn = redis.get().await?;
+            let _: usize = conn.zrem(queue_key(&ticket.mode), user_id.to_string()).await?;
+            let _: usize = conn.del(ticket_key(user_id)).await?;
+            Ok(true)
+        }
+
+        // Polling the status doubles as the queue heartbeat; abandoned tickets lapse after the TTL.
+        pub(crate) async fn status(redis: &RedisPool, user_id: Uuid) -> anyhow::Result<MatchmakingStatus> {
+            let ticket = load_ticket(redis, user_id).await?;
+            if ticket.is_some() {
+                let mut conn = redis.get().await?;
+                let _: bool = conn.expire(ticket_key(user_id), TICKET_TTL_SECS as i64).await?;
+            }
+            Ok(status_of(ticket.as_ref()))
+        }
+
+        async fn in_live_match(pg: &PgPool, users: &[Uuid]) -> anyhow::Result<Vec<Uuid>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT DISTINCT p.user_id
+                FROM match_players p
+                JOIN matches m ON m.id = p.match_id
+                WHERE p.user_id = ANY($1) AND m.state IN ('waiting', 'running')
+                "#,
+            )
+            .bind(users)
+            .fetch_all(pg)
+            .await?;
+            Ok(rows.into_iter().map(|r| r.get::<Uuid, _>("user_id")).collect())
+        }
+
+        async fn match_mode(app: &state::AppState, mode: &str) -> anyhow::Result<usize> {
+            let mut conn = app.redis.get().await?;
+            // One matcher per mode per tick across all instances; the lock simply lapses.
+            let locked: Option<String> = redis::cmd("SET")
+                .arg(lock_key(mode))
+                .arg("1")
+                .arg("NX")
+                .arg("PX")
+                .arg(MATCHER_LOCK_MS)
+                .query_async(&mut conn)
+                .await?;
+            if locked.is_none() {
+                return Ok(0);
+            }
+
+            let members: Vec<String> = conn.zrange(queue_key(mode), 0, MAX_SCAN - 1).await?;
+            if members.is_empty() {
+                let _: usize = conn.srem(MODES_KEY, mode).await?;
+                return Ok(0);
+            }
+            let keys = members
+                .iter()
+                .filter_map(|m| Uuid::parse_str(m).ok())
+                .map(ticket_key)
+                .collect::<Vec<_>>();
+            let raw: Vec<Option<String>> = redis::cmd("MGET").arg(&keys).query_async(&mut conn).await?;
+
+            let mut tickets = Vec::new();
+            let mut lapsed = Vec::new();
+            for (member, raw) in members.iter().zip(raw) {
+                match raw.and_then(|raw| serde_json::from_str::<Ticket>(&raw).ok()) {
+                    Some(ticket) if ticket.mode == mode => tickets.push(ticket),
+                    _ => lapsed.push(member.clone()),
+                }
+            }
+
+            // Someone who accepted an invite while queued should not be matched twice.
+            let busy = in_live_match(&app.pg, &tickets.iter().map(|t| t.user_id).collect::<Vec<_>>()).await?;
+            tickets.retain(|t| {
+                let keep = !busy.contains(&t.user_id);
+                if !keep {
+                    lapsed.push(t.user_id.to_string());
+                }
+                keep
+            });
+            if !lapsed.is_empty() {
+                let _: usize = conn.zrem(queue_key(mode), &lapsed).await?;
+            }
+
+            let pairs = pair(tickets, chrono::Utc::now().timestamp_millis());
+            for (a, b) in &pairs {
+                let _: usize = conn.zrem(queue_key(mode), &[a.user_id.to_string(), b.user_id.to_string()]).await?;
+                let _: usize = conn.del(&[ticket_key(a.user_id), ticket_key(b.user_id)]).await?;
+
+                let created = async {
+                    let mut tx = app.pg.begin().await?;
+                    let ready = services::matches::create(&mut *tx, None, mode, &[a.user_id, b.user_id]).await?;
+                    tx.commit().await?;
+                    anyhow::Ok(ready)
+                }
+                .await;
+                match created {
+                    Ok(ready) => services::matches::announce(app, ready).await,
+                    Err(err) => {
+                        tracing::warn!(?err, mode, "matchmaking match creation failed");
+                        store_ticket(&app.redis, a).await?;
+                        store_ticket(&app.redis, b).await?;
+                    }
+                }
+            }
+            Ok(pairs.len())
+        }
+
+        pub async fn run_matcher(app: Arc<state::AppState>) {
+            let mut every = tokio::time::interval(std::time::Duration::from_millis(MATCHER_TICK_MS));
+            loop {
+                every.tick().await;
+                let modes: Vec<String> = match app.redis.get().await {
+                    Ok(mut conn) => conn.smembers(MODES_KEY).await.unwrap_or_default(),
+                    Err(err) => {
+                        tracing::warn!(?err, "matchmaking redis unavailable");
+                        continue;
+                    }
+                };
+                for mode in modes {
+                    match match_mode(&app, &mode).await {
+                        Ok(0) => {}
+                        Ok(count) => tracing::info!(count, %mode, "matchmaking paired players"),
+                        Err(err) => tracing::warn!(?err, %mode, "matchmaking tick failed"),
+                    }
+                }
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn ticket(rating: f64, enqueued_at_ms: i64, near: bool, at: Option<(f64, f64)>) -> Ticket {
+                Ticket {
+                    user_id: Uuid::new_v4(),
+                    mode: "duel".to_string(),
+                    rating,
+                    enqueued_at_ms,
+                    near,
+                    lon: at.map(|p| p.0),
+                    lat: at.map(|p| p.1),
+                }
+            }
+
+            fn ids(pairs: &[(Ticket, Ticket)]) -> Vec<(Uuid, Uuid)> {
+                pairs.iter().map(|(a, b)| (a.user_id, b.user_id)).collect()
+            }
+
+            #[test]
+            fn window_widens_with_wait() {
+                assert_eq!(rating_window(-1), BASE_RATING_WINDOW);
+                assert_eq!(rating_window(0), BASE_RATING_WINDOW);
+                assert_eq!(rating_window(WIDEN_EVERY_MS - 1), BASE_RATING_WINDOW);
+                assert_eq!(rating_window(WIDEN_EVERY_MS), BASE_RATING_WINDOW + RATING_WINDOW_STEP);
+                assert!(rating_window(3 * WIDEN_EVERY_MS) > rating_window(2 * WIDEN_EVERY_MS));
+                assert_eq!(rating_window(i64::MAX), MAX_RATING_WINDOW);
+
+                let (a, b) = (ticket(1500.0, 0, false, None), ticket(1700.0, 0, false, None));
+                assert_eq!(cost(&a, &b, 0), None);
+                assert_eq!(cost(&a, &b, 2 * WIDEN_EVERY_MS), Some(200.0));
+            }
+
+            #[test]
+            fn both_players_must_accept_the_gap() {
+                let veteran = ticket(1500.0, 0, false, None);
+                let newcomer = ticket(1700.0, 10 * WIDEN_EVERY_MS, false, None);
+                assert_eq!(cost(&veteran, &newcomer, 10 * WIDEN_EVERY_MS), None);
+                assert!(pair(vec![veteran, newcomer], 10 * WIDEN_EVERY_MS).is_empty());
+            }
+
+            #[test]
+            fn near_players_pair_locally() {
+                let berlin = (13.40, 52.52);
+                let potsdam = (13.06, 52.40);
+                let madrid = (-3.70, 40.42);
+                let now = 3 * WIDEN_EVERY_MS;
+
+                let me = ticket(1500.0, 0, true, Some(berlin));
+                let far_twin = ticket(1500.0, 0, false, Some(madrid));
+                let close = ticket(1540.0, 0, false, Some(potsdam));
+                assert_eq!(cost(&me, &far_twin, now), None);
+                assert_eq!(cost(&me, &close, now), Some(40.0));
+                let pairs = pair(vec![me.clone(), far_twin.clone(), close.clone()], now);
+                assert_eq!(ids(&pairs), vec![(me.user_id, close.user_id)]);
+
+                // Distance to someone without a location is unknown, so it only counts once the radius gives up.
+                let unknown = ticket(1500.0, 0, false, None);
+                assert_eq!(cost(&me, &unknown, now), None);
+                assert_eq!(cost(&me, &unknown, GEO_GIVE_UP_MS), Some(0.0));
+                assert_eq!(cost(&me, &far_twin, GEO_GIVE_UP_MS), Some(0.0));
+            }
+
+            #[test]
+            fn nobody_is_paired_twice_or_with_themselves() {
+                let now = 4 * WIDEN_EVERY_MS;
+                let mut tickets = (0..9).map(|i| ticket(1500.0 + i as f64, i, false, None)).collect::<Vec<_>>();
+                let mut again = tickets[0].clone();
+                again.enqueued_at_ms = 100;
+                tickets.push(again);
+                tickets.push(tickets[3].clone());
+
+                let pairs = pair(tickets, now);
+                assert_eq!(pairs.len(), 4);
+                let mut seen = std::collections::HashSet::new();
+                for (a, b) in &pairs {
+                    assert_ne!(a.user_id, b.user_id);
+                    assert!(seen.insert(a.user_id) && seen.insert(b.user_id));
+                }
+
+                let solo = ticket(1500.0, 0, false, None);
+                assert!(pair(vec![solo.clone(), solo], now).is_empty());
+            }
+
+            #[test]
+            fn longest_waiting_player_picks_first() {
+                let now = 20 * WIDEN_EVERY_MS;
+                let oldest = ticket(1500.0, 0, false, None);
+                let newer = ticket(1600.0, now - WIDEN_EVERY_MS, false, None);
+                let best = ticket(1510.0, now, false, None);
+                let pairs = pair(vec![newer.clone(), best.clone(), oldest.clone()], now);
+                assert_eq!(ids(&pairs), vec![(oldest.user_id, best.user_id)]);
+            }
+        }
+    }
+
+    pub mod ratings {
+        use super::*;
+
+        pub const DEFAULT_RATING: f64 = 1500.0;
+        const DEFAULT_RD: f64 = 350.0;
+        const DEFAULT_VOLATILITY: f64 = 0.06;
+        const MIN_RD: f64 = 30.0;
+        // Glicko-2 system constant; smaller values keep volatility from swinging on upsets.
+        const TAU: f64 = 0.5;
+        const SCALE: f64 = 173.7178;
+        const CONVERGENCE: f64 = 0.000_001;
+        pub const MAX_LIMIT: usize = 100;
+        pub const DEFAULT_RADIUS_KM: f64 = 50.0;
+        pub const MAX_RADIUS_KM: f64 = 500.0;
+        // Cap on players pulled from PostGIS for a regional board before ranking them.
+        const NEAR_SCAN: i64 = 5_000;
+        // A warm board is rebuilt from Postgres at least this often, whatever Redis missed.
+        const WARM_TTL_SECS: u64 = 60 * 60;
+
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub struct Glicko {
+            pub rating: f64,
+            pub rd: f64,
+            pub volatility: f64,
+        }
+
+        impl Default for Glicko {
+            fn default() -> Self {
+                Self {
+                    rating: DEFAULT_RATING,
+                    rd: DEFAULT_RD,
+                    volatility: DEFAULT_VOLATILITY,
+                }
+            }
+        }
+
+        fn g(phi: f64) -> f64 {
+            1.0 / (1.0 + 3.0 * phi * phi / (std::f64::consts::PI * std::f64::consts::PI)).sqrt()
+        }
+
+        // One Glicko-2 rating period for `player`, who scored `score` (1, 0.5 or 0) against each
+        // opponent. Every match is its own period, so ratings move right after the result.
+        pub fn update(player: Glicko, results: &[(Glicko, f64)]) -> Glicko {
+            if results.is_empty() {
+                return player;
+            }
+            let mu = (player.rating - DEFAULT_RATING) / SCALE;
+            let phi = player.rd / SCALE;
+
+            let mut inv_v = 0.0;
+            let mut gain = 0.0;
+            for (opponent, score) in results {
+                let g = g(opponent.rd / SCALE);
+                let expected = 1.0 / (1.0 + (-g * (mu - (opponent.rating - DEFAULT_RATING) / SCALE)).exp());
+                inv_v += g * g * expected * (1.0 - expected);
+                gain += g * (score - expected);
+            }
+            let v = 1.0 / inv_v;
+            let delta = v * gain;
+
+            // New volatility by the Illinois method, as in Glickman's paper (step 5).
+            let a = (player.volatility * player.volatility).ln();
+            let f = |x: f64| {
+                let ex = x.exp();
+                let d = phi * phi + v + ex;
+                ex * (delta * delta - d) / (2.0 * d * d) - (x - a) / (TAU * TAU)
+            };
+            let mut lo = a;
+            let mut hi = if delta * delta > phi * phi + v {
+                (delta * delta - phi * phi - v).ln()
+            } else {
+                let mut k = 1.0;
+                while f(a - k * TAU) < 0.0 {
+                    k += 1.0;
+                }
+                a - k * TAU
+            };
+            let (mut f_lo, mut f_hi) = (f(lo), f(hi));
+            while (hi - lo).abs() > CONVERGENCE {
+                let mid = lo + (lo - hi) * f_lo / (f_hi - f_lo);
+                let f_mid = f(mid);
+                if f_mid * f_hi <= 0.0 {
+                    lo = hi;
+                    f_lo = f_hi;
+                } else {
+                    f_lo /= 2.0;
+                }
+                hi = mid;
+                f_hi = f_mid;
+            }
+            let volatility = (lo / 2.0).exp();
+
+            let phi_star = (phi * phi + volatility * volatility).sqrt();
+            let phi_new = 1.0 / (1.0 / (phi_star * phi_star) + inv_v).sqrt();
+            let mu_new = mu + phi_new * phi_new * gain;
+            Glicko {
+                rating: mu_new * SCALE + DEFAULT_RATING,
+                rd: (phi_new * SCALE).clamp(MIN_RD, DEFAULT_RD),
+                volatility,
+            }
+        }
+
+        // Score of `slot` against `other`: a single winner beats everyone, everyone else draws.
+        fn score(players: &[Uuid], winner: Option<Uuid>, slot: usize, other: usize) -> f64 {
+            match winner {
+                Some(w) if w == players[slot] => 1.0,
+                Some(w) if w == players[other] => 0.0,
+                _ => 0.5,
+            }
+        }
+
+        fn board_key(mode: &str) -> String {
+            format!("lb:{mode}")
+        }
+
+        fn warm_key(mode: &str) -> String {
+            format!("lb:warm:{mode}")
+        }
+
+        // Applies a finished match to every player's rating in one transaction. `match_results`
+        // is keyed by match and player, so a match can never be counted twice.
+        pub async fn record(app: &state::AppState, ready: &shared::MatchReady, winner: Option<Uuid>) -> anyhow::Result<()> {
+            let players = &ready.players;
+            if players.len() < 2 {
+                return Ok(());
+            }
+            let mut tx = app.pg.begin().await?;
+            sqlx::query(
+                r#"
+                INSERT INTO player_ratings (user_id, mode)
+                SELECT unnest($1::uuid[]), $2
+                ON CONFLICT (user_id, mode) DO NOTHING
+                "#,
+            )
+            .bind(players)
+            .bind(&ready.mode)
+            .execute(&mut *tx)
+            .await?;
+
+            let rows = sqlx::query(
+                r#"
+                SELECT user_id, rating, rd, volatility
+                FROM player_ratings
+                WHERE mode = $1 AND user_id = ANY($2)
+                ORDER BY user_id
+                FOR UPDATE
+                "#,
+            )
+            .bind(&ready.mode)
+            .bind(players)
+            .fetch_all(&mut *tx)
+            .await?;
+            let before = players
+                .iter()
+                .map(|id| {
+                    rows.iter()
+                        .find(|r| r.get::<Uuid, _>("user_id") == *id)
+                        .map(|r| Glicko {
+                            rating: r.get("rating"),
+                            rd: r.get("rd"),
+                            volatility: r.get("volatility"),
+                        })
+                        .unwrap_or_default()
+                })
+                .collect::<Vec<_>>();
+
+            let mut after = Vec::with_capacity(players.len());
+            for slot in 0..players.len() {
+                let results = (0..players.len())
+                    .filter(|&other| other != slot)
+                    .map(|other| (before[other], score(players, winner, slot, other)))
+                    .collect::<Vec<_>>();
+                let next = update(before[slot], &results);
+                let outcome = match winner {
+                    Some(w) if w == players[slot] => "win",
+                    Some(_) => "loss",
+                    None => "draw",
+                };
+                let inserted = sqlx::query(
+                    r#"
+                    INSERT INTO match_results (match_id, user_id, mode, slot, outcome, rating_before, rating_after, rd_after)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                    ON CONFLICT (match_id, user_id) DO NOTHING
+                    "#,
+                )
+                .bind(ready.match_id)
+                .bind(players[slot])
+                .bind(&ready.mode)
+                .bind(slot as i16)
+                .bind(outcome)
+                .bind(before[slot].rating)
+                .bind(next.rating)
+                .bind(next.rd)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+                if inserted == 0 {
+                    return Ok(());
+                }
+
+                sqlx::query(
+                    r#"
+                    UPDATE player_ratings
+                    SET rating = $3, rd = $4, volatility = $5, games = games + 1,
+                        wins = wins + ($6 = 'win')::int,
+                        losses = losses + ($6 = 'loss')::int,
+                        draws = draws + ($6 = 'draw')::int,
+                        updated_at = now()
+                    WHERE user_id = $1 AND mode = $2
+                    "#,
+                )
+                .bind(players[slot])
+                .bind(&ready.mode)
+                .bind(next.rating)
+                .bind(next.rd)
+                .bind(next.volatility)
+                .bind(outcome)
+                .execute(&mut *tx)
+                .await?;
+                after.push(next);
+            }
+            tx.commit().await?;
+
+            // Postgres stays the source of truth. A failed write drops the warm marker so the
+            // next read rebuilds the board; the marker's TTL covers the case where that fails too.
+            let mut conn = app.redis.get().await?;
+            for (user_id, rating) in players.iter().zip(&after) {
+                let added: redis::RedisResult<usize> = conn.zadd(board_key(&ready.mode), user_id.to_string(), rating.rating).await;
+                if let Err(err) = added {
+                    let _: redis::RedisResult<usize> = conn.del(warm_key(&ready.mode)).await;
+                    return Err(err.into());
+                }
+            }
+            Ok(())
+        }
+
+        // Rebuilds a mode's sorted set from Postgres when Redis has lost it. The marker key is
+        // separate from the set because `record` may have recreated the set with a few members.
+        async fn ensure_board(app: &state::AppState, mode: &str) -> anyhow::Result<()> {
+            let mut conn = app.redis.get().await?;
+            if conn.exists(warm_key(mode)).await? {
+                return Ok(());
+            }
+            let rows = sqlx::query("SELECT user_id, rating FROM player_ratings WHERE mode = $1 AND games > 0")
+                .bind(mode)
+                .fetch_all(&app.pg)
+                .await?;
+            for chunk in rows.chunks(500) {
+                let items = chunk
+                    .iter()
+                    .map(|r| (r.get::<f64, _>("rating"), r.get::<Uuid, _>("user_id").to_string()))
+                    .collect::<Vec<_>>();
+                let _: usize = conn.zadd_multiple(board_key(mode), &items).await?;
+            }
+            let _: () = conn.set_ex(warm_key(mode), "1", WARM_TTL_SECS).await?;
+            Ok(())
+        }
+
+        struct Standing {
+            user_id: Uuid,
+            rating: f64,
+            distance_km: Option<f64>,
+        }
+
+        // Fills in names and record for ranked players; the order always comes from Redis.
+        async fn entries(pg: &PgPool, mode: &str, offset: usize, standings: Vec<Standing>) -> anyhow::Result<Vec<LeaderboardEntry>> {
+            let ids = standings.iter().map(|s| s.user_id).collect::<Vec<_>>();
+            let rows = sqlx::query(
+                r#"
+                SELECT u.id, u.username, r.rd, r.games, r.wins, r.losses, r.draws
+                FROM users u
+                LEFT JOIN player_ratings r ON r.user_id = u.id AND r.mode = $2
+                WHERE u.id = ANY($1)
+                "#,
+            )
+            .bind(&ids)
+            .bind(mode)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(standings
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, s)| {
+                    let row = rows.iter().find(|r| r.get::<Uuid, _>("id") == s.user_id)?;
+                    Some(LeaderboardEntry {
+                        rank: offset + i + 1,
+                        user_id: s.user_id,
+                        username: row.get("username"),
+                        rating: s.rating,
+                        rd: row.get::<Option<f64>, _>("rd").unwrap_or(DEFAULT_RD),
+                        games: row.get::<Option<i32>, _>("games").unwrap_or_default(),
+                        wins: row.get::<Option<i32>, _>("wins").unwrap_or_default(),
+                        losses: row.get::<Option<i32>, _>("losses").unwrap_or_default(),
+                        draws: row.get::<Option<i32>, _>("draws").unwrap_or_default(),
+                        distance_km: s.distance_km,
+                    })
+                })
+                .collect())
+        }
+
+        pub(crate) async fn global(app: &state::AppState, mode: &str, offset: usize, limit: usize) -> anyhow::Result<Vec<LeaderboardEntry>> {
+            ensure_board(app, mode).await?;
+            let mut conn = app.redis.get().await?;
+            let top: Vec<(String, f64)> = conn
+                .zrevrange_withscores(board_key(mode), offset as isize, (offset + limit) as isize - 1)
+                .await?;
+            let standings = top
+                .into_iter()
+                .filter_map(|(member, rating)| {
+                    Some(Standing {
+                        user_id: Uuid::parse_str(&member).ok()?,
+                        rating,
+                        distance_km: None,
+                    })
+                })
+                .collect();
+            entries(&app.pg, mode, offset, standings).await
+        }
+
+        // "Best near me": PostGIS picks the highest-rated players in range, Redis supplies the
+        // ratings they are ranked by.
+        pub(crate) async fn near(
+            app: &state::AppState,
+            mode: &str,
+            (lon, lat): (f64, f64),
+            radius_km: f64,
+            limit: usize,
+        ) -> anyhow::Result<Vec<LeaderboardEntry>> {
+            ensure_board(app, mode).await?;
+            let rows = sqlx::query(
+                r#"
+                SELECT l.user_id, ST_Distance(l.location, ST_Point($1, $2)::geography) / 1000.0 AS distance_km
+                FROM user_locations l
+                JOIN player_ratings r ON r.user_id = l.user_id AND r.mode = $4 AND r.games > 0
+                WHERE ST_DWithin(l.location, ST_Point($1, $2)::geography, $3)
+                ORDER BY r.rating DESC
+                LIMIT $5
+                "#,
+            )
+            .bind(lon)
+            .bind(lat)
+            .bind(radius_km * 1000.0)
+            .bind(mode)
+            .bind(NEAR_SCAN)
+            .fetch_all(&app.pg)
+            .await?;
+            if rows.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            let members = rows.iter().map(|r| r.get::<Uuid, _>("user_id").to_string()).collect::<Vec<_>>();
+            let mut conn = app.redis.get().await?;
+            let scores: Vec<Option<f64>> = redis::cmd("ZMSCORE")
+                .arg(board_key(mode))
+                .arg(&members)
+                .query_async(&mut conn)
+                .await?;
+
+            let mut standings = rows
+                .iter()
+                .zip(scores)
+                .filter_map(|(row, rating)| {
+                    Some(Standing {
+                        user_id: row.get("user_id"),
+                        rating: rating?,
+                        distance_km: Some(row.get("distance_km")),
+                    })
+                })
+                .collect::<Vec<_>>();
+            standings.sort_by(|a, b| b.rating.total_cmp(&a.rating));
+            standings.truncate(limit);
+            entries(&app.pg, mode, 0, standings).await
+        }
+
+        pub(crate) async fn standing(app: &state::AppState, mode: &str, user_id: Uuid) -> anyhow::Result<Option<LeaderboardEntry>> {
+            ensure_board(app, mode).await?;
+            let mut conn = app.redis.get().await?;
+            let member = user_id.to_string();
+            let rank: Option<usize> = conn.zrevrank(board_key(mode), &member).await?;
+            let rating: Option<f64> = conn.zscore(board_key(mode), &member).await?;
+            let (Some(rank), Some(rating)) = (rank, rating) else {
+                return Ok(None);
+            };
+            let standing = Standing {
+                user_id,
+                rating,
+                distance_km: None,
+            };
+            Ok(entries(&app.pg, mode, rank, vec![standing]).await?.pop())
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn player(rating: f64, rd: f64) -> Glicko {
+                Glicko {
+                    rating,
+                    rd,
+                    volatility: DEFAULT_VOLATILITY,
+                }
+            }
+
+            // The worked example from Glickman's "Example of the Glicko-2 system".
+            #[test]
+            fn matches_glickmans_example() {
+                let results = [(player(1400.0, 30.0), 1.0), (player(1550.0, 100.0), 0.0), (player(1700.0, 300.0), 0.0)];
+                let after = update(player(1500.0, 200.0), &results);
+                assert!((after.rating - 1464.06).abs() < 0.01, "{after:?}");
+                assert!((after.rd - 151.52).abs() < 0.01, "{after:?}");
+                assert!((after.volatility - 0.05999).abs() < 0.00001, "{after:?}");
+            }
+
+            #[test]
+            fn draws_pull_ratings_together() {
+                let even = update(player(1500.0, 200.0), &[(player(1500.0, 200.0), 0.5)]);
+                assert!((even.rating - 1500.0).abs() < 1e-9);
+                assert!(even.rd < 200.0);
+
+                let underdog = update(player(1400.0, 80.0), &[(player(1600.0, 80.0), 0.5)]);
+                let favourite = update(player(1600.0, 80.0), &[(player(1400.0, 80.0), 0.5)]);
+                assert!(underdog.rating > 1400.0);
+                assert!(favourite.rating < 1600.0);
+                assert!(((underdog.rating - 1400.0) - (1600.0 - favourite.rating)).abs() < 1e-6);
+            }
+
+            #[test]
+            fn no_games_leave_the_rating_alone() {
+                let before = player(1720.0, 90.0);
+                assert_eq!(update(before, &[]), before);
+            }
+
+            #[test]
+            fn deviation_stays_within_bounds() {
+                let settled = player(1500.0, MIN_RD);
+                let results = vec![(player(1500.0, MIN_RD), 1.0); 50];
+                assert_eq!(update(settled, &results).rd, MIN_RD);
+                assert!(update(Glicko::default(), &[(Glicko::default(), 1.0)]).rd <= DEFAULT_RD);
+            }
+        }
+    }
+
+    pub mod runtime {
+        use super::*;
+        use std::collections::{BTreeMap, VecDeque};
+
+        const IDLE_FORFEIT_SECS: u32 = 30;
+        const MAX_INPUTS_PER_TICK: u8 = 4;
+        const MAX_FRAMES_PER_PACKET: usize = 64;
+        const MAX_FRAME_LEAD: u32 = 30;
+        const CHECKSUM_HISTORY: usize = 256;
+
+        pub use sim::Outcome;
+
+        // Rules for one game mode. The runtime owns timing, input bookkeeping and transport;
+        // a mode only sees validated inputs, one per player slot, once per tick.
+        pub trait GameMode: Send {
+            fn tick_hz(&self) -> u32;
+            fn validate(&self, input: &shared::PlayerInput) -> bool;
+            fn step(&mut self, inputs: &[shared::PlayerInput]);
+            fn forfeit(&mut self, slot: usize);
+            fn outcome(&self) -> Option<Outcome>;
+            fn players(&self) -> Vec<shared::PlayerSnapshot>;
+            fn entities(&self) -> Vec<shared::EntitySnapshot>;
+        }
+
+        pub fn supports(mode: &str) -> bool {
+            matches!(mode, "duel")
+        }
+
+        // Modes whose clients simulate locally with rollback; the runtime relays their
+        // frame inputs and keeps a confirmed copy of the game instead of stepping on a timer.
+        pub fn uses_rollback(mode: &str) -> bool {
+            matches!(mode, "duel")
+        }
+
+        pub fn build(mode: &str, players: usize) -> Option<Box<dyn GameMode>> {
+            match mode {
+                "duel" if players == 2 => Some(Box::new(sim::duel::State::new())),
+                _ => None,
+            }
+        }
+
+        // Duel rules live in the shared `sim` crate so the wasm client predicts with the same code.
+        impl GameMode for sim::duel::State {
+            fn tick_hz(&self) -> u32 {
+                sim::duel::TICK_HZ
+            }
+
+            fn validate(&self, input: &shared::PlayerInput) -> bool {
+                sim::duel::valid(input)
+            }
+
+            fn step(&mut self, inputs: &[shared::PlayerInput]) {
+                sim::duel::State::step(self, inputs);
+            }
+
+            fn forfeit(&mut self, slot: usize) {
+                sim::duel::State::forfeit(self, slot);
+            }
+
+            fn outcome(&self) -> Option<Outcome> {
+                sim::duel::State::outcome(self)
+            }
+
+            fn players(&self) -> Vec<shared::PlayerSnapshot> {
+                sim::duel::State::players(self)
+            }
+
+            fn entities(&self) -> Vec<shared::EntitySnapshot> {
+                sim::duel::State::entities(self)
+            }
+        }
+
+        pub fn input_subject(channel: &str) -> String {
+            format!("{channel}.input")
+        }
+
+        pub fn state_subject(channel: &str) -> String {
+            format!("{channel}.state")
+        }
+
+        // Frames on the input subject carry the sender's id ahead of the packet, stamped by the
+        // socket that authenticated them, so the runtime never trusts an id from the payload.
+        fn decode_input(players: &[Uuid], payload: &[u8]) -> Option<(usize, shared::GamePacket)> {
+            if payload.len() < 16 {
+                return None;
+            }
+            let sender = Uuid::from_slice(&payload[..16]).ok()?;
+            let slot = players.iter().position(|p| *p == sender)?;
+            let packet = rmp_serde::from_slice::<shared::GamePacket>(&payload[16..]).ok()?;
+            Some((slot, packet))
+        }
+
+        async fn publish(app: &state::AppState, channel: &str, packet: &shared::GamePacket) {
+            match rmp_serde::to_vec(packet) {
+                Ok(payload) => {
+                    if let Err(err) = app.nats.publish(state_subject(channel), payload.into()).await {
+                        tracing::warn!(?err, %channel, "match state publish failed");
+                    }
+                }
+                Err(err) => tracing::warn!(?err, %channel, "match packet encode failed"),
+            }
+        }
+
+        // One task per running match, started by the instance whose join flipped it to running.
+        pub async fn run(app: Arc<state::AppState>, ready: shared::MatchReady) {
+            if uses_rollback(&ready.mode) {
+                return run_rollback(app, ready).await;
+            }
+            let match_id = ready.match_id;
+            let Some(mut game) = build(&ready.mode, ready.players.len()) else {
+                tracing::warn!(%match_id, mode = %ready.mode, "no rules for match mode");
+                services::matches::finish(&app, match_id, shared::MatchState::Abandoned, None).await;
+                return;
+            };
+            let mut inputs = match app.nats.subscribe(input_subject(&ready.channel)).await {
+                Ok(sub) => sub,
+                Err(err) => {
+                    tracing::warn!(?err, %match_id, "match input subscribe failed");
+                    services::matches::finish(&app, match_id, shared::MatchState::Abandoned, None).await;
+                    return;
+                }
+            };
+
+            let tick_hz = game.tick_hz().max(1);
+            let mut ticker = tokio::time::interval(std::time::Duration::from_micros(1_000_000 / tick_hz as u64));
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            let slots = ready.players.len();
+            let mut held = vec![shared::PlayerInput::default(); slots];
+            let mut acks = vec![0_u32; slots];
+            let mut heard_at = vec![0_u32; slots];
+            let mut this_tick = vec![0_u8; slots];
+            let idle_limit = tick_hz * IDLE_FORFEIT_SECS;
+            let mut tick = 0_u32;
+            let mut recorder = services::replay::Recorder::new(&ready, tick_hz);
+
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        tick += 1;
+                        recorder.frame(&held);
+                        game.step(&held);
+                        this_tick.fill(0);
+                        for (slot, heard) in heard_at.iter().enumerate() {
+                            if tick - heard > idle_limit {
+                                game.forfeit(slot);
+                                recorder.forfeit(tick, slot);
+                            }
+                        }
+
+                        let outcome = game.outcome();
+                        let winner = match outcome {
+                            Some(Outcome::Winner(slot)) => ready.players.get(slot).copied(),
+                            _ => None,
+                        };
+                        if services::replay::keyframe_due(tick) {
+                            recorder.keyframe(tick, game.players(), game.entities());
+                        }
+                        let snapshot = shared::GamePacket::Snapshot(shared::GameSnapshot {
+                            match_id,
+                            tick,
+                            tick_hz,
+                            acks: acks.clone(),
+                            players: game.players(),
+                            entities: game.entities(),
+                            finished: outcome.is_some(),
+                            winner,
+                        });
+                        publish(&app, &ready.channel, &snapshot).await;
+
+                        if outcome.is_some() {
+                            recorder.keyframe(tick, game.players(), game.entities());
+                            services::matches::finish(&app, match_id, shared::MatchState::Finished, winner).await;
+                            services::replay::save(&app, recorder, winner).await;
+                            return;
+                        }
+                    }
+                    message = inputs.next() => {
+                        let Some(message) = message else {
+                            break;
+                        };
+                        let Some((slot, shared::GamePacket::Input(input))) = decode_input(&ready.players, &message.payload) else {
+                            continue;
+                        };
+                        // Stale, replayed, flooding or out-of-range inputs are dropped without a reply.
+                        if input.seq <= acks[slot] || this_tick[slot] >= MAX_INPUTS_PER_TICK || !game.validate(&input) {
+                            continue;
+                        }
+                        this_tick[slot] += 1;
+                        held[slot] = input;
+                        acks[slot] = input.seq;
+                        heard_at[slot] = tick;
+                    }
+                }
+            }
+
+            tracing::warn!(%match_id, "match input stream closed");
+            services::matches::finish(&app, match_id, shared::MatchState::Abandoned, None).await;
+            services::replay::save(&app, recorder, None).await;
+        }
+
+        // Rollback matches: clients exchange frame-numbered inputs through this task. It relays
+        // only inputs it accepted, in order, so both clients and the server see the same stream,
+        // and it steps its own copy whenever both inputs for the next frame are in. That copy
+        // decides the result and is the reference for client checksums.
+        async fn run_rollback(app: Arc<state::AppState>, ready: shared::MatchReady) {
+            use sim::rollback::INPUT_DELAY;
+
+            let match_id = ready.match_id;
+            if ready.players.len() != 2 {
+                services::matches::finish(&app, match_id, shared::MatchState::Abandoned, None).await;
+                return;
+            }
+            let mut inputs = match app.nats.subscribe(input_subject(&ready.channel)).await {
+                Ok(sub) => sub,
+                Err(err) => {
+                    tracing::warn!(?err, %match_id, "match input subscribe failed");
+                    services::matches::finish(&app, match_id, shared::MatchState::Abandoned, None).await;
+                    return;
+                }
+            };
+
+            let tick_hz = sim::duel::TICK_HZ;
+            let mut ticker = tokio::time::interval(std::time::Duration::from_micros(1_000_000 / tick_hz as u64));
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            let mut game = sim::duel::State::new();
+            let mut queued = [BTreeMap::<u32, shared::PlayerInput>::new(), BTreeMap::new()];
+            let mut next_frame = [INPUT_DELAY; 2];
+            let mut claimed = [None::<shared::FrameChecksum>; 2];
+            let mut checksums = VecDeque::<shared::FrameChecksum>::new();
+            let mut heard_at = [0_u32; 2];
+            let idle_limit = tick_hz * IDLE_FORFEIT_SECS;
+            let mut tick = 0_u32;
+            let mut recorder = services::replay::Recorder::new(&ready, tick_hz);
+
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        tick += 1;
+                        for (slot, heard) in heard_at.iter().enumerate() {
+                            if tick - heard > idle_limit {
+                                game.forfeit(slot);
+                                recorder.forfeit(game.tick, slot);
+                            }
+                        }
+
+                        let outcome = game.outcome();
+                        let winner = match outcome {
+                            Some(Outcome::Winner(slot)) => ready.players.get(slot).copied(),
+                            _ => None,
+                        };
+                        let snapshot = shared::GamePacket::Snapshot(shared::GameSnapshot {
+                            match_id,
+                            tick: game.tick,
+                            tick_hz,
+                            acks: next_frame.iter().map(|f| f.saturating_sub(1)).collect(),
+                            players: game.players(),
+                            entities: game.entities(),
+                            finished: outcome.is_some(),
+                            winner,
+                        });
+                        publish(&app, &ready.channel, &snapshot).await;
+
+                        if outcome.is_some() {
+                            recorder.keyframe(game.tick, game.players(), game.entities());
+                            services::matches::finish(&app, match_id, shared::MatchState::Finished, winner).await;
+                            services::replay::save(&app, recorder, winner).await;
+                            return;
+                        }
+                    }
+                    message = inputs.next() => {
+                        let Some(message) = message else {
+                            break;
+                        };
+                        let Some((slot, shared::GamePacket::Frames(mut frames))) = decode_input(&ready.players, &message.payload) else {
+                            continue;
+                        };
+                        if frames.inputs.len() > MAX_FRAMES_PER_PACKET || !frames.inputs.iter().all(sim::duel::valid) {
+                            continue;
+                        }
+                        heard_at[slot] = tick;
+
+                        // Frames must arrive in order and may not run ahead of the match clock.
+                        let lead_limit = tick + INPUT_DELAY + MAX_FRAME_LEAD;
+                        for (frame, input) in (frames.start..).zip(&frames.inputs) {
+                            if frame < next_frame[slot] {
+                                continue;
+                            }
+                            if frame > next_frame[slot] || frame > lead_limit {
+                                break;
+                            }
+                            queued[slot].insert(frame, *input);
+                            next_frame[slot] += 1;
+                        }
+                        let accepted = next_frame[slot].saturating_sub(frames.start) as usize;
+                        frames.inputs.truncate(accepted);
+                        frames.slot = slot as u8;
+                        if let Some(check) = frames.checksum {
+                            claimed[slot] = Some(check);
+                        }
+                        publish(&app, &ready.channel, &shared::GamePacket::Frames(frames)).await;
+
+                        while game.outcome().is_none() {
+                            let frame = game.tick;
+                            let step = if frame < INPUT_DELAY {
+                                [shared::PlayerInput::default(); 2]
+                            } else {
+                                match (queued[0].get(&frame), queued[1].get(&frame)) {
+                                    (Some(a), Some(b)) => [*a, *b],
+                                    _ => break,
+                                }
+                            };
+                            queued[0].remove(&frame);
+                            queued[1].remove(&frame);
+                            recorder.frame(&step);
+                            game.step(&step);
+                            if services::replay::keyframe_due(game.tick) {
+                                recorder.keyframe(game.tick, game.players(), game.entities());
+                            }
+                            checksums.push_back(shared::FrameChecksum {
+                                frame: game.tick,
+                                value: game.checksum(),
+                            });
+                            if checksums.len() > CHECKSUM_HISTORY {
+                                checksums.pop_front();
+                            }
+                        }
+
+                        for (slot, claim) in claimed.iter_mut().enumerate() {
+                            let Some(check) = *claim else {
+                                continue;
+                            };
+                            if let Some(ours) = checksums.iter().find(|c| c.frame == check.frame) {
+                                if ours.value != check.value {
+                                    tracing::warn!(%match_id, slot, frame = check.frame, "rollback desync");
+                                    publish(&app, &ready.channel, &shared::GamePacket::Desync(*ours)).await;
+                                }
+                                *claim = None;
+                            } else if checksums.front().is_some_and(|c| check.frame < c.frame) {
+                                *claim = None;
+                            }
+                        }
+                    }
+                }
+            }
+
+            tracing::warn!(%match_id, "match input stream closed");
+            services::matches::finish(&app, match_id, shared::MatchState::Abandoned, None).await;
+            services::replay::save(&app, recorder, None).await;
+        }
+    }
+
+    pub mod replay {
+        use super::*;
+        use aws_sdk_s3::primitives::ByteStream;
+
+        pub const LIST_LIMIT: i64 = 20;
+
+        const REPLAY_COLUMNS: &str = r#"
+            r.match_id, m.mode, m.state, m.winner_id, r.object_key, r.tick_hz, r.frames, r.size_bytes, r.created_at,
+            array_agg(p.user_id ORDER BY p.slot) AS players
+        "#;
+
+        // Collects what the runtime fed into the rules: one input per player per frame,
+        // forfeits, and periodic keyframes that let playback prove it still agrees.
+        pub struct Recorder {
+            replay: shared::Replay,
+        }
+
+        impl Recorder {
+            pub fn new(ready: &shared::MatchReady, tick_hz: u32) -> Self {
+                Self {
+                    replay: shared::Replay {
+                        match_id: ready.match_id,
+                        mode: ready.mode.clone(),
+                        players: ready.players.clone(),
+                        tick_hz,
+                        frames: 0,
+                        runs: Vec::new(),
+                        keyframes: Vec::new(),
+                        forfeits: Vec::new(),
+                        winner: None,
+                        recorded_at: chrono::Utc::now(),
+                    },
+                }
+            }
+
+            pub fn frame(&mut self, inputs: &[shared::PlayerInput]) {
+                shared::replay::push_frame(&mut self.replay, inputs);
+            }
+
+            pub fn forfeit(&mut self, tick: u32, slot: usize) {
+                self.replay.forfeits.push(shared::Forfeit { tick, slot: slot as u8 });
+            }
+
+            pub fn keyframe(&mut self, tick: u32, players: Vec<shared::PlayerSnapshot>, entities: Vec<shared::EntitySnapshot>) {
+                if self.replay.keyframes.last().is_some_and(|k| k.tick == tick) {
+                    return;
+                }
+                self.replay.keyframes.push(shared::GameSnapshot {
+                    match_id: self.replay.match_id,
+                    tick,
+                    tick_hz: self.replay.tick_hz,
+                    acks: Vec::new(),
+                    players,
+                    entities,
+                    finished: false,
+                    winner: None,
+                });
+            }
+        }
+
+        pub fn keyframe_due(tick: u32) -> bool {
+            tick > 0 && tick.is_multiple_of(shared::replay::KEYFRAME_EVERY)
+        }
+
+        fn object_key(match_id: Uuid) -> String {
+            format!("replays/{match_id}.rpl")
+        }
+
+        pub async fn save(app: &state::AppState, recorder: Recorder, winner: Option<Uuid>) {
+            let mut replay = recorder.replay;
+            replay.winner = winner;
+            if let Some(last) = replay.keyframes.last_mut() {
+                last.finished = true;
+                last.winner = winner;
+            }
+            let match_id = replay.match_id;
+            let Some(bytes) = shared::replay::encode(&replay) else {
+                tracing::warn!(%match_id, "replay encode failed");
+                return;
+            };
+            let key = object_key(match_id);
+            let size_bytes = bytes.len() as i32;
+
+            let uploaded = app
+                .r2
+                .put_object()
+                .bucket(&app.r2_bucket)
+                .key(&key)
+                .content_type("application/octet-stream")
+                .body(ByteStream::from(bytes))
+                .send()
+                .await;
+            if let Err(err) = uploaded {
+                tracing::warn!(?err, %match_id, "replay upload failed");
+                return;
+            }
+
+            let stored = sqlx::query(
+                r#"
+                INSERT INTO match_replays(match_id, object_key, format_version, tick_hz, frames, size_bytes)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (match_id) DO UPDATE
+                SET object_key = EXCLUDED.object_key,
+                    format_version = EXCLUDED.format_version,
+                    tick_hz = EXCLUDED.tick_hz,
+                    frames = EXCLUDED.frames,
+                    size_bytes = EXCLUDED.size_bytes,
+                    created_at = now()
+                "#,
+            )
+            .bind(match_id)
+            .bind(&key)
+            .bind(shared::replay::REPLAY_VERSION as i16)
+            .bind(replay.tick_hz as i32)
+            .bind(replay.frames as i32)
+            .bind(size_bytes)
+            .execute(&app.pg)
+            .await;
+            if let Err(err) = stored {
+                tracing::warn!(?err, %match_id, "replay metadata insert failed");
+            }
+        }
+
+        async fn info_from_row(app: &state::AppState, row: &sqlx::postgres::PgRow) -> anyhow::Result<MatchReplayInfo> {
+            let object_key = row.get::<String, _>("object_key");
+            Ok(MatchReplayInfo {
+                match_id: row.get("match_id"),
+                mode: row.get("mode"),
+                state: shared::matches::parse(&row.get::<String, _>("state")).unwrap_or_default(),
+                players: row.get("players"),
+                winner: row.get("winner_id"),
+                tick_hz: row.get("tick_hz"),
+                frames: row.get("frames"),
+                size_bytes: row.get("size_bytes"),
+                created_at: row.get("created_at"),
+                url: services::attachment::download_url(app, &object_key).await?,
+            })
+        }
+
+        pub(crate) async fn info(app: &state::AppState, match_id: Uuid) -> anyhow::Result<Option<MatchReplayInfo>> {
+            let row = sqlx::query(&format!(
+                r#"
+                SELECT {REPLAY_COLUMNS}
+                FROM match_replays r
+                JOIN matches m ON m.id = r.match_id
+                JOIN match_players p ON p.match_id = r.match_id
+                WHERE r.match_id = $1
+                GROUP BY r.match_id, m.id
+                "#
+            ))
+            .bind(match_id)
+            .fetch_optional(&app.pg)
+            .await?;
+            match row {
+                Some(row) => Ok(Some(info_from_row(app, &row).await?)),
+                None => Ok(None),
+            }
+        }
+
+        pub(crate) async fn recent_for_user(app: &state::AppState, user_id: Uuid, limit: i64) -> anyhow::Result<Vec<MatchReplayInfo>> {
+            let rows = sqlx::query(&format!(
+                r#"
+                SELECT {REPLAY_COLUMNS}
+                FROM match_replays r
+                JOIN matches m ON m.id = r.match_id
+                JOIN match_players p ON p.match_id = r.match_id
+                WHERE EXISTS (SELECT 1 FROM match_players me WHERE me.match_id = r.match_id AND me.user_id = $1)
+                GROUP BY r.match_id, m.id
+                ORDER BY r.created_at DESC
+                LIMIT $2
+                "#
+            ))
+            .bind(user_id)
+            .bind(limit)
+            .fetch_all(&app.pg)
+            .await?;
+            let mut out = Vec::with_capacity(rows.len());
+            for row in &rows {
+                out.push(info_from_row(app, row).await?);
+            }
+            Ok(out)
+        }
+    }
+
+    pub mod spectate {
+        use super::*;
+        use once_cell::sync::Lazy;
+        use std::collections::{HashMap, VecDeque};
+        use std::sync::Mutex;
+
+        // Viewers see a match this far behind the players, too late to be useful to either side.
+        pub const DELAY_SECS: u64 = 3;
+        // Every other tick is smooth enough to watch and halves the fanout.
+        const EVERY_TICKS: u32 = 2;
+        const VIEWER_BUFFER: usize = 64;
+        pub const LIVE_LIMIT: i64 = 50;
+
+        // One relay per watched match on this instance, holding the only state subscription its
+        // viewers need. A viewer that falls behind lags on the broadcast channel and skips frames;
+        // nothing it does reaches the runtime or the players' sockets.
+        static RELAYS: Lazy<Mutex<HashMap<Uuid, broadcast::Sender<Vec<u8>>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+        fn relays() -> std::sync::MutexGuard<'static, HashMap<Uuid, broadcast::Sender<Vec<u8>>>> {
+            RELAYS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+        }
+
+        pub fn watch(app: &Arc<state::AppState>, ready: &shared::MatchReady) -> broadcast::Receiver<Vec<u8>> {
+            let mut relays = relays();
+            if let Some(tx) = relays.get(&ready.match_id) {
+                return tx.subscribe();
+            }
+            let (tx, rx) = broadcast::channel(VIEWER_BUFFER);
+            relays.insert(ready.match_id, tx.clone());
+            tokio::spawn(relay(app.clone(), ready.clone(), tx));
+            rx
+        }
+
+        // Stops the relay once nobody is watching. Checked under the same lock `watch` subscribes
+        // under, so a viewer joining at that moment either keeps the relay or starts a new one.
+        fn release(match_id: Uuid, ended: bool) -> bool {
+            let mut relays = relays();
+            let idle = relays.get(&match_id).is_none_or(|tx| tx.receiver_count() == 0);
+            if ended || idle {
+                relays.remove(&match_id);
+                return true;
+            }
+            false
+        }
+
+        // Passes on snapshots only, held back by `DELAY_SECS`. Frame inputs and desync notices on
+        // the same subject are for players and never leave here.
+        async fn relay(app: Arc<state::AppState>, ready: shared::MatchReady, tx: broadcast::Sender<Vec<u8>>) {
+            let match_id = ready.match_id;
+            let mut updates = match app.nats.subscribe(services::runtime::state_subject(&ready.channel)).await {
+                Ok(sub) => sub,
+                Err(err) => {
+                    tracing::warn!(?err, %match_id, "spectator subscribe failed");
+                    release(match_id, true);
+                    return;
+                }
+            };
+            let delay = std::time::Duration::from_secs(DELAY_SECS);
+            let mut pending = VecDeque::<(tokio::time::Instant, Vec<u8>)>::new();
+            let mut ended = false;
+
+            loop {
+                let due = pending.front().map(|(at, _)| *at + delay);
+                tokio::select! {
+                    message = updates.next(), if !ended => {
+                        let Some(message) = message else {
+                            ended = true;
+                            continue;
+                        };
+                        let Ok(shared::GamePacket::Snapshot(snapshot)) = rmp_serde::from_slice(&message.payload) else {
+                            continue;
+                        };
+                        if snapshot.finished {
+                            ended = true;
+                        } else if !snapshot.tick.is_multiple_of(EVERY_TICKS) {
+                            continue;
+                        }
+                        pending.push_back((tokio::time::Instant::now(), message.payload.to_vec()));
+                    }
+                    _ = tokio::time::sleep_until(due.unwrap_or_else(tokio::time::Instant::now)), if due.is_some() => {
+                        if let Some((_, payload)) = pending.pop_front() {
+                            let _ = tx.send(payload);
+                        }
+                        if tx.receiver_count() == 0 && release(match_id, false) {
+                            return;
+                        }
+                    }
+                }
+                if ended && pending.is_empty() {
+                    release(match_id, true);
+                    return;
+                }
+            }
+        }
+
+        // Viewer sockets have no input path: whatever a viewer sends is read and dropped.
+        pub async fn connect(mut ws: WebSocket, mut frames: broadcast::Receiver<Vec<u8>>) {
+            loop {
+                tokio::select! {
+                    incoming = ws.recv() => {
+                        match incoming {
+                            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                            _ => {}
+                        }
+                    }
+                    frame = frames.recv() => {
+                        match frame {
+                            Ok(payload) => {
+                                if ws.send(Message::Binary(payload.into())).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(broadcast::error::RecvError::Lagged(_)) => {}
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        }
+                    }
+                }
+            }
+        }
+
+        pub(crate) async fn live(pg: &PgPool, limit: i64) -> anyhow::Result<Vec<LiveMatch>> {
+            let rows = sqlx::query(
+                r#"
+                SELECT
+                    m.id, m.mode, m.started_at,
+                    array_agg(p.user_id ORDER BY p.slot) AS players,
+                    array_agg(u.username ORDER BY p.slot) AS usernames,
+                    array_agg(ST_X(l.location::geometry) ORDER BY p.slot) AS lons,
+                    array_agg(ST_Y(l.location::geometry) ORDER BY p.slot) AS lats
+                FROM matches m
+                JOIN match_players p ON p.match_id = m.id
+                JOIN users u ON u.id = p.user_id
+                LEFT JOIN user_locations l ON l.user_id = p.user_id
+                WHERE m.state = 'running'
+                GROUP BY m.id
+                ORDER BY m.started_at DESC
+                LIMIT $1
+                "#,
+            )
+            .bind(limit)
+            .fetch_all(pg)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|r| {
+                    let ids = r.get::<Vec<Uuid>, _>("players");
+                    let names = r.get::<Vec<String>, _>("usernames");
+                    let lons = r.get::<Vec<Option<f64>>, _>("lons");
+                    let lats = r.get::<Vec<Option<f64>>, _>("lats");
+                    let players = ids
+                        .into_iter()
+                        .zip(names)
+                        .zip(lons.into_iter().zip(lats))
+                        .map(|((user_id, username), (lon, lat))| LiveMatchPlayer {
+                            user_id,
+                            username,
+                            lon,
+                            lat,
+                        })
+                        .collect();
+                    LiveMatch {
+                        match_id: r.get("id"),
+                        mode: r.get("mode"),
+                        started_at: r.get("started_at"),
+                        players,
+                        delay_secs: DELAY_SECS,
+                    }
+                })
+                .collect())
+        }
+    }
+
+    pub mod game {
+        use super::*;
+
+        pub async fn websocket_fallback_loop(
+            mut ws: WebSocket,
+            app: Arc<state::AppState>,
+            auth_user: Uuid,
+            mut rx: broadcast::Receiver<state::Outbound>,
+        ) {
+            let typing_ttl = std::time::Duration::from_millis(services::typing::TYPING_TTL_MS as u64);
+            let mut typing_rooms: std::collections::HashMap<String, tokio::time::Instant> = std::collections::HashMap::new();
+            let mut typing_sweep = tokio::time::interval(std::time::Duration::from_secs(1));
+
+            // Drain anything queued while the user was offline before live traffic;
+            // `rx` is already subscribed, so nothing published meanwhile is lost.
+            let mut drained_up_to = 0_i64;
+            loop {
+                let batch = match services::mailbox::pending(&app.pg, auth_user, drained_up_to, services::mailbox::DRAIN_BATCH).await {
+                    Ok(batch) => batch,
+                    Err(err) => {
+                        tracing::warn!(?err, %auth_user, "mailbox drain failed");
+                        break;
+                    }
+                };
+                let done = (batch.len() as i64) < services::mailbox::DRAIN_BATCH;
+                for envelope in batch {
+                    drained_up_to = envelope.id;
+                    let Ok(payload) = rmp_serde::to_vec(&shared::RealtimePacket::Mailbox(envelope)) else {
+                        continue;
+                    };
+                    if ws.send(Message::Binary(payload.into())).await.is_err() {
+                        return;
+                    }
+                }
+                if done {
+                    break;
+                }
+            }
+
+            loop {
+                tokio::select! {
+                    incoming = ws.recv() => {
+                        match incoming {
+                            Some(Ok(Message::Binary(bin))) => {
+                                let Ok(packet) = rmp_serde::from_slice::<shared::RealtimePacket>(&bin) else {
+                                    continue;
+                                };
+
+                                if let shared::RealtimePacket::Position(mut pos) = packet {
+                                    pos.user_id = auth_user;
+                                    let _ = services::realtime::ingest_position(&app, auth_user, pos.lon, pos.lat).await;
+                                } else if let shared::RealtimePacket::Chat(mut chat) = packet {
+                                    chat.from_user = auth_user;
+                                    chat.id = None;
+                                    if chat.room_id.trim().is_empty() {
+                                        chat.room_id = "global".to_string();
+                                    }
+                                    if chat.text.trim().is_empty() && chat.attachments.is_empty() && chat.e2ee.is_none() {
+                                        continue;
+                                    }
+                                    typing_rooms.remove(&chat.room_id);
+                                    match services::chat::send(&app, chat).await {
+                                        Ok(sent) if sent.duplicate => {
+                                            // Resent after a reconnect: echo the stored copy to this socket only.
+                                            if let Ok(payload) = rmp_serde::to_vec(&shared::RealtimePacket::Chat(sent.message)) {
+                                                if ws.send(Message::Binary(payload.into())).await.is_err() {
+                                                    break;
+                                                }
+                                            }
+                                        }
+                                        Ok(_) => {}
+                                        Err(err) => tracing::debug!(reason = %err.message(), "ws chat rejected"),
+                                    }
+                                } else if let shared::RealtimePacket::Invite(invite) = packet {
+                                    // Clients may only open invites here; answers and cancels go through the HTTP API.
+                                    if invite.status == shared::InviteStatus::Pending {
+                                        if let Err(err) = services::invite::create(&app, auth_user, invite.to_user, &invite.mode).await {
+                                            tracing::debug!(reason = %err.message(), "ws invite rejected");
+                                        }
+                                    }
+                                } else if let shared::RealtimePacket::Receipt(receipt) = packet {
+                                    if !services::chat::can_view(&receipt.room_id, auth_user) {
+                                        continue;
+                                    }
+                                    let applied = match receipt.kind {
+                                        shared::ReceiptKind::Delivered => services::chat::mark_delivered(&app.pg, &receipt.room_id, auth_user, receipt.message_id)
+                                            .await
+                                            .map(|ok| ok.then_some(receipt.message_id)),
+                                        shared::ReceiptKind::Read => services::chat::mark_read(&app.pg, &receipt.room_id, auth_user, Some(receipt.message_id)).await,
+                                    };
+                                    if let Ok(Some(message_id)) = applied {
+                                        services::chat::broadcast_receipt(&app, &receipt.room_id, message_id, auth_user, receipt.kind);
+                                    }
+                                } else if let shared::RealtimePacket::PollVote(vote) = packet {
+                                    if let Err(err) = services::polls::vote(&app, auth_user, vote.poll_id, vote.choices).await {
+                                        tracing::debug!(reason = %err.message(), "ws poll vote rejected");
+                                    }
+                                } else if let shared::RealtimePacket::MailboxAck(ack) = packet {
+                                    if let Err(err) = services::mailbox::ack(&app.pg, auth_user, ack.up_to).await {
+                                        tracing::warn!(?err, %auth_user, "mailbox ack failed");
+                                    }
+                                } else if let shared::RealtimePacket::Typing(typing) = packet {
+                                    let room_id = if typing.room_id.trim().is_empty() {
+                                        "global".to_string()
+                                    } else {
+                                        typing.room_id
+                                    };
+                                    if !services::chat::can_view(&room_id, auth_user) {
+                                        continue;
+                                    }
+                                    if typing.typing {
+                                        typing_rooms.insert(room_id.clone(), tokio::time::Instant::now() + typing_ttl);
+                                        if services::typing::start(&app.redis, &room_id, auth_user).await.unwrap_or(false) {
+                                            services::typing::broadcast(&app, &room_id, auth_user, true);
+                                        }
+                                    } else {
+                                        typing_rooms.remove(&room_id);
+                                        services::typing::clear(&app, &room_id, auth_user).await;
+                                    }
+                                }
+                            }
+                            Some(Ok(Message::Close(_))) | None => break,
+                            _ => {}
+                        }
+                    }
+                    outbound = rx.recv() => {
+                        match outbound {
+                            Ok(outbound) => {
+                                if !outbound.reaches(auth_user) {
+                                    continue;
+                                }
+                                if ws.send(Message::Binary(outbound.payload.into())).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                    _ = typing_sweep.tick(), if !typing_rooms.is_empty() => {
+                        let now = tokio::time::Instant::now();
+                        let expired = typing_rooms
+                            .iter()
+                            .filter(|(_, deadline)| **deadline <= now)
+                            .map(|(room_id, _)| room_id.clone())
+                            .collect::<Vec<_>>();
+                        for room_id in expired {
+                            typing_rooms.remove(&room_id);
+                            services::typing::clear(&app, &room_id, auth_user).await;
+                        }
+                    }
+                }
+            }
+
+            for room_id in typing_rooms.into_keys() {
+                services::typing::clear(&app, &room_id, auth_user).await;
+            }
+        }
+
+        pub fn webtransport_placeholder() {
+            let _ = "webtransport-enabled";
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RegisterBody {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct LoginBody {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct WsQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct PositionBody {
+    token: String,
+    lon: f64,
+    lat: f64,
+}
+
+#[derive(Deserialize)]
+struct SendChatBody {
+    token: String,
+    room_id: String,
+    text: String,
+    parent_id: Option<i64>,
+    #[serde(default)]
+    attachment_ids: Vec<Uuid>,
+    client_id: Option<Uuid>,
+    e2ee: Option<shared::E2eePayload>,
+    #[serde(default)]
+    format: shared::MessageFormat,
+    send_at: Option<chrono::DateTime<chrono::Utc>>,
+    ttl_secs: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct SendChatResponse {
+    id: Option<i64>,
+    client_id: Option<Uuid>,
+    ts: chrono::DateTime<chrono::Utc>,
+    duplicate: bool,
+    scheduled_id: Option<i64>,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ScheduledItem {
+    id: i64,
+    room_id: String,
+    text: String,
+    format: shared::MessageFormat,
+    send_at: chrono::DateTime<chrono::Utc>,
+    ttl_secs: Option<i32>,
+    status: String,
+    last_error: Option<String>,
+    message_id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ScheduledQuery {
+    token: String,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ScheduledCancelBody {
+    token: String,
+    id: i64,
+}
+
+#[derive(Deserialize)]
+struct AttachmentPresignBody {
+    token: String,
+    room_id: String,
+    file_name: String,
+    mime_type: String,
+    size_bytes: i64,
+}
+
+#[derive(Serialize)]
+struct AttachmentPresignResponse {
+    attachment_id: String,
+    object_key: String,
+    upload_url: String,
+    method: &'static str,
+    content_type: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Deserialize)]
+struct AttachmentConfirmBody {
+    token: String,
+    attachment_id: String,
+    width: Option<i32>,
+    height: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct ChatHistoryQuery {
+    room_id: String,
+    limit: Option<i64>,
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    token: String,
+    q: String,
+    room_id: Option<String>,
+    author_id: Option<String>,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    until: Option<chrono::DateTime<chrono::Utc>>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+pub(crate) struct SearchFilter {
+    query: String,
+    room_id: Option<String>,
+    author_id: Option<Uuid>,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    until: Option<chrono::DateTime<chrono::Utc>>,
+    limit: i64,
+    offset: i64,
+}
+
+#[derive(Serialize, async_graphql::SimpleObject)]
+#[graphql(complex)]
+pub(crate) struct SearchHit {
+    id: i64,
+    room_id: String,
+    from_user: String,
+    text: String,
+    snippet: String,
+    parent_id: Option<i64>,
+    rank: f32,
+    #[graphql(skip)]
+    ts: chrono::DateTime<chrono::Utc>,
+}
+
+#[async_graphql::ComplexObject]
+impl SearchHit {
+    async fn ts(&self) -> String {
+        self.ts.to_rfc3339()
+    }
+}
+
+#[derive(Deserialize)]
+struct LocalRoomsQuery {
+    token: String,
+    lon: Option<f64>,
+    lat: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LocalRoomActivity {
+    room_id: String,
+    cell: String,
+    lon: f64,
+    lat: f64,
+    bounds: [f64; 4],
+    message_count: i64,
+    member_count: i64,
+    last_message_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Serialize)]
+struct LocalRoomsResponse {
+    current: Option<LocalRoomActivity>,
+    neighbours: Vec<LocalRoomActivity>,
+}
+
+#[derive(Deserialize)]
+struct ChatActivityQuery {
+    min_lon: f64,
+    min_lat: f64,
+    max_lon: f64,
+    max_lat: f64,
+}
+
+#[derive(Deserialize)]
+struct ThreadQuery {
+    token: String,
+    room_id: String,
+    parent_id: i64,
+    before_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ThreadResponse {
+    parent: ChatHistoryItem,
+    replies: Vec<ChatHistoryItem>,
+    unread_count: i64,
+    next_before_id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ThreadMarkReadBody {
+    token: String,
+    room_id: String,
+    parent_id: i64,
+}
+
+#[derive(Deserialize)]
+struct RoomStateQuery {
+    token: String,
+    room_id: String,
+    include_typing: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct RoomMemberState {
+    user_id: String,
+    online: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ThreadUnreadState {
+    parent_id: i64,
+    unread_count: i64,
+}
+
+#[derive(Serialize)]
+struct RoomStateResponse {
+    room_id: String,
+    unread_count: i64,
+    members: Vec<RoomMemberState>,
+    threads: Vec<ThreadUnreadState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    typing: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct MarkReadBody {
+    token: String,
+    room_id: String,
+    message_id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ReceiptsQuery {
+    token: String,
+    room_id: String,
+    message_id: i64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct MessageReceipt {
+    user_id: String,
+    delivered: bool,
+    read: bool,
+    read_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Serialize)]
+struct ReceiptsResponse {
+    message_id: i64,
+    receipts: Vec<MessageReceipt>,
+}
+
+#[derive(Deserialize)]
+struct InboxQuery {
+    token: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct InboxItem {
+    room_id: String,
+    unread_count: i64,
+    last_message_id: Option<i64>,
+    last_from_user: Option<String>,
+    last_preview: Option<String>,
+    last_activity_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ChatHistoryItem {
+    id: i64,
+    room_id: String,
+    from_user: String,
+    text: String,
+    ts: chrono::DateTime<chrono::Utc>,
+    parent_id: Option<i64>,
+    reply_count: i64,
+    last_reply_at: Option<chrono::DateTime<chrono::Utc>>,
+    attachments: Vec<shared::ChatAttachment>,
+    e2ee: Option<shared::E2eePayload>,
+    format: shared::MessageFormat,
+    event: Option<shared::ChatEvent>,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Deserialize)]
+struct ModerationActionBody {
+    token: String,
+    room_id: String,
+    action: String,
+    target_user: Uuid,
+    duration_secs: Option<i64>,
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ModerationSettingsBody {
+    token: String,
+    room_id: String,
+    slow_mode_secs: Option<i32>,
+    filter_action: Option<String>,
+    blocked_words: Option<Vec<String>>,
+    blocked_patterns: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct ModeratorGrantBody {
+    token: String,
+    room_id: String,
+    user_id: Uuid,
+    grant: bool,
+}
+
+#[derive(Deserialize)]
+struct ModerationLogQuery {
+    token: String,
+    room_id: String,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ModerationLogItem {
+    id: i64,
+    room_id: String,
+    actor: Option<String>,
+    action: String,
+    target_user: Option<String>,
+    details: serde_json::Value,
+    ts: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+struct ModerationLogResponse {
+    room_id: String,
+    entries: Vec<ModerationLogItem>,
+}
+
+#[derive(Deserialize)]
+struct NotificationsQuery {
+    token: String,
+    #[serde(default)]
+    unread_only: bool,
+    before_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct NotificationItem {
+    id: i64,
+    kind: String,
+    room_id: String,
+    message_id: i64,
+    from_user: String,
+    from_username: String,
+    preview: String,
+    ts: chrono::DateTime<chrono::Utc>,
+    read: bool,
+}
+
+#[derive(Serialize)]
+struct NotificationsResponse {
+    unread_count: i64,
+    items: Vec<NotificationItem>,
+}
+
+#[derive(Deserialize)]
+struct NotificationsMarkReadBody {
+    token: String,
+    ids: Option<Vec<i64>>,
+}
+
+#[derive(Deserialize)]
+struct KeyBundleBody {
+    token: String,
+    identity_key: String,
+    prekey_id: i64,
+    prekey: String,
+    prekey_signature: String,
+}
+
+#[derive(Deserialize)]
+struct KeyBundleQuery {
+    token: String,
+    user_id: Uuid,
+}
+
+#[derive(Deserialize)]
+struct CreatePollBody {
+    token: String,
+    room_id: String,
+    question: String,
+    options: Vec<String>,
+    #[serde(default)]
+    multi_choice: bool,
+    closes_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Deserialize)]
+struct PollVoteBody {
+    token: String,
+    poll_id: i64,
+    #[serde(default)]
+    choices: Vec<u32>,
+}
+
+#[derive(Deserialize)]
+struct PollCloseBody {
+    token: String,
+    poll_id: i64,
+}
+
+#[derive(Deserialize)]
+struct PollsQuery {
+    token: String,
+    room_id: String,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct PollView {
+    #[serde(flatten)]
+    poll: shared::PollState,
+    my_choices: Vec<u32>,
+}
+
+#[derive(Deserialize)]
+struct InviteBody {
+    token: String,
+    to_user: String,
+    mode: String,
+}
+
+#[derive(Deserialize)]
+struct InviteRespondBody {
+    token: String,
+    invite_id: String,
+    action: String,
+}
+
+#[derive(Serialize)]
+struct InviteRespondResponse {
+    invite: shared::InviteEvent,
+    match_ready: Option<shared::MatchReady>,
+}
+
+#[derive(Deserialize)]
+struct MatchmakingEnqueueBody {
+    token: String,
+    #[serde(default)]
+    mode: String,
+    #[serde(default)]
+    near: bool,
+}
+
+#[derive(Deserialize)]
+struct MatchmakingTokenBody {
+    token: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct MatchmakingStatus {
+    queued: bool,
+    mode: Option<String>,
+    rating: Option<f64>,
+    waited_secs: i64,
+    rating_window: Option<f64>,
+    radius_km: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct MatchReplayInfo {
+    match_id: Uuid,
+    mode: String,
+    state: shared::MatchState,
+    players: Vec<Uuid>,
+    winner: Option<Uuid>,
+    tick_hz: i32,
+    frames: i32,
+    size_bytes: i32,
+    created_at: chrono::DateTime<chrono::Utc>,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct MatchReplayQuery {
+    token: String,
+    match_id: Uuid,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LeaderboardEntry {
+    rank: usize,
+    user_id: Uuid,
+    username: String,
+    rating: f64,
+    rd: f64,
+    games: i32,
+    wins: i32,
+    losses: i32,
+    draws: i32,
+    distance_km: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct LeaderboardResponse {
+    mode: String,
+    radius_km: Option<f64>,
+    entries: Vec<LeaderboardEntry>,
+    me: Option<LeaderboardEntry>,
+}
+
+#[derive(Deserialize)]
+struct LeaderboardQuery {
+    token: String,
+    #[serde(default)]
+    mode: String,
+    #[serde(default)]
+    near: bool,
+    lon: Option<f64>,
+    lat: Option<f64>,
+    radius_km: Option<f64>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LiveMatchPlayer {
+    user_id: Uuid,
+    username: String,
+    lon: Option<f64>,
+    lat: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LiveMatch {
+    match_id: Uuid,
+    mode: String,
+    started_at: Option<chrono::DateTime<chrono::Utc>>,
+    players: Vec<LiveMatchPlayer>,
+    delay_secs: u64,
+}
+
+#[derive(Deserialize)]
+struct MatchActiveQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct MatchWsQuery {
+    token: String,
+    match_id: Uuid,
+}
+
+#[derive(Deserialize)]
+struct InviteCancelBody {
+    token: String,
+    invite_id: String,
+}
+
+#[derive(Deserialize)]
+struct InvitePendingQuery {
+    token: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct InviteItem {
+    invite_id: String,
+    from_user: String,
+    to_user: String,
+    mode: String,
+    status: String,
+    ts: chrono::DateTime<chrono::Utc>,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+struct RegisterResult {
+    token: String,
+    user_id: String,
+    username: String,
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct PublicMapConfig {
+    style_url: String,
+    center_lon: f64,
+    center_lat: f64,
+    zoom: f64,
+}
+
+#[derive(Serialize)]
+struct ReadinessResponse {
+    status: String,
+    postgres: bool,
+    redis: bool,
+}
+
+struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn health(&self, _ctx: &Context<'_>) -> &str {
+        "ok"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn search_messages(
+        &self,
+        ctx: &Context<'_>,
+        token: String,
+        query: String,
+        room_id: Option<String>,
+        author_id: Option<String>,
+        since: Option<String>,
+        until: Option<String>,
+        limit: Option<i64>,
+        offset: Option<i64>,
+    ) -> async_graphql::Result<Vec<SearchHit>> {
+        let app = ctx.data::<Arc<state::AppState>>()?;
+        let user_id = services::auth::parse_jwt(&token, &app.jwt)
+            .map_err(|_| async_graphql::Error::new("unauthorized"))?;
+        let author_id = match author_id {
+            Some(raw) => Some(Uuid::parse_str(&raw).map_err(|_| async_graphql::Error::new("invalid author_id"))?),
+            None => None,
+        };
+        let parse_ts = |raw: Option<String>, name: &str| -> async_graphql::Result<Option<chrono::DateTime<chrono::Utc>>> {
+            match raw {
+                Some(value) => chrono::DateTime::parse_from_rfc3339(&value)
+                    .map(|ts| Some(ts.with_timezone(&chrono::Utc)))
+                    .map_err(|_| async_graphql::Error::new(format!("invalid {name}, expected RFC 3339"))),
+                None => Ok(None),
+            }
+        };
+
+        let filter = SearchFilter {
+            query,
+            room_id,
+            author_id,
+            since: parse_ts(since, "since")?,
+            until: parse_ts(until, "until")?,
+            limit: limit.unwrap_or(20).clamp(1, 100),
+            offset: offset.unwrap_or(0).max(0),
+        };
+
+        services::search::search_messages(&app.pg, user_id, &filter)
+            .await
+            .map_err(|e| async_graphql::Error::new(e.to_string()))
+    }
+}
+
+type AppSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+async fn health() -> impl IntoResponse {
+    Json(serde_json::json!({"status": "ok"}))
+}
+
+async fn ready(State(app): State<Arc<state::AppState>>) -> impl IntoResponse {
+    let postgres_ok = sqlx::query_scalar::<_, i32>("SELECT 1")
+        .fetch_one(&app.pg)
+        .await
+        .is_ok();
+
+    let redis_ok = if let Ok(mut conn) = app.redis.get().await {
+        redis::cmd("PING")
+            .query_async::<String>(&mut conn)
+            .await
+            .is_ok()
+    } else {
+        false
+    };
+
+    let all_ready = postgres_ok && redis_ok;
+    let payload = ReadinessResponse {
+        status: if all_ready { "ready" } else { "degraded" }.to_string(),
+        postgres: postgres_ok,
+        redis: redis_ok,
+    };
+
+    if all_ready {
+        (StatusCode::OK, Json(payload)).into_response()
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(payload)).into_response()
+    }
+}
+
+async fn public_map_config() -> impl IntoResponse {
+    let style_url = std::env::var("MAP_STYLE_URL")
+        .unwrap_or_else(|_| "https://demotiles.maplibre.org/style.json".to_string());
+    let center_lon = std::env::var("MAP_CENTER_LON")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .unwrap_or(116.397);
+    let center_lat = std::env::var("MAP_CENTER_LAT")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .unwrap_or(39.908);
+    let zoom = std::env::var("MAP_DEFAULT_ZOOM")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .unwrap_or(11.0);
+
+    Json(PublicMapConfig {
+        style_url,
+        center_lon,
+        center_lat,
+        zoom,
+    })
+}
+
+async fn register(State(app): State<Arc<state::AppState>>, Json(body): Json<RegisterBody>) -> Response {
+    if body.username.trim().is_empty() || body.password.trim().is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiError {
+                error: "username and password are required".to_string(),
+            }),
+        )
+            .into_response();
+    }
+
+    let hashed = match services::auth::hash_password(&body.password) {
+        Ok(value) => value,
+        Err(err) => {
+            tracing::error!(?err, "password hash failed");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError {
+                    error: "failed to process credentials".to_string(),
+                }),
+            )
+                .into_response();
+        }
+    };
+    let username = body.username;
+    let user_id = Uuid::new_v4();
+    let row = sqlx::query(
+        "INSERT INTO users(id, username, password_hash) VALUES($1, $2, $3) ON CONFLICT (username) DO NOTHING RETURNING id::text, username"
+    )
+    .bind(user_id)
+    .bind(&username)
+    .bind(hashed)
+    .fetch_optional(&app.pg)
+    .await;
+
+    let row = match row {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(ApiError {
+                    error: "username already exists".to_string(),
+                }),
+            )
+                .into_response();
+        }
+        Err(err) => {
+            tracing::error!(?err, "register query failed");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError {
+                    error: "failed to create account".to_string(),
+                }),
+            )
+                .into_response();
+        }
+    };
+
+    let user_id_str = row.get::<String, _>("id");
+    let user_id = Uuid::parse_str(&user_id_str).unwrap_or_else(|_| Uuid::nil());
+    let token = match services::auth::make_jwt(user_id, &app.jwt) {
+        Ok(value) => value,
+        Err(err) => {
+            tracing::error!(?err, "jwt generation failed on register");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError {
+                    error: "failed to issue token".to_string(),
+                }),
+            )
+                .into_response();
+        }
+    };
+
+    (
+        StatusCode::CREATED,
+        Json(RegisterResult {
+            token,
+            user_id: user_id.to_string(),
+            username: row.get::<String, _>("username"),
+        }),
+    )
+        .into_response()
+}
+
+async fn login(State(app): State<Arc<state::AppState>>, Json(body): Json<LoginBody>) -> Response {
+    if body.username.trim().is_empty() || body.password.trim().is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiError {
+                error: "username and password are required".to_string(),
+            }),
+        )
+            .into_response();
+    }
+
+    let row = sqlx::query("SELECT id::text, username, password_hash FROM users WHERE username = $1")
+        .bind(&body.username)
+        .fetch_optional(&app.pg)
+        .await;
+
+    let row = match row {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiError {
+                    error: "user not found".to_string(),
+                }),
+            )
+                .into_response();
+        }
+        Err(err) => {
+            tracing::error!(?err, "login query failed");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError {
+                    error: "failed to load account".to_string(),
+                }),
+            )
+                .into_response();
+        }
+    };
+
+    let hash = row.get::<String, _>("password_hash");
+    let valid = services::auth::verify_password(&body.password, &hash);
+    if !valid {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(ApiError {
+                error: "invalid credentials".to_string(),
+            }),
+        )
+            .into_response();
+    }
+
+    let user_id = Uuid::parse_str(&row.get::<String, _>("id")).unwrap_or_else(|_| Uuid::nil());
+    let token = match services::auth::make_jwt(user_id, &app.jwt) {
+        Ok(value) => value,
+        Err(err) => {
+            tracing::error!(?err, "jwt generation failed on login");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError {
+                    error: "failed to issue token".to_string(),
+                }),
+            )
+                .into_response();
+        }
+    };
+
+    (
+        StatusCode::OK,
+        Json(RegisterResult {
+            token,
+            user_id: user_id.to_string(),
+            username: row.get::<String, _>("username"),
+        }),
+    )
+        .into_response()
+}
+
+async fn ingest_position_http(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<PositionBody>,
+) -> impl IntoResponse {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+
+    match services::realtime::ingest_position(&app, user_id, body.lon, body.lat).await {
+        Ok(_) => StatusCode::ACCEPTED,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn send_chat(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<SendChatBody>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let text = body.text.trim().to_string();
+    if text.is_empty() && body.attachment_ids.is_empty() && body.e2ee.is_none() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let room_id = if body.room_id.trim().is_empty() {
+        "global".to_string()
+    } else {
+        body.room_id
+    };
+    let scheduled_for = body.send_at.filter(|at| *at > chrono::Utc::now() + chrono::Duration::seconds(5));
+    let expires_at = if scheduled_for.is_some() {
+        None
+    } else {
+        match services::scheduled::expiry_after(body.ttl_secs) {
+            Ok(at) => at,
+            Err(err) => return chat_send_error(err),
+        }
+    };
+
+    let message = shared::ChatMessage {
+        room_id,
+        from_user: user_id,
+        text,
+        ts: chrono::Utc::now(),
+        id: None,
+        parent_id: body.parent_id,
+        attachments: body
+            .attachment_ids
+            .into_iter()
+            .map(|id| shared::ChatAttachment {
+                id,
+                file_name: String::new(),
+                mime_type: String::new(),
+                size_bytes: 0,
+                width: None,
+                height: None,
+                url: None,
+            })
+            .collect(),
+        client_id: body.client_id,
+        e2ee: body.e2ee,
+        format: body.format,
+        event: None,
+        expires_at,
+    };
+
+    if let Some(send_at) = scheduled_for {
+        let client_id = message.client_id;
+        return match services::scheduled::schedule(&app, message, send_at, body.ttl_secs).await {
+            Ok(scheduled_id) => (
+                StatusCode::ACCEPTED,
+                Json(SendChatResponse {
+                    id: None,
+                    client_id,
+                    ts: send_at,
+                    duplicate: false,
+                    scheduled_id: Some(scheduled_id),
+                    expires_at: None,
+                }),
+            )
+                .into_response(),
+            Err(err) => chat_send_error(err),
+        };
+    }
+
+    match services::chat::send(&app, message).await {
+        Ok(sent) => (
+            StatusCode::ACCEPTED,
+            Json(SendChatResponse {
+                id: sent.message.id,
+                client_id: sent.message.client_id,
+                ts: sent.message.ts,
+                duplicate: sent.duplicate,
+                scheduled_id: None,
+                expires_at: sent.message.expires_at,
+            }),
+        )
+            .into_response(),
+        Err(err) => chat_send_error(err),
     }
 }
 
-#[derive(Deserialize)]
-struct RegisterBody {
-    username: String,
-    password: String,
+fn chat_send_error(err: services::chat::SendError) -> Response {
+    if let services::chat::SendError::Storage(ref cause) = err {
+        tracing::error!(?cause, "chat insert failed");
+    }
+    (
+        err.status_code(),
+        Json(ApiError {
+            error: err.message(),
+        }),
+    )
+        .into_response()
 }
 
-#[derive(Deserialize)]
-struct LoginBody {
-    username: String,
-    password: String,
-}
+async fn chat_scheduled_list(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<ScheduledQuery>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
 
-#[derive(Deserialize)]
-struct WsQuery {
-    token: String,
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    match services::scheduled::list(&app.pg, user_id, limit).await {
+        Ok(items) => Json(items).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
 }
 
-#[derive(Deserialize)]
-struct PositionBody {
-    token: String,
-    lon: f64,
-    lat: f64,
-}
+async fn chat_scheduled_cancel(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<ScheduledCancelBody>,
+) -> StatusCode {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED;
+    };
 
-#[derive(Deserialize)]
-struct SendChatBody {
-    token: String,
-    room_id: String,
-    text: String,
+    match services::scheduled::cancel(&app.pg, user_id, body.id).await {
+        Ok(true) => StatusCode::ACCEPTED,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
 
-#[derive(Deserialize)]
-struct ChatHistoryQuery {
-    room_id: String,
-    limit: Option<i64>,
-}
+async fn chat_attachment_presign(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<AttachmentPresignBody>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
 
-#[derive(Deserialize)]
-struct RoomStateQuery {
-    token: String,
-    room_id: String,
-}
+    let mime_type = body.mime_type.trim().to_ascii_lowercase();
+    if !services::attachment::is_allowed_mime(&mime_type) {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(ApiError {
+                error: format!("mime type {mime_type} is not allowed"),
+            }),
+        )
+            .into_response();
+    }
+    if body.size_bytes <= 0 || body.size_bytes > services::attachment::MAX_ATTACHMENT_BYTES {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(ApiError {
+                error: format!(
+                    "attachment size must be between 1 and {} bytes",
+                    services::attachment::MAX_ATTACHMENT_BYTES
+                ),
+            }),
+        )
+            .into_response();
+    }
 
-#[derive(Serialize)]
-struct RoomMemberState {
-    user_id: String,
-    online: bool,
-}
+    let room_id = if body.room_id.trim().is_empty() {
+        "global".to_string()
+    } else {
+        body.room_id
+    };
 
-#[derive(Serialize)]
-struct RoomStateResponse {
-    room_id: String,
-    unread_count: i64,
-    members: Vec<RoomMemberState>,
+    match services::attachment::presign_upload(&app, &room_id, user_id, &body.file_name, &mime_type, body.size_bytes).await {
+        Ok(pending) => (
+            StatusCode::CREATED,
+            Json(AttachmentPresignResponse {
+                attachment_id: pending.attachment_id.to_string(),
+                object_key: pending.object_key,
+                upload_url: pending.upload_url,
+                method: "PUT",
+                content_type: mime_type,
+                expires_at: pending.expires_at,
+            }),
+        )
+            .into_response(),
+        Err(err) => {
+            tracing::error!(?err, "attachment presign failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
 }
 
-#[derive(Deserialize)]
-struct MarkReadBody {
-    token: String,
-    room_id: String,
-}
+async fn chat_attachment_confirm(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<AttachmentConfirmBody>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let Ok(attachment_id) = Uuid::parse_str(&body.attachment_id) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
 
-#[derive(Serialize)]
-pub(crate) struct ChatHistoryItem {
-    room_id: String,
-    from_user: String,
-    text: String,
-    ts: chrono::DateTime<chrono::Utc>,
+    match services::attachment::confirm(&app, attachment_id, user_id, body.width, body.height).await {
+        Ok(attachment) => Json(attachment).into_response(),
+        Err(services::attachment::ConfirmError::NotFound) => StatusCode::NOT_FOUND.into_response(),
+        Err(services::attachment::ConfirmError::NotUploaded) => (
+            StatusCode::CONFLICT,
+            Json(ApiError {
+                error: "object has not been uploaded yet".to_string(),
+            }),
+        )
+            .into_response(),
+        Err(services::attachment::ConfirmError::Mismatch(reason)) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ApiError {
+                error: reason.to_string(),
+            }),
+        )
+            .into_response(),
+        Err(services::attachment::ConfirmError::Storage(err)) => {
+            tracing::error!(?err, "attachment confirm failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
 }
 
-#[derive(Deserialize)]
-struct InviteBody {
-    token: String,
-    to_user: String,
-    mode: String,
-}
+async fn chat_history(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<ChatHistoryQuery>,
+) -> impl IntoResponse {
+    let room_id = if query.room_id.trim().is_empty() {
+        "global".to_string()
+    } else {
+        query.room_id
+    };
 
-#[derive(Deserialize)]
-struct InviteRespondBody {
-    token: String,
-    invite_id: String,
-    action: String,
-}
+    if room_id.starts_with(shared::dm::DM_ROOM_PREFIX) {
+        let viewer = query
+            .token
+            .as_deref()
+            .and_then(|token| services::auth::parse_jwt(token, &app.jwt).ok());
+        match viewer {
+            Some(user_id) if shared::dm::is_participant(&room_id, user_id) => {}
+            Some(_) => return StatusCode::FORBIDDEN.into_response(),
+            None => return StatusCode::UNAUTHORIZED.into_response(),
+        }
+    }
 
-#[derive(Deserialize)]
-struct InvitePendingQuery {
-    token: String,
-}
+    let limit = query.limit.unwrap_or(100).clamp(1, 500);
 
-#[derive(Serialize)]
-pub(crate) struct InviteItem {
-    invite_id: String,
-    from_user: String,
-    to_user: String,
-    mode: String,
-    status: String,
-    ts: chrono::DateTime<chrono::Utc>,
+    let mut rows = match services::chat::history(&app.pg, &room_id, limit).await {
+        Ok(rows) => rows,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    if services::attachment::fill_history(&app, &mut rows).await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    Json(rows).into_response()
 }
 
-#[derive(Serialize)]
-struct RegisterResult {
-    token: String,
-    user_id: String,
-    username: String,
+async fn chat_search(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<SearchQuery>,
+) -> impl IntoResponse {
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    if query.q.trim().is_empty() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let author_id = match query.author_id.as_deref().map(str::trim).filter(|v| !v.is_empty()) {
+        Some(raw) => match Uuid::parse_str(raw) {
+            Ok(id) => Some(id),
+            Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+        },
+        None => None,
+    };
+
+    let filter = SearchFilter {
+        query: query.q,
+        room_id: query.room_id.filter(|v| !v.trim().is_empty()),
+        author_id,
+        since: query.since,
+        until: query.until,
+        limit: query.limit.unwrap_or(20).clamp(1, 100),
+        offset: query.offset.unwrap_or(0).max(0),
+    };
+
+    match services::search::search_messages(&app.pg, user_id, &filter).await {
+        Ok(hits) => Json(hits).into_response(),
+        Err(err) => {
+            tracing::error!(?err, "chat search failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
 }
 
-#[derive(Serialize)]
-struct ApiError {
-    error: String,
+async fn chat_local_rooms(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<LocalRoomsQuery>,
+) -> impl IntoResponse {
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let cell = match (query.lon, query.lat) {
+        (Some(lon), Some(lat)) => Some(shared::geocell::encode(lon, lat, shared::geocell::LOCAL_ROOM_PRECISION)),
+        _ => match services::local::current_cell(&app.redis, user_id).await {
+            Ok(cell) => cell,
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        },
+    };
+    let Some(cell) = cell else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let mut cells = vec![cell.clone()];
+    cells.extend(shared::geocell::neighbours(&cell));
+
+    let mut activity = match services::local::activity(&app.pg, &cells).await {
+        Ok(rows) => rows,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let current = activity
+        .iter()
+        .position(|a| a.cell == cell)
+        .map(|idx| activity.swap_remove(idx));
+
+    Json(LocalRoomsResponse {
+        current,
+        neighbours: activity,
+    })
+    .into_response()
 }
 
-#[derive(Serialize)]
-struct PublicMapConfig {
-    style_url: String,
-    center_lon: f64,
-    center_lat: f64,
-    zoom: f64,
+async fn map_chat_activity(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<ChatActivityQuery>,
+) -> impl IntoResponse {
+    if query.min_lon > query.max_lon || query.min_lat > query.max_lat {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    match services::local::active_cells_in(&app.pg, query.min_lon, query.min_lat, query.max_lon, query.max_lat).await {
+        Ok(rows) => Json(rows).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
 }
 
-#[derive(Serialize)]
-struct ReadinessResponse {
-    status: String,
-    postgres: bool,
-    redis: bool,
+async fn chat_thread(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<ThreadQuery>,
+) -> impl IntoResponse {
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let room_id = if query.room_id.trim().is_empty() {
+        "global".to_string()
+    } else {
+        query.room_id
+    };
+
+    if room_id.starts_with(shared::dm::DM_ROOM_PREFIX) && !shared::dm::is_participant(&room_id, user_id) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+
+    let mut parent = match services::chat::thread_root(&app.pg, &room_id, query.parent_id).await {
+        Ok(Some(item)) => item,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let mut replies = match services::chat::thread_replies(&app.pg, query.parent_id, query.before_id, limit).await {
+        Ok(rows) => rows,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    if services::attachment::fill_history(&app, std::slice::from_mut(&mut parent)).await.is_err()
+        || services::attachment::fill_history(&app, &mut replies).await.is_err()
+    {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    let unread_count = match services::chat::thread_unread_count(&app.pg, query.parent_id, user_id).await {
+        Ok(value) => value,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let next_before_id = if replies.len() as i64 == limit {
+        replies.first().map(|r| r.id)
+    } else {
+        None
+    };
+
+    Json(ThreadResponse {
+        parent,
+        replies,
+        unread_count,
+        next_before_id,
+    })
+    .into_response()
 }
 
-struct QueryRoot;
+async fn chat_thread_mark_read(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<ThreadMarkReadBody>,
+) -> impl IntoResponse {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED;
+    };
 
-#[Object]
-impl QueryRoot {
-    async fn health(&self, _ctx: &Context<'_>) -> &str {
-        "ok"
+    let room_id = if body.room_id.trim().is_empty() {
+        "global".to_string()
+    } else {
+        body.room_id
+    };
+
+    if room_id.starts_with(shared::dm::DM_ROOM_PREFIX) && !shared::dm::is_participant(&room_id, user_id) {
+        return StatusCode::FORBIDDEN;
     }
-}
 
-type AppSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+    match services::chat::is_thread_root(&app.pg, &room_id, body.parent_id).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+    }
 
-async fn health() -> impl IntoResponse {
-    Json(serde_json::json!({"status": "ok"}))
+    match services::chat::mark_thread_read(&app.pg, &room_id, body.parent_id, user_id).await {
+        Ok(_) => StatusCode::ACCEPTED,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
 
-async fn ready(State(app): State<Arc<state::AppState>>) -> impl IntoResponse {
-    let postgres_ok = sqlx::query_scalar::<_, i32>("SELECT 1")
-        .fetch_one(&app.pg)
-        .await
-        .is_ok();
-
-    let redis_ok = if let Ok(mut conn) = app.redis.get().await {
-        redis::cmd("PING")
-            .query_async::<String>(&mut conn)
-            .await
-            .is_ok()
-    } else {
-        false
+async fn notifications_inbox(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<NotificationsQuery>,
+) -> impl IntoResponse {
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
     };
 
-    let all_ready = postgres_ok && redis_ok;
-    let payload = ReadinessResponse {
-        status: if all_ready { "ready" } else { "degraded" }.to_string(),
-        postgres: postgres_ok,
-        redis: redis_ok,
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let items = match services::mention::inbox(&app.pg, user_id, query.unread_only, query.before_id, limit).await {
+        Ok(items) => items,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let unread_count = match services::mention::unread_count(&app.pg, user_id).await {
+        Ok(value) => value,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     };
 
-    if all_ready {
-        (StatusCode::OK, Json(payload)).into_response()
-    } else {
-        (StatusCode::SERVICE_UNAVAILABLE, Json(payload)).into_response()
-    }
+    Json(NotificationsResponse { unread_count, items }).into_response()
 }
 
-async fn public_map_config() -> impl IntoResponse {
-    let style_url = std::env::var("MAP_STYLE_URL")
-        .unwrap_or_else(|_| "https://demotiles.maplibre.org/style.json".to_string());
-    let center_lon = std::env::var("MAP_CENTER_LON")
-        .ok()
-        .and_then(|v| v.parse::<f64>().ok())
-        .unwrap_or(116.397);
-    let center_lat = std::env::var("MAP_CENTER_LAT")
-        .ok()
-        .and_then(|v| v.parse::<f64>().ok())
-        .unwrap_or(39.908);
-    let zoom = std::env::var("MAP_DEFAULT_ZOOM")
-        .ok()
-        .and_then(|v| v.parse::<f64>().ok())
-        .unwrap_or(11.0);
+async fn notifications_mark_read(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<NotificationsMarkReadBody>,
+) -> impl IntoResponse {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED;
+    };
 
-    Json(PublicMapConfig {
-        style_url,
-        center_lon,
-        center_lat,
-        zoom,
-    })
+    match services::mention::mark_read(&app.pg, user_id, body.ids.as_deref()).await {
+        Ok(_) => StatusCode::ACCEPTED,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
 
-async fn register(State(app): State<Arc<state::AppState>>, Json(body): Json<RegisterBody>) -> Response {
-    if body.username.trim().is_empty() || body.password.trim().is_empty() {
+async fn keys_publish(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<KeyBundleBody>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let bundle = shared::KeyBundle {
+        user_id,
+        identity_key: body.identity_key,
+        prekey_id: body.prekey_id,
+        prekey: body.prekey,
+        prekey_signature: body.prekey_signature,
+        updated_at: None,
+    };
+    if let Err(reason) = services::keys::validate(&bundle) {
         return (
             StatusCode::BAD_REQUEST,
             Json(ApiError {
-                error: "username and password are required".to_string(),
+                error: reason.to_string(),
             }),
         )
             .into_response();
     }
 
-    let hashed = match services::auth::hash_password(&body.password) {
-        Ok(value) => value,
-        Err(err) => {
-            tracing::error!(?err, "password hash failed");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "failed to process credentials".to_string(),
-                }),
-            )
-                .into_response();
-        }
-    };
-    let username = body.username;
-    let user_id = Uuid::new_v4();
-    let row = sqlx::query(
-        "INSERT INTO users(id, username, password_hash) VALUES($1, $2, $3) ON CONFLICT (username) DO NOTHING RETURNING id::text, username"
-    )
-    .bind(user_id)
-    .bind(&username)
-    .bind(hashed)
-    .fetch_optional(&app.pg)
-    .await;
+    match services::keys::publish(&app.pg, &bundle).await {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
 
-    let row = match row {
-        Ok(Some(row)) => row,
-        Ok(None) => {
-            return (
-                StatusCode::CONFLICT,
-                Json(ApiError {
-                    error: "username already exists".to_string(),
-                }),
-            )
-                .into_response();
-        }
-        Err(err) => {
-            tracing::error!(?err, "register query failed");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "failed to create account".to_string(),
-                }),
-            )
-                .into_response();
-        }
+async fn keys_fetch(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<KeyBundleQuery>,
+) -> impl IntoResponse {
+    if services::auth::parse_jwt(&query.token, &app.jwt).is_err() {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    match services::keys::fetch(&app.pg, query.user_id).await {
+        Ok(Some(bundle)) => Json(bundle).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+fn poll_error(err: services::polls::PollError) -> Response {
+    if let services::polls::PollError::Storage(ref cause) = err {
+        tracing::error!(?cause, "poll storage failed");
+    }
+    (err.status_code(), Json(ApiError { error: err.message() })).into_response()
+}
+
+async fn poll_create(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<CreatePollBody>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
     };
 
-    let user_id_str = row.get::<String, _>("id");
-    let user_id = Uuid::parse_str(&user_id_str).unwrap_or_else(|_| Uuid::nil());
-    let token = match services::auth::make_jwt(user_id, &app.jwt) {
-        Ok(value) => value,
-        Err(err) => {
-            tracing::error!(?err, "jwt generation failed on register");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "failed to issue token".to_string(),
-                }),
-            )
-                .into_response();
-        }
+    let room_id = if body.room_id.trim().is_empty() {
+        "global".to_string()
+    } else {
+        body.room_id
+    };
+    let poll = services::polls::NewPoll {
+        room_id,
+        question: body.question,
+        options: body.options,
+        multi_choice: body.multi_choice,
+        closes_at: body.closes_at,
     };
 
-    (
-        StatusCode::CREATED,
-        Json(RegisterResult {
-            token,
-            user_id: user_id.to_string(),
-            username: row.get::<String, _>("username"),
-        }),
-    )
-        .into_response()
+    match services::polls::create(&app, user_id, poll).await {
+        Ok(poll) => (StatusCode::CREATED, Json(PollView { poll, my_choices: Vec::new() })).into_response(),
+        Err(err) => poll_error(err),
+    }
 }
 
-async fn login(State(app): State<Arc<state::AppState>>, Json(body): Json<LoginBody>) -> Response {
-    if body.username.trim().is_empty() || body.password.trim().is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(ApiError {
-                error: "username and password are required".to_string(),
-            }),
-        )
-            .into_response();
-    }
+async fn poll_vote(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<PollVoteBody>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
 
-    let row = sqlx::query("SELECT id::text, username, password_hash FROM users WHERE username = $1")
-        .bind(&body.username)
-        .fetch_optional(&app.pg)
-        .await;
+    let mut my_choices = body.choices.clone();
+    my_choices.sort_unstable();
+    my_choices.dedup();
+    match services::polls::vote(&app, user_id, body.poll_id, body.choices).await {
+        Ok(poll) => Json(PollView { poll, my_choices }).into_response(),
+        Err(err) => poll_error(err),
+    }
+}
 
-    let row = match row {
-        Ok(Some(row)) => row,
-        Ok(None) => {
-            return (
-                StatusCode::NOT_FOUND,
-                Json(ApiError {
-                    error: "user not found".to_string(),
-                }),
-            )
-                .into_response();
-        }
-        Err(err) => {
-            tracing::error!(?err, "login query failed");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "failed to load account".to_string(),
-                }),
-            )
-                .into_response();
-        }
+async fn poll_close(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<PollCloseBody>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
     };
 
-    let hash = row.get::<String, _>("password_hash");
-    let valid = services::auth::verify_password(&body.password, &hash);
-    if !valid {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(ApiError {
-                error: "invalid credentials".to_string(),
-            }),
-        )
-            .into_response();
+    match services::polls::close(&app, user_id, body.poll_id).await {
+        Ok(poll) => Json(poll).into_response(),
+        Err(err) => poll_error(err),
     }
+}
 
-    let user_id = Uuid::parse_str(&row.get::<String, _>("id")).unwrap_or_else(|_| Uuid::nil());
-    let token = match services::auth::make_jwt(user_id, &app.jwt) {
-        Ok(value) => value,
-        Err(err) => {
-            tracing::error!(?err, "jwt generation failed on login");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "failed to issue token".to_string(),
-                }),
-            )
-                .into_response();
-        }
+async fn polls_list(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<PollsQuery>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
     };
 
-    (
-        StatusCode::OK,
-        Json(RegisterResult {
-            token,
-            user_id: user_id.to_string(),
-            username: row.get::<String, _>("username"),
-        }),
+    match services::polls::can_view(&app.pg, &query.room_id, user_id).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::FORBIDDEN.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    let polls = match services::polls::for_room(&app.pg, &query.room_id, limit).await {
+        Ok(polls) => polls,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let ids = polls.iter().map(|p| p.id).collect::<Vec<_>>();
+    let mut mine = match services::polls::choices_of(&app.pg, &ids, user_id).await {
+        Ok(mine) => mine,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    Json(
+        polls
+            .into_iter()
+            .map(|poll| {
+                let my_choices = mine.remove(&poll.id).unwrap_or_default();
+                PollView { poll, my_choices }
+            })
+            .collect::<Vec<_>>(),
     )
-        .into_response()
+    .into_response()
 }
 
-async fn ingest_position_http(
+fn moderation_error(status: StatusCode, error: impl Into<String>) -> Response {
+    (status, Json(ApiError { error: error.into() })).into_response()
+}
+
+async fn require_moderator(app: &state::AppState, room_id: &str, user_id: Uuid) -> Result<(), Response> {
+    match services::moderation::is_moderator(&app.pg, room_id, user_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(moderation_error(StatusCode::FORBIDDEN, "moderator role required for this room")),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    }
+}
+
+async fn moderation_action(
     State(app): State<Arc<state::AppState>>,
-    Json(body): Json<PositionBody>,
-) -> impl IntoResponse {
-    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
-        return StatusCode::UNAUTHORIZED;
+    Json(body): Json<ModerationActionBody>,
+) -> Response {
+    let Ok(actor) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    if body.room_id.trim().is_empty() {
+        return moderation_error(StatusCode::BAD_REQUEST, "room_id is required");
+    }
+    if let Err(resp) = require_moderator(&app, &body.room_id, actor).await {
+        return resp;
+    }
+    if body.duration_secs.is_some_and(|secs| secs <= 0) {
+        return moderation_error(StatusCode::BAD_REQUEST, "duration_secs must be positive");
+    }
+
+    use services::moderation::SanctionKind;
+    let reason = body.reason.as_deref().map(str::trim).filter(|r| !r.is_empty());
+    let result = match body.action.as_str() {
+        "mute" | "ban" => {
+            if body.target_user == actor {
+                return moderation_error(StatusCode::BAD_REQUEST, "moderators cannot sanction themselves");
+            }
+            let kind = if body.action == "ban" { SanctionKind::Ban } else { SanctionKind::Mute };
+            services::moderation::sanction(&app, &body.room_id, actor, body.target_user, kind, body.duration_secs, reason)
+                .await
+                .map(|_| ())
+        }
+        "unmute" => services::moderation::lift(&app, &body.room_id, actor, body.target_user, SanctionKind::Mute)
+            .await
+            .map(|_| ()),
+        "unban" => services::moderation::lift(&app, &body.room_id, actor, body.target_user, SanctionKind::Ban)
+            .await
+            .map(|_| ()),
+        _ => return moderation_error(StatusCode::BAD_REQUEST, "action must be one of mute, unmute, ban, unban"),
     };
 
-    match services::realtime::ingest_position(&app, user_id, body.lon, body.lat).await {
-        Ok(_) => StatusCode::ACCEPTED,
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    match result {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(err) => {
+            tracing::error!(?err, "moderation action failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
     }
 }
 
-async fn send_chat(
+async fn moderation_settings(
     State(app): State<Arc<state::AppState>>,
-    Json(body): Json<SendChatBody>,
-) -> impl IntoResponse {
-    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
-        return StatusCode::UNAUTHORIZED;
+    Json(body): Json<ModerationSettingsBody>,
+) -> Response {
+    let Ok(actor) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
     };
+    if body.room_id.trim().is_empty() {
+        return moderation_error(StatusCode::BAD_REQUEST, "room_id is required");
+    }
+    if let Err(resp) = require_moderator(&app, &body.room_id, actor).await {
+        return resp;
+    }
 
-    let text = body.text.trim().to_string();
-    if text.is_empty() {
-        return StatusCode::BAD_REQUEST;
+    if body.slow_mode_secs.is_some_and(|secs| !(0..=3600).contains(&secs)) {
+        return moderation_error(StatusCode::BAD_REQUEST, "slow_mode_secs must be between 0 and 3600");
+    }
+    let filter_action = match body.filter_action.as_deref() {
+        None => None,
+        Some(raw) => match services::moderation::FilterAction::parse(raw) {
+            Some(action) => Some(action),
+            None => return moderation_error(StatusCode::BAD_REQUEST, "filter_action must be reject or mask"),
+        },
+    };
+    let blocked_words = body.blocked_words.map(|words| {
+        words
+            .into_iter()
+            .map(|w| w.trim().to_string())
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<_>>()
+    });
+    if let Err(err) = services::moderation::check_lists(blocked_words.as_deref(), body.blocked_patterns.as_deref()) {
+        return moderation_error(StatusCode::BAD_REQUEST, err);
     }
 
-    let room_id = if body.room_id.trim().is_empty() {
-        "global".to_string()
-    } else {
-        body.room_id
+    let update = services::moderation::SettingsUpdate {
+        slow_mode_secs: body.slow_mode_secs,
+        filter_action,
+        blocked_words,
+        blocked_patterns: body.blocked_patterns,
     };
+    match services::moderation::update_settings(&app, &body.room_id, actor, update).await {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(err) => {
+            tracing::error!(?err, "moderation settings update failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
 
-    let message = shared::ChatMessage {
-        room_id,
-        from_user: user_id,
-        text,
-        ts: chrono::Utc::now(),
+async fn moderation_moderators(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<ModeratorGrantBody>,
+) -> Response {
+    let Ok(actor) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
     };
+    if !services::moderation::is_admin(actor) {
+        return moderation_error(StatusCode::FORBIDDEN, "only platform admins can manage moderators");
+    }
+    if body.room_id.trim().is_empty() {
+        return moderation_error(StatusCode::BAD_REQUEST, "room_id is required");
+    }
 
-    if services::chat::insert_message(&app.pg, &message).await.is_err() {
-        return StatusCode::INTERNAL_SERVER_ERROR;
+    match services::moderation::set_moderator(&app.pg, &body.room_id, actor, body.user_id, body.grant).await {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     }
+}
 
-    if let Ok(payload) = rmp_serde::to_vec(&shared::RealtimePacket::Chat(message)) {
-        let _ = app.realtime_tx.send(payload);
+async fn moderation_log(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<ModerationLogQuery>,
+) -> Response {
+    let Ok(actor) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    if let Err(resp) = require_moderator(&app, &query.room_id, actor).await {
+        return resp;
     }
 
-    StatusCode::ACCEPTED
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    match services::moderation::recent_log(&app.pg, &query.room_id, limit).await {
+        Ok(entries) => Json(ModerationLogResponse {
+            room_id: query.room_id,
+            entries,
+        })
+        .into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
 }
 
-async fn chat_history(
+async fn chat_inbox(
     State(app): State<Arc<state::AppState>>,
-    Query(query): Query<ChatHistoryQuery>,
+    Query(query): Query<InboxQuery>,
 ) -> impl IntoResponse {
-    let room_id = if query.room_id.trim().is_empty() {
-        "global".to_string()
-    } else {
-        query.room_id
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
     };
 
-    let limit = query.limit.unwrap_or(100).clamp(1, 500);
-
-    match services::chat::history(&app.pg, &room_id, limit).await {
-        Ok(rows) => Json(rows).into_response(),
+    match services::chat::inbox(&app.pg, user_id).await {
+        Ok(items) => Json(items).into_response(),
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     }
 }
@@ -908,6 +8265,9 @@ async fn chat_room_state(
     } else {
         query.room_id
     };
+    if !services::chat::can_view(&room_id, user_id) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
 
     let unread_count = match services::chat::unread_count(&app.pg, &room_id, user_id).await {
         Ok(value) => value,
@@ -919,6 +8279,11 @@ async fn chat_room_state(
         Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     };
 
+    let threads = match services::chat::followed_thread_unreads(&app.pg, &room_id, user_id).await {
+        Ok(rows) => rows,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
     let mut members = Vec::with_capacity(member_ids.len());
     if let Ok(mut conn) = app.redis.get().await {
         for id in member_ids {
@@ -931,10 +8296,21 @@ async fn chat_room_state(
         }
     }
 
+    let typing = if query.include_typing.unwrap_or(false) {
+        services::typing::typing_users(&app.redis, &room_id)
+            .await
+            .ok()
+            .map(|users| users.into_iter().filter(|id| *id != user_id.to_string()).collect())
+    } else {
+        None
+    };
+
     Json(RoomStateResponse {
         room_id,
         unread_count,
         members,
+        threads,
+        typing,
     })
     .into_response()
 }
@@ -952,48 +8328,76 @@ async fn chat_mark_read(
     } else {
         body.room_id
     };
+    if !services::chat::can_view(&room_id, user_id) {
+        return StatusCode::FORBIDDEN;
+    }
 
-    match services::chat::mark_read(&app.pg, &room_id, user_id).await {
-        Ok(_) => StatusCode::ACCEPTED,
+    match services::chat::mark_read(&app.pg, &room_id, user_id, body.message_id).await {
+        Ok(Some(message_id)) => {
+            services::chat::broadcast_receipt(&app, &room_id, message_id, user_id, shared::ReceiptKind::Read);
+            StatusCode::ACCEPTED
+        }
+        Ok(None) if body.message_id.is_some() => StatusCode::NOT_FOUND,
+        Ok(None) => StatusCode::ACCEPTED,
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
 
-async fn send_invite(
+async fn chat_receipts(
     State(app): State<Arc<state::AppState>>,
-    Json(body): Json<InviteBody>,
+    Query(query): Query<ReceiptsQuery>,
 ) -> impl IntoResponse {
-    let Ok(from_user) = services::auth::parse_jwt(&body.token, &app.jwt) else {
-        return StatusCode::UNAUTHORIZED;
-    };
-    let Ok(to_user) = Uuid::parse_str(&body.to_user) else {
-        return StatusCode::BAD_REQUEST;
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
     };
 
-    let mode = if body.mode.trim().is_empty() {
-        "duel".to_string()
+    let room_id = if query.room_id.trim().is_empty() {
+        "global".to_string()
     } else {
-        body.mode
+        query.room_id
     };
+    if !services::chat::can_view(&room_id, user_id) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
 
-    let Ok(invite_id) = services::invite::create(&app.pg, from_user, to_user, &mode).await else {
-        return StatusCode::INTERNAL_SERVER_ERROR;
-    };
+    match services::chat::message_exists(&app.pg, &room_id, query.message_id).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
 
-    let packet = shared::RealtimePacket::Invite(shared::InviteEvent {
-        invite_id,
-        from_user,
-        to_user,
-        mode,
-        status: "pending".to_string(),
-        ts: chrono::Utc::now(),
-    });
+    match services::chat::receipts(&app.pg, &room_id, query.message_id).await {
+        Ok(receipts) => Json(ReceiptsResponse {
+            message_id: query.message_id,
+            receipts,
+        })
+        .into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
 
-    if let Ok(payload) = rmp_serde::to_vec(&packet) {
-        let _ = app.realtime_tx.send(payload);
+fn invite_error(err: services::invite::InviteError) -> Response {
+    if let services::invite::InviteError::Storage(ref cause) = err {
+        tracing::error!(?cause, "invite storage failed");
     }
+    (err.status_code(), Json(ApiError { error: err.message() })).into_response()
+}
+
+async fn send_invite(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<InviteBody>,
+) -> Response {
+    let Ok(from_user) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let Ok(to_user) = Uuid::parse_str(body.to_user.trim()) else {
+        return invite_error(services::invite::InviteError::Invalid("to_user must be a user id"));
+    };
 
-    StatusCode::ACCEPTED
+    match services::invite::create(&app, from_user, to_user, &body.mode).await {
+        Ok(event) => (StatusCode::ACCEPTED, Json(event)).into_response(),
+        Err(err) => invite_error(err),
+    }
 }
 
 async fn invite_pending(
@@ -1013,40 +8417,262 @@ async fn invite_pending(
 async fn invite_respond(
     State(app): State<Arc<state::AppState>>,
     Json(body): Json<InviteRespondBody>,
-) -> impl IntoResponse {
-    let Ok(to_user) = services::auth::parse_jwt(&body.token, &app.jwt) else {
-        return StatusCode::UNAUTHORIZED;
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let Ok(invite_id) = Uuid::parse_str(&body.invite_id) else {
+        return invite_error(services::invite::InviteError::Invalid("invite_id must be a uuid"));
+    };
+
+    let next = match body.action.as_str() {
+        "accept" => shared::InviteStatus::Accepted,
+        "reject" => shared::InviteStatus::Rejected,
+        _ => return invite_error(services::invite::InviteError::Invalid("action must be accept or reject")),
+    };
+
+    match services::invite::transition(&app, invite_id, user_id, next).await {
+        Ok((invite, match_ready)) => (StatusCode::ACCEPTED, Json(InviteRespondResponse { invite, match_ready })).into_response(),
+        Err(err) => invite_error(err),
+    }
+}
+
+async fn invite_cancel(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<InviteCancelBody>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
     };
     let Ok(invite_id) = Uuid::parse_str(&body.invite_id) else {
-        return StatusCode::BAD_REQUEST;
+        return invite_error(services::invite::InviteError::Invalid("invite_id must be a uuid"));
+    };
+
+    match services::invite::transition(&app, invite_id, user_id, shared::InviteStatus::Cancelled).await {
+        Ok((event, _)) => (StatusCode::ACCEPTED, Json(event)).into_response(),
+        Err(err) => invite_error(err),
+    }
+}
+
+async fn match_active(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<MatchActiveQuery>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
     };
 
-    let status = match body.action.as_str() {
-        "accept" => "accepted",
-        "reject" => "rejected",
-        _ => return StatusCode::BAD_REQUEST,
+    match services::matches::active_for_user(&app.pg, user_id).await {
+        Ok(ready) => Json(ready).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn match_replay(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<MatchReplayQuery>,
+) -> Response {
+    if services::auth::parse_jwt(&query.token, &app.jwt).is_err() {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    match services::replay::info(&app, query.match_id).await {
+        Ok(Some(info)) => Json(info).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!(?err, "replay lookup failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn match_replays(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<MatchActiveQuery>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
     };
 
-    let Ok(updated) = services::invite::respond(&app.pg, invite_id, to_user, status).await else {
-        return StatusCode::INTERNAL_SERVER_ERROR;
+    match services::replay::recent_for_user(&app, user_id, services::replay::LIST_LIMIT).await {
+        Ok(list) => Json(list).into_response(),
+        Err(err) => {
+            tracing::error!(?err, "replay list failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn leaderboard(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<LeaderboardQuery>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let mode = match services::invite::normalize_mode(&query.mode) {
+        Ok(mode) => mode,
+        Err(err) => return invite_error(err),
+    };
+    let limit = query.limit.unwrap_or(20).clamp(1, services::ratings::MAX_LIMIT);
+
+    let (entries, radius_km) = if query.near {
+        let point = match (query.lon, query.lat) {
+            (Some(lon), Some(lat)) => Some((lon, lat)),
+            _ => match services::matchmaking::last_location(&app.pg, user_id).await {
+                Ok(point) => point,
+                Err(err) => {
+                    tracing::error!(?err, "leaderboard location lookup failed");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            },
+        };
+        let Some(point) = point else {
+            let error = "share your location to see nearby rankings".to_string();
+            return (StatusCode::BAD_REQUEST, Json(ApiError { error })).into_response();
+        };
+        let radius_km = query
+            .radius_km
+            .filter(|r| r.is_finite() && *r > 0.0)
+            .unwrap_or(services::ratings::DEFAULT_RADIUS_KM)
+            .min(services::ratings::MAX_RADIUS_KM);
+        (services::ratings::near(&app, &mode, point, radius_km, limit).await, Some(radius_km))
+    } else {
+        (services::ratings::global(&app, &mode, query.offset, limit).await, None)
     };
-    let Some((from_user, to_user, mode)) = updated else {
-        return StatusCode::NOT_FOUND;
+
+    let result = match entries {
+        Ok(entries) => services::ratings::standing(&app, &mode, user_id).await.map(|me| (entries, me)),
+        Err(err) => Err(err),
     };
+    match result {
+        Ok((entries, me)) => Json(LeaderboardResponse { mode, radius_km, entries, me }).into_response(),
+        Err(err) => {
+            tracing::error!(?err, "leaderboard failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
 
-    let packet = shared::RealtimePacket::Invite(shared::InviteEvent {
-        invite_id,
-        from_user,
-        to_user,
-        mode,
-        status: status.to_string(),
-        ts: chrono::Utc::now(),
-    });
-    if let Ok(payload) = rmp_serde::to_vec(&packet) {
-        let _ = app.realtime_tx.send(payload);
+async fn match_live(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<MatchActiveQuery>,
+) -> Response {
+    if services::auth::parse_jwt(&query.token, &app.jwt).is_err() {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    match services::spectate::live(&app.pg, services::spectate::LIVE_LIMIT).await {
+        Ok(list) => Json(list).into_response(),
+        Err(err) => {
+            tracing::error!(?err, "live match list failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn matchmaking_error(err: services::matchmaking::MatchmakingError) -> Response {
+    if let services::matchmaking::MatchmakingError::Storage(ref cause) = err {
+        tracing::error!(?cause, "matchmaking storage failed");
+    }
+    (err.status_code(), Json(ApiError { error: err.message() })).into_response()
+}
+
+async fn matchmaking_enqueue(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<MatchmakingEnqueueBody>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    match services::matchmaking::enqueue(&app, user_id, &body.mode, body.near).await {
+        Ok(status) => (StatusCode::ACCEPTED, Json(status)).into_response(),
+        Err(err) => matchmaking_error(err),
+    }
+}
+
+async fn matchmaking_leave(
+    State(app): State<Arc<state::AppState>>,
+    Json(body): Json<MatchmakingTokenBody>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&body.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    match services::matchmaking::leave(&app.redis, user_id).await {
+        Ok(_) => Json(services::matchmaking::status_of(None)).into_response(),
+        Err(err) => matchmaking_error(err.into()),
+    }
+}
+
+async fn matchmaking_status(
+    State(app): State<Arc<state::AppState>>,
+    Query(query): Query<MatchActiveQuery>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    match services::matchmaking::status(&app.redis, user_id).await {
+        Ok(status) => Json(status).into_response(),
+        Err(err) => matchmaking_error(err.into()),
+    }
+}
+
+async fn match_ws_handler(
+    ws: WebSocketUpgrade,
+    Query(query): Query<MatchWsQuery>,
+    State(app): State<Arc<state::AppState>>,
+) -> Response {
+    let Ok(user_id) = services::auth::parse_jwt(&query.token, &app.jwt) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let ready = match services::matches::join(&app, query.match_id, user_id).await {
+        Ok(Some((ready, started))) => {
+            if started {
+                tokio::spawn(services::runtime::run(app.clone(), ready.clone()));
+            }
+            ready
+        }
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!(?err, "match join failed");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    ws.on_upgrade(move |socket| services::matches::connect(socket, app, ready, user_id))
+}
+
+// Spectators never go through `matches::join`, so they hold no slot and have no input subject.
+async fn match_watch_handler(
+    ws: WebSocketUpgrade,
+    Query(query): Query<MatchWsQuery>,
+    State(app): State<Arc<state::AppState>>,
+) -> Response {
+    if services::auth::parse_jwt(&query.token, &app.jwt).is_err() {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let ready = match services::matches::load(&app.pg, query.match_id).await {
+        Ok(Some(ready)) => ready,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!(?err, "match lookup failed");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if ready.state != shared::MatchState::Running {
+        let error = "match is not live".to_string();
+        return (StatusCode::CONFLICT, Json(ApiError { error })).into_response();
     }
 
-    StatusCode::ACCEPTED
+    ws.on_upgrade(move |socket| async move {
+        let frames = services::spectate::watch(&app, &ready);
+        services::spectate::connect(socket, frames).await;
+    })
 }
 
 async fn ws_handler(
@@ -1080,6 +8706,48 @@ async fn bootstrap_schema(pg: &PgPool) -> anyhow::Result<()> {
         }
         sqlx::query(sql).execute(pg).await?;
     }
+    run_migrations(pg).await
+}
+
+// Returns whether this boot gets to apply `name`. The row is written inside the migration's
+// transaction, so a failed migration is retried and concurrent boots wait for the winner.
+async fn claim_migration(conn: &mut sqlx::PgConnection, name: &str) -> anyhow::Result<bool> {
+    let claimed = sqlx::query("INSERT INTO schema_migrations(name) VALUES ($1) ON CONFLICT (name) DO NOTHING")
+        .bind(name)
+        .execute(&mut *conn)
+        .await?;
+    Ok(claimed.rows_affected() > 0)
+}
+
+// Data fixes that must run once. init.sql is applied on every boot, so it only holds
+// idempotent DDL.
+async fn run_migrations(pg: &PgPool) -> anyhow::Result<()> {
+    let mut tx = pg.begin().await?;
+    if claim_migration(&mut tx, "drop_dm_outsider_memberships").await? {
+        sqlx::query("DELETE FROM room_memberships WHERE room_id LIKE 'dm:%' AND position(user_id::text IN room_id) = 0")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM room_member_reads WHERE room_id LIKE 'dm:%' AND position(user_id::text IN room_id) = 0")
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    let mut tx = pg.begin().await?;
+    if claim_migration(&mut tx, "backfill_room_stats").await? {
+        services::chat::backfill_stats(&mut tx).await?;
+    }
+    tx.commit().await?;
+
+    // Markdown rows indexed from their raw text before `search::backfill` used plain text;
+    // clearing the vectors has the startup backfill index them again.
+    let mut tx = pg.begin().await?;
+    if claim_migration(&mut tx, "reindex_markdown_search").await? {
+        sqlx::query("UPDATE room_messages SET search_vector = NULL WHERE format = 'markdown' AND redacted_at IS NULL")
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
     Ok(())
 }
 
@@ -1134,8 +8802,32 @@ pub async fn run() -> anyhow::Result<()> {
 
     let clickhouse = clickhouse::Client::default().with_url(clickhouse_url).with_database("default");
 
-    let aws_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
-    let r2 = aws_sdk_s3::Client::new(&aws_config);
+    let r2_bucket = std::env::var("R2_BUCKET").unwrap_or_else(|_| "platform-assets".to_string());
+    let r2_region = std::env::var("R2_REGION").unwrap_or_else(|_| "auto".to_string());
+    let mut aws_loader = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .region(aws_config::Region::new(r2_region));
+    if let (Ok(access_key), Ok(secret_key)) = (
+        std::env::var("R2_ACCESS_KEY_ID"),
+        std::env::var("R2_SECRET_ACCESS_KEY"),
+    ) {
+        if !access_key.is_empty() && !secret_key.is_empty() {
+            aws_loader = aws_loader.credentials_provider(aws_sdk_s3::config::Credentials::new(
+                access_key,
+                secret_key,
+                None,
+                None,
+                "r2-env",
+            ));
+        }
+    }
+    let aws_config = aws_loader.load().await;
+    let mut s3_config = aws_sdk_s3::config::Builder::from(&aws_config);
+    if let Ok(endpoint) = std::env::var("R2_ENDPOINT") {
+        if !endpoint.is_empty() {
+            s3_config = s3_config.endpoint_url(endpoint).force_path_style(true);
+        }
+    }
+    let r2 = aws_sdk_s3::Client::from_conf(s3_config.build());
 
     let private_key_pem = std::env::var("JWT_PRIVATE_KEY_PEM").unwrap_or_default();
     let public_key_pem = std::env::var("JWT_PUBLIC_KEY_PEM").unwrap_or_default();
@@ -1163,11 +8855,32 @@ pub async fn run() -> anyhow::Result<()> {
         jetstream,
         clickhouse,
         r2,
+        r2_bucket,
         jwt,
         realtime_tx,
     });
 
-    let schema = Schema::build(QueryRoot, EmptyMutation, EmptySubscription).finish();
+    let schema = Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .data(app_state.clone())
+        .finish();
+
+    let backfill_pg = app_state.pg.clone();
+    tokio::spawn(async move {
+        match services::search::backfill(&backfill_pg).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(count, "search vectors backfilled"),
+            Err(err) => tracing::error!(?err, "search backfill failed"),
+        }
+    });
+
+    tokio::spawn(services::mailbox::run_cleanup(app_state.pg.clone()));
+    tokio::spawn(services::polls::run_closer(app_state.clone()));
+    tokio::spawn(services::scheduled::run_dispatcher(app_state.clone()));
+    tokio::spawn(services::scheduled::run_expiry(app_state.clone()));
+    tokio::spawn(services::invite::run_expiry(app_state.clone()));
+    tokio::spawn(services::matches::run_abandoner(app_state.clone()));
+    tokio::spawn(services::matchmaking::run_matcher(app_state.clone()));
+    tokio::spawn(services::local::run_cleanup(app_state.clone()));
 
     let consumer_state = app_state.clone();
     tokio::spawn(async move {
@@ -1206,13 +8919,45 @@ pub async fn run() -> anyhow::Result<()> {
         .route("/api/position", post(ingest_position_http))
         .route("/api/chat/send", post(send_chat))
         .route("/api/chat/history", get(chat_history))
+        .route("/api/chat/attachments/presign", post(chat_attachment_presign))
+        .route("/api/chat/attachments/confirm", post(chat_attachment_confirm))
         .route("/api/chat/room-state", get(chat_room_state))
         .route("/api/chat/mark-read", post(chat_mark_read))
+        .route("/api/chat/receipts", get(chat_receipts))
+        .route("/api/chat/local-rooms", get(chat_local_rooms))
+        .route("/api/map/chat-activity", get(map_chat_activity))
+        .route("/api/chat/search", get(chat_search))
+        .route("/api/chat/thread", get(chat_thread))
+        .route("/api/chat/thread/mark-read", post(chat_thread_mark_read))
+        .route("/api/chat/inbox", get(chat_inbox))
+        .route("/api/chat/scheduled", get(chat_scheduled_list))
+        .route("/api/chat/scheduled/cancel", post(chat_scheduled_cancel))
+        .route("/api/chat/polls", get(polls_list).post(poll_create))
+        .route("/api/chat/polls/vote", post(poll_vote))
+        .route("/api/chat/polls/close", post(poll_close))
+        .route("/api/keys/bundle", get(keys_fetch).post(keys_publish))
+        .route("/api/notifications", get(notifications_inbox))
+        .route("/api/notifications/mark-read", post(notifications_mark_read))
+        .route("/api/moderation/action", post(moderation_action))
+        .route("/api/moderation/settings", post(moderation_settings))
+        .route("/api/moderation/moderators", post(moderation_moderators))
+        .route("/api/moderation/log", get(moderation_log))
         .route("/api/invite/send", post(send_invite))
         .route("/api/invite/pending", get(invite_pending))
         .route("/api/invite/respond", post(invite_respond))
+        .route("/api/invite/cancel", post(invite_cancel))
+        .route("/api/match/active", get(match_active))
+        .route("/api/match/replay", get(match_replay))
+        .route("/api/match/replays", get(match_replays))
+        .route("/api/match/live", get(match_live))
+        .route("/api/leaderboard", get(leaderboard))
+        .route("/api/matchmaking/enqueue", post(matchmaking_enqueue))
+        .route("/api/matchmaking/leave", post(matchmaking_leave))
+        .route("/api/matchmaking/status", get(matchmaking_status))
         .route("/graphql", post(graphql_handler))
         .route("/ws", get(ws_handler))
+        .route("/ws/match", get(match_ws_handler))
+        .route("/ws/match/watch", get(match_watch_handler))
         .route("/metrics", get(|| async move { metric_handle.render() }))
         .fallback_service(site_service)
         .layer(prometheus_layer)
@@ -1,13 +1,384 @@
+use std::collections::HashSet;
+
 use bevy::prelude::*;
+use wasm_bindgen::prelude::*;
+
+mod net;
+
+use net::Connection;
+
+use sim::duel;
+use sim::replay::Playback;
+use sim::Fx;
+use sim::rollback::Session;
+
+const BULLET_SIZE: f32 = 8.0;
+const REMOTE_SMOOTHING: f32 = 15.0;
+
+const SLOT_COLORS: [Color; 2] = [Color::srgb(0.25, 0.6, 1.0), Color::srgb(1.0, 0.55, 0.2)];
+
+#[derive(Resource)]
+struct LocalPlayer {
+    slot: usize,
+    user_id: String,
+}
+
+// The local rollback session starts with the first server snapshot, which means both
+// players are connected and the server is accepting frames.
+#[derive(Resource, Default)]
+struct Rollback {
+    session: Option<Session>,
+    desynced: bool,
+}
+
+// Playback of a recorded match, stepped at the recording's tick rate.
+#[derive(Resource)]
+struct Replaying(Playback);
+
+// The state currently on screen, from the rollback session, a replay or a spectated match.
+#[derive(Resource, Default)]
+struct Arena(Option<duel::State>);
+
+// Latest server snapshot; only its result fields matter to players.
+#[derive(Resource, Default)]
+struct Latest {
+    snapshot: Option<shared::GameSnapshot>,
+}
+
+#[derive(Component)]
+struct PlayerSprite(usize);
+
+#[derive(Component)]
+struct HpBar(usize);
+
+#[derive(Component)]
+struct BulletSprite(u32);
+
+fn arena() -> Vec2 {
+    Vec2::new(duel::ARENA_W.to_f32(), duel::ARENA_H.to_f32())
+}
+
+fn player_radius() -> f32 {
+    duel::PLAYER_RADIUS.to_f32()
+}
+
+fn to_screen(x: f32, y: f32) -> Vec2 {
+    Vec2::new(x - arena().x / 2.0, arena().y / 2.0 - y)
+}
+
+fn arena_app(canvas: String) -> App {
+    let mut app = App::new();
+    app.add_plugins(DefaultPlugins.set(WindowPlugin {
+        primary_window: Some(Window {
+            canvas: Some(canvas),
+            fit_canvas_to_parent: true,
+            prevent_default_event_handling: true,
+            ..default()
+        }),
+        ..default()
+    }))
+    .insert_resource(ClearColor(Color::srgb(0.04, 0.05, 0.08)))
+    .insert_resource(Time::<Fixed>::from_hz(duel::TICK_HZ as f64))
+    .init_resource::<Arena>()
+    .add_systems(Startup, setup)
+    .add_systems(Update, (render_players, render_bullets).chain());
+    app
+}
+
+/// Starts the match client on `canvas` (a CSS selector). `ws_url` is the full
+/// `/ws/match` address including the JWT; `slot` is the player's index in the match.
+#[wasm_bindgen]
+pub fn start_match(canvas: String, ws_url: String, slot: u32, user_id: String) -> Result<(), JsValue> {
+    let connection = Connection::open(&ws_url)?;
+
+    arena_app(canvas)
+        .insert_resource(LocalPlayer {
+            slot: slot as usize,
+            user_id,
+        })
+        .init_resource::<Rollback>()
+        .init_resource::<Latest>()
+        .insert_non_send_resource(connection)
+        .add_systems(FixedUpdate, exchange_frames)
+        .add_systems(Update, show_result.after(render_bullets))
+        .run();
+    Ok(())
+}
+
+/// Plays a recorded match (the bytes behind a replay URL) on `canvas`. The match is
+/// resimulated from its inputs, so what is shown is exactly what the server decided.
+#[wasm_bindgen]
+pub fn start_replay(canvas: String, bytes: Vec<u8>) -> Result<(), JsValue> {
+    let replay = shared::replay::decode(&bytes).ok_or_else(|| JsValue::from_str("invalid replay"))?;
+    let playback = Playback::new(&replay).ok_or_else(|| JsValue::from_str("unsupported replay mode"))?;
+
+    arena_app(canvas)
+        .insert_resource(Replaying(playback))
+        .add_systems(FixedUpdate, play_back)
+        .run();
+    Ok(())
+}
+
+/// Watches a live match on `canvas`. `ws_url` is the `/ws/match/watch` address; the
+/// server sends delayed snapshots only and ignores anything sent back.
+#[wasm_bindgen]
+pub fn start_spectate(canvas: String, ws_url: String) -> Result<(), JsValue> {
+    let connection = Connection::open(&ws_url)?;
 
-#[wasm_bindgen::prelude::wasm_bindgen]
-pub fn run_game() {
-    App::new()
-        .add_plugins(DefaultPlugins)
-        .add_systems(Startup, setup)
+    arena_app(canvas)
+        .init_resource::<Latest>()
+        .insert_non_send_resource(connection)
+        .add_systems(Update, follow_snapshots.before(render_players))
         .run();
+    Ok(())
 }
 
 fn setup(mut commands: Commands) {
     commands.spawn(Camera2dBundle::default());
+    commands.spawn(SpriteBundle {
+        sprite: Sprite {
+            color: Color::srgb(0.09, 0.11, 0.16),
+            custom_size: Some(arena()),
+            ..default()
+        },
+        transform: Transform::from_xyz(0.0, 0.0, -1.0),
+        ..default()
+    });
+
+    for (slot, color) in SLOT_COLORS.iter().enumerate() {
+        commands.spawn((
+            SpriteBundle {
+                sprite: Sprite {
+                    color: *color,
+                    custom_size: Some(Vec2::splat(player_radius() * 2.0)),
+                    ..default()
+                },
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+            PlayerSprite(slot),
+        ));
+        commands.spawn((
+            SpriteBundle {
+                sprite: Sprite {
+                    color: Color::srgb(0.3, 0.9, 0.4),
+                    custom_size: Some(Vec2::new(player_radius() * 2.5, 4.0)),
+                    ..default()
+                },
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+            HpBar(slot),
+        ));
+    }
+}
+
+// One rollback frame per fixed tick: apply the peer's frames, sample the keyboard, step,
+// then send every input the peer has not acknowledged yet.
+fn exchange_frames(
+    connection: NonSend<Connection>,
+    keys: Res<ButtonInput<KeyCode>>,
+    local: Res<LocalPlayer>,
+    mut latest: ResMut<Latest>,
+    mut rollback: ResMut<Rollback>,
+    mut arena: ResMut<Arena>,
+) {
+    let rollback = &mut *rollback;
+    for packet in connection.drain() {
+        match packet {
+            shared::GamePacket::Snapshot(snapshot) => {
+                rollback.session.get_or_insert_with(|| Session::new(local.slot));
+                latest.snapshot = Some(snapshot);
+            }
+            shared::GamePacket::Frames(frames) => {
+                if let Some(Err(desync)) = rollback.session.as_mut().map(|s| s.receive(&frames)) {
+                    warn!("desync with peer at frame {}: {:x} vs {:x}", desync.frame, desync.local, desync.remote);
+                    rollback.desynced = true;
+                }
+            }
+            shared::GamePacket::Desync(check) => {
+                warn!("desync with server at frame {}", check.frame);
+                rollback.desynced = true;
+            }
+            shared::GamePacket::Input(_) => {}
+        }
+    }
+
+    let Some(session) = rollback.session.as_mut() else {
+        return;
+    };
+    if !connection.is_open() || latest.snapshot.as_ref().is_some_and(|s| s.finished) {
+        return;
+    }
+    let axis = |neg: [KeyCode; 2], pos: [KeyCode; 2]| -> i8 { keys.any_pressed(pos) as i8 - keys.any_pressed(neg) as i8 };
+    let input = shared::PlayerInput {
+        seq: 0,
+        move_x: axis([KeyCode::KeyA, KeyCode::ArrowLeft], [KeyCode::KeyD, KeyCode::ArrowRight]),
+        move_y: axis([KeyCode::KeyW, KeyCode::ArrowUp], [KeyCode::KeyS, KeyCode::ArrowDown]),
+        fire: keys.pressed(KeyCode::Space),
+    };
+    if let Err(desync) = session.advance(input) {
+        warn!("desync with peer at frame {}: {:x} vs {:x}", desync.frame, desync.local, desync.remote);
+        rollback.desynced = true;
+    }
+    arena.0 = Some(session.state().clone());
+    // Sent every tick, stalled or not: it repeats unacknowledged frames and doubles as the heartbeat.
+    connection.send(&shared::GamePacket::Frames(session.outgoing()));
+}
+
+// Rebuilds enough of the match state to draw it. Snapshot velocities are per second.
+fn state_from_snapshot(snapshot: &shared::GameSnapshot) -> duel::State {
+    let per_tick = |v: f32| Fx::from_f32(v / duel::TICK_HZ as f32);
+    let mut state = duel::State::new();
+    state.tick = snapshot.tick;
+    for (player, p) in state.players.iter_mut().zip(&snapshot.players) {
+        player.x = Fx::from_f32(p.x);
+        player.y = Fx::from_f32(p.y);
+        player.vx = per_tick(p.vx);
+        player.vy = per_tick(p.vy);
+        player.hp = p.hp;
+        player.score = p.score;
+    }
+    state.bullets = snapshot
+        .entities
+        .iter()
+        .filter(|e| e.kind == duel::BULLET_KIND)
+        .map(|e| duel::Bullet {
+            id: e.id,
+            owner: e.owner as usize,
+            x: Fx::from_f32(e.x),
+            y: Fx::from_f32(e.y),
+            vx: per_tick(e.vx),
+            vy: per_tick(e.vy),
+            ttl: duel::BULLET_TTL_TICKS,
+        })
+        .collect();
+    state
+}
+
+// Spectators draw whatever the server last sent; once the match is over the arena is
+// tinted with the winner's side colour.
+fn follow_snapshots(
+    connection: NonSend<Connection>,
+    mut latest: ResMut<Latest>,
+    mut arena: ResMut<Arena>,
+    mut clear: ResMut<ClearColor>,
+) {
+    for packet in connection.drain() {
+        if let shared::GamePacket::Snapshot(snapshot) = packet {
+            arena.0 = Some(state_from_snapshot(&snapshot));
+            latest.snapshot = Some(snapshot);
+        }
+    }
+    if !latest.snapshot.as_ref().is_some_and(|s| s.finished) {
+        return;
+    }
+    clear.0 = match arena.0.as_ref().and_then(|state| state.outcome()) {
+        Some(sim::Outcome::Winner(0)) => Color::srgb(0.05, 0.14, 0.3),
+        Some(sim::Outcome::Winner(_)) => Color::srgb(0.3, 0.14, 0.05),
+        _ => Color::srgb(0.2, 0.2, 0.22),
+    };
+}
+
+fn play_back(mut replaying: ResMut<Replaying>, mut arena: ResMut<Arena>) {
+    if arena.0.is_some() && !replaying.0.step() {
+        return;
+    }
+    arena.0 = Some(replaying.0.state().clone());
+}
+
+// The local player is drawn exactly where the session has it; everyone else is eased
+// toward their position so rollback corrections don't snap.
+fn render_players(
+    local: Option<Res<LocalPlayer>>,
+    arena: Res<Arena>,
+    time: Res<Time>,
+    mut players: Query<(&PlayerSprite, &mut Transform, &mut Visibility), Without<HpBar>>,
+    mut bars: Query<(&HpBar, &mut Transform, &mut Visibility, &mut Sprite), Without<PlayerSprite>>,
+) {
+    let Some(state) = arena.0.as_ref() else {
+        return;
+    };
+    let local_slot = local.map(|l| l.slot);
+    let states = state.players();
+    let blend = 1.0 - (-REMOTE_SMOOTHING * time.delta_seconds()).exp();
+
+    let mut drawn = [Vec2::ZERO; 2];
+    for (PlayerSprite(slot), mut transform, mut visibility) in &mut players {
+        let Some(state) = states.get(*slot) else {
+            continue;
+        };
+        *visibility = if state.hp > 0 { Visibility::Visible } else { Visibility::Hidden };
+        let target = to_screen(state.x, state.y);
+        let current = transform.translation.truncate();
+        let next = if Some(*slot) == local_slot || *visibility == Visibility::Hidden {
+            target
+        } else {
+            current.lerp(target, blend)
+        };
+        transform.translation = next.extend(1.0);
+        if let Some(slot_pos) = drawn.get_mut(*slot) {
+            *slot_pos = next;
+        }
+    }
+
+    for (HpBar(slot), mut transform, mut visibility, mut sprite) in &mut bars {
+        let Some(state) = states.get(*slot) else {
+            continue;
+        };
+        *visibility = if state.hp > 0 { Visibility::Visible } else { Visibility::Hidden };
+        let width = player_radius() * 2.5 * (state.hp.max(0) as f32 / duel::MAX_HP as f32);
+        sprite.custom_size = Some(Vec2::new(width, 4.0));
+        let anchor = drawn.get(*slot).copied().unwrap_or_default();
+        transform.translation = (anchor + Vec2::new(0.0, player_radius() + 8.0)).extend(2.0);
+    }
+}
+
+fn render_bullets(mut commands: Commands, arena: Res<Arena>, mut bullets: Query<(Entity, &BulletSprite, &mut Transform)>) {
+    let Some(state) = arena.0.as_ref() else {
+        return;
+    };
+    let entities = state.entities();
+    let mut shown = HashSet::new();
+
+    for (entity, BulletSprite(id), mut transform) in &mut bullets {
+        match entities.iter().find(|e| e.id == *id) {
+            Some(e) => {
+                transform.translation = to_screen(e.x, e.y).extend(0.5);
+                shown.insert(*id);
+            }
+            None => commands.entity(entity).despawn(),
+        }
+    }
+
+    for e in entities.iter().filter(|e| !shown.contains(&e.id)) {
+        commands.spawn((
+            SpriteBundle {
+                sprite: Sprite {
+                    color: SLOT_COLORS.get(e.owner as usize).copied().unwrap_or(Color::WHITE),
+                    custom_size: Some(Vec2::splat(BULLET_SIZE)),
+                    ..default()
+                },
+                transform: Transform::from_translation(to_screen(e.x, e.y).extend(0.5)),
+                ..default()
+            },
+            BulletSprite(e.id),
+        ));
+    }
+}
+
+// The result always comes from the server's copy of the match. A desync only tints the
+// arena while playing, since the server's outcome stands either way.
+fn show_result(local: Res<LocalPlayer>, latest: Res<Latest>, rollback: Res<Rollback>, mut clear: ResMut<ClearColor>) {
+    let Some(snapshot) = latest.snapshot.as_ref().filter(|s| s.finished) else {
+        if rollback.desynced {
+            clear.0 = Color::srgb(0.25, 0.18, 0.04);
+        }
+        return;
+    };
+    clear.0 = match snapshot.winner.map(|w| w.to_string()) {
+        Some(winner) if winner == local.user_id => Color::srgb(0.05, 0.3, 0.12),
+        Some(_) => Color::srgb(0.35, 0.06, 0.08),
+        None => Color::srgb(0.2, 0.2, 0.22),
+    };
 }
@@ -0,0 +1,61 @@
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+
+// The socket lives on the main thread, so it is a non-send resource; the message
+// callback only queues decoded packets for the next frame to pick up.
+pub struct Connection {
+    ws: web_sys::WebSocket,
+    inbox: Rc<RefCell<VecDeque<shared::GamePacket>>>,
+    _on_message: Closure<dyn FnMut(web_sys::MessageEvent)>,
+}
+
+impl Connection {
+    pub fn open(url: &str) -> Result<Self, JsValue> {
+        let ws = web_sys::WebSocket::new(url)?;
+        ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
+
+        let inbox = Rc::new(RefCell::new(VecDeque::new()));
+        let queue = inbox.clone();
+        let on_message = Closure::wrap(Box::new(move |event: web_sys::MessageEvent| {
+            let Ok(buf) = event.data().dyn_into::<js_sys::ArrayBuffer>() else {
+                return;
+            };
+            let bytes = js_sys::Uint8Array::new(&buf).to_vec();
+            if let Ok(packet) = rmp_serde::from_slice(&bytes) {
+                queue.borrow_mut().push_back(packet);
+            }
+        }) as Box<dyn FnMut(web_sys::MessageEvent)>);
+        ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        Ok(Self {
+            ws,
+            inbox,
+            _on_message: on_message,
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.ws.ready_state() == web_sys::WebSocket::OPEN
+    }
+
+    pub fn send(&self, packet: &shared::GamePacket) {
+        if !self.is_open() {
+            return;
+        }
+        if let Ok(bytes) = rmp_serde::to_vec(packet) {
+            let _ = self.ws.send_with_u8_array(&bytes);
+        }
+    }
+
+    pub fn drain(&self) -> Vec<shared::GamePacket> {
+        self.inbox.borrow_mut().drain(..).collect()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.ws.set_onmessage(None);
+        let _ = self.ws.close();
+    }
+}
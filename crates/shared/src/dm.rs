@@ -0,0 +1,31 @@
+use uuid::Uuid;
+
+pub const DM_ROOM_PREFIX: &str = "dm:";
+
+pub fn room_id(a: Uuid, b: Uuid) -> String {
+    let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+    format!("{DM_ROOM_PREFIX}{lo}:{hi}")
+}
+
+pub fn participants(room_id: &str) -> Option<(Uuid, Uuid)> {
+    let rest = room_id.strip_prefix(DM_ROOM_PREFIX)?;
+    let (lo, hi) = rest.split_once(':')?;
+    let lo = Uuid::parse_str(lo).ok()?;
+    let hi = Uuid::parse_str(hi).ok()?;
+    (lo < hi).then_some((lo, hi))
+}
+
+pub fn is_participant(room_id: &str, user_id: Uuid) -> bool {
+    participants(room_id).is_some_and(|(lo, hi)| user_id == lo || user_id == hi)
+}
+
+pub fn peer_of(room_id: &str, user_id: Uuid) -> Option<Uuid> {
+    let (lo, hi) = participants(room_id)?;
+    if user_id == lo {
+        Some(hi)
+    } else if user_id == hi {
+        Some(lo)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,205 @@
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+pub const LOCAL_ROOM_PRECISION: usize = 6;
+pub const LOCAL_ROOM_PREFIX: &str = "local:";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellBounds {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+impl CellBounds {
+    pub fn center(&self) -> (f64, f64) {
+        (
+            (self.min_lon + self.max_lon) / 2.0,
+            (self.min_lat + self.max_lat) / 2.0,
+        )
+    }
+
+    pub fn contains(&self, lon: f64, lat: f64) -> bool {
+        lon >= self.min_lon && lon <= self.max_lon && lat >= self.min_lat && lat <= self.max_lat
+    }
+}
+
+pub fn encode(lon: f64, lat: f64, precision: usize) -> String {
+    let (mut lon_lo, mut lon_hi) = (-180.0, 180.0);
+    let (mut lat_lo, mut lat_hi) = (-90.0, 90.0);
+    let lon = lon.clamp(-180.0, 180.0);
+    let lat = lat.clamp(-90.0, 90.0);
+
+    let mut out = String::with_capacity(precision);
+    let mut even = true;
+    let mut bits = 0_u8;
+    let mut idx = 0_usize;
+
+    while out.len() < precision {
+        if even {
+            let mid = (lon_lo + lon_hi) / 2.0;
+            if lon >= mid {
+                idx = idx * 2 + 1;
+                lon_lo = mid;
+            } else {
+                idx *= 2;
+                lon_hi = mid;
+            }
+        } else {
+            let mid = (lat_lo + lat_hi) / 2.0;
+            if lat >= mid {
+                idx = idx * 2 + 1;
+                lat_lo = mid;
+            } else {
+                idx *= 2;
+                lat_hi = mid;
+            }
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            out.push(BASE32[idx] as char);
+            bits = 0;
+            idx = 0;
+        }
+    }
+    out
+}
+
+pub fn decode(hash: &str) -> Option<CellBounds> {
+    if hash.is_empty() {
+        return None;
+    }
+
+    let (mut lon_lo, mut lon_hi) = (-180.0, 180.0);
+    let (mut lat_lo, mut lat_hi) = (-90.0, 90.0);
+    let mut even = true;
+
+    for c in hash.bytes() {
+        let value = BASE32.iter().position(|b| *b == c.to_ascii_lowercase())?;
+        for shift in (0..5).rev() {
+            let bit = (value >> shift) & 1 == 1;
+            if even {
+                let mid = (lon_lo + lon_hi) / 2.0;
+                if bit {
+                    lon_lo = mid;
+                } else {
+                    lon_hi = mid;
+                }
+            } else {
+                let mid = (lat_lo + lat_hi) / 2.0;
+                if bit {
+                    lat_lo = mid;
+                } else {
+                    lat_hi = mid;
+                }
+            }
+            even = !even;
+        }
+    }
+
+    Some(CellBounds {
+        min_lon: lon_lo,
+        min_lat: lat_lo,
+        max_lon: lon_hi,
+        max_lat: lat_hi,
+    })
+}
+
+pub fn neighbours(hash: &str) -> Vec<String> {
+    let Some(bounds) = decode(hash) else {
+        return Vec::new();
+    };
+    let (lon, lat) = bounds.center();
+    let width = bounds.max_lon - bounds.min_lon;
+    let height = bounds.max_lat - bounds.min_lat;
+
+    let mut out = Vec::with_capacity(8);
+    for dy in [1.0, 0.0, -1.0] {
+        for dx in [-1.0, 0.0, 1.0] {
+            if dx == 0.0 && dy == 0.0 {
+                continue;
+            }
+            let n_lat = lat + dy * height;
+            if !(-90.0..=90.0).contains(&n_lat) {
+                continue;
+            }
+            let mut n_lon = lon + dx * width;
+            if n_lon > 180.0 {
+                n_lon -= 360.0;
+            } else if n_lon < -180.0 {
+                n_lon += 360.0;
+            }
+            let cell = encode(n_lon, n_lat, hash.len());
+            if cell != hash && !out.contains(&cell) {
+                out.push(cell);
+            }
+        }
+    }
+    out
+}
+
+pub fn local_room_id(cell: &str) -> String {
+    format!("{LOCAL_ROOM_PREFIX}{cell}")
+}
+
+pub fn cell_of_room(room_id: &str) -> Option<&str> {
+    room_id
+        .strip_prefix(LOCAL_ROOM_PREFIX)
+        .filter(|cell| decode(cell).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_matches_reference_hashes() {
+        assert_eq!(encode(-5.6, 42.6, 5), "ezs42");
+        assert_eq!(encode(10.40744, 57.64911, 11), "u4pruydqqvj");
+        assert_eq!(encode(-180.0, -90.0, 6), "000000");
+        assert_eq!(encode(180.0, 90.0, 6), "zzzzzz");
+        assert_eq!(encode(540.0, 200.0, 6), "zzzzzz");
+
+        let bounds = decode(&encode(151.2093, -33.8688, LOCAL_ROOM_PRECISION)).unwrap();
+        assert!(bounds.contains(151.2093, -33.8688));
+    }
+
+    #[test]
+    fn neighbours_surround_the_cell() {
+        let cell = encode(2.3522, 48.8566, LOCAL_ROOM_PRECISION);
+        let around = neighbours(&cell);
+        assert_eq!(around.len(), 8);
+        assert!(!around.contains(&cell));
+
+        let bounds = decode(&cell).unwrap();
+        let (lon, lat) = bounds.center();
+        let width = bounds.max_lon - bounds.min_lon;
+        let height = bounds.max_lat - bounds.min_lat;
+        assert!(around.contains(&encode(lon + width, lat, cell.len())));
+        assert!(around.contains(&encode(lon, lat - height, cell.len())));
+        assert!(neighbours("not a cell").is_empty());
+    }
+
+    #[test]
+    fn neighbours_wrap_across_the_antimeridian() {
+        let east_edge = encode(179.999, 0.5, LOCAL_ROOM_PRECISION);
+        let west_edge = encode(-179.999, 0.5, LOCAL_ROOM_PRECISION);
+        assert!(neighbours(&east_edge).contains(&west_edge));
+        assert!(neighbours(&west_edge).contains(&east_edge));
+        assert_eq!(neighbours(&east_edge).len(), 8);
+    }
+
+    #[test]
+    fn neighbours_stop_at_the_poles() {
+        for (lon, lat) in [(10.0, 89.999), (10.0, -89.999), (180.0, 90.0), (-180.0, -90.0)] {
+            let cell = encode(lon, lat, LOCAL_ROOM_PRECISION);
+            let around = neighbours(&cell);
+            assert_eq!(around.len(), 5, "{cell}");
+            for n in &around {
+                let bounds = decode(n).unwrap();
+                assert!(bounds.min_lat >= -90.0 && bounds.max_lat <= 90.0);
+            }
+        }
+    }
+}
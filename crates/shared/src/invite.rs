@@ -0,0 +1,93 @@
+use crate::InviteStatus;
+
+pub fn as_str(status: InviteStatus) -> &'static str {
+    match status {
+        InviteStatus::Pending => "pending",
+        InviteStatus::Accepted => "accepted",
+        InviteStatus::Rejected => "rejected",
+        InviteStatus::Cancelled => "cancelled",
+        InviteStatus::Expired => "expired",
+    }
+}
+
+pub fn parse(raw: &str) -> Option<InviteStatus> {
+    match raw {
+        "pending" => Some(InviteStatus::Pending),
+        "accepted" => Some(InviteStatus::Accepted),
+        "rejected" => Some(InviteStatus::Rejected),
+        "cancelled" => Some(InviteStatus::Cancelled),
+        "expired" => Some(InviteStatus::Expired),
+        _ => None,
+    }
+}
+
+pub fn is_final(status: InviteStatus) -> bool {
+    status != InviteStatus::Pending
+}
+
+// Every invite starts pending and settles exactly once.
+pub fn can_transition(from: InviteStatus, to: InviteStatus) -> bool {
+    from == InviteStatus::Pending && is_final(to)
+}
+
+// Only the recipient answers, only the sender cancels, and expiry is left to the server.
+pub fn may_set(to: InviteStatus, is_sender: bool, is_recipient: bool) -> bool {
+    match to {
+        InviteStatus::Accepted | InviteStatus::Rejected => is_recipient,
+        InviteStatus::Cancelled => is_sender,
+        InviteStatus::Pending | InviteStatus::Expired => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [InviteStatus; 5] = [
+        InviteStatus::Pending,
+        InviteStatus::Accepted,
+        InviteStatus::Rejected,
+        InviteStatus::Cancelled,
+        InviteStatus::Expired,
+    ];
+
+    #[test]
+    fn only_pending_invites_settle() {
+        use InviteStatus::*;
+        let allowed = [(Pending, Accepted), (Pending, Rejected), (Pending, Cancelled), (Pending, Expired)];
+        for from in ALL {
+            for to in ALL {
+                assert_eq!(can_transition(from, to), allowed.contains(&(from, to)), "{from:?} -> {to:?}");
+            }
+        }
+        assert!(!can_transition(Cancelled, Accepted));
+        assert!(!can_transition(Expired, Accepted));
+        assert!(!can_transition(Accepted, Rejected));
+    }
+
+    #[test]
+    fn each_side_sets_only_its_own_answers() {
+        use InviteStatus::*;
+        // (status, sender may set, recipient may set)
+        let table = [
+            (Pending, false, false),
+            (Accepted, false, true),
+            (Rejected, false, true),
+            (Cancelled, true, false),
+            (Expired, false, false),
+        ];
+        for (to, sender, recipient) in table {
+            assert_eq!(may_set(to, true, false), sender, "sender -> {to:?}");
+            assert_eq!(may_set(to, false, true), recipient, "recipient -> {to:?}");
+            assert!(!may_set(to, false, false), "outsider -> {to:?}");
+        }
+    }
+
+    #[test]
+    fn status_names_round_trip() {
+        for status in ALL {
+            assert_eq!(parse(as_str(status)), Some(status));
+        }
+        assert_eq!(parse("Accepted"), None);
+    }
+}
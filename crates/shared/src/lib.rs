@@ -2,6 +2,13 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+pub mod dm;
+pub mod geocell;
+pub mod invite;
+pub mod markdown;
+pub mod matches;
+pub mod replay;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PositionUpdate {
     pub user_id: Uuid,
@@ -10,12 +17,100 @@ pub struct PositionUpdate {
     pub ts: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatAttachment {
+    pub id: Uuid,
+    #[serde(default)]
+    pub file_name: String,
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub size_bytes: i64,
+    #[serde(default)]
+    pub width: Option<i32>,
+    #[serde(default)]
+    pub height: Option<i32>,
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub room_id: String,
     pub from_user: Uuid,
     pub text: String,
     pub ts: DateTime<Utc>,
+    #[serde(default)]
+    pub id: Option<i64>,
+    #[serde(default)]
+    pub parent_id: Option<i64>,
+    #[serde(default)]
+    pub attachments: Vec<ChatAttachment>,
+    #[serde(default)]
+    pub client_id: Option<Uuid>,
+    #[serde(default)]
+    pub e2ee: Option<E2eePayload>,
+    #[serde(default)]
+    pub format: MessageFormat,
+    #[serde(default)]
+    pub event: Option<ChatEvent>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageFormat {
+    #[default]
+    Plain,
+    Markdown,
+    System,
+    Event,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatEvent {
+    pub name: String,
+    #[serde(default)]
+    pub params: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct E2eeKeyWrap {
+    pub user_id: Uuid,
+    pub prekey_id: i64,
+    pub ephemeral_key: String,
+    pub nonce: String,
+    pub wrapped_key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct E2eePayload {
+    pub version: u8,
+    pub nonce: String,
+    pub ciphertext: String,
+    pub recipients: Vec<E2eeKeyWrap>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyBundle {
+    pub user_id: Uuid,
+    pub identity_key: String,
+    pub prekey_id: i64,
+    pub prekey: String,
+    pub prekey_signature: String,
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InviteStatus {
+    #[default]
+    Pending,
+    Accepted,
+    Rejected,
+    Cancelled,
+    Expired,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,14 +119,265 @@ pub struct InviteEvent {
     pub from_user: Uuid,
     pub to_user: Uuid,
     pub mode: String,
-    pub status: String,
+    pub status: InviteStatus,
+    pub ts: DateTime<Utc>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchState {
+    #[default]
+    Waiting,
+    Running,
+    Finished,
+    Abandoned,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchReady {
+    pub match_id: Uuid,
+    #[serde(default)]
+    pub invite_id: Option<Uuid>,
+    pub mode: String,
+    pub players: Vec<Uuid>,
+    pub state: MatchState,
+    pub channel: String,
+    pub ws_path: String,
     pub ts: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerInput {
+    pub seq: u32,
+    pub move_x: i8,
+    pub move_y: i8,
+    pub fire: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlayerSnapshot {
+    pub x: f32,
+    pub y: f32,
+    pub vx: f32,
+    pub vy: f32,
+    pub hp: i32,
+    pub score: i32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EntitySnapshot {
+    pub id: u32,
+    pub kind: u8,
+    pub owner: u8,
+    pub x: f32,
+    pub y: f32,
+    pub vx: f32,
+    pub vy: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameSnapshot {
+    pub match_id: Uuid,
+    pub tick: u32,
+    pub tick_hz: u32,
+    pub acks: Vec<u32>,
+    pub players: Vec<PlayerSnapshot>,
+    pub entities: Vec<EntitySnapshot>,
+    pub finished: bool,
+    #[serde(default)]
+    pub winner: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameChecksum {
+    pub frame: u32,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameInputs {
+    pub slot: u8,
+    pub start: u32,
+    pub inputs: Vec<PlayerInput>,
+    pub ack: u32,
+    #[serde(default)]
+    pub checksum: Option<FrameChecksum>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputRun {
+    pub len: u32,
+    pub inputs: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Forfeit {
+    pub tick: u32,
+    pub slot: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub match_id: Uuid,
+    pub mode: String,
+    pub players: Vec<Uuid>,
+    pub tick_hz: u32,
+    pub frames: u32,
+    pub runs: Vec<InputRun>,
+    pub keyframes: Vec<GameSnapshot>,
+    pub forfeits: Vec<Forfeit>,
+    pub winner: Option<Uuid>,
+    pub recorded_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GamePacket {
+    Input(PlayerInput),
+    Snapshot(GameSnapshot),
+    Frames(FrameInputs),
+    Desync(FrameChecksum),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingEvent {
+    pub room_id: String,
+    pub user_id: Uuid,
+    pub typing: bool,
+    pub ts: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiptEvent {
+    pub room_id: String,
+    pub message_id: i64,
+    pub user_id: Uuid,
+    pub kind: ReceiptKind,
+    pub ts: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalRoomEvent {
+    pub user_id: Uuid,
+    pub room_id: String,
+    pub cell: String,
+    pub neighbours: Vec<String>,
+    pub left_room_id: Option<String>,
+    pub ts: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModerationAction {
+    Mute,
+    Unmute,
+    Ban,
+    Unban,
+    SettingsChanged,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationEvent {
+    pub room_id: String,
+    pub actor: Uuid,
+    pub action: ModerationAction,
+    pub target_user: Option<Uuid>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub ts: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NotificationKind {
+    Mention,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotificationEvent {
+    pub id: i64,
+    pub user_id: Uuid,
+    pub kind: NotificationKind,
+    pub room_id: String,
+    pub message_id: i64,
+    pub from_user: Uuid,
+    pub preview: String,
+    pub ts: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxEnvelope {
+    pub id: i64,
+    pub user_id: Uuid,
+    pub packet: Box<RealtimePacket>,
+    pub ts: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxAck {
+    pub up_to: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageRedaction {
+    pub room_id: String,
+    pub message_id: i64,
+    pub ts: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollOption {
+    pub text: String,
+    #[serde(default)]
+    pub votes: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollState {
+    pub id: i64,
+    pub room_id: String,
+    #[serde(default)]
+    pub message_id: Option<i64>,
+    pub created_by: Uuid,
+    pub question: String,
+    pub options: Vec<PollOption>,
+    pub multi_choice: bool,
+    #[serde(default)]
+    pub closes_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub closed: bool,
+    #[serde(default)]
+    pub voters: i64,
+    pub ts: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollVote {
+    pub poll_id: i64,
+    pub user_id: Uuid,
+    #[serde(default)]
+    pub choices: Vec<u32>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RealtimePacket {
     Position(PositionUpdate),
     Chat(ChatMessage),
     Invite(InviteEvent),
     Heartbeat,
+    Typing(TypingEvent),
+    Receipt(ReceiptEvent),
+    LocalRoom(LocalRoomEvent),
+    Moderation(ModerationEvent),
+    Notification(NotificationEvent),
+    Mailbox(MailboxEnvelope),
+    MailboxAck(MailboxAck),
+    Poll(PollState),
+    PollVote(PollVote),
+    Redaction(MessageRedaction),
+    MatchReady(MatchReady),
 }
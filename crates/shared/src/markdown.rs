@@ -0,0 +1,366 @@
+// A deliberately small markdown subset for chat. Parsing produces plain data that
+// renderers turn into text nodes, so raw HTML in a message can never reach the DOM.
+
+const SAFE_SCHEMES: [&str; 3] = ["https://", "http://", "mailto:"];
+const MAX_LANG_LEN: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inline {
+    Text(String),
+    Code(String),
+    Strong(String),
+    Emphasis(String),
+    Link { label: String, url: String },
+    Break,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Block {
+    Paragraph(Vec<Inline>),
+    Heading(u8, Vec<Inline>),
+    Quote(Vec<Inline>),
+    List { ordered: bool, items: Vec<Vec<Inline>> },
+    Code { lang: Option<String>, text: String },
+}
+
+pub fn safe_url(url: &str) -> Option<&str> {
+    let url = url.trim();
+    let lower = url.to_ascii_lowercase();
+    let allowed = SAFE_SCHEMES
+        .iter()
+        .any(|scheme| lower.starts_with(scheme) && url.len() > scheme.len());
+    (allowed && !url.chars().any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '<' || c == '>')).then_some(url)
+}
+
+fn heading(line: &str) -> Option<(u8, &str)> {
+    let level = line.bytes().take_while(|b| *b == b'#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    line[level..].strip_prefix(' ').map(|rest| (level as u8, rest.trim()))
+}
+
+fn list_item(line: &str) -> Option<(bool, &str)> {
+    let trimmed = line.trim_start();
+    for marker in ["- ", "* ", "+ "] {
+        if let Some(rest) = trimmed.strip_prefix(marker) {
+            return Some((false, rest));
+        }
+    }
+    let digits = trimmed.bytes().take_while(u8::is_ascii_digit).count();
+    if (1..=9).contains(&digits) {
+        if let Some(rest) = trimmed[digits..].strip_prefix(". ") {
+            return Some((true, rest));
+        }
+    }
+    None
+}
+
+fn quote(line: &str) -> Option<&str> {
+    let rest = line.trim_start().strip_prefix('>')?;
+    Some(rest.strip_prefix(' ').unwrap_or(rest))
+}
+
+fn fence(line: &str) -> Option<&str> {
+    line.trim_start().strip_prefix("```").map(str::trim)
+}
+
+pub fn parse(text: &str) -> Vec<Block> {
+    let lines = text.lines().collect::<Vec<_>>();
+    let mut blocks = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+
+        if let Some(info) = fence(line) {
+            let lang = info
+                .split_whitespace()
+                .next()
+                .filter(|l| l.len() <= MAX_LANG_LEN && l.chars().all(|c| c.is_ascii_alphanumeric() || "+-#._".contains(c)))
+                .map(str::to_string);
+            let mut body = Vec::new();
+            i += 1;
+            while i < lines.len() && fence(lines[i]).is_none() {
+                body.push(lines[i]);
+                i += 1;
+            }
+            // Skip the closing fence; an unterminated block runs to the end of the message.
+            i += 1;
+            blocks.push(Block::Code { lang, text: body.join("\n") });
+            continue;
+        }
+
+        if line.trim().is_empty() {
+            i += 1;
+            continue;
+        }
+
+        if let Some((level, rest)) = heading(line) {
+            blocks.push(Block::Heading(level, parse_inline(rest)));
+            i += 1;
+            continue;
+        }
+
+        if quote(line).is_some() {
+            let mut inlines = Vec::new();
+            while let Some(rest) = lines.get(i).and_then(|l| quote(l)) {
+                if !inlines.is_empty() {
+                    inlines.push(Inline::Break);
+                }
+                inlines.extend(parse_inline(rest));
+                i += 1;
+            }
+            blocks.push(Block::Quote(inlines));
+            continue;
+        }
+
+        if let Some((ordered, _)) = list_item(line) {
+            let mut items = Vec::new();
+            while let Some((kind, rest)) = lines.get(i).and_then(|l| list_item(l)) {
+                if kind != ordered {
+                    break;
+                }
+                items.push(parse_inline(rest));
+                i += 1;
+            }
+            blocks.push(Block::List { ordered, items });
+            continue;
+        }
+
+        let mut inlines = Vec::new();
+        while let Some(line) = lines.get(i) {
+            if line.trim().is_empty() || fence(line).is_some() || heading(line).is_some() || quote(line).is_some() || list_item(line).is_some() {
+                break;
+            }
+            if !inlines.is_empty() {
+                inlines.push(Inline::Break);
+            }
+            inlines.extend(parse_inline(line));
+            i += 1;
+        }
+        blocks.push(Block::Paragraph(inlines));
+    }
+    blocks
+}
+
+fn push_text(out: &mut Vec<Inline>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    if let Some(Inline::Text(last)) = out.last_mut() {
+        last.push_str(text);
+    } else {
+        out.push(Inline::Text(text.to_string()));
+    }
+}
+
+// Finds where `close` starts after `from`, rejecting empty spans like `**` or a lone backtick pair.
+fn closing(s: &str, from: usize, close: &str) -> Option<usize> {
+    let end = s[from..].find(close)? + from;
+    (end > from).then_some(end)
+}
+
+fn autolink_end(s: &str) -> usize {
+    let end = s.find(char::is_whitespace).unwrap_or(s.len());
+    s[..end].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '"', '\'']).len()
+}
+
+pub fn parse_inline(s: &str) -> Vec<Inline> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    let mut text_start = 0;
+
+    while i < s.len() {
+        let rest = &s[i..];
+        let prev_is_word = s[..i].chars().next_back().is_some_and(char::is_alphanumeric);
+
+        let token = if let Some(escaped) = rest.strip_prefix('\\').and_then(|r| r.chars().next()).filter(|c| c.is_ascii_punctuation()) {
+            push_text(&mut out, &s[text_start..i]);
+            push_text(&mut out, &escaped.to_string());
+            Some(i + 1 + escaped.len_utf8())
+        } else if rest.starts_with('`') {
+            closing(s, i + 1, "`").map(|end| {
+                push_text(&mut out, &s[text_start..i]);
+                out.push(Inline::Code(s[i + 1..end].to_string()));
+                end + 1
+            })
+        } else if rest.starts_with("**") {
+            closing(s, i + 2, "**").map(|end| {
+                push_text(&mut out, &s[text_start..i]);
+                out.push(Inline::Strong(s[i + 2..end].to_string()));
+                end + 2
+            })
+        } else if rest.starts_with('*') && !prev_is_word && !rest[1..].starts_with(char::is_whitespace) {
+            closing(s, i + 1, "*").filter(|end| !s[..*end].ends_with(char::is_whitespace)).map(|end| {
+                push_text(&mut out, &s[text_start..i]);
+                out.push(Inline::Emphasis(s[i + 1..end].to_string()));
+                end + 1
+            })
+        } else if rest.starts_with('[') {
+            rest.find("](").and_then(|mid| {
+                let label = &rest[1..mid];
+                let close = rest[mid + 2..].find(')')? + mid + 2;
+                let url = safe_url(&rest[mid + 2..close])?;
+                if label.is_empty() || label.contains('[') {
+                    return None;
+                }
+                push_text(&mut out, &s[text_start..i]);
+                out.push(Inline::Link {
+                    label: label.to_string(),
+                    url: url.to_string(),
+                });
+                Some(i + close + 1)
+            })
+        } else if !prev_is_word && (rest.starts_with("https://") || rest.starts_with("http://")) {
+            let len = autolink_end(rest);
+            safe_url(&rest[..len]).map(|url| {
+                push_text(&mut out, &s[text_start..i]);
+                out.push(Inline::Link {
+                    label: url.to_string(),
+                    url: url.to_string(),
+                });
+                i + len
+            })
+        } else {
+            None
+        };
+
+        match token {
+            Some(next) => {
+                i = next;
+                text_start = next;
+            }
+            None => i += rest.chars().next().map_or(1, char::len_utf8),
+        }
+    }
+    push_text(&mut out, &s[text_start..]);
+    out
+}
+
+fn inline_plain(inlines: &[Inline], out: &mut String) {
+    for inline in inlines {
+        match inline {
+            Inline::Text(t) | Inline::Code(t) | Inline::Strong(t) | Inline::Emphasis(t) => out.push_str(t),
+            Inline::Link { label, .. } => out.push_str(label),
+            Inline::Break => out.push(' '),
+        }
+    }
+}
+
+pub fn to_plain(text: &str) -> String {
+    let mut out = String::new();
+    for block in parse(text) {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        match block {
+            Block::Paragraph(inlines) | Block::Heading(_, inlines) | Block::Quote(inlines) => inline_plain(&inlines, &mut out),
+            Block::List { items, .. } => {
+                for (n, item) in items.iter().enumerate() {
+                    if n > 0 {
+                        out.push(' ');
+                    }
+                    inline_plain(item, &mut out);
+                }
+            }
+            Block::Code { text, .. } => out.push_str(&text.split_whitespace().collect::<Vec<_>>().join(" ")),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has_link(inlines: &[Inline]) -> bool {
+        inlines.iter().any(|inline| matches!(inline, Inline::Link { .. }))
+    }
+
+    #[test]
+    fn script_and_data_urls_are_rejected() {
+        for url in [
+            "javascript:alert(1)",
+            "JavaScript:alert(1)",
+            "  jAvAsCrIpT:alert(1)",
+            "\tjavascript:alert(1)",
+            "data:text/html;base64,PHNjcmlwdD4=",
+            "DATA:text/html,<script>",
+            "vbscript:msgbox",
+            "https://",
+        ] {
+            assert_eq!(safe_url(url), None, "{url}");
+        }
+        assert_eq!(safe_url("  HTTPS://example.com/a "), Some("HTTPS://example.com/a"));
+        assert_eq!(safe_url("mailto:someone@example.com"), Some("mailto:someone@example.com"));
+    }
+
+    #[test]
+    fn quotes_and_angle_brackets_never_reach_a_url() {
+        for url in ["https://a.com/\"onmouseover=alert(1)", "https://a.com/<script>", "https://a.com/>x", "https://a.com/ x"] {
+            assert_eq!(safe_url(url), None, "{url}");
+        }
+        for text in [
+            "[x](javascript:alert(1))",
+            "[x]( JavaScript:alert(1))",
+            "[x](data:text/html,hi)",
+            "[x](https://a.com/\"><img src=x>)",
+            "see https://a.com/<b>bold</b>",
+        ] {
+            assert!(!has_link(&parse_inline(text)), "{text}");
+        }
+        assert_eq!(
+            parse_inline("[docs](https://a.com/x)"),
+            vec![Inline::Link {
+                label: "docs".to_string(),
+                url: "https://a.com/x".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn unclosed_markers_stay_text() {
+        for text in ["**bold", "*lean", "`code", "[label](", "a ** b"] {
+            assert_eq!(parse_inline(text), vec![Inline::Text(text.to_string())], "{text}");
+        }
+        assert_eq!(
+            parse_inline("**a** *b* `c`"),
+            vec![
+                Inline::Strong("a".to_string()),
+                Inline::Text(" ".to_string()),
+                Inline::Emphasis("b".to_string()),
+                Inline::Text(" ".to_string()),
+                Inline::Code("c".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unclosed_fence_runs_to_the_end() {
+        assert_eq!(
+            parse("```rust\nlet x = 1;\n**not bold**"),
+            vec![Block::Code {
+                lang: Some("rust".to_string()),
+                text: "let x = 1;\n**not bold**".to_string(),
+            }]
+        );
+        assert_eq!(
+            parse("```<script>\nx\n```"),
+            vec![Block::Code {
+                lang: None,
+                text: "x".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn plain_text_drops_markup() {
+        assert_eq!(
+            to_plain("# Title\n**bold** and [link](https://x.y)\n- a\n- b\n```\ncode   here\n```"),
+            "Title bold and link a b code here"
+        );
+        assert_eq!(to_plain("[x](javascript:alert(1))"), "[x](javascript:alert(1))");
+        assert_eq!(to_plain(""), "");
+    }
+}
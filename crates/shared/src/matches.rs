@@ -0,0 +1,37 @@
+use uuid::Uuid;
+
+use crate::MatchState;
+
+pub const MATCH_SUBJECT_PREFIX: &str = "match.";
+pub const MATCH_WS_PATH: &str = "/ws/match";
+
+pub fn channel(match_id: Uuid) -> String {
+    format!("{MATCH_SUBJECT_PREFIX}{match_id}")
+}
+
+pub fn ws_path(match_id: Uuid) -> String {
+    format!("{MATCH_WS_PATH}?match_id={match_id}")
+}
+
+pub fn as_str(state: MatchState) -> &'static str {
+    match state {
+        MatchState::Waiting => "waiting",
+        MatchState::Running => "running",
+        MatchState::Finished => "finished",
+        MatchState::Abandoned => "abandoned",
+    }
+}
+
+pub fn parse(raw: &str) -> Option<MatchState> {
+    match raw {
+        "waiting" => Some(MatchState::Waiting),
+        "running" => Some(MatchState::Running),
+        "finished" => Some(MatchState::Finished),
+        "abandoned" => Some(MatchState::Abandoned),
+        _ => None,
+    }
+}
+
+pub fn is_live(state: MatchState) -> bool {
+    matches!(state, MatchState::Waiting | MatchState::Running)
+}
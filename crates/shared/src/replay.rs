@@ -0,0 +1,53 @@
+use crate::{InputRun, PlayerInput, Replay};
+
+pub const REPLAY_MAGIC: &[u8; 4] = b"RPL1";
+pub const REPLAY_VERSION: u8 = 1;
+pub const KEYFRAME_EVERY: u32 = 150;
+
+pub fn pack_input(input: &PlayerInput) -> u8 {
+    let axis = |v: i8| (v.clamp(-1, 1) + 1) as u8;
+    axis(input.move_x) | (axis(input.move_y) << 2) | ((input.fire as u8) << 4)
+}
+
+pub fn unpack_input(byte: u8, seq: u32) -> PlayerInput {
+    PlayerInput {
+        seq,
+        move_x: (byte & 0b11) as i8 - 1,
+        move_y: ((byte >> 2) & 0b11) as i8 - 1,
+        fire: byte & 0b1_0000 != 0,
+    }
+}
+
+pub fn push_frame(replay: &mut Replay, inputs: &[PlayerInput]) {
+    let packed = inputs.iter().map(pack_input).collect::<Vec<_>>();
+    match replay.runs.last_mut() {
+        Some(run) if run.inputs == packed => run.len += 1,
+        _ => replay.runs.push(InputRun { len: 1, inputs: packed }),
+    }
+    replay.frames += 1;
+}
+
+pub fn frame_inputs(replay: &Replay) -> impl Iterator<Item = Vec<PlayerInput>> + '_ {
+    replay
+        .runs
+        .iter()
+        .flat_map(|run| std::iter::repeat_n(&run.inputs, run.len as usize))
+        .enumerate()
+        .map(|(frame, packed)| packed.iter().map(|b| unpack_input(*b, frame as u32)).collect())
+}
+
+pub fn encode(replay: &Replay) -> Option<Vec<u8>> {
+    let mut out = REPLAY_MAGIC.to_vec();
+    out.push(REPLAY_VERSION);
+    out.extend(rmp_serde::to_vec(replay).ok()?);
+    Some(out)
+}
+
+pub fn decode(bytes: &[u8]) -> Option<Replay> {
+    let body = bytes.strip_prefix(REPLAY_MAGIC.as_slice())?;
+    let (version, body) = body.split_first()?;
+    if *version != REPLAY_VERSION {
+        return None;
+    }
+    rmp_serde::from_slice(body).ok()
+}
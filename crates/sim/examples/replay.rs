@@ -0,0 +1,50 @@
+// Headless playback of a recorded match: decodes the file, resimulates it from inputs
+// alone and checks every keyframe.
+//
+//   cargo run -p sim --example replay -- path/to/<match_id>.rpl
+
+fn main() {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: replay <file.rpl>");
+        std::process::exit(2);
+    };
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("{path}: {err}");
+            std::process::exit(1);
+        }
+    };
+    let Some(replay) = shared::replay::decode(&bytes) else {
+        eprintln!("{path}: not a replay or unsupported version");
+        std::process::exit(1);
+    };
+
+    println!(
+        "match {} mode {} players {:?} frames {} ({}s) keyframes {} forfeits {}",
+        replay.match_id,
+        replay.mode,
+        replay.players,
+        replay.frames,
+        replay.frames / replay.tick_hz.max(1),
+        replay.keyframes.len(),
+        replay.forfeits.len(),
+    );
+    match sim::replay::verify(&replay) {
+        Ok(state) => {
+            let winner = match state.outcome() {
+                Some(sim::Outcome::Winner(slot)) => replay.players.get(slot).copied(),
+                _ => None,
+            };
+            let hp = state.players.iter().map(|p| p.hp).collect::<Vec<_>>();
+            println!("verified: tick {} hp {hp:?} winner {winner:?}", state.tick);
+            if winner != replay.winner {
+                println!("recorded winner differs: {:?}", replay.winner);
+            }
+        }
+        Err(mismatch) => {
+            println!("mismatch at tick {}", mismatch.tick);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{Fx, Outcome};
+
+pub const TICK_HZ: u32 = 30;
+pub const MAX_TICKS: u32 = TICK_HZ * 180;
+pub const ARENA_W: Fx = Fx::from_int(800);
+pub const ARENA_H: Fx = Fx::from_int(600);
+pub const PLAYER_RADIUS: Fx = Fx::from_int(16);
+pub const MAX_HP: i32 = 100;
+pub const BULLET_RADIUS: Fx = Fx::from_int(4);
+pub const BULLET_DAMAGE: i32 = 20;
+pub const BULLET_TTL_TICKS: u32 = 60;
+pub const FIRE_COOLDOWN_TICKS: u32 = 8;
+pub const BULLET_KIND: u8 = 1;
+
+// Speeds are per tick: 180 and 420 units per second at 30Hz.
+const PLAYER_STEP: Fx = Fx::from_int(6);
+const BULLET_STEP: Fx = Fx::from_int(14);
+const MUZZLE: Fx = Fx::from_int(21);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Player {
+    pub x: Fx,
+    pub y: Fx,
+    pub vx: Fx,
+    pub vy: Fx,
+    pub aim: (i8, i8),
+    pub hp: i32,
+    pub score: i32,
+    pub cooldown: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bullet {
+    pub id: u32,
+    pub owner: usize,
+    pub x: Fx,
+    pub y: Fx,
+    pub vx: Fx,
+    pub vy: Fx,
+    pub ttl: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub tick: u32,
+    pub players: [Player; 2],
+    pub bullets: Vec<Bullet>,
+    pub next_id: u32,
+}
+
+pub fn valid(input: &shared::PlayerInput) -> bool {
+    (-1..=1).contains(&input.move_x) && (-1..=1).contains(&input.move_y)
+}
+
+// Scales a unit step along one of the eight directions, keeping diagonals the same length.
+fn along(dir: (i8, i8), step: Fx) -> (Fx, Fx) {
+    let step = if dir.0 != 0 && dir.1 != 0 { step.diagonal() } else { step };
+    (step * dir.0 as i32, step * dir.1 as i32)
+}
+
+// Movement for one player over one tick. Clients run this for prediction, so it must stay
+// the exact rule `State::step` applies.
+pub fn move_player(x: Fx, y: Fx, input: &shared::PlayerInput) -> (Fx, Fx, Fx, Fx) {
+    let (vx, vy) = along((input.move_x.signum(), input.move_y.signum()), PLAYER_STEP);
+    let x = (x + vx).clamp(PLAYER_RADIUS, ARENA_W - PLAYER_RADIUS);
+    let y = (y + vy).clamp(PLAYER_RADIUS, ARENA_H - PLAYER_RADIUS);
+    (x, y, vx, vy)
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    pub fn new() -> Self {
+        let spawn = |x: i32, aim: i8| Player {
+            x: Fx::from_int(x),
+            y: Fx::from_int(300),
+            vx: Fx::ZERO,
+            vy: Fx::ZERO,
+            aim: (aim, 0),
+            hp: MAX_HP,
+            score: 0,
+            cooldown: 0,
+        };
+        Self {
+            tick: 0,
+            players: [spawn(160, 1), spawn(640, -1)],
+            bullets: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn step(&mut self, inputs: &[shared::PlayerInput]) {
+        self.tick += 1;
+
+        for (slot, player) in self.players.iter_mut().enumerate() {
+            let input = inputs.get(slot).copied().filter(valid).unwrap_or_default();
+            player.cooldown = player.cooldown.saturating_sub(1);
+            if player.hp <= 0 {
+                player.vx = Fx::ZERO;
+                player.vy = Fx::ZERO;
+                continue;
+            }
+
+            (player.x, player.y, player.vx, player.vy) = move_player(player.x, player.y, &input);
+            if input.move_x != 0 || input.move_y != 0 {
+                player.aim = (input.move_x, input.move_y);
+            }
+
+            if input.fire && player.cooldown == 0 {
+                player.cooldown = FIRE_COOLDOWN_TICKS;
+                let (ox, oy) = along(player.aim, MUZZLE);
+                let (vx, vy) = along(player.aim, BULLET_STEP);
+                self.bullets.push(Bullet {
+                    id: self.next_id,
+                    owner: slot,
+                    x: player.x + ox,
+                    y: player.y + oy,
+                    vx,
+                    vy,
+                    ttl: BULLET_TTL_TICKS,
+                });
+                self.next_id += 1;
+            }
+        }
+
+        let players = &mut self.players;
+        self.bullets.retain_mut(|bullet| {
+            bullet.x += bullet.vx;
+            bullet.y += bullet.vy;
+            bullet.ttl = bullet.ttl.saturating_sub(1);
+            if bullet.ttl == 0 || !(Fx::ZERO..=ARENA_W).contains(&bullet.x) || !(Fx::ZERO..=ARENA_H).contains(&bullet.y) {
+                return false;
+            }
+            let target = 1 - bullet.owner;
+            let (dx, dy) = (players[target].x - bullet.x, players[target].y - bullet.y);
+            let reach = PLAYER_RADIUS + BULLET_RADIUS;
+            if players[target].hp > 0 && dx.squared() + dy.squared() <= reach.squared() {
+                players[target].hp -= BULLET_DAMAGE;
+                players[bullet.owner].score += 1;
+                return false;
+            }
+            true
+        });
+    }
+
+    pub fn forfeit(&mut self, slot: usize) {
+        if let Some(player) = self.players.get_mut(slot) {
+            player.hp = 0;
+        }
+    }
+
+    pub fn outcome(&self) -> Option<Outcome> {
+        match (self.players[0].hp > 0, self.players[1].hp > 0) {
+            (true, false) => return Some(Outcome::Winner(0)),
+            (false, true) => return Some(Outcome::Winner(1)),
+            (false, false) => return Some(Outcome::Draw),
+            (true, true) => {}
+        }
+        if self.tick < MAX_TICKS {
+            return None;
+        }
+        Some(match self.players[0].hp.cmp(&self.players[1].hp) {
+            std::cmp::Ordering::Greater => Outcome::Winner(0),
+            std::cmp::Ordering::Less => Outcome::Winner(1),
+            std::cmp::Ordering::Equal => Outcome::Draw,
+        })
+    }
+
+    // FNV-1a over every field that affects future ticks. Equal checksums on two machines
+    // after the same inputs mean the simulations agree.
+    pub fn checksum(&self) -> u64 {
+        let mut hash = crate::Checksum::default();
+        hash.write(self.tick as i64);
+        hash.write(self.next_id as i64);
+        for p in &self.players {
+            for v in [p.x.0, p.y.0, p.vx.0, p.vy.0, p.aim.0 as i32, p.aim.1 as i32, p.hp, p.score] {
+                hash.write(v as i64);
+            }
+            hash.write(p.cooldown as i64);
+        }
+        for b in &self.bullets {
+            hash.write(b.id as i64);
+            hash.write(b.owner as i64);
+            for v in [b.x.0, b.y.0, b.vx.0, b.vy.0] {
+                hash.write(v as i64);
+            }
+            hash.write(b.ttl as i64);
+        }
+        hash.finish()
+    }
+
+    // Snapshot velocities are per second so clients can extrapolate with wall-clock time.
+    pub fn players(&self) -> Vec<shared::PlayerSnapshot> {
+        self.players
+            .iter()
+            .map(|p| shared::PlayerSnapshot {
+                x: p.x.to_f32(),
+                y: p.y.to_f32(),
+                vx: (p.vx * TICK_HZ as i32).to_f32(),
+                vy: (p.vy * TICK_HZ as i32).to_f32(),
+                hp: p.hp,
+                score: p.score,
+            })
+            .collect()
+    }
+
+    pub fn entities(&self) -> Vec<shared::EntitySnapshot> {
+        self.bullets
+            .iter()
+            .map(|b| shared::EntitySnapshot {
+                id: b.id,
+                kind: BULLET_KIND,
+                owner: b.owner as u8,
+                x: b.x.to_f32(),
+                y: b.y.to_f32(),
+                vx: (b.vx * TICK_HZ as i32).to_f32(),
+                vy: (b.vy * TICK_HZ as i32).to_f32(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fixed pseudo-random input stream: the same (seed, tick, slot) always gives the same input.
+    fn scripted(seed: u32, tick: u32, slot: usize) -> shared::PlayerInput {
+        let mut x = seed ^ tick.wrapping_mul(2_654_435_761) ^ (slot as u32).wrapping_mul(40_503);
+        x ^= x >> 13;
+        x = x.wrapping_mul(0x5bd1_e995);
+        x ^= x >> 15;
+        shared::PlayerInput {
+            seq: tick,
+            move_x: (x % 3) as i8 - 1,
+            move_y: ((x / 3) % 3) as i8 - 1,
+            fire: (x / 9).is_multiple_of(2),
+        }
+    }
+
+    fn run(seed: u32, ticks: u32) -> Vec<u64> {
+        let mut state = State::new();
+        (0..ticks)
+            .map(|tick| {
+                state.step(&[scripted(seed, tick, 0), scripted(seed, tick, 1)]);
+                state.checksum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn identical_inputs_give_identical_checksums() {
+        assert_eq!(run(7, 900), run(7, 900));
+    }
+
+    #[test]
+    fn different_inputs_diverge() {
+        assert_ne!(run(7, 900).last(), run(8, 900).last());
+    }
+}
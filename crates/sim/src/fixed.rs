@@ -0,0 +1,79 @@
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+
+// Q8 fixed point: 1/256 of a unit. Arena coordinates stay below 2^24 raw, so every value
+// converts to f32 and back exactly and snapshots can keep their float fields.
+pub const FRAC_BITS: u32 = 8;
+pub const ONE: i32 = 1 << FRAC_BITS;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fx(pub i32);
+
+impl Fx {
+    pub const ZERO: Fx = Fx(0);
+
+    pub const fn from_int(v: i32) -> Fx {
+        Fx(v << FRAC_BITS)
+    }
+
+    pub fn from_f32(v: f32) -> Fx {
+        Fx((v * ONE as f32).round() as i32)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / ONE as f32
+    }
+
+    // 181/256 ≈ 1/√2, used for diagonal movement without any float math.
+    pub const fn diagonal(self) -> Fx {
+        Fx(self.0 * 181 / 256)
+    }
+
+    pub fn squared(self) -> i64 {
+        let v = self.0 as i64;
+        v * v
+    }
+}
+
+impl Add for Fx {
+    type Output = Fx;
+
+    fn add(self, rhs: Fx) -> Fx {
+        Fx(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Fx {
+    fn add_assign(&mut self, rhs: Fx) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for Fx {
+    type Output = Fx;
+
+    fn sub(self, rhs: Fx) -> Fx {
+        Fx(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Fx {
+    fn sub_assign(&mut self, rhs: Fx) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Neg for Fx {
+    type Output = Fx;
+
+    fn neg(self) -> Fx {
+        Fx(-self.0)
+    }
+}
+
+impl Mul<i32> for Fx {
+    type Output = Fx;
+
+    fn mul(self, rhs: i32) -> Fx {
+        Fx(self.0 * rhs)
+    }
+}
@@ -0,0 +1,37 @@
+// Game rules shared by the server runtime and the wasm client. Everything here is integer
+// math over fixed-point values, so the same inputs give bit-identical states on any target.
+
+mod fixed;
+
+pub mod duel;
+pub mod replay;
+pub mod rollback;
+
+pub use fixed::Fx;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Winner(usize),
+    Draw,
+}
+
+pub struct Checksum(u64);
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Checksum(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Checksum {
+    pub fn write(&mut self, value: i64) {
+        for byte in value.to_le_bytes() {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
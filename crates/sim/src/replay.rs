@@ -0,0 +1,156 @@
+use shared::{Forfeit, PlayerInput, Replay};
+
+use crate::duel;
+
+// Steps a recorded duel frame by frame from its input stream and forfeits alone. Keyframes
+// in the recording are only used to check that the result still matches what was played.
+pub struct Playback {
+    state: duel::State,
+    frames: Vec<Vec<PlayerInput>>,
+    forfeits: Vec<Forfeit>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    pub tick: u32,
+}
+
+impl Playback {
+    pub fn new(replay: &Replay) -> Option<Self> {
+        if replay.mode != "duel" || replay.players.len() != 2 {
+            return None;
+        }
+        let mut playback = Self {
+            state: duel::State::new(),
+            frames: shared::replay::frame_inputs(replay).collect(),
+            forfeits: replay.forfeits.clone(),
+        };
+        playback.settle();
+        Some(playback)
+    }
+
+    pub fn state(&self) -> &duel::State {
+        &self.state
+    }
+
+    pub fn finished(&self) -> bool {
+        self.state.tick as usize >= self.frames.len()
+    }
+
+    pub fn step(&mut self) -> bool {
+        let Some(inputs) = self.frames.get(self.state.tick as usize) else {
+            return false;
+        };
+        self.state.step(inputs);
+        self.settle();
+        true
+    }
+
+    // Forfeits were applied by the runtime after the step that reached their tick.
+    fn settle(&mut self) {
+        let tick = self.state.tick;
+        for forfeit in self.forfeits.iter().filter(|f| f.tick == tick) {
+            self.state.forfeit(forfeit.slot as usize);
+        }
+    }
+}
+
+// Replays the whole recording and compares each keyframe against the resimulated state.
+pub fn verify(replay: &Replay) -> Result<duel::State, Mismatch> {
+    let Some(mut playback) = Playback::new(replay) else {
+        return Err(Mismatch { tick: 0 });
+    };
+    let mut keyframes = replay.keyframes.iter().peekable();
+    loop {
+        while let Some(key) = keyframes.next_if(|k| k.tick <= playback.state.tick) {
+            let state = &playback.state;
+            if key.tick != state.tick || key.players != state.players() || key.entities != state.entities() {
+                return Err(Mismatch { tick: key.tick });
+            }
+        }
+        if !playback.step() {
+            return Ok(playback.state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(tick: u32, slot: usize) -> PlayerInput {
+        let phase = tick / 20 + slot as u32;
+        PlayerInput {
+            seq: tick,
+            move_x: (phase % 3) as i8 - 1,
+            move_y: ((phase / 3) % 3) as i8 - 1,
+            fire: tick.is_multiple_of(5),
+        }
+    }
+
+    // Records a duel the way the runtime does: one frame of inputs per step and a keyframe
+    // every `KEYFRAME_EVERY` ticks, with player 1 forfeiting near the end.
+    fn record(frames: u32, forfeit_at: u32) -> (Replay, duel::State) {
+        let mut replay = Replay {
+            match_id: uuid::Uuid::new_v4(),
+            mode: "duel".to_string(),
+            players: vec![uuid::Uuid::new_v4(), uuid::Uuid::new_v4()],
+            tick_hz: duel::TICK_HZ,
+            frames: 0,
+            runs: Vec::new(),
+            keyframes: Vec::new(),
+            forfeits: Vec::new(),
+            winner: None,
+            recorded_at: chrono::Utc::now(),
+        };
+        let mut state = duel::State::new();
+        for tick in 0..frames {
+            let inputs = [input(tick, 0), input(tick, 1)];
+            state.step(&inputs);
+            shared::replay::push_frame(&mut replay, &inputs);
+            if state.tick == forfeit_at {
+                state.forfeit(1);
+                replay.forfeits.push(Forfeit { tick: state.tick, slot: 1 });
+            }
+            if state.tick.is_multiple_of(shared::replay::KEYFRAME_EVERY) {
+                replay.keyframes.push(shared::GameSnapshot {
+                    match_id: replay.match_id,
+                    tick: state.tick,
+                    tick_hz: replay.tick_hz,
+                    acks: Vec::new(),
+                    players: state.players(),
+                    entities: state.entities(),
+                    finished: false,
+                    winner: None,
+                });
+            }
+        }
+        (replay, state)
+    }
+
+    #[test]
+    fn encoded_replay_verifies() {
+        let (replay, played) = record(700, 640);
+        let bytes = shared::replay::encode(&replay).unwrap();
+        let decoded = shared::replay::decode(&bytes).unwrap();
+        assert_eq!(decoded.frames, 700);
+        assert_eq!(decoded.keyframes.len(), replay.keyframes.len());
+
+        let verified = verify(&decoded).unwrap();
+        assert_eq!(verified, played);
+        assert_eq!(verified.checksum(), played.checksum());
+
+        let mut playback = Playback::new(&decoded).unwrap();
+        while playback.step() {}
+        assert!(playback.finished());
+        assert_eq!(playback.state(), &played);
+    }
+
+    #[test]
+    fn altered_keyframe_is_reported() {
+        let (mut replay, _) = record(700, 640);
+        replay.keyframes[1].players[0].x += 1.0;
+        let decoded = shared::replay::decode(&shared::replay::encode(&replay).unwrap()).unwrap();
+        assert_eq!(verify(&decoded), Err(Mismatch { tick: replay.keyframes[1].tick }));
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use shared::{FrameChecksum, FrameInputs, PlayerInput};
+
+use crate::duel;
+
+// Local inputs apply this many frames after they are sampled, which hides most of the
+// round trip before any rollback is needed.
+pub const INPUT_DELAY: u32 = 2;
+// How far the simulation may run ahead of the last confirmed remote input. Also the
+// number of saved states kept for rewinding.
+pub const MAX_ROLLBACK: u32 = 8;
+const CHECKSUM_HISTORY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Desync {
+    pub frame: u32,
+    pub local: u64,
+    pub remote: u64,
+}
+
+// Frames before the input delay have no sampled input on either side.
+fn is_blank(frame: u32) -> bool {
+    frame < INPUT_DELAY
+}
+
+fn same(a: &PlayerInput, b: &PlayerInput) -> bool {
+    (a.move_x, a.move_y, a.fire) == (b.move_x, b.move_y, b.fire)
+}
+
+// One player's view of a two-player duel. Remote inputs are predicted by repeating the
+// last confirmed one; when a confirmed input disagrees, the state is rewound to that frame
+// and resimulated. Frame numbers equal `State::tick`: frame `f` steps the state at tick `f`.
+pub struct Session {
+    local: usize,
+    state: duel::State,
+    saved: VecDeque<duel::State>,
+    inputs: [BTreeMap<u32, PlayerInput>; 2],
+    predicted: BTreeMap<u32, PlayerInput>,
+    remote_next: u32,
+    remote_ack: u32,
+    rollback_to: Option<u32>,
+    confirmed: u32,
+    checksums: VecDeque<FrameChecksum>,
+    remote_check: Option<FrameChecksum>,
+}
+
+impl Session {
+    pub fn new(local: usize) -> Self {
+        Self {
+            local: local.min(1),
+            state: duel::State::new(),
+            saved: VecDeque::new(),
+            inputs: [BTreeMap::new(), BTreeMap::new()],
+            predicted: BTreeMap::new(),
+            remote_next: INPUT_DELAY,
+            remote_ack: INPUT_DELAY,
+            rollback_to: None,
+            confirmed: 0,
+            checksums: VecDeque::new(),
+            remote_check: None,
+        }
+    }
+
+    pub fn local_slot(&self) -> usize {
+        self.local
+    }
+
+    pub fn frame(&self) -> u32 {
+        self.state.tick
+    }
+
+    pub fn confirmed_frame(&self) -> u32 {
+        self.confirmed
+    }
+
+    pub fn state(&self) -> &duel::State {
+        &self.state
+    }
+
+    // Lets tools corrupt one side on purpose to reproduce a desync. Saved states get the
+    // same change so a rollback cannot quietly undo it.
+    pub fn tamper(&mut self, change: impl Fn(&mut duel::State)) {
+        change(&mut self.state);
+        self.saved.iter_mut().for_each(change);
+    }
+
+    pub fn can_advance(&self) -> bool {
+        self.frame() < self.remote_next + MAX_ROLLBACK
+    }
+
+    // Samples the local input for `frame() + INPUT_DELAY` and simulates one frame,
+    // rewinding first if a remote input proved a prediction wrong. Does nothing while stalled.
+    pub fn advance(&mut self, input: PlayerInput) -> Result<(), Desync> {
+        if !self.can_advance() {
+            return Ok(());
+        }
+        let target = self.frame() + INPUT_DELAY;
+        self.inputs[self.local].insert(
+            target,
+            PlayerInput {
+                seq: target,
+                ..input
+            },
+        );
+
+        if let Some(from) = self.rollback_to.take() {
+            let back = (self.frame() - from) as usize;
+            if back <= self.saved.len() {
+                let until = self.frame();
+                let index = self.saved.len() - back;
+                self.state = self.saved[index].clone();
+                self.saved.truncate(index);
+                while self.frame() < until {
+                    self.simulate();
+                }
+            }
+        }
+        self.simulate();
+        self.confirm()
+    }
+
+    fn simulate(&mut self) {
+        let frame = self.frame();
+        let inputs = [self.input_for(0, frame), self.input_for(1, frame)];
+        self.saved.push_back(self.state.clone());
+        while self.saved.len() > MAX_ROLLBACK as usize {
+            self.saved.pop_front();
+        }
+        self.state.step(&inputs);
+    }
+
+    fn input_for(&mut self, slot: usize, frame: u32) -> PlayerInput {
+        if is_blank(frame) {
+            return PlayerInput::default();
+        }
+        if let Some(input) = self.inputs[slot].get(&frame) {
+            return *input;
+        }
+        let guess = self.inputs[slot].values().next_back().copied().unwrap_or_default();
+        if slot != self.local {
+            self.predicted.insert(frame, guess);
+        }
+        guess
+    }
+
+    fn state_at(&self, frame: u32) -> Option<&duel::State> {
+        let back = self.frame().checked_sub(frame)? as usize;
+        match back {
+            0 => Some(&self.state),
+            _ => self.saved.len().checked_sub(back).map(|i| &self.saved[i]),
+        }
+    }
+
+    // A frame is final once both players' inputs for every earlier frame are known.
+    fn confirm(&mut self) -> Result<(), Desync> {
+        let upto = self.frame().min(self.remote_next);
+        while self.confirmed < upto {
+            self.confirmed += 1;
+            if let Some(state) = self.state_at(self.confirmed) {
+                self.checksums.push_back(FrameChecksum {
+                    frame: self.confirmed,
+                    value: state.checksum(),
+                });
+            }
+        }
+        while self.checksums.len() > CHECKSUM_HISTORY {
+            self.checksums.pop_front();
+        }
+        self.check()
+    }
+
+    fn check(&mut self) -> Result<(), Desync> {
+        let Some(remote) = self.remote_check else {
+            return Ok(());
+        };
+        if let Some(local) = self.checksums.iter().find(|c| c.frame == remote.frame) {
+            self.remote_check = None;
+            if local.value != remote.value {
+                return Err(Desync {
+                    frame: remote.frame,
+                    local: local.value,
+                    remote: remote.value,
+                });
+            }
+        } else if self.checksums.front().is_some_and(|c| remote.frame < c.frame) {
+            self.remote_check = None;
+        }
+        Ok(())
+    }
+
+    // Takes the peer's inputs, acknowledgement and latest checksum. Packets repeat every
+    // unacknowledged input, so duplicates are skipped and a gap waits for the next packet.
+    pub fn receive(&mut self, packet: &FrameInputs) -> Result<(), Desync> {
+        let remote = 1 - self.local;
+        if packet.slot as usize != remote {
+            return Ok(());
+        }
+        for (frame, input) in (packet.start..).zip(&packet.inputs) {
+            if frame < self.remote_next {
+                continue;
+            }
+            if frame > self.remote_next {
+                break;
+            }
+            self.inputs[remote].insert(frame, *input);
+            self.remote_next += 1;
+            if let Some(guess) = self.predicted.remove(&frame) {
+                if !same(&guess, input) {
+                    self.rollback_to = Some(self.rollback_to.map_or(frame, |f| f.min(frame)));
+                }
+            }
+        }
+        self.remote_ack = self.remote_ack.max(packet.ack);
+
+        let keep_from = self.frame().saturating_sub(MAX_ROLLBACK + 1);
+        let local_from = keep_from.min(self.remote_ack);
+        self.inputs[self.local].retain(|frame, _| *frame >= local_from);
+        let remote_from = keep_from.min(self.remote_next.saturating_sub(1));
+        self.inputs[remote].retain(|frame, _| *frame >= remote_from);
+
+        if let Some(check) = packet.checksum {
+            if self.remote_check.is_none_or(|c| c.frame < check.frame) {
+                self.remote_check = Some(check);
+            }
+        }
+        self.check()
+    }
+
+    // Everything the peer has not acknowledged yet, plus the newest confirmed checksum.
+    pub fn outgoing(&self) -> FrameInputs {
+        let inputs = self.inputs[self.local]
+            .range(self.remote_ack..)
+            .map(|(_, input)| *input)
+            .collect::<Vec<_>>();
+        FrameInputs {
+            slot: self.local as u8,
+            start: self.remote_ack,
+            inputs,
+            ack: self.remote_next,
+            checksum: self.checksums.back().copied(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Changes often enough that the repeat-last-input prediction keeps missing.
+    fn sampled(frame: u32, slot: usize) -> PlayerInput {
+        let phase = frame / (3 + 2 * slot as u32);
+        PlayerInput {
+            seq: 0,
+            move_x: (phase % 3) as i8 - 1,
+            move_y: ((phase / 3) % 3) as i8 - 1,
+            fire: phase.is_multiple_of(2),
+        }
+    }
+
+    // The same duel stepped once per frame with every input known up front.
+    fn straight(frames: u32) -> duel::State {
+        let mut state = duel::State::new();
+        while state.tick < frames {
+            let frame = state.tick;
+            let inputs = if is_blank(frame) {
+                [PlayerInput::default(); 2]
+            } else {
+                [sampled(frame - INPUT_DELAY, 0), sampled(frame - INPUT_DELAY, 1)]
+            };
+            state.step(&inputs);
+        }
+        state
+    }
+
+    #[test]
+    fn resimulation_matches_straight_run() {
+        const LATENCY: usize = 4;
+        let mut sessions = [Session::new(0), Session::new(1)];
+        let mut links: [VecDeque<FrameInputs>; 2] = [VecDeque::new(), VecDeque::new()];
+        let mut rollbacks = 0;
+
+        for _ in 0..400 {
+            for slot in 0..2 {
+                if links[1 - slot].len() > LATENCY {
+                    let packet = links[1 - slot].pop_front().unwrap();
+                    sessions[slot].receive(&packet).unwrap();
+                }
+                rollbacks += sessions[slot].rollback_to.is_some() as u32;
+                let frame = sessions[slot].frame();
+                sessions[slot].advance(sampled(frame, slot)).unwrap();
+                links[slot].push_back(sessions[slot].outgoing());
+            }
+        }
+        assert!(rollbacks > 0, "the delayed link never forced a rollback");
+
+        // With the link drained every frame, each side confirms up to where it stands.
+        for _ in 0..MAX_ROLLBACK * 2 {
+            for slot in 0..2 {
+                let packet = sessions[1 - slot].outgoing();
+                sessions[slot].receive(&packet).unwrap();
+                let frame = sessions[slot].frame();
+                sessions[slot].advance(sampled(frame, slot)).unwrap();
+            }
+        }
+        for session in &sessions {
+            assert_eq!(session.confirmed_frame(), session.frame());
+            let expected = straight(session.frame());
+            assert_eq!(session.state(), &expected);
+            assert_eq!(session.state().checksum(), expected.checksum());
+        }
+    }
+}
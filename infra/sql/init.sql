@@ -1,6 +1,11 @@
 CREATE EXTENSION IF NOT EXISTS postgis;
 CREATE EXTENSION IF NOT EXISTS pgcrypto;
 
+CREATE TABLE IF NOT EXISTS schema_migrations (
+  name text PRIMARY KEY,
+  applied_at timestamptz NOT NULL DEFAULT now()
+);
+
 CREATE TABLE IF NOT EXISTS users (
   id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
   username text UNIQUE NOT NULL,
@@ -29,6 +34,193 @@ CREATE TABLE IF NOT EXISTS room_member_reads (
   PRIMARY KEY (room_id, user_id)
 );
 
+ALTER TABLE room_messages
+  ADD COLUMN IF NOT EXISTS parent_id bigint REFERENCES room_messages(id) ON DELETE CASCADE;
+
+ALTER TABLE room_messages
+  ADD COLUMN IF NOT EXISTS search_vector tsvector;
+
+ALTER TABLE room_messages
+  ADD COLUMN IF NOT EXISTS client_id uuid;
+
+ALTER TABLE room_messages
+  ADD COLUMN IF NOT EXISTS e2ee jsonb;
+
+ALTER TABLE room_messages
+  ADD COLUMN IF NOT EXISTS format text NOT NULL DEFAULT 'plain';
+
+ALTER TABLE room_messages
+  ADD COLUMN IF NOT EXISTS event jsonb;
+
+ALTER TABLE room_messages
+  ADD COLUMN IF NOT EXISTS expires_at timestamptz;
+
+ALTER TABLE room_messages
+  ADD COLUMN IF NOT EXISTS redacted_at timestamptz;
+
+CREATE TABLE IF NOT EXISTS user_key_bundles (
+  user_id uuid PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  identity_key text NOT NULL,
+  prekey_id bigint NOT NULL,
+  prekey text NOT NULL,
+  prekey_signature text NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+ALTER TABLE room_member_reads
+  ADD COLUMN IF NOT EXISTS last_read_message_id bigint;
+
+ALTER TABLE room_member_reads
+  ADD COLUMN IF NOT EXISTS last_delivered_message_id bigint;
+
+CREATE TABLE IF NOT EXISTS room_thread_reads (
+  room_id text NOT NULL,
+  parent_id bigint NOT NULL REFERENCES room_messages(id) ON DELETE CASCADE,
+  user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  last_read_at timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (parent_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS room_memberships (
+  room_id text NOT NULL,
+  user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  joined_at timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (room_id, user_id)
+);
+
+ALTER TABLE room_memberships
+  ADD COLUMN IF NOT EXISTS unread_count integer NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS room_stats (
+  room_id text PRIMARY KEY,
+  message_count bigint NOT NULL DEFAULT 0,
+  last_message_id bigint,
+  last_from_user uuid REFERENCES users(id) ON DELETE SET NULL,
+  last_preview text NOT NULL DEFAULT '',
+  last_activity_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS room_moderators (
+  room_id text NOT NULL,
+  user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  granted_by uuid REFERENCES users(id) ON DELETE SET NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (room_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS room_sanctions (
+  room_id text NOT NULL,
+  user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  kind text NOT NULL,
+  reason text,
+  expires_at timestamptz,
+  created_by uuid REFERENCES users(id) ON DELETE SET NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (room_id, user_id, kind)
+);
+
+CREATE TABLE IF NOT EXISTS room_settings (
+  room_id text PRIMARY KEY,
+  slow_mode_secs integer NOT NULL DEFAULT 0,
+  filter_action text NOT NULL DEFAULT 'reject',
+  blocked_words text[] NOT NULL DEFAULT '{}',
+  blocked_patterns text[] NOT NULL DEFAULT '{}',
+  updated_by uuid REFERENCES users(id) ON DELETE SET NULL,
+  updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS moderation_log (
+  id bigserial PRIMARY KEY,
+  room_id text NOT NULL,
+  actor uuid REFERENCES users(id) ON DELETE SET NULL,
+  action text NOT NULL,
+  target_user uuid REFERENCES users(id) ON DELETE SET NULL,
+  details jsonb NOT NULL DEFAULT '{}'::jsonb,
+  created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS chat_attachments (
+  id uuid PRIMARY KEY,
+  room_id text NOT NULL,
+  uploader uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  message_id bigint REFERENCES room_messages(id) ON DELETE CASCADE,
+  object_key text NOT NULL,
+  file_name text NOT NULL,
+  mime_type text NOT NULL,
+  size_bytes bigint NOT NULL,
+  width integer,
+  height integer,
+  status text NOT NULL DEFAULT 'pending',
+  created_at timestamptz NOT NULL DEFAULT now(),
+  uploaded_at timestamptz
+);
+
+CREATE TABLE IF NOT EXISTS notifications (
+  id bigserial PRIMARY KEY,
+  user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  kind text NOT NULL,
+  room_id text NOT NULL,
+  message_id bigint NOT NULL REFERENCES room_messages(id) ON DELETE CASCADE,
+  from_user uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  preview text NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  read_at timestamptz,
+  UNIQUE (user_id, message_id, kind)
+);
+
+CREATE TABLE IF NOT EXISTS user_mailbox (
+  id bigserial PRIMARY KEY,
+  user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  kind text NOT NULL,
+  payload bytea NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  expires_at timestamptz NOT NULL,
+  acked_at timestamptz
+);
+
+ALTER TABLE user_mailbox
+  ADD COLUMN IF NOT EXISTS message_id bigint;
+
+CREATE TABLE IF NOT EXISTS polls (
+  id bigserial PRIMARY KEY,
+  room_id text NOT NULL,
+  message_id bigint REFERENCES room_messages(id) ON DELETE SET NULL,
+  created_by uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  question text NOT NULL,
+  options text[] NOT NULL,
+  multi_choice boolean NOT NULL DEFAULT false,
+  closes_at timestamptz,
+  closed_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS poll_ballots (
+  poll_id bigint NOT NULL REFERENCES polls(id) ON DELETE CASCADE,
+  user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  choices int[] NOT NULL,
+  voted_at timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (poll_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS scheduled_messages (
+  id bigserial PRIMARY KEY,
+  room_id text NOT NULL,
+  from_user uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  message text NOT NULL,
+  format text NOT NULL DEFAULT 'plain',
+  parent_id bigint REFERENCES room_messages(id) ON DELETE CASCADE,
+  client_id uuid NOT NULL,
+  ttl_secs integer,
+  send_at timestamptz NOT NULL,
+  status text NOT NULL DEFAULT 'pending',
+  attempts integer NOT NULL DEFAULT 0,
+  claimed_at timestamptz,
+  last_error text,
+  message_id bigint REFERENCES room_messages(id) ON DELETE SET NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  UNIQUE (from_user, client_id)
+);
+
 CREATE TABLE IF NOT EXISTS invites (
   id uuid PRIMARY KEY,
   from_user uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
@@ -39,11 +231,168 @@ CREATE TABLE IF NOT EXISTS invites (
   responded_at timestamptz
 );
 
+ALTER TABLE invites
+  ADD COLUMN IF NOT EXISTS expires_at timestamptz NOT NULL DEFAULT now() + interval '5 minutes';
+
+UPDATE invites
+SET status = 'expired', responded_at = now()
+WHERE status = 'pending'
+  AND id NOT IN (
+    SELECT DISTINCT ON (from_user, to_user) id
+    FROM invites
+    WHERE status = 'pending'
+    ORDER BY from_user, to_user, created_at DESC
+  );
+
+CREATE TABLE IF NOT EXISTS matches (
+  id uuid PRIMARY KEY,
+  invite_id uuid UNIQUE REFERENCES invites(id) ON DELETE SET NULL,
+  mode text NOT NULL,
+  state text NOT NULL DEFAULT 'waiting',
+  channel text NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  started_at timestamptz,
+  ended_at timestamptz
+);
+
+ALTER TABLE matches
+  ADD COLUMN IF NOT EXISTS winner_id uuid REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE TABLE IF NOT EXISTS player_ratings (
+  user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  mode text NOT NULL,
+  rating double precision NOT NULL DEFAULT 1500,
+  games integer NOT NULL DEFAULT 0,
+  updated_at timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (user_id, mode)
+);
+
+ALTER TABLE player_ratings
+  ADD COLUMN IF NOT EXISTS rd double precision NOT NULL DEFAULT 350,
+  ADD COLUMN IF NOT EXISTS volatility double precision NOT NULL DEFAULT 0.06,
+  ADD COLUMN IF NOT EXISTS wins integer NOT NULL DEFAULT 0,
+  ADD COLUMN IF NOT EXISTS losses integer NOT NULL DEFAULT 0,
+  ADD COLUMN IF NOT EXISTS draws integer NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS match_results (
+  match_id uuid NOT NULL REFERENCES matches(id) ON DELETE CASCADE,
+  user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  mode text NOT NULL,
+  slot smallint NOT NULL,
+  outcome text NOT NULL,
+  rating_before double precision NOT NULL,
+  rating_after double precision NOT NULL,
+  rd_after double precision NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (match_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS match_players (
+  match_id uuid NOT NULL REFERENCES matches(id) ON DELETE CASCADE,
+  user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  slot smallint NOT NULL,
+  joined_at timestamptz,
+  PRIMARY KEY (match_id, user_id),
+  UNIQUE (match_id, slot)
+);
+
+CREATE TABLE IF NOT EXISTS match_replays (
+  match_id uuid PRIMARY KEY REFERENCES matches(id) ON DELETE CASCADE,
+  object_key text NOT NULL,
+  format_version smallint NOT NULL,
+  tick_hz integer NOT NULL,
+  frames integer NOT NULL,
+  size_bytes integer NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT now()
+);
+
 CREATE INDEX IF NOT EXISTS idx_user_locations_gist
   ON user_locations USING GIST (location);
 
 CREATE INDEX IF NOT EXISTS idx_room_messages_room_time
   ON room_messages (room_id, created_at DESC);
 
+CREATE UNIQUE INDEX IF NOT EXISTS idx_room_messages_client_id
+  ON room_messages (from_user, client_id)
+  WHERE client_id IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_room_messages_parent_time
+  ON room_messages (parent_id, created_at DESC)
+  WHERE parent_id IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_room_messages_search
+  ON room_messages USING GIN (search_vector);
+
+CREATE INDEX IF NOT EXISTS idx_chat_attachments_message
+  ON chat_attachments (message_id)
+  WHERE message_id IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_chat_attachments_expired
+  ON chat_attachments (id)
+  WHERE status = 'expired';
+
+CREATE INDEX IF NOT EXISTS idx_room_memberships_user
+  ON room_memberships (user_id);
+
+CREATE INDEX IF NOT EXISTS idx_moderation_log_room_time
+  ON moderation_log (room_id, created_at DESC);
+
+CREATE INDEX IF NOT EXISTS idx_room_thread_reads_user
+  ON room_thread_reads (room_id, user_id);
+
+CREATE INDEX IF NOT EXISTS idx_notifications_user_time
+  ON notifications (user_id, id DESC);
+
+CREATE INDEX IF NOT EXISTS idx_notifications_user_unread
+  ON notifications (user_id)
+  WHERE read_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS idx_user_mailbox_pending
+  ON user_mailbox (user_id, id)
+  WHERE acked_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS idx_user_mailbox_message
+  ON user_mailbox (message_id)
+  WHERE message_id IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_polls_room_time
+  ON polls (room_id, created_at DESC);
+
+CREATE INDEX IF NOT EXISTS idx_polls_open_deadline
+  ON polls (closes_at)
+  WHERE closed_at IS NULL AND closes_at IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_room_messages_expiring
+  ON room_messages (expires_at)
+  WHERE expires_at IS NOT NULL AND redacted_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS idx_scheduled_messages_due
+  ON scheduled_messages (send_at)
+  WHERE status = 'pending';
+
+CREATE INDEX IF NOT EXISTS idx_scheduled_messages_user
+  ON scheduled_messages (from_user, send_at DESC);
+
 CREATE INDEX IF NOT EXISTS idx_invites_to_status
   ON invites (to_user, status, created_at DESC);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_invites_pending_pair
+  ON invites (from_user, to_user)
+  WHERE status = 'pending';
+
+CREATE INDEX IF NOT EXISTS idx_invites_pending_expiry
+  ON invites (expires_at)
+  WHERE status = 'pending';
+
+CREATE INDEX IF NOT EXISTS idx_match_players_user
+  ON match_players (user_id, match_id);
+
+CREATE INDEX IF NOT EXISTS idx_matches_live
+  ON matches (state, created_at)
+  WHERE state IN ('waiting', 'running');
+
+CREATE INDEX IF NOT EXISTS idx_match_results_user_time
+  ON match_results (user_id, created_at DESC);
+
+CREATE INDEX IF NOT EXISTS idx_player_ratings_mode_rating
+  ON player_ratings (mode, rating DESC);